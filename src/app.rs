@@ -1,20 +1,185 @@
-use std::collections::{HashMap, VecDeque};
-use std::fs;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{stdout, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
 
-use crate::expr::{Query, Rule, Term, Variable};
-use crate::parser::{parse_file_content, parse_query};
-use crate::unifier::{compose, search, unify, Subst};
+use crate::atom::{self, Atom};
+use crate::builtins;
+use crate::chr::{self, ChrRule};
+use crate::coroutine;
+use crate::debug::{self, Debugger, Port};
+use crate::error::Exception;
+use crate::expr::{Location, Query, Rule, Term, Variable};
+use crate::filter::AnswerFilter;
+use crate::flags::Flags;
+use crate::frame::{self, Frames, Kind};
+use crate::gc;
+use crate::limits;
+use crate::load::SourceFile;
+use crate::module::{self, qualify, Module};
+use crate::parser::parse_query;
+use crate::profile::Profiler;
+use crate::proof::{Failure, PartialProof, ProofTree, Steps};
+use crate::store::{Cell, Clause, Store, Trail};
+use crate::stream::Streams;
+use crate::unifier::{search, Subst};
+use crate::wam::{key_of, PredKey, Program};
+use crate::write::{term_to_string, WriteOptions};
+
+// memory use below which no garbage collection happens.
+const MIN_GC_THRESHOLD: usize = 8 << 20;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Engine {
+    // breadth-first resolution over `QueueItem`s.
+    Bfs,
+    // compiled clauses run on the abstract machine in `wam`.
+    Wam,
+}
 
 pub struct App {
     pub rules: Vec<Rule>,
     pub queue: VecDeque<QueueItem>,
     pub asked_vars: Vec<Variable>,
-    pub vars_count: i32,
+    pub engine: Engine,
+    pub flags: Flags,
+    // predicates declared with `dynamic/1`.
+    pub dynamic: HashSet<PredKey>,
+    // files consulted so far, by canonical path.
+    pub(crate) loaded: HashMap<PathBuf, SourceFile>,
+    // files being consulted, the innermost last.
+    pub(crate) loading: Vec<PathBuf>,
+    pub(crate) modules: HashMap<Atom, Module>,
+    // modules having clauses for `term_expansion/2` or `goal_expansion/2`.
+    pub(crate) hooks: HashSet<(Atom, PredKey)>,
+    // rules of `chr` and the constraints they are written with.
+    pub(crate) chr_rules: Rc<Vec<ChrRule>>,
+    pub(crate) chr_constraints: HashSet<PredKey>,
+    // module the running builtin was called from.
+    pub(crate) context: Atom,
+    // terms of the running query.
+    pub store: Store,
+    // goal list nodes allocated since the last garbage collection, plus the
+    // ones that survived it.
+    goal_nodes: usize,
+    // memory use at which the next garbage collection runs.
+    next_gc: usize,
+    // answers found by the running query.
+    answers: usize,
+    // answers of the running query reported so far, unless all are.
+    filter: AnswerFilter,
+    // values of `asked_vars` in the answers of the running query, when they
+    // are collected rather than printed.
+    collected: Option<Vec<Vec<Term>>>,
+    // proofs of the answers of the running query, when it is explained.
+    proofs: Option<Vec<Vec<ProofTree>>>,
+    // the deepest derivations that failed, while the query is explained.
+    pub(crate) failures: Option<Vec<PartialProof>>,
+    pub debugger: Debugger,
+    // where `user_output` writes.
+    pub output: Box<dyn Write>,
+    // the open streams and the current input and output.
+    pub(crate) streams: Streams,
+    // the frame entered by the running builtin, with the cells it refers
+    // to.
+    pub(crate) entered: Option<(Kind, Vec<Cell>)>,
+    // counts of the goal being resolved, if it runs under `profile/1`.
+    pub(crate) profiler: Option<Profiler>,
+    // `rules` written into cells and indexed by predicate, rebuilt lazily
+    // after they change.
+    database: Option<Rc<Database>>,
+    // compiled form of `rules` for the abstract machine.
+    program: Option<Program>,
+}
+
+struct Database {
+    clauses: Vec<Clause>,
+    locations: Vec<Option<Location>>,
+    // clauses of each predicate, by module.
+    index: HashMap<(Atom, PredKey), Vec<usize>>,
+}
+
+impl Database {
+    fn new(rules: &[Rule]) -> Database {
+        let mut index: HashMap<(Atom, PredKey), Vec<usize>> = HashMap::new();
+        for (i, rule) in rules.iter().enumerate() {
+            if let Some(key) = key_of(&rule.lhs) {
+                index.entry((rule.module, key)).or_default().push(i);
+            }
+        }
+        Database {
+            // the goals in the body of a clause run in its module.
+            clauses: rules
+                .iter()
+                .map(|rule| {
+                    if rule.module == module::user() {
+                        return Clause::new(rule);
+                    }
+                    Clause::new(&Rule {
+                        rhs: rule
+                            .rhs
+                            .iter()
+                            .map(|goal| qualify(rule.module, goal.clone()))
+                            .collect(),
+                        ..rule.clone()
+                    })
+                })
+                .collect(),
+            locations: rules.iter().map(|rule| rule.location).collect(),
+            index,
+        }
+    }
+}
+
+// stack of goals left to prove, resolved from left to right so that
+// arithmetic sees the bindings of the goals before it. Queue items share the
+// goals below their own.
+#[derive(Clone, Default)]
+pub struct Goals(pub(crate) Option<Rc<GoalNode>>);
+
+// a goal, the index of the clause whose body it comes from (none for the
+// query) and the goals after it.
+pub(crate) type GoalNode = (Cell, Option<usize>, Goals);
+
+impl Goals {
+    pub(crate) fn pop(&self) -> Option<GoalNode> {
+        self.0
+            .as_ref()
+            .map(|node| (node.0.clone(), node.1, node.2.clone()))
+    }
+
+    // pushes `goals` of `clause` so that the first one is on top.
+    pub(crate) fn push(&self, goals: Vec<Cell>, clause: Option<usize>) -> Goals {
+        goals.into_iter().rev().fold(self.clone(), |rest, goal| {
+            Goals(Some(Rc::new((goal, clause, rest))))
+        })
+    }
+}
+
+impl Drop for Goals {
+    // drops long goal lists iteratively to avoid overflowing the stack.
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(node) = next {
+            match Rc::try_unwrap(node) {
+                Ok((_, _, mut rest)) => next = rest.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
 }
 
 pub struct QueueItem {
-    goals: Vec<Term>,
-    subst: Subst,
+    pub(crate) goals: Goals,
+    pub(crate) trail: Trail,
+    // `$answer(...)` holding the variables asked in the query.
+    pub(crate) answer: Cell,
+    // steps that led to the item, recorded when the query is explained.
+    pub(crate) steps: Steps,
+    pub(crate) frames: Frames,
 }
 
 pub struct Status {
@@ -22,78 +187,114 @@ pub struct Status {
     pub subst: Subst,
 }
 
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl App {
     pub fn new() -> App {
         App {
             rules: Vec::new(),
             queue: VecDeque::new(),
             asked_vars: Vec::new(),
-            vars_count: 0,
+            engine: Engine::Bfs,
+            flags: Flags::new(),
+            dynamic: HashSet::new(),
+            loaded: HashMap::new(),
+            loading: Vec::new(),
+            modules: HashMap::new(),
+            hooks: HashSet::new(),
+            chr_rules: Rc::new(Vec::new()),
+            chr_constraints: HashSet::new(),
+            context: module::user(),
+            store: Store::new(),
+            goal_nodes: 0,
+            next_gc: MIN_GC_THRESHOLD,
+            answers: 0,
+            filter: AnswerFilter::default(),
+            collected: None,
+            proofs: None,
+            failures: None,
+            debugger: Debugger::new(),
+            output: Box::new(stdout()),
+            streams: Streams::new(),
+            entered: None,
+            profiler: None,
+            database: None,
+            program: None,
         }
     }
 
     pub fn handle_query(&mut self, input: &str) -> Status {
-        match parse_query(&input).expect("parse_query failed") {
-            Query::File(file) => {
-                let mut contents = fs::read_to_string(file).expect("No such file");
-                contents.retain(|c| !c.is_whitespace());
-
-                let (_, (new_rules, _)) =
-                    parse_file_content(&contents).expect("Error parsing file");
-                self.rules.extend_from_slice(&new_rules);
+        limits::clear_interrupt();
+        self.line_typed();
+        // `explain Query.` prints the proof of each answer after it.
+        let (input, explain) = match input.strip_prefix("explain ") {
+            Some(query) => (query, true),
+            None => (input, false),
+        };
+        let query = match parse_query(input) {
+            Ok(query) => query,
+            Err(e) => {
+                self.print(&format!("ERROR: Syntax error: {}\n", e.message));
+                return Status {
+                    done: false,
+                    subst: HashMap::new(),
+                };
+            }
+        };
+        match query {
+            Query::Files(files) => {
+                self.context = module::user();
+                for file in files {
+                    if let Err(e) = self.consult(&file) {
+                        self.fresh_line();
+                        self.print(&format!("ERROR: {}\n", e));
+                    }
+                }
                 Status {
                     done: true,
                     subst: HashMap::new(),
                 }
             }
             Query::Terms(goals) => {
-                self.asked_vars.append(&mut Term::free_vars_sum(&goals));
-                self.queue.push_back(QueueItem {
-                    goals,
-                    subst: HashMap::new(),
-                });
-
-                while let Some(QueueItem { mut goals, subst }) = self.queue.pop_front() {
-                    match &goals.pop() {
-                        None => {
-                            self.asked_vars.iter().for_each(|var| {
-                                search(&Term::Var(var.clone()), &subst)
-                                    .map(|t| println!("{} = {term}.", var, term = t.to_string()));
-                            });
-                            continue;
-                            // TODO: return here and wait for user input (continue or not)
-                            //return Status { done: false, subst };
+                for var in Term::free_vars_sum(&goals) {
+                    if !var.is_hidden() && !self.asked_vars.contains(&var) {
+                        self.asked_vars.push(var);
+                    }
+                }
+                if self.engine == Engine::Wam && !explain {
+                    return self.handle_query_wam(&goals);
+                }
+                if explain {
+                    self.proofs = Some(Vec::new());
+                    self.failures = Some(Vec::new());
+                }
+                let result = self.solve(&goals);
+                self.proofs = None;
+                let mut failures = self.failures.take().unwrap_or_default();
+                if let (Ok(0), false) = (&result, failures.is_empty()) {
+                    failures.sort_by_key(|failure| Reverse(failure.steps));
+                    self.print("% no answers, the deepest derivations failed at:\n");
+                    for (i, failure) in failures.into_iter().enumerate() {
+                        if i > 0 {
+                            self.print("\n");
                         }
-                        Some(goal) => {
-                            for rule in self.rules.iter() {
-                                match rule.lhs.var_to_term_map(&goal) {
-                                    None => continue,
-                                    Some(map) => {
-                                        let new_rule = rule.instantiate(&mut self.vars_count, &map);
-                                        let mut constraints =
-                                            vec![(goal.clone(), new_rule.lhs.clone())];
-                                        match unify(&mut constraints) {
-                                            Err(_) => continue,
-                                            Ok(sub) => {
-                                                let new_subst = compose(&sub, &subst);
-                                                let new_goals = vec![goals.clone(), new_rule.rhs]
-                                                    .concat()
-                                                    .iter()
-                                                    .map(|t| t.subst(&new_subst))
-                                                    .collect();
-                                                self.queue.push_back(QueueItem {
-                                                    goals: new_goals,
-                                                    subst: new_subst,
-                                                });
-                                            }
-                                        }
-                                    }
-                                };
-                            }
+                        for tree in failure.proofs {
+                            self.print(&tree.to_string());
                         }
                     }
                 }
-
+                if let Err((e, location)) = result {
+                    self.fresh_line();
+                    match location {
+                        _ if e == Exception::aborted() => self.print(&format!("% {}\n", e)),
+                        Some(location) => self.print(&format!("ERROR: {}: {}\n", location, e)),
+                        None => self.print(&format!("ERROR: {}\n", e)),
+                    }
+                }
                 Status {
                     done: true,
                     subst: HashMap::new(),
@@ -101,4 +302,535 @@ impl App {
             }
         }
     }
+
+    // must be called after changing `rules`.
+    pub(crate) fn rules_changed(&mut self) {
+        self.database = None;
+        self.program = None;
+    }
+
+    // runs `f` with the state of the running query set aside, e.g. to run
+    // the directives of a file consulted by the query.
+    pub(crate) fn aside<T>(&mut self, f: impl FnOnce(&mut App) -> T) -> T {
+        let store = mem::replace(&mut self.store, Store::new());
+        let queue = mem::take(&mut self.queue);
+        let asked_vars = mem::take(&mut self.asked_vars);
+        let (goal_nodes, next_gc, answers) = (self.goal_nodes, self.next_gc, self.answers);
+        let filter = mem::take(&mut self.filter);
+        let collected = self.collected.take();
+        let proofs = self.proofs.take();
+        let failures = self.failures.take();
+        let context = self.context;
+        let result = f(self);
+        self.context = context;
+        self.filter = filter;
+        self.collected = collected;
+        self.proofs = proofs;
+        self.failures = failures;
+        self.store = store;
+        self.queue = queue;
+        self.asked_vars = asked_vars;
+        self.goal_nodes = goal_nodes;
+        self.next_gc = next_gc;
+        self.answers = answers;
+        result
+    }
+
+    // the values of `vars` in the answers to `goals`, which are solved with
+    // the running query set aside.
+    pub(crate) fn answers(
+        &mut self,
+        vars: Vec<Variable>,
+        goals: &[Term],
+    ) -> Result<Vec<Vec<Term>>, (Exception, Option<Location>)> {
+        self.aside(|app| {
+            app.asked_vars = vars;
+            app.collected = Some(Vec::new());
+            let result = app.solve(goals);
+            let answers = app.collected.take().unwrap_or_default();
+            result.map(|_| answers)
+        })
+    }
+
+    // the proof trees of the answers to `goals`, one for each goal, which
+    // are solved with the running query set aside.
+    pub fn explain(
+        &mut self,
+        goals: &[Term],
+    ) -> Result<Vec<Vec<ProofTree>>, (Exception, Option<Location>)> {
+        self.aside(|app| {
+            app.collected = Some(Vec::new());
+            app.proofs = Some(Vec::new());
+            let result = app.solve(goals);
+            let proofs = app.proofs.take().unwrap_or_default();
+            result.map(|_| proofs)
+        })
+    }
+
+    // the derivations of `goals` that got the furthest before failing, the
+    // deepest first, which are solved with the running query set aside.
+    pub fn why_not(
+        &mut self,
+        goals: &[Term],
+    ) -> Result<Vec<PartialProof>, (Exception, Option<Location>)> {
+        self.aside(|app| {
+            app.collected = Some(Vec::new());
+            app.proofs = Some(Vec::new());
+            app.failures = Some(Vec::new());
+            let result = app.solve(goals);
+            let mut failures = app.failures.take().unwrap_or_default();
+            failures.sort_by_key(|failure| Reverse(failure.steps));
+            result.map(|_| failures)
+        })
+    }
+
+    // `rules` written into cells, built again if they changed.
+    fn database(&mut self) -> Rc<Database> {
+        let rules = &self.rules;
+        Rc::clone(
+            self.database
+                .get_or_insert_with(|| Rc::new(Database::new(rules))),
+        )
+    }
+
+    // solves the query and returns the number of answers, stopping at the
+    // first error raised, which is returned with the location of the clause
+    // the failing goal comes from.
+    pub(crate) fn solve(&mut self, goals: &[Term]) -> Result<usize, (Exception, Option<Location>)> {
+        self.store = Store::new();
+        // the first cell is a variable holding the state of the query in its
+        // attributes, such as the store of `chr`.
+        self.store.var();
+        self.goal_nodes = goals.len();
+        self.next_gc = MIN_GC_THRESHOLD;
+        self.answers = 0;
+        self.filter = AnswerFilter::default();
+
+        let mut vars = HashMap::new();
+        let answer = Term::Combined {
+            functor: atom::ANSWER,
+            args: self.asked_vars.iter().map(|v| Term::Var(*v)).collect(),
+        };
+        let answer = self.store.from_term(&answer, &mut vars);
+        let goals = goals
+            .iter()
+            .map(|goal| self.store.from_term(goal, &mut vars))
+            .collect::<Vec<_>>();
+        // the first step of an explained query stands for the query itself.
+        let mut steps = Steps::default();
+        if self.proofs.is_some() {
+            steps = steps.push(answer.clone(), None, goals.len());
+        }
+        self.enqueue(QueueItem {
+            goals: Goals::default().push(goals, None),
+            trail: self.store.trail(),
+            answer,
+            steps,
+            frames: Frames::default(),
+        });
+
+        while let Some(item) = self.queue.pop_front() {
+            // clauses are numbered in the database of the time the goal was
+            // added, which is the current one unless a file was consulted.
+            let database = self.database();
+            let clause = item.goals.0.as_ref().and_then(|node| node.1);
+            let location = clause.and_then(|i| database.locations.get(i).copied().flatten());
+            // what the item refers to once resolved, until it is left.
+            let mut resolved = QueueItem {
+                goals: Goals::default(),
+                trail: Trail::default(),
+                answer: item.answer.clone(),
+                steps: item.steps.clone(),
+                frames: item.frames.clone(),
+            };
+            let profiled = resolved.frames.profiled();
+            if let Some(frame) = &profiled {
+                self.profiler = frame.swap_profiler(None);
+            }
+            let started = self.profiler.as_ref().map(|_| Instant::now());
+            let result = match self.leave_exceeded(&item) {
+                Ok(false) => self.step(item),
+                left => left.map(|_| ()),
+            };
+            if let (Some(profiler), Some(started)) = (&mut self.profiler, started) {
+                profiler.step(started.elapsed());
+            }
+            if let Some(frame) = &profiled {
+                frame.swap_profiler(self.profiler.take());
+            }
+            let result = result
+                .map_err(|e| (e, location))
+                .and_then(|_| self.check_memory(&mut resolved).map_err(|e| (e, None)))
+                .and_then(|_| self.check_limits().map_err(|e| (e, None)))
+                .or_else(|(e, location)| {
+                    let QueueItem {
+                        frames,
+                        answer,
+                        steps,
+                        ..
+                    } = &resolved;
+                    self.raise(e, frames, answer, steps)
+                        .map_err(|e| (e, location))
+                })
+                .and_then(|_| {
+                    let QueueItem {
+                        frames,
+                        answer,
+                        steps,
+                        ..
+                    } = &resolved;
+                    self.leave(frames, answer, steps).map_err(|e| (e, None))
+                });
+            if let Err(e) = result {
+                self.queue.clear();
+                return Err(e);
+            }
+        }
+        Ok(self.answers)
+    }
+
+    // resolves the next goal of `item`, adding one item per alternative.
+    fn step(&mut self, item: QueueItem) -> Result<(), Exception> {
+        let QueueItem {
+            goals,
+            trail,
+            answer,
+            steps,
+            frames,
+        } = item;
+        self.store.switch_to(&trail);
+        let (goal, clause, rest) = match goals.pop() {
+            Some(next) => next,
+            None => {
+                let values: Vec<Term> = (self.store.args(&answer).iter())
+                    .map(|value| self.store.to_term(value))
+                    .collect();
+                if !self.filter.admit(self.flags.answer_mode(), &values) {
+                    return Ok(());
+                }
+                self.answers += 1;
+                let proof = self.proofs.as_ref().map(|_| self.proof(&steps, &answer));
+                if let Some(collected) = &mut self.collected {
+                    collected.push(values);
+                } else {
+                    self.print_answer(&answer);
+                    for tree in proof.iter().flatten() {
+                        self.print(&tree.to_string());
+                    }
+                }
+                if let (Some(proofs), Some(proof)) = (&mut self.proofs, proof) {
+                    proofs.push(proof);
+                }
+                // TODO: return here and wait for user input (continue or not)
+                return Ok(());
+            }
+        };
+        let (module, goal) = self.strip_module(module::user(), &goal)?;
+        let key = match self.store.key(&goal) {
+            Some(key) => key,
+            None => {
+                return Err(match self.store.deref(&goal) {
+                    Cell::Ref(_) => Exception::instantiation_error(),
+                    other => Exception::type_error("callable", self.store.to_term(&other)),
+                })
+            }
+        };
+        if frame::is_exit(key) {
+            return self.exit_frame(&rest, &trail, (&frames, &answer, &steps));
+        }
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.call(key);
+        }
+
+        // the depth of the goal, if its ports are to be shown.
+        let depth = match self.debugger.is_active() && !debug::is_mark(key) {
+            true => Some(self.depth(&rest)),
+            false => None,
+        };
+        if let Some(depth) = depth {
+            if self.port(Port::Call, depth, &goal, None)? {
+                self.port(Port::Fail, depth, &goal, None)?;
+                return Ok(());
+            }
+        }
+
+        if let Some(builtin) = builtins::lookup(key) {
+            self.context = module;
+            let args = self.store.args(&goal);
+            let capture = self.capture_output(&frames);
+            let mut result = builtin(self, &args);
+            if let Some(capture) = capture {
+                self.captured(capture, &mut result);
+            }
+            let frames = self.branch_frames(&frames, &rest, &trail);
+            let branches = match (result, depth) {
+                (Err(e), Some(depth)) => {
+                    self.store.switch_to(&trail);
+                    self.port(Port::Exception, depth, &goal, Some(&e))?;
+                    return Err(e);
+                }
+                (branches, _) => branches?,
+            };
+            if let Some(profiler) = &mut self.profiler {
+                profiler.inferences(key, branches.len());
+            }
+            if branches.is_empty() {
+                self.store.switch_to(&trail);
+                self.note_failure(&steps, &answer, &goal, Failure::Builtin);
+                if let Some(depth) = depth {
+                    self.port(Port::Fail, depth, &goal, None)?;
+                }
+            }
+            for (n, branch) in branches.into_iter().enumerate() {
+                // goals called by a builtin run in the module it was called
+                // from.
+                let mut goals = branch.goals;
+                if module != module::user() {
+                    goals = goals
+                        .into_iter()
+                        .map(|goal| self.qualify(module, goal))
+                        .collect();
+                }
+                self.store.switch_to(&branch.trail);
+                let woken = self.wakeups(&trail);
+                goals = [woken, goals].concat();
+                if let Some(depth) = depth {
+                    goals = self.mark_ports(goals, n, depth, &goal);
+                }
+                self.goal_nodes += goals.len();
+                self.enqueue(QueueItem {
+                    steps: self.record(&steps, &goal, None, goals.len()),
+                    goals: rest.push(goals, clause),
+                    trail: branch.trail,
+                    answer: answer.clone(),
+                    frames: frames.clone(),
+                });
+            }
+            return Ok(());
+        }
+
+        let database = self.database();
+        let defining = self.defining_module(&database, module, key);
+        let goal = self.qualify_meta_args(goal, module, defining, key);
+        let clauses = database.index.get(&(defining, key)).map_or(&[][..], |v| v);
+        let queued = self.queue.len();
+        for &i in clauses {
+            let len = self.store.len();
+            let (head, body) = self.store.instantiate(&database.clauses[i]);
+            if self.store.unify_head(&goal, &head, &database.clauses[i]) {
+                let mut body = [self.wakeups(&trail), body].concat();
+                if let Some(depth) = depth {
+                    body = self.mark_ports(body, self.queue.len() - queued, depth, &goal);
+                }
+                self.goal_nodes += body.len();
+                self.enqueue(QueueItem {
+                    steps: self.record(&steps, &goal, Some(i), body.len()),
+                    goals: rest.push(body, Some(i)),
+                    trail: self.store.trail(),
+                    answer: answer.clone(),
+                    frames: frames.clone(),
+                });
+                self.store.switch_to(&trail);
+            } else {
+                // nothing refers to the copy of a clause whose head did not
+                // match.
+                self.store.switch_to(&trail);
+                self.store.truncate(len);
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.unify_failure(key);
+                }
+            }
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.inferences(key, self.queue.len() - queued);
+        }
+        if self.queue.len() == queued {
+            let failure = match clauses {
+                [] => Failure::NoClauses(key),
+                _ => Failure::NoMatch,
+            };
+            self.note_failure(&steps, &answer, &goal, failure);
+            if let Some(depth) = depth {
+                self.port(Port::Fail, depth, &goal, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    // `steps` followed by the resolution of `goal`, if the query is
+    // explained.
+    fn record(&self, steps: &Steps, goal: &Cell, clause: Option<usize>, subgoals: usize) -> Steps {
+        match self.proofs {
+            Some(_) => steps.push(goal.clone(), clause, subgoals),
+            None => Steps::default(),
+        }
+    }
+
+    // the module `goal` runs in, given as `Module:Goal` or else `context`,
+    // and the goal without the qualification.
+    fn strip_module(&self, context: Atom, goal: &Cell) -> Result<(Atom, Cell), Exception> {
+        let (mut module, mut goal) = (context, goal.clone());
+        while let Some((colon, 2)) = self.store.key(&goal) {
+            if colon != atom::COLON {
+                break;
+            }
+            let args = self.store.args(&goal);
+            module = match self.store.deref(&args[0]) {
+                Cell::Atom(name) => name,
+                Cell::Ref(_) => return Err(Exception::instantiation_error()),
+                other => return Err(Exception::type_error("module", self.store.to_term(&other))),
+            };
+            goal = args[1].clone();
+        }
+        Ok((module, goal))
+    }
+
+    // `module:goal` as a cell.
+    pub(crate) fn qualify(&mut self, module: Atom, goal: Cell) -> Cell {
+        self.store
+            .compound(atom::COLON, vec![Cell::Atom(module), goal])
+    }
+
+    // the module whose clauses define `key` for goals running in `module`:
+    // that module if it has clauses for it, the one it was imported from, or
+    // else the same for `user`.
+    fn defining_module(&self, database: &Database, module: Atom, key: PredKey) -> Atom {
+        let mut candidates = vec![module];
+        if module != module::user() && module != module::system() {
+            candidates.push(module::user());
+        }
+        for candidate in candidates {
+            if database.index.contains_key(&(candidate, key)) {
+                return candidate;
+            }
+            if let Some(from) = self
+                .modules
+                .get(&candidate)
+                .and_then(|m| m.imports.get(&key))
+            {
+                return *from;
+            }
+        }
+        module
+    }
+
+    // `goal` with the arguments declared by `meta_predicate` qualified with
+    // the module it is called from, when defined in another module.
+    fn qualify_meta_args(
+        &mut self,
+        goal: Cell,
+        module: Atom,
+        defining: Atom,
+        key: PredKey,
+    ) -> Cell {
+        let spec = match self.modules.get(&defining).and_then(|m| m.meta.get(&key)) {
+            Some(spec) if defining != module => spec.clone(),
+            _ => return goal,
+        };
+        let args = self
+            .store
+            .args(&goal)
+            .into_iter()
+            .zip(spec)
+            .map(|(arg, meta)| match self.store.key(&arg) {
+                Some((atom::COLON, 2)) => arg,
+                _ if meta => self.qualify(module, arg),
+                _ => arg,
+            })
+            .collect();
+        self.store.compound(key.0, args)
+    }
+
+    // the names of the asked variables left unbound in `answer`.
+    pub(crate) fn var_names(&self, answer: &Cell) -> Subst {
+        let mut names = Subst::new();
+        for (var, cell) in self.asked_vars.iter().zip(self.store.args(answer)) {
+            if let Term::Var(fresh) = self.store.to_term(&cell) {
+                names.entry(fresh).or_insert(Term::Var(*var));
+            }
+        }
+        names
+    }
+
+    fn print_answer(&mut self, answer: &Cell) {
+        let values = self.store.args(answer);
+        let mut text = String::new();
+        for (var, cell) in self.asked_vars.iter().zip(&values) {
+            match self.store.to_term(cell) {
+                t if !t.has_free_var() => {
+                    let value = term_to_string(&t, WriteOptions::WRITEQ);
+                    text += &format!("{} = {}.\n", var, value);
+                }
+                _ => {}
+            }
+        }
+        // unbound variables keep their names in residual goals.
+        let names = self.var_names(answer);
+        let mut residual = coroutine::residual_goals(&self.store, &values);
+        residual.extend(chr::residual_goals(&self.store));
+        for goal in residual {
+            let goal = term_to_string(&goal.subst(&names), WriteOptions::WRITEQ);
+            text += &format!("{}.\n", goal);
+        }
+        if !text.is_empty() {
+            self.fresh_line();
+        }
+        self.print(&text);
+    }
+
+    // approximate number of bytes used by the running query.
+    pub fn memory_used(&self) -> usize {
+        self.store.memory_used()
+            + self.goal_nodes * mem::size_of::<GoalNode>()
+            + self.queue.len() * mem::size_of::<QueueItem>()
+    }
+
+    // collects garbage when the query grew enough since the last collection
+    // or hit `max_memory`, and raises a resource error if that did not help.
+    // `resolved` is the item just resolved.
+    fn check_memory(&mut self, resolved: &mut QueueItem) -> Result<(), Exception> {
+        let limit = self.flags.max_memory();
+        let used = self.memory_used();
+        if used < self.next_gc && limit.is_none_or(|limit| used <= limit) {
+            return Ok(());
+        }
+        let collected = gc::collect(&mut self.store, &mut self.queue, Some(resolved));
+        self.goal_nodes = collected.goal_nodes;
+        let used = self.memory_used();
+        self.next_gc = MIN_GC_THRESHOLD.max(2 * used);
+        match limit {
+            Some(limit) if used > limit => Err(Exception::resource_error("memory")),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_query_wam(&mut self, goals: &[Term]) -> Status {
+        if let Err(e) = self.solve_wam(goals) {
+            self.fresh_line();
+            self.print(&format!("ERROR: {}\n", e));
+        }
+        Status {
+            done: true,
+            subst: HashMap::new(),
+        }
+    }
+
+    fn solve_wam(&mut self, goals: &[Term]) -> Result<(), Exception> {
+        if self.program.is_none() {
+            self.program = Some(Program::compile(&self.rules)?);
+        }
+        let answers = self.program.as_mut().unwrap().run_query(goals)?;
+        let mut text = String::new();
+        for subst in answers {
+            for var in self.asked_vars.iter() {
+                if let Some(t) = search(&Term::Var(*var), &subst) {
+                    let value = term_to_string(&t, WriteOptions::WRITEQ);
+                    text += &format!("{} = {}.\n", var, value);
+                }
+            }
+        }
+        self.print(&text);
+        Ok(())
+    }
 }
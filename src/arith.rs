@@ -0,0 +1,516 @@
+// Evaluation of arithmetic expressions for `is/2` and the comparison
+// predicates. Integers are unbounded; integer division that is not exact
+// gives a rational or a float depending on the `prefer_rationals` flag, and
+// any float operand makes the result a float.
+
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::atom::{intern, Atom};
+use crate::bigint::BigInt;
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::flags::Flags;
+use crate::rational::Rational;
+use crate::store::{Cell, Store};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Int(BigInt),
+    // never integral.
+    Rational(Rational),
+    Float(f64),
+}
+
+impl Number {
+    fn from_rational(r: Rational) -> Number {
+        if r.is_integer() {
+            Number::Int(r.numerator().clone())
+        } else {
+            Number::Rational(r)
+        }
+    }
+
+    // the exact value of an integer or rational.
+    fn to_rational(&self) -> Option<Rational> {
+        match self {
+            Number::Int(i) => Some(Rational::from_int(i.clone())),
+            Number::Rational(r) => Some(r.clone()),
+            Number::Float(_) => None,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(i) => i.to_f64(),
+            Number::Rational(r) => r.to_f64(),
+            Number::Float(f) => *f,
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self, Number::Float(_))
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(i) => i.is_zero(),
+            Number::Rational(_) => false,
+            Number::Float(f) => *f == 0.0,
+        }
+    }
+
+    pub fn to_cell(self) -> Cell {
+        match self {
+            Number::Int(i) => Cell::Int(Rc::new(i)),
+            Number::Rational(r) => Cell::Rational(Rc::new(r)),
+            Number::Float(f) => Cell::Float(f),
+        }
+    }
+
+    pub fn to_term(self) -> Term {
+        match self {
+            Number::Int(i) => Term::Const(Constant::Int(i)),
+            Number::Rational(r) => Term::Const(Constant::Rational(r)),
+            Number::Float(f) => Term::Const(Constant::Float(f)),
+        }
+    }
+}
+
+// None when either number is `nan`.
+pub fn compare(a: &Number, b: &Number) -> Option<Ordering> {
+    match (a, b) {
+        (Number::Int(a), Number::Int(b)) => Some(a.cmp(b)),
+        _ if a.is_float() || b.is_float() => a.to_f64().partial_cmp(&b.to_f64()),
+        _ => Some(a.to_rational().cmp(&b.to_rational())),
+    }
+}
+
+fn int(i: BigInt) -> Result<Number, Exception> {
+    Ok(Number::Int(i))
+}
+
+fn rational(r: Rational) -> Result<Number, Exception> {
+    Ok(Number::from_rational(r))
+}
+
+// a float computed from `args`. Operations on finite floats that overflow or
+// are undefined raise an error unless the flags ask for `inf` or `nan`.
+fn float(flags: &Flags, args: &[f64], f: f64) -> Result<Number, Exception> {
+    if args.iter().all(|a| a.is_finite()) {
+        if f.is_nan() && !flags.is("float_undefined", "nan") {
+            return Err(undefined());
+        }
+        if f.is_infinite() && !flags.is("float_overflow", "infinity") {
+            return Err(Exception::evaluation_error("float_overflow"));
+        }
+    }
+    Ok(Number::Float(f))
+}
+
+fn require_int(n: Number) -> Result<BigInt, Exception> {
+    match n {
+        Number::Int(i) => Ok(i),
+        n => Err(Exception::type_error("integer", n.to_term())),
+    }
+}
+
+fn require_rational(n: Number) -> Result<Rational, Exception> {
+    match n.to_rational() {
+        Some(r) => Ok(r),
+        None => Err(Exception::type_error("rational", n.to_term())),
+    }
+}
+
+fn zero_divisor() -> Exception {
+    Exception::evaluation_error("zero_divisor")
+}
+
+fn undefined() -> Exception {
+    Exception::evaluation_error("undefined")
+}
+
+// the integer a float rounds to with `round`, e.g. `f64::floor`.
+fn float_to_int(f: f64, round: fn(f64) -> f64) -> Result<Number, Exception> {
+    BigInt::from_f64(round(f))
+        .map(Number::Int)
+        .ok_or_else(undefined)
+}
+
+// the exact value of a finite float.
+fn float_to_rational(f: f64) -> Result<Rational, Exception> {
+    if !f.is_finite() {
+        return Err(undefined());
+    }
+    let bits = f.to_bits();
+    let (mantissa, exp) = match ((bits >> 52) & 0x7ff) as i32 {
+        0 => (bits & ((1 << 52) - 1), -1074),
+        exp => ((bits & ((1 << 52) - 1)) | (1 << 52), exp - 1075),
+    };
+    let mut num = BigInt::from(mantissa as i64);
+    if f < 0.0 {
+        num = num.neg();
+    }
+    let two = BigInt::from(2);
+    Ok(if exp >= 0 {
+        Rational::from_int(num.mul(&two.pow(exp as u32)))
+    } else {
+        Rational::new(num, two.pow(-exp as u32)).unwrap()
+    })
+}
+
+// the simplest rational that converts back to the same float, found by
+// expanding the float as a continued fraction.
+fn rationalize(f: f64) -> Result<Rational, Exception> {
+    let exact = float_to_rational(f)?;
+    let (mut p0, mut q0) = (BigInt::zero(), BigInt::one());
+    let (mut p1, mut q1) = (BigInt::one(), BigInt::zero());
+    let mut x = f;
+    for _ in 0..100 {
+        let a = match BigInt::from_f64(x.floor()) {
+            Some(a) => a,
+            None => break,
+        };
+        let p2 = a.mul(&p1).add(&p0);
+        let q2 = a.mul(&q1).add(&q0);
+        let r = Rational::new(p2.clone(), q2.clone()).unwrap();
+        if r.to_f64() == f {
+            return Ok(r);
+        }
+        x = 1.0 / (x - x.floor());
+        p0 = p1;
+        q0 = q1;
+        p1 = p2;
+        q1 = q2;
+    }
+    Ok(exact)
+}
+
+// exponents are limited to what fits the result in memory at all.
+fn exponent(i: &BigInt) -> Result<u32, Exception> {
+    i.abs()
+        .to_i64()
+        .and_then(|i| u32::try_from(i).ok())
+        .ok_or_else(|| Exception::resource_error("memory"))
+}
+
+fn evaluable(name: Atom, arity: usize) -> Exception {
+    Exception::type_error(
+        "evaluable",
+        Term::Combined {
+            functor: intern!("/"),
+            args: vec![
+                Term::Const(Constant::Name(name)),
+                Term::Const(Constant::Int(BigInt::from(arity as i64))),
+            ],
+        },
+    )
+}
+
+pub fn eval(store: &Store, flags: &Flags, cell: &Cell) -> Result<Number, Exception> {
+    match store.deref(cell) {
+        Cell::Int(i) => Ok(Number::Int((*i).clone())),
+        Cell::Rational(r) => Ok(Number::Rational((*r).clone())),
+        Cell::Float(f) => Ok(Number::Float(f)),
+        Cell::Ref(_) => Err(Exception::instantiation_error()),
+        Cell::Atom(name) => eval_constant(name),
+        Cell::Struct(a) => {
+            let (name, arity) = match store.cells[a] {
+                Cell::Functor(f, n) => (f, n),
+                _ => panic!("Structure without functor cell"),
+            };
+            let mut args = Vec::with_capacity(arity);
+            for arg in store.args(&Cell::Struct(a)) {
+                args.push(eval(store, flags, &arg)?);
+            }
+            let mut args = args.into_iter();
+            match arity {
+                1 => eval_unary(flags, name, args.next().unwrap()),
+                2 => eval_binary(flags, name, args.next().unwrap(), args.next().unwrap()),
+                _ => Err(evaluable(name, arity)),
+            }
+        }
+        other => Err(Exception::type_error("evaluable", store.to_term(&other))),
+    }
+}
+
+fn eval_constant(name: Atom) -> Result<Number, Exception> {
+    let f = match name.name() {
+        "pi" => std::f64::consts::PI,
+        "e" => std::f64::consts::E,
+        "inf" | "infinite" => f64::INFINITY,
+        "nan" => f64::NAN,
+        "epsilon" => f64::EPSILON,
+        _ => return Err(evaluable(name, 0)),
+    };
+    Ok(Number::Float(f))
+}
+
+fn eval_unary(flags: &Flags, name: Atom, x: Number) -> Result<Number, Exception> {
+    let f = x.to_f64();
+    let float_fn: fn(f64) -> f64 = match name.name() {
+        "sqrt" => f64::sqrt,
+        "sin" => f64::sin,
+        "cos" => f64::cos,
+        "tan" => f64::tan,
+        "asin" => f64::asin,
+        "acos" => f64::acos,
+        "atan" => f64::atan,
+        "sinh" => f64::sinh,
+        "cosh" => f64::cosh,
+        "tanh" => f64::tanh,
+        "asinh" => f64::asinh,
+        "acosh" => f64::acosh,
+        "atanh" => f64::atanh,
+        "exp" => f64::exp,
+        "log" | "log2" if f <= 0.0 && !f.is_nan() => return Err(undefined()),
+        "log" => f64::ln,
+        "log2" => f64::log2,
+        "float" => |f| f,
+        "float_integer_part" => f64::trunc,
+        "float_fractional_part" => f64::fract,
+        _ => return eval_exact_unary(name, x),
+    };
+    float(flags, &[f], float_fn(f))
+}
+
+// the unary functions that keep integers and rationals exact.
+fn eval_exact_unary(name: Atom, x: Number) -> Result<Number, Exception> {
+    match (name.name(), x) {
+        ("+", x) => Ok(x),
+        ("-", Number::Int(i)) => int(i.neg()),
+        ("-", Number::Rational(r)) => rational(r.neg()),
+        ("-", Number::Float(f)) => Ok(Number::Float(-f)),
+        ("abs", Number::Int(i)) => int(i.abs()),
+        ("abs", Number::Rational(r)) if r.numerator().is_negative() => rational(r.neg()),
+        ("abs", Number::Float(f)) => Ok(Number::Float(f.abs())),
+        ("abs", x) => Ok(x),
+        ("sign", Number::Int(i)) => int(BigInt::from(i.signum() as i64)),
+        ("sign", Number::Rational(r)) => int(BigInt::from(r.numerator().signum() as i64)),
+        ("sign", Number::Float(f)) if f == 0.0 || f.is_nan() => Ok(Number::Float(f)),
+        ("sign", Number::Float(f)) => Ok(Number::Float(f.signum())),
+        ("numerator", x) => int(require_rational(x)?.numerator().clone()),
+        ("denominator", x) => int(require_rational(x)?.denominator().clone()),
+        ("rational", Number::Float(f)) => rational(float_to_rational(f)?),
+        ("rationalize", Number::Float(f)) => rational(rationalize(f)?),
+        ("rational", x) | ("rationalize", x) => Ok(x),
+        ("truncate", Number::Float(f)) => float_to_int(f, f64::trunc),
+        ("integer", Number::Float(f)) | ("round", Number::Float(f)) => float_to_int(f, f64::round),
+        ("floor", Number::Float(f)) => float_to_int(f, f64::floor),
+        ("ceiling", Number::Float(f)) => float_to_int(f, f64::ceil),
+        ("truncate", x) => int(x.to_rational().unwrap().truncate()),
+        ("integer", x) | ("round", x) => int(x.to_rational().unwrap().round()),
+        ("floor", x) => int(x.to_rational().unwrap().floor()),
+        ("ceiling", x) => int(x.to_rational().unwrap().ceiling()),
+        _ => Err(evaluable(name, 1)),
+    }
+}
+
+fn eval_binary(flags: &Flags, name: Atom, x: Number, y: Number) -> Result<Number, Exception> {
+    let any_float = x.is_float() || y.is_float();
+    let (a, b) = (x.to_f64(), y.to_f64());
+    match name.name() {
+        "+" | "-" | "*" => {
+            if any_float {
+                let f = match name.name() {
+                    "+" => a + b,
+                    "-" => a - b,
+                    _ => a * b,
+                };
+                return float(flags, &[a, b], f);
+            }
+            if let (Number::Int(a), Number::Int(b)) = (&x, &y) {
+                return int(match name.name() {
+                    "+" => a.add(b),
+                    "-" => a.sub(b),
+                    _ => a.mul(b),
+                });
+            }
+            let (a, b) = (x.to_rational().unwrap(), y.to_rational().unwrap());
+            rational(match name.name() {
+                "+" => a.add(&b),
+                "-" => a.sub(&b),
+                _ => a.mul(&b),
+            })
+        }
+        "/" => {
+            if y.is_zero() {
+                if !(any_float && flags.is("float_zero_div", "infinity")) {
+                    return Err(zero_divisor());
+                }
+                if a != 0.0 {
+                    return Ok(Number::Float(a / b));
+                }
+            }
+            let ints = matches!((&x, &y), (Number::Int(_), Number::Int(_)));
+            if any_float || (ints && !flags.is("prefer_rationals", "true")) {
+                if let (Number::Int(i), Number::Int(j)) = (&x, &y) {
+                    // exact integer division stays an integer.
+                    let (q, r) = i.div_rem(j).unwrap();
+                    if r.is_zero() {
+                        return int(q);
+                    }
+                }
+                return float(flags, &[a, b], a / b);
+            }
+            let q = x.to_rational().unwrap().div(&y.to_rational().unwrap());
+            rational(q.unwrap())
+        }
+        "rdiv" => rational(
+            require_rational(x)?
+                .div(&require_rational(y)?)
+                .ok_or_else(zero_divisor)?,
+        ),
+        "//" | "rem" | "div" | "mod" => {
+            let (a, b) = (require_int(x)?, require_int(y)?);
+            let (q, r) = match name.name() {
+                "//" | "rem" => a.div_rem(&b),
+                _ => a.div_mod_floor(&b),
+            }
+            .ok_or_else(zero_divisor)?;
+            int(match name.name() {
+                "//" | "div" => q,
+                _ => r,
+            })
+        }
+        "gcd" => int(require_int(x)?.gcd(&require_int(y)?)),
+        "min" => Ok(match compare(&y, &x) {
+            Some(Ordering::Less) => y,
+            _ => x,
+        }),
+        "max" => Ok(match compare(&y, &x) {
+            Some(Ordering::Greater) => y,
+            _ => x,
+        }),
+        "atan2" | "atan" => float(flags, &[a, b], a.atan2(b)),
+        "copysign" => float(flags, &[a, b], a.copysign(b)),
+        "log" if a <= 0.0 || b <= 0.0 => Err(undefined()),
+        "log" => float(flags, &[a, b], b.ln() / a.ln()),
+        "^" | "**" => {
+            if any_float {
+                return float(flags, &[a, b], a.powf(b));
+            }
+            let base = require_rational(x.clone())?;
+            let e = require_int(y)?;
+            if e.is_negative() {
+                let unit = matches!(&x, Number::Int(i) if i.abs() == BigInt::one());
+                if base.numerator().is_zero() {
+                    return Err(zero_divisor());
+                }
+                if !unit && !flags.is("prefer_rationals", "true") {
+                    if name == intern!("^") {
+                        return Err(Exception::type_error("float", x.to_term()));
+                    }
+                    return float(flags, &[a, b], a.powf(b));
+                }
+                let power = base.pow(exponent(&e)?);
+                return rational(Rational::from_int(BigInt::one()).div(&power).unwrap());
+            }
+            rational(base.pow(exponent(&e)?))
+        }
+        _ => Err(evaluable(name, 2)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Query;
+    use crate::parser::parse_query;
+    use crate::testing::values_of_x;
+    use std::collections::HashMap;
+
+    fn eval_with(flags: &Flags, expr: &str) -> Result<String, Exception> {
+        let term = match parse_query(&format!("{}.", expr)).unwrap() {
+            Query::Terms(mut terms) => terms.pop().unwrap(),
+            _ => panic!("not a term"),
+        };
+        let mut store = Store::new();
+        let cell = store.from_term(&term, &mut HashMap::new());
+        eval(&store, flags, &cell).map(|n| n.to_term().to_string())
+    }
+
+    fn eval_str(expr: &str) -> Result<String, Exception> {
+        eval_with(&Flags::new(), expr)
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval_str("1 + 2 * 3").unwrap(), "7");
+        assert_eq!(
+            eval_str("2 ** 100").unwrap(),
+            "1267650600228229401496703205376"
+        );
+        assert_eq!(
+            eval_str("9223372036854775807 + 1").unwrap(),
+            "9223372036854775808"
+        );
+        assert_eq!(eval_str("1 / 3 + 1r6").unwrap(), "1r2");
+        assert_eq!(eval_str("6 / 3").unwrap(), "2");
+        assert_eq!(eval_str("2 ^ -2").unwrap(), "1r4");
+        assert_eq!(eval_str("-7 // 2").unwrap(), "-3");
+        assert_eq!(eval_str("-7 mod 2").unwrap(), "1");
+        assert_eq!(eval_str("floor(-7r2)").unwrap(), "-4");
+        assert_eq!(eval_str("max(1r2, 1)").unwrap(), "1");
+        assert_eq!(
+            eval_str("1 / 0"),
+            Err(Exception::evaluation_error("zero_divisor"))
+        );
+        assert_eq!(eval_str("X + 1"), Err(Exception::instantiation_error()));
+        assert!(eval_str("foo + 1").is_err());
+    }
+
+    #[test]
+    fn test_goal_order() {
+        // `is/2` and comparisons run after the goals binding their operands.
+        assert_eq!(values_of_x("Y = 3, X is Y + 1."), vec!["4"]);
+        assert_eq!(values_of_x("X = 2, X > 1, 1 < X."), vec!["2"]);
+        assert_eq!(
+            values_of_x("X is Y + 1, Y = 3."),
+            vec!["Arguments are not sufficiently instantiated"]
+        );
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(eval_str("1.5 + 1").unwrap(), "2.5");
+        assert_eq!(eval_str("sqrt(2.25)").unwrap(), "1.5");
+        assert_eq!(eval_str("0.1 + 0.2").unwrap(), "0.30000000000000004");
+        assert_eq!(eval_str("float_integer_part(-2.5)").unwrap(), "-2.0");
+        assert_eq!(eval_str("truncate(-2.5)").unwrap(), "-2");
+        assert_eq!(eval_str("round(2.5)").unwrap(), "3");
+        assert_eq!(eval_str("ceiling(2.1)").unwrap(), "3");
+        assert_eq!(eval_str("floor(1.0e20)").unwrap(), "100000000000000000000");
+        assert_eq!(eval_str("rational(0.25)").unwrap(), "1r4");
+        assert_eq!(eval_str("rationalize(0.1)").unwrap(), "1r10");
+        assert_eq!(eval_str("exp(0)").unwrap(), "1.0");
+        assert_eq!(
+            eval_str("sqrt(-1)"),
+            Err(Exception::evaluation_error("undefined"))
+        );
+        assert_eq!(
+            eval_str("1 / 0.0"),
+            Err(Exception::evaluation_error("zero_divisor"))
+        );
+        assert_eq!(
+            eval_str("1.0e308 * 10"),
+            Err(Exception::evaluation_error("float_overflow"))
+        );
+        assert_eq!(eval_str("inf + 1").unwrap(), "1.0Inf");
+
+        let mut flags = Flags::new();
+        let set = |flags: &mut Flags, name: &str, value: &str| {
+            let value = Term::atom(value);
+            flags.set(Atom::new(name), value).unwrap();
+        };
+        set(&mut flags, "prefer_rationals", "false");
+        set(&mut flags, "float_zero_div", "infinity");
+        set(&mut flags, "float_undefined", "nan");
+        assert_eq!(eval_with(&flags, "1 / 4").unwrap(), "0.25");
+        assert_eq!(eval_with(&flags, "4 / 2").unwrap(), "2");
+        assert_eq!(eval_with(&flags, "2 ** -1").unwrap(), "0.5");
+        assert_eq!(eval_with(&flags, "-1 / 0.0").unwrap(), "-1.0Inf");
+        assert_eq!(eval_with(&flags, "0 / 0.0").unwrap(), "1.5NaN");
+    }
+}
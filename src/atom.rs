@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+/// Interned name of an atom or functor. Cheap to copy and compare.
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Atom(u32);
+
+// atoms interned before any other, in this order, so that the ones used on
+// every resolution step are constants.
+const PREDEFINED: [&str; 6] = ["[]", ".", ":", "$answer", "user", "system"];
+
+pub const NIL: Atom = Atom(0);
+pub const DOT: Atom = Atom(1);
+pub const COLON: Atom = Atom(2);
+pub const ANSWER: Atom = Atom(3);
+pub const USER: Atom = Atom(4);
+pub const SYSTEM: Atom = Atom(5);
+
+// the names of the atoms, by id, in chunks of 1, 2, 4, ... names. A chunk
+// never moves once allocated and a name is set once, so names are read
+// without taking the lock of the table.
+type Chunk = Box<[OnceLock<&'static str>]>;
+static NAMES: [OnceLock<Chunk>; 32] = [const { OnceLock::new() }; 32];
+
+// the chunk holding the name of atom `id` and its index in it.
+fn slot(id: u32) -> (usize, usize) {
+    let n = id as u64 + 1;
+    let chunk = 63 - n.leading_zeros() as usize;
+    (chunk, (n - (1 << chunk)) as usize)
+}
+
+fn read(id: u32) -> Option<&'static str> {
+    let (chunk, i) = slot(id);
+    NAMES[chunk].get()?[i].get().copied()
+}
+
+/// Maps atom names to ids. Names are never freed, so they are handed out
+/// as `&'static str`.
+pub struct AtomTable {
+    ids: HashMap<&'static str, Atom>,
+}
+
+impl AtomTable {
+    fn new() -> AtomTable {
+        let mut table = AtomTable {
+            ids: HashMap::new(),
+        };
+        for name in PREDEFINED.iter() {
+            table.intern(name);
+        }
+        table
+    }
+
+    pub fn intern(&mut self, name: &str) -> Atom {
+        if let Some(atom) = self.ids.get(name) {
+            return *atom;
+        }
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let atom = Atom(self.ids.len() as u32);
+        let (chunk, i) = slot(atom.0);
+        let names = NAMES[chunk].get_or_init(|| (0..1 << chunk).map(|_| OnceLock::new()).collect());
+        let _ = names[i].set(name);
+        self.ids.insert(name, atom);
+        atom
+    }
+
+    fn global() -> &'static Mutex<AtomTable> {
+        static TABLE: OnceLock<Mutex<AtomTable>> = OnceLock::new();
+        TABLE.get_or_init(|| Mutex::new(AtomTable::new()))
+    }
+}
+
+impl Atom {
+    pub fn new(name: &str) -> Atom {
+        AtomTable::global().lock().unwrap().intern(name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match read(self.0) {
+            Some(name) => name,
+            // a predefined atom used before any atom was interned.
+            None => {
+                AtomTable::global();
+                read(self.0).unwrap()
+            }
+        }
+    }
+}
+
+// the atom named by the literal `$name`, interned the first time this use
+// of the macro runs rather than each time.
+macro_rules! intern {
+    ($name:literal) => {{
+        static ATOM: std::sync::OnceLock<$crate::atom::Atom> = std::sync::OnceLock::new();
+        *ATOM.get_or_init(|| $crate::atom::Atom::new($name))
+    }};
+}
+pub(crate) use intern;
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Atom({})", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Atom::new("kobo");
+        let b = Atom::new("koji");
+        assert_eq!(a, Atom::new("kobo"));
+        assert_ne!(a, b);
+        assert_eq!(a.name(), "kobo");
+        assert_eq!(b.to_string(), "koji");
+        assert_eq!(COLON.name(), ":");
+        assert_eq!(Atom::new("[]"), NIL);
+        assert_eq!(intern!("kobo"), a);
+        // names of atoms interned in other threads are read without a lock.
+        let c = std::thread::spawn(|| Atom::new("sanae")).join().unwrap();
+        assert_eq!(c.name(), "sanae");
+    }
+}
@@ -0,0 +1,518 @@
+// Arbitrary-precision signed integers, stored as sign and magnitude with
+// 32-bit limbs, least significant first.
+
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct BigInt {
+    negative: bool,
+    // no trailing zero limbs; zero has no limbs and is never negative.
+    mag: Vec<u32>,
+}
+
+const DECIMAL_BASE: u32 = 1_000_000_000;
+
+impl BigInt {
+    pub fn zero() -> BigInt {
+        BigInt::default()
+    }
+
+    pub fn one() -> BigInt {
+        BigInt::from(1)
+    }
+
+    fn new(negative: bool, mut mag: Vec<u32>) -> BigInt {
+        while mag.last() == Some(&0) {
+            mag.pop();
+        }
+        BigInt {
+            negative: negative && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    pub fn parse(digits: &str) -> Option<BigInt> {
+        let (negative, digits) = match digits.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, digits),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut mag = Vec::new();
+        let head = digits.len() % 9;
+        let chunks = std::iter::once(&digits[..head])
+            .filter(|c| !c.is_empty())
+            .chain(
+                digits.as_bytes()[head..]
+                    .chunks(9)
+                    .map(|c| std::str::from_utf8(c).unwrap()),
+            );
+        for chunk in chunks {
+            let scale = 10u32.pow(chunk.len() as u32);
+            mul_small_add(&mut mag, scale, chunk.parse::<u32>().unwrap());
+        }
+        Some(BigInt::new(negative, mag))
+    }
+
+    // non-negative integer from `digits` in base `radix`, at most 36.
+    pub fn parse_radix(digits: &str, radix: u32) -> Option<BigInt> {
+        if digits.is_empty() {
+            return None;
+        }
+        let mut mag = Vec::new();
+        for c in digits.chars() {
+            mul_small_add(&mut mag, radix, c.to_digit(radix)?);
+        }
+        Some(BigInt::new(false, mag))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mag.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn signum(&self) -> i32 {
+        if self.negative {
+            -1
+        } else if self.mag.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.mag.clone())
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.mag.len() > 2 {
+            return None;
+        }
+        let mag = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0u64, |acc, limb| (acc << 32) | *limb as u64);
+        if self.negative {
+            if mag <= i64::MAX as u64 + 1 {
+                Some((mag as i64).wrapping_neg())
+            } else {
+                None
+            }
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mag = self
+            .mag
+            .iter()
+            .rev()
+            .fold(0.0, |acc, limb| acc * 4294967296.0 + *limb as f64);
+        if self.negative {
+            -mag
+        } else {
+            mag
+        }
+    }
+
+    // the integer part of a finite float.
+    pub fn from_f64(f: f64) -> Option<BigInt> {
+        if !f.is_finite() {
+            return None;
+        }
+        let f = f.trunc();
+        if f == 0.0 {
+            return Some(BigInt::zero());
+        }
+        let bits = f.to_bits();
+        let exp = ((bits >> 52) & 0x7ff) as i32 - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let mag = if exp >= 0 {
+            BigInt::from(mantissa as i64).mul(&BigInt::from(2).pow(exp as u32))
+        } else {
+            BigInt::from((mantissa >> -exp) as i64)
+        };
+        Some(if f < 0.0 { mag.neg() } else { mag })
+    }
+
+    pub fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_mag(&self.mag, &other.mag));
+        }
+        match cmp_mag(&self.mag, &other.mag) {
+            Ordering::Equal => BigInt::zero(),
+            Ordering::Greater => BigInt::new(self.negative, sub_mag(&self.mag, &other.mag)),
+            Ordering::Less => BigInt::new(other.negative, sub_mag(&other.mag, &self.mag)),
+        }
+    }
+
+    pub fn neg(&self) -> BigInt {
+        BigInt::new(!self.negative, self.mag.clone())
+    }
+
+    pub fn sub(&self, other: &BigInt) -> BigInt {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> BigInt {
+        BigInt::new(
+            self.negative != other.negative,
+            mul_mag(&self.mag, &other.mag),
+        )
+    }
+
+    // quotient rounded toward zero and the remainder with the sign of self.
+    // Returns None when dividing by zero.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = div_rem_mag(&self.mag, &other.mag);
+        Some((
+            BigInt::new(self.negative != other.negative, q),
+            BigInt::new(self.negative, r),
+        ))
+    }
+
+    // quotient rounded toward negative infinity and the remainder with the
+    // sign of other.
+    pub fn div_mod_floor(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        let (q, r) = self.div_rem(other)?;
+        if !r.is_zero() && r.negative != other.negative {
+            Some((q.sub(&BigInt::one()), r.add(other)))
+        } else {
+            Some((q, r))
+        }
+    }
+
+    pub fn pow(&self, mut exp: u32) -> BigInt {
+        let mut base = self.clone();
+        let mut result = BigInt::one();
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = result.mul(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.mul(&base);
+            }
+        }
+        result
+    }
+
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let (mut a, mut b) = (self.abs(), other.abs());
+        while !b.is_zero() {
+            let (_, r) = a.div_rem(&b).unwrap();
+            a = b;
+            b = r;
+        }
+        a
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(i: i64) -> BigInt {
+        let mag = i.unsigned_abs();
+        BigInt::new(i < 0, vec![mag as u32, (mag >> 32) as u32])
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_mag(&self.mag, &other.mag),
+            (true, true) => cmp_mag(&other.mag, &self.mag),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        let mut chunks = Vec::new();
+        let mut mag = self.mag.clone();
+        while !mag.is_empty() {
+            chunks.push(div_small(&mut mag, DECIMAL_BASE));
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+fn trim(mut mag: Vec<u32>) -> Vec<u32> {
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    mag
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut result = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, limb) in long.iter().enumerate() {
+        let sum = *limb as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    result.push(carry as u32);
+    trim(result)
+}
+
+// a - b where |a| >= |b|.
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, limb) in a.iter().enumerate() {
+        let diff = *limb as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        result.push(diff as u32);
+        borrow = if diff < 0 { 1 } else { 0 };
+    }
+    trim(result)
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let t = *x as u64 * *y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = t as u32;
+            carry = t >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    trim(result)
+}
+
+fn mul_small_add(mag: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for limb in mag.iter_mut() {
+        let t = *limb as u64 * factor as u64 + carry;
+        *limb = t as u32;
+        carry = t >> 32;
+    }
+    if carry > 0 {
+        mag.push(carry as u32);
+    }
+}
+
+// divides in place and returns the remainder.
+fn div_small(mag: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut rem = 0u64;
+    for limb in mag.iter_mut().rev() {
+        let cur = (rem << 32) | *limb as u64;
+        *limb = (cur / divisor as u64) as u32;
+        rem = cur % divisor as u64;
+    }
+    while mag.last() == Some(&0) {
+        mag.pop();
+    }
+    rem as u32
+}
+
+fn shl_bits(mag: &[u32], shift: u32) -> Vec<u32> {
+    if shift == 0 {
+        return mag.to_vec();
+    }
+    let mut result = Vec::with_capacity(mag.len() + 1);
+    let mut carry = 0u32;
+    for limb in mag {
+        result.push((limb << shift) | carry);
+        carry = limb >> (32 - shift);
+    }
+    result.push(carry);
+    result
+}
+
+fn shr_bits(mag: &[u32], shift: u32) -> Vec<u32> {
+    if shift == 0 {
+        return trim(mag.to_vec());
+    }
+    let mut result = vec![0u32; mag.len()];
+    for i in 0..mag.len() {
+        let high = if i + 1 < mag.len() {
+            mag[i + 1] << (32 - shift)
+        } else {
+            0
+        };
+        result[i] = (mag[i] >> shift) | high;
+    }
+    trim(result)
+}
+
+// long division of magnitudes (Knuth, algorithm D).
+fn div_rem_mag(u: &[u32], v: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_mag(u, v) == Ordering::Less {
+        return (Vec::new(), u.to_vec());
+    }
+    if v.len() == 1 {
+        let mut q = u.to_vec();
+        let r = div_small(&mut q, v[0]);
+        return (q, trim(vec![r]));
+    }
+    let n = v.len();
+    let m = u.len() - n;
+    let shift = v[n - 1].leading_zeros();
+    let vn = trim(shl_bits(v, shift));
+    let mut un = shl_bits(u, shift);
+    if un.len() == u.len() {
+        un.push(0);
+    }
+    let mut q = vec![0u32; m + 1];
+    let base = 1u64 << 32;
+    for j in (0..=m).rev() {
+        let num = ((un[j + n] as u64) << 32) | un[j + n - 1] as u64;
+        let mut qhat = num / vn[n - 1] as u64;
+        let mut rhat = num % vn[n - 1] as u64;
+        while qhat >= base || qhat * vn[n - 2] as u64 > ((rhat << 32) | un[j + n - 2] as u64) {
+            qhat -= 1;
+            rhat += vn[n - 1] as u64;
+            if rhat >= base {
+                break;
+            }
+        }
+        let mut borrow = 0i64;
+        let mut carry = 0u64;
+        for i in 0..n {
+            let p = qhat * vn[i] as u64 + carry;
+            carry = p >> 32;
+            let t = un[i + j] as i64 - borrow - (p & 0xffff_ffff) as i64;
+            un[i + j] = t as u32;
+            borrow = if t < 0 { 1 } else { 0 };
+        }
+        let t = un[j + n] as i64 - borrow - carry as i64;
+        un[j + n] = t as u32;
+        if t < 0 {
+            // qhat was one too large; add the divisor back.
+            qhat -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let s = un[i + j] as u64 + vn[i] as u64 + carry;
+                un[i + j] = s as u32;
+                carry = s >> 32;
+            }
+            un[j + n] = un[j + n].wrapping_add(carry as u32);
+        }
+        q[j] = qhat as u32;
+    }
+    (trim(q), shr_bits(&un[..n], shift))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        BigInt::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_print() {
+        for s in [
+            "0",
+            "7",
+            "-42",
+            "4294967296",
+            "123456789012345678901234567890",
+            "-1000000000000000000000",
+        ] {
+            assert_eq!(big(s).to_string(), s);
+        }
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(big("000123").to_string(), "123");
+        assert_eq!(BigInt::parse("12a"), None);
+        assert_eq!(BigInt::parse_radix("ff", 16), Some(big("255")));
+        assert_eq!(
+            BigInt::parse_radix("10000000000000000000000000000000000", 2),
+            Some(big("17179869184"))
+        );
+        assert_eq!(BigInt::parse_radix("12", 2), None);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!(a.add(&b).to_string(), "-864197532086419753208641975320");
+        assert_eq!(a.sub(&b).to_string(), "1111111110111111111011111111100");
+        assert_eq!(
+            a.mul(&b).to_string(),
+            "-121932631137021795226185032733622923332237463801111263526900"
+        );
+        assert_eq!(
+            big("2").pow(100).to_string(),
+            "1267650600228229401496703205376"
+        );
+        assert_eq!(big("12").gcd(&big("-18")).to_string(), "6");
+        assert_eq!(BigInt::from(i64::MIN).to_string(), "-9223372036854775808");
+        assert_eq!(BigInt::from(i64::MIN).to_i64(), Some(i64::MIN));
+        assert!(big("-5") < big("3") && big("-5") < big("-3") && a > big("99"));
+    }
+
+    #[test]
+    fn test_division() {
+        let a = big("1267650600228229401496703205377");
+        let b = big("4294967297");
+        let (q, r) = a.div_rem(&b).unwrap();
+        assert_eq!(q.mul(&b).add(&r), a);
+        assert!(r < b);
+        let (q, r) = big("-7").div_rem(&big("2")).unwrap();
+        assert_eq!(
+            (q.to_string(), r.to_string()),
+            ("-3".to_string(), "-1".to_string())
+        );
+        let (q, r) = big("-7").div_mod_floor(&big("2")).unwrap();
+        assert_eq!(
+            (q.to_string(), r.to_string()),
+            ("-4".to_string(), "1".to_string())
+        );
+        let x = big("340282366920938463463374607431768211455");
+        let y = big("18446744073709551615");
+        assert_eq!(x.div_rem(&y).unwrap().0.to_string(), "18446744073709551617");
+        assert_eq!(big("1").div_rem(&BigInt::zero()), None);
+    }
+
+    #[test]
+    fn test_float_conversion() {
+        assert_eq!(BigInt::from_f64(-2.75), Some(big("-2")));
+        assert_eq!(BigInt::from_f64(1e20), Some(big("100000000000000000000")));
+        assert_eq!(BigInt::from_f64(f64::NAN), None);
+        assert_eq!(big("-100000000000000000000").to_f64(), -1e20);
+    }
+}
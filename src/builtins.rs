@@ -0,0 +1,562 @@
+// Predicates implemented in Rust. A builtin is called with the arguments of
+// the selected goal and returns one `Branch` per solution.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::app::App;
+use crate::arith::{self, Number};
+use crate::atom::{self, intern, Atom};
+use crate::bigint::BigInt;
+use crate::chr;
+use crate::clpfd;
+use crate::coroutine;
+use crate::dcg::{self, Translator};
+use crate::debug::{self, Port};
+use crate::error::Exception;
+use crate::expr::{Constant, Term, Variable};
+use crate::frame::{self, Kind};
+use crate::limits;
+use crate::load;
+use crate::module;
+use crate::profile;
+use crate::store::{Cell, Trail};
+use crate::stream;
+use crate::wam::{key_of, PredKey};
+use crate::write::{self, WriteOptions};
+
+pub struct Branch {
+    // bindings of this solution.
+    pub trail: Trail,
+    // goals to prove before the rest, e.g. the goal of `call/1`.
+    pub goals: Vec<Cell>,
+}
+
+pub type Builtin = fn(&mut App, &[Cell]) -> Result<Vec<Branch>, Exception>;
+
+// adds the builtins of `name => builtin` arms, written like those of a
+// match on the name and arity, to `table`.
+macro_rules! builtins {
+    ($table:ident; $($(($name:literal, $arity:literal))|+ => $builtin:expr,)*) => {
+        $(
+            let builtin: Builtin = $builtin;
+            $($table.insert((Atom::new($name), $arity), builtin);)+
+        )*
+    };
+}
+
+// the builtins by predicate, matched on every resolution step.
+fn table() -> HashMap<PredKey, Builtin> {
+    let mut table = HashMap::new();
+    builtins! { table;
+        ("true", 0) => true_,
+        ("fail", 0) | ("false", 0) => |_, _| Ok(fail()),
+        (",", 2) => |app, args| {
+            Ok(vec![Branch {
+                trail: app.store.trail(),
+                goals: args.to_vec(),
+            }])
+        },
+        (";", 2) => or,
+        ("->", 2) => |app, args| {
+            let fail = Cell::Atom(intern!("fail"));
+            Ok(if_then_else(app, &args[0], &args[1], &fail))
+        },
+        ("\\+", 1) => |app, args| {
+            let (fail, true_) = (Cell::Atom(intern!("fail")), Cell::Atom(intern!("true")));
+            Ok(if_then_else(app, &args[0], &fail, &true_))
+        },
+        ("call", 1) | ("call", 2) | ("call", 3) | ("call", 4)
+        | ("call", 5) | ("call", 6) | ("call", 7) | ("call", 8) => call,
+        ("phrase", 2) | ("phrase", 3) => phrase,
+        ("=", 2) => |app, args| Ok(unify(app, &args[0], &args[1])),
+        ("set_prolog_flag", 2) => set_prolog_flag,
+        ("current_prolog_flag", 2) => current_prolog_flag,
+        ("is", 2) => is,
+        ("=:=", 2) => |app, args| compare(app, args, |o| o == Some(Ordering::Equal)),
+        ("=\\=", 2) => |app, args| compare(app, args, |o| o != Some(Ordering::Equal)),
+        ("<", 2) => |app, args| compare(app, args, |o| o == Some(Ordering::Less)),
+        (">", 2) => |app, args| compare(app, args, |o| o == Some(Ordering::Greater)),
+        ("=<", 2) => |app, args| compare(app, args, |o| o.is_some_and(|o| o.is_le())),
+        (">=", 2) => |app, args| compare(app, args, |o| o.is_some_and(|o| o.is_ge())),
+        ("integer", 1) => |app, args| type_check(app, args, |c| matches!(c, Cell::Int(_))),
+        ("float", 1) => |app, args| type_check(app, args, |c| matches!(c, Cell::Float(_))),
+        ("rational", 1) => {
+            |app, args| type_check(app, args, |c| matches!(c, Cell::Int(_) | Cell::Rational(_)))
+        },
+        ("number", 1) => |app, args| {
+            type_check(app, args, |c| {
+                matches!(c, Cell::Int(_) | Cell::Rational(_) | Cell::Float(_))
+            })
+        },
+        ("rational", 3) => rational,
+        ("clause_property", 2) => clause_property,
+        ("put_attr", 3) => coroutine::put_attr,
+        ("get_attr", 3) => coroutine::get_attr,
+        ("del_attr", 2) => coroutine::del_attr,
+        ("attvar", 1) => |app, args| Ok(coroutine::check(app, app.store.attrs(&args[0]).is_some())),
+        ("freeze", 2) => coroutine::freeze,
+        ("frozen", 2) => coroutine::frozen,
+        ("dif", 2) => coroutine::dif,
+        ("when", 2) => coroutine::when,
+        ("$when", 3) => coroutine::wake_when,
+        ("#=", 2) => |app, args| clpfd::relation(app, args, "#="),
+        ("#\\=", 2) => |app, args| clpfd::relation(app, args, "#\\="),
+        ("#<", 2) => |app, args| clpfd::relation(app, args, "#<"),
+        ("#>", 2) => |app, args| clpfd::relation(app, args, "#>"),
+        ("#=<", 2) => |app, args| clpfd::relation(app, args, "#=<"),
+        ("#>=", 2) => |app, args| clpfd::relation(app, args, "#>="),
+        ("in", 2) => clpfd::in_,
+        ("ins", 2) => clpfd::ins,
+        ("all_different", 1) => clpfd::all_different,
+        ("sum", 3) => clpfd::sum,
+        ("label", 1) => |app, args| {
+            let options = Cell::Atom(atom::NIL);
+            clpfd::labeling(app, &[options, args[0].clone()])
+        },
+        ("labeling", 2) => clpfd::labeling,
+        ("$fd_unify", 2) => clpfd::unify_hook,
+        ("$chr_add", 1) => chr::add,
+        ("$chr_activate", 1) => chr::activate,
+        ("find_chr_constraint", 1) => chr::find_constraint,
+        ("trace", 0) => |app, _| Ok(debug::set_mode(app, true, true)),
+        ("notrace", 0) => |app, _| Ok(debug::set_mode(app, false, app.debugger.debugging)),
+        ("debug", 0) => |app, _| Ok(debug::set_mode(app, false, true)),
+        ("nodebug", 0) => |app, _| Ok(debug::set_mode(app, false, false)),
+        ("spy", 1) => debug::spy,
+        ("nospy", 1) => debug::nospy,
+        ("leash", 1) => debug::leash,
+        ("$trace_exit", 2) => |app, args| debug::mark(app, args, Port::Exit),
+        ("$trace_redo", 2) => |app, args| debug::mark(app, args, Port::Redo),
+        ("profile", 1) | ("profile", 2) => profile::profile,
+        ("write", 1) | ("write", 2) => |app, args| write::write(app, args, WriteOptions::WRITE),
+        ("print", 1) | ("print", 2) | ("writeq", 1) | ("writeq", 2) => {
+            |app, args| write::write(app, args, WriteOptions::WRITEQ)
+        },
+        ("write_canonical", 1) | ("write_canonical", 2) => {
+            |app, args| write::write(app, args, WriteOptions::CANONICAL)
+        },
+        ("nl", 0) | ("nl", 1) => write::nl,
+        ("format", 1) => |app, args| {
+            let args = [args[0].clone(), Cell::Atom(atom::NIL)];
+            write::format2(app, &args)
+        },
+        ("format", 2) => write::format2,
+        ("format", 3) => write::format3,
+        ("open", 3) | ("open", 4) => stream::open,
+        ("close", 1) | ("close", 2) => stream::close,
+        ("current_input", 1) => |app, args| stream::current(app, args, false),
+        ("current_output", 1) => |app, args| stream::current(app, args, true),
+        ("set_input", 1) => |app, args| stream::set(app, args, false),
+        ("set_output", 1) => |app, args| stream::set(app, args, true),
+        ("get_char", 1) | ("get_char", 2) => stream::get_char,
+        ("put_char", 1) | ("put_char", 2) => stream::put_char,
+        ("read_term", 2) | ("read_term", 3) => stream::read_term,
+        ("read", 1) => |app, args| {
+            let args = [args[0].clone(), Cell::Atom(atom::NIL)];
+            stream::read_term(app, &args)
+        },
+        ("read", 2) => |app, args| {
+            let args = [
+                args[0].clone(),
+                args[1].clone(),
+                Cell::Atom(atom::NIL),
+            ];
+            stream::read_term(app, &args)
+        },
+        ("with_output_to", 2) => stream::with_output_to,
+        ("catch", 3) => catch,
+        ("throw", 1) => |app, args| match app.store.deref(&args[0]) {
+            Cell::Ref(_) => Err(Exception::instantiation_error()),
+            ball => Err(Exception(app.store.to_term(&ball))),
+        },
+        ("call_with_inference_limit", 3) => limits::call_with_inference_limit,
+        ("call_with_time_limit", 2) => limits::call_with_time_limit,
+        ("consult", 1) => |app, args| {
+            let files = app.store.to_term(&args[0]);
+            consult_all(app, &files, App::consult)
+        },
+        // `[File|Files]` as a goal.
+        (".", 2) => |app, args| {
+            let files = Term::list(
+                vec![app.store.to_term(&args[0])],
+                app.store.to_term(&args[1]),
+            );
+            consult_all(app, &files, App::consult)
+        },
+        ("ensure_loaded", 1) => |app, args| {
+            let files = app.store.to_term(&args[0]);
+            consult_all(app, &files, App::ensure_loaded)
+        },
+        ("load_files", 2) => load_files,
+        ("use_module", 1) | ("use_module", 2) => use_module,
+        ("make", 0) => |app, _| {
+            app.make()?;
+            Ok(succeed(app))
+        },
+    }
+    table
+}
+
+pub fn lookup(key: PredKey) -> Option<Builtin> {
+    static TABLE: OnceLock<HashMap<PredKey, Builtin>> = OnceLock::new();
+    TABLE.get_or_init(table).get(&key).copied()
+}
+
+pub(crate) fn succeed(app: &App) -> Vec<Branch> {
+    vec![Branch {
+        trail: app.store.trail(),
+        goals: Vec::new(),
+    }]
+}
+
+pub(crate) fn fail() -> Vec<Branch> {
+    Vec::new()
+}
+
+// the single solution in which `a` and `b` are unified, if any.
+fn unify(app: &mut App, a: &Cell, b: &Cell) -> Vec<Branch> {
+    if app.store.unify(a, b) {
+        succeed(app)
+    } else {
+        fail()
+    }
+}
+
+pub(crate) fn atom_arg(app: &App, cell: &Cell) -> Result<Atom, Exception> {
+    match app.store.deref(cell) {
+        Cell::Atom(name) => Ok(name),
+        Cell::Ref(_) => Err(Exception::instantiation_error()),
+        other => Err(Exception::type_error("atom", app.store.to_term(&other))),
+    }
+}
+
+fn true_(app: &mut App, _: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    Ok(succeed(app))
+}
+
+// A ; B, or the if-then-else (Condition -> Then ; Else).
+fn or(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    if let Some((name, 2)) = app.store.key(&args[0]) {
+        if name == intern!("->") {
+            let condition = app.store.args(&args[0]);
+            return Ok(if_then_else(app, &condition[0], &condition[1], &args[1]));
+        }
+    }
+    let trail = app.store.trail();
+    Ok(args
+        .iter()
+        .map(|goal| Branch {
+            trail: trail.clone(),
+            goals: vec![goal.clone()],
+        })
+        .collect())
+}
+
+// runs `then` after the first answer of `condition`, dropping the others, or
+// `else_` if it has none.
+fn if_then_else(app: &mut App, condition: &Cell, then: &Cell, else_: &Cell) -> Vec<Branch> {
+    let goals = vec![
+        in_context(app, then.clone()),
+        in_context(app, else_.clone()),
+    ];
+    frame::call(app, Kind::Condition, condition.clone(), goals)
+}
+
+// `goal`, run later by a builtin, in the module the builtin was called in.
+fn in_context(app: &mut App, goal: Cell) -> Cell {
+    match app.context {
+        m if m == module::user() => goal,
+        m => app.qualify(m, goal),
+    }
+}
+
+fn set_prolog_flag(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let name = atom_arg(app, &args[0])?;
+    let value = app.store.to_term(&args[1]);
+    app.flags.set(name, value)?;
+    Ok(succeed(app))
+}
+
+fn current_prolog_flag(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let start = app.store.trail();
+    let mut branches = Vec::new();
+    for (name, value) in app.flags.iter() {
+        let value = app.store.from_term(&value, &mut HashMap::new());
+        if app.store.unify(&args[0], &Cell::Atom(name)) && app.store.unify(&args[1], &value) {
+            branches.push(Branch {
+                trail: app.store.trail(),
+                goals: Vec::new(),
+            });
+        }
+        app.store.switch_to(&start);
+    }
+    Ok(branches)
+}
+
+fn is(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let value = arith::eval(&app.store, &app.flags, &args[1])?.to_cell();
+    Ok(unify(app, &args[0], &value))
+}
+
+fn compare(
+    app: &mut App,
+    args: &[Cell],
+    test: fn(Option<Ordering>) -> bool,
+) -> Result<Vec<Branch>, Exception> {
+    let x = arith::eval(&app.store, &app.flags, &args[0])?;
+    let y = arith::eval(&app.store, &app.flags, &args[1])?;
+    if test(arith::compare(&x, &y)) {
+        Ok(succeed(app))
+    } else {
+        Ok(fail())
+    }
+}
+
+fn type_check(
+    app: &mut App,
+    args: &[Cell],
+    test: fn(&Cell) -> bool,
+) -> Result<Vec<Branch>, Exception> {
+    if test(&app.store.deref(&args[0])) {
+        Ok(succeed(app))
+    } else {
+        Ok(fail())
+    }
+}
+
+// rational(X, Numerator, Denominator)
+fn rational(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let (num, den) = match app.store.deref(&args[0]) {
+        Cell::Int(i) => ((*i).clone(), BigInt::one()),
+        Cell::Rational(r) => (r.numerator().clone(), r.denominator().clone()),
+        _ => return Ok(fail()),
+    };
+    let num = Number::Int(num).to_cell();
+    let den = Number::Int(den).to_cell();
+    if app.store.unify(&args[1], &num) && app.store.unify(&args[2], &den) {
+        Ok(succeed(app))
+    } else {
+        Ok(fail())
+    }
+}
+
+// call(Goal, Args...) adds `Args` to the arguments of `Goal`, which may be
+// qualified with a module.
+fn call(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let mut goal = args[0].clone();
+    let mut module = None;
+    while let Some((colon, 2)) = app.store.key(&goal) {
+        if colon != atom::COLON {
+            break;
+        }
+        let parts = app.store.args(&goal);
+        module = Some(parts[0].clone());
+        goal = parts[1].clone();
+    }
+    let (name, mut goal_args) = match app.store.deref(&goal) {
+        Cell::Ref(_) => return Err(Exception::instantiation_error()),
+        Cell::Atom(name) => (name, Vec::new()),
+        cell @ Cell::Struct(_) => (app.store.key(&cell).unwrap().0, app.store.args(&cell)),
+        other => return Err(Exception::type_error("callable", app.store.to_term(&other))),
+    };
+    if args.len() > 1 {
+        goal_args.extend(args[1..].iter().cloned());
+        goal = app.store.compound(name, goal_args);
+    }
+    if let Some(module) = module {
+        goal = app.store.compound(atom::COLON, vec![module, goal]);
+    }
+    Ok(vec![Branch {
+        trail: app.store.trail(),
+        goals: vec![goal],
+    }])
+}
+
+// catch(Goal, Catcher, Recovery): the answers of `Goal`, or those of
+// `Recovery` if it raises an error unifying with `Catcher`, which drops the
+// alternatives of `Goal` left. An aborted query cannot be caught.
+fn catch(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let catcher = vec![args[1].clone(), in_context(app, args[2].clone())];
+    Ok(frame::call(app, Kind::Catch, args[0].clone(), catcher))
+}
+
+// phrase(Body, List) or phrase(Body, List, Rest): parses `List` with the
+// grammar body `Body`, leaving `Rest`, or nothing.
+fn phrase(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let body = app.store.to_term(&args[0]);
+    if let Term::Var(_) = body {
+        return Err(Exception::instantiation_error());
+    }
+    for list in &args[1..] {
+        match app.store.deref(list) {
+            Cell::Ref(_) | Cell::Struct(_) => {}
+            Cell::Atom(name) if name == atom::NIL => {}
+            other => return Err(Exception::type_error("list", app.store.to_term(&other))),
+        }
+    }
+    // the variables of `body` are the cells they were read from.
+    let mut vars: HashMap<Variable, usize> = HashMap::new();
+    for var in body.free_vars() {
+        if let Variable::Fresh(addr) = var {
+            vars.insert(var, addr);
+        }
+    }
+    let mut list = |name: &str, cell: &Cell| {
+        let var = Variable::Named(Atom::new(name));
+        // arguments are always references to the argument cells.
+        if let Cell::Ref(addr) = cell {
+            vars.insert(var, *addr);
+        }
+        Term::Var(var)
+    };
+    let s0 = list("S0", &args[1]);
+    let rest = match args.get(2) {
+        Some(cell) => list("S", cell),
+        None => Term::nil(),
+    };
+    let goal = Translator::new(&[&body]).body(&body, s0, rest)?;
+    let goals = dcg::goals(goal)
+        .iter()
+        .map(|goal| app.store.from_term(goal, &mut vars))
+        .collect();
+    Ok(vec![Branch {
+        trail: app.store.trail(),
+        goals,
+    }])
+}
+
+// the body of a clause as a single goal.
+fn conjoin(goals: &[Term]) -> Term {
+    match goals.split_last() {
+        Some((last, init)) => init
+            .iter()
+            .rev()
+            .fold(last.clone(), |rest, goal| Term::Combined {
+                functor: intern!(","),
+                args: vec![goal.clone(), rest],
+            }),
+        None => Term::atom("true"),
+    }
+}
+
+// clause_property(Clause, Property) for the clauses read from a file that
+// match `Clause`, written as `Head` or `Head :- Body`. The properties are
+// `file(File)` and `line_count(Line)`.
+fn clause_property(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let (head, body) = match app.store.key(&args[0]) {
+        Some((name, 2)) if name == intern!(":-") => {
+            let parts = app.store.args(&args[0]);
+            (parts[0].clone(), Some(parts[1].clone()))
+        }
+        Some(_) => (args[0].clone(), None),
+        None => return Err(Exception::instantiation_error()),
+    };
+    let key = app.store.key(&head);
+    let start = app.store.trail();
+    let mut branches = Vec::new();
+    for i in 0..app.rules.len() {
+        let location = match app.rules[i].location {
+            Some(location) if key_of(&app.rules[i].lhs) == key => location,
+            _ => continue,
+        };
+        let mut vars = HashMap::new();
+        let rule_head = app.store.from_term(&app.rules[i].lhs, &mut vars);
+        let rule_body = app.store.from_term(&conjoin(&app.rules[i].rhs), &mut vars);
+        let matched = app.store.unify(&head, &rule_head)
+            && body
+                .as_ref()
+                .is_none_or(|body| app.store.unify(body, &rule_body));
+        if matched {
+            let clause = app.store.trail();
+            let properties = [
+                ("file", Constant::Name(location.file)),
+                (
+                    "line_count",
+                    Constant::Int(BigInt::from(location.line as i64)),
+                ),
+            ];
+            for (name, value) in properties {
+                let property = Term::Combined {
+                    functor: Atom::new(name),
+                    args: vec![Term::Const(value)],
+                };
+                let property = app.store.from_term(&property, &mut HashMap::new());
+                if app.store.unify(&args[1], &property) {
+                    branches.push(Branch {
+                        trail: app.store.trail(),
+                        goals: Vec::new(),
+                    });
+                }
+                app.store.switch_to(&clause);
+            }
+        }
+        app.store.switch_to(&start);
+    }
+    Ok(branches)
+}
+
+// loads each file named by `files`, an atom or a list, with `load`.
+fn consult_all(
+    app: &mut App,
+    files: &Term,
+    load: fn(&mut App, &str) -> Result<(), Exception>,
+) -> Result<Vec<Branch>, Exception> {
+    for file in load::file_args(files)? {
+        load(app, &file)?;
+    }
+    Ok(succeed(app))
+}
+
+// load_files(Files, Options), where only `if(true)` and `if(not_loaded)` are
+// understood among the options.
+fn load_files(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let files = app.store.to_term(&args[0]);
+    let mut options = app.store.to_term(&args[1]);
+    let mut load: fn(&mut App, &str) -> Result<(), Exception> = App::consult;
+    loop {
+        match options {
+            Term::Var(_) => return Err(Exception::instantiation_error()),
+            Term::Combined {
+                functor,
+                ref mut args,
+            } if functor == atom::DOT && args.len() == 2 => {
+                let tail = args.pop().unwrap();
+                let option = args.pop().unwrap();
+                options = tail;
+                if let Term::Combined { functor, args } = &option {
+                    if *functor == intern!("if") && args.len() == 1 {
+                        load = match &args[0] {
+                            Term::Const(Constant::Name(name)) if *name == intern!("not_loaded") => {
+                                App::ensure_loaded
+                            }
+                            Term::Const(Constant::Name(name)) if *name == intern!("true") => {
+                                App::consult
+                            }
+                            other => {
+                                return Err(Exception::domain_error("load_option", other.clone()))
+                            }
+                        };
+                    }
+                }
+            }
+            Term::Const(Constant::Name(name)) if name == atom::NIL => break,
+            other => return Err(Exception::type_error("list", other)),
+        }
+    }
+    consult_all(app, &files, load)
+}
+
+// use_module(Files) or use_module(Files, Imports).
+fn use_module(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let files = app.store.to_term(&args[0]);
+    let imports = match args.get(1) {
+        Some(imports) => Some(load::pred_indicators(&app.store.to_term(imports))?),
+        None => None,
+    };
+    for file in load::file_args(&files)? {
+        app.use_module(&file, imports.as_deref())?;
+    }
+    Ok(succeed(app))
+}
@@ -0,0 +1,541 @@
+// Constraint Handling Rules. A constraint declared with `:- chr_constraint
+// leq/2.` is defined by a clause adding it to the constraint store of the
+// query. The rules of a file
+//
+//     Name @ Kept \ Removed <=> Guard | Body.   (simpagation)
+//     Heads <=> Guard | Body.                   (simplification)
+//     Heads ==> Guard | Body.                   (propagation)
+//
+// are kept in `App`. A constraint is active when it is added to the store
+// and again whenever one of its variables is bound: a rule fires if one of
+// its heads matches it, other constraints in the store match the other
+// heads without binding their variables, and the guard then holds without
+// binding them either. Firing removes the constraints matched by removed
+// heads and runs the body before the active constraint tries the rules
+// again. A propagation rule fires once for each combination of constraints,
+// as recorded in the propagation history.
+//
+// The store is the `chr` attribute of the first cell of the query,
+// `chr(NextId, [Id-Constraint, ...], [Rule-Ids, ...])`, so that it follows
+// backtracking. The variables of a constraint have a `chr` attribute
+// listing the ids of the constraints they occur in.
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+
+use crate::app::App;
+use crate::atom::{intern, Atom};
+use crate::bigint::BigInt;
+use crate::builtins::{succeed, Branch};
+use crate::coroutine::{attr, items, list, set_attr};
+use crate::dcg;
+use crate::error::Exception;
+use crate::expr::{Location, Term, Variable};
+use crate::module::{self, indicator, qualify};
+use crate::store::{Cell, Store};
+use crate::unifier::Subst;
+use crate::wam::{key_of, PredKey};
+
+fn chr() -> Atom {
+    intern!("chr")
+}
+
+fn int_cell(n: i64) -> Cell {
+    Cell::Int(Rc::new(BigInt::from(n)))
+}
+
+fn int_of(store: &Store, cell: &Cell) -> i64 {
+    match store.deref(cell) {
+        Cell::Int(n) => n.to_i64().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+// the variable holding the store of the running query.
+fn global() -> Cell {
+    Cell::Ref(0)
+}
+
+#[derive(Clone, Debug)]
+pub struct ChrRule {
+    // heads in the order written, and whether the constraints they match
+    // are removed.
+    heads: Vec<(Term, bool)>,
+    guard: Vec<Term>,
+    body: Term,
+    pub(crate) location: Location,
+    module: Atom,
+}
+
+impl ChrRule {
+    fn is_propagation(&self) -> bool {
+        self.heads.iter().all(|(_, removed)| !removed)
+    }
+}
+
+// whether the clause `term` is a rule, named or not.
+pub(crate) fn is_rule(term: &Term) -> bool {
+    match term {
+        Term::Combined { functor, args } if args.len() == 2 => {
+            ["@", "<=>", "==>", "pragma"].contains(&functor.name())
+        }
+        _ => false,
+    }
+}
+
+// the rule written as `term`, whose heads must be declared constraints.
+pub(crate) fn rule(
+    mut term: Term,
+    location: Location,
+    module: Atom,
+    constraints: &HashSet<PredKey>,
+) -> Result<ChrRule, Exception> {
+    let (functor, mut args) = match term {
+        Term::Combined {
+            functor,
+            ref mut args,
+        } if args.len() == 2 => (functor, mem::take(args)),
+        other => return Err(Exception::type_error("chr_rule", other)),
+    };
+    let mut right = args.pop().unwrap();
+    let mut left = args.pop().unwrap();
+    let simplification = match functor.name() {
+        // the name and pragmas of a rule are not used.
+        "@" => return rule(right, location, module, constraints),
+        "pragma" => return rule(left, location, module, constraints),
+        "<=>" => true,
+        "==>" => false,
+        _ => {
+            let term = Term::Combined {
+                functor,
+                args: vec![left, right],
+            };
+            return Err(Exception::type_error("chr_rule", term));
+        }
+    };
+    let heads = match left {
+        Term::Combined {
+            functor,
+            ref mut args,
+        } if simplification && functor == intern!("\\") && args.len() == 2 => {
+            let removed = dcg::goals(args.pop().unwrap());
+            let kept = dcg::goals(args.pop().unwrap());
+            let kept = kept.into_iter().map(|head| (head, false));
+            kept.chain(removed.into_iter().map(|head| (head, true)))
+                .collect()
+        }
+        left => dcg::goals(left)
+            .into_iter()
+            .map(|head| (head, simplification))
+            .collect::<Vec<_>>(),
+    };
+    for (head, _) in &heads {
+        match key_of(head) {
+            Some(key) if constraints.contains(&key) => {}
+            Some(key) => return Err(Exception::existence_error("chr_constraint", indicator(key))),
+            None => return Err(Exception::type_error("callable", head.clone())),
+        }
+    }
+    let (guard, body) = match right {
+        Term::Combined {
+            functor,
+            ref mut args,
+        } if functor == intern!("|") && args.len() == 2 => {
+            let body = args.pop().unwrap();
+            (dcg::goals(args.pop().unwrap()), body)
+        }
+        right => (Vec::new(), right),
+    };
+    Ok(ChrRule {
+        heads,
+        guard,
+        body,
+        location,
+        module,
+    })
+}
+
+// the constraints of `chr_constraint` declarations: `Name/Arity` or
+// `name(Modes...)`, in sequences `(A, B)`.
+pub(crate) fn constraint_keys(term: &Term) -> Result<Vec<PredKey>, Exception> {
+    let mut keys = Vec::new();
+    for spec in dcg::goals(term.clone()) {
+        match &spec {
+            Term::Var(_) => return Err(Exception::instantiation_error()),
+            Term::Combined { functor, .. } if *functor == intern!("/") => {
+                keys.extend(crate::load::pred_indicators(&spec)?)
+            }
+            _ => match key_of(&spec) {
+                Some(key) => keys.push(key),
+                None => return Err(Exception::type_error("chr_constraint", spec.clone())),
+            },
+        }
+    }
+    Ok(keys)
+}
+
+// whether `pattern` matches `term` with the bindings in `subst`, which are
+// extended. The variables of `term` only match themselves.
+fn matches(pattern: &Term, term: &Term, subst: &mut Subst) -> bool {
+    match (pattern, term) {
+        (Term::Var(v), _) if v.is_anonymous() => true,
+        (Term::Var(v), _) => match subst.get(v) {
+            Some(value) => value == term,
+            None => {
+                subst.insert(*v, term.clone());
+                true
+            }
+        },
+        (Term::Const(a), Term::Const(b)) => a == b,
+        (
+            Term::Combined { functor, args },
+            Term::Combined {
+                functor: other,
+                args: other_args,
+            },
+        ) => {
+            functor == other
+                && args.len() == other_args.len()
+                && args
+                    .iter()
+                    .zip(other_args)
+                    .all(|(a, b)| matches(a, b, subst))
+        }
+        _ => false,
+    }
+}
+
+// the constraint store of the running query.
+struct Constraints {
+    next: i64,
+    entries: Vec<(i64, Cell)>,
+    // rules and the ids of the constraints they propagated from.
+    history: Vec<(usize, Vec<i64>)>,
+}
+
+impl Constraints {
+    fn read(store: &Store) -> Constraints {
+        let value = match attr(store, &global(), chr()) {
+            Some(value) => store.args(&value),
+            None => {
+                return Constraints {
+                    next: 0,
+                    entries: Vec::new(),
+                    history: Vec::new(),
+                }
+            }
+        };
+        let pairs = |list: &Cell| -> Vec<(i64, Cell)> {
+            items(store, list)
+                .iter()
+                .map(|pair| {
+                    let args = store.args(pair);
+                    (int_of(store, &args[0]), args[1].clone())
+                })
+                .collect()
+        };
+        Constraints {
+            next: int_of(store, &value[0]),
+            entries: pairs(&value[1]),
+            history: pairs(&value[2])
+                .into_iter()
+                .map(|(rule, ids)| {
+                    let ids = items(store, &ids);
+                    (
+                        rule as usize,
+                        ids.iter().map(|id| int_of(store, id)).collect(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn write(&self, store: &mut Store) {
+        let pair = |store: &mut Store, key: i64, value: Cell| {
+            store.compound(intern!("-"), vec![int_cell(key), value])
+        };
+        let entries = self
+            .entries
+            .iter()
+            .map(|(id, constraint)| pair(store, *id, constraint.clone()))
+            .collect();
+        let entries = list(store, entries);
+        let mut history = Vec::new();
+        for (rule, ids) in &self.history {
+            let ids = list(store, ids.iter().map(|id| int_cell(*id)).collect());
+            history.push(pair(store, *rule as i64, ids));
+        }
+        let history = list(store, history);
+        let value = store.compound(chr(), vec![int_cell(self.next), entries, history]);
+        set_attr(store, &global(), chr(), Some(value));
+    }
+}
+
+// the constraints left in the store, as they are shown with answers.
+pub fn residual_goals(store: &Store) -> Vec<Term> {
+    if store.is_empty() {
+        return Vec::new();
+    }
+    Constraints::read(store)
+        .entries
+        .iter()
+        .map(|(_, constraint)| store.to_term(constraint))
+        .collect()
+}
+
+// the goal waking the constraints of a variable whose `chr` attribute is
+// `ids`.
+pub(crate) fn wake(store: &mut Store, ids: &Cell) -> Vec<Cell> {
+    items(store, ids)
+        .into_iter()
+        .map(|id| store.compound(intern!("$chr_activate"), vec![id]))
+        .collect()
+}
+
+// '$chr_add'(Constraint)
+pub(crate) fn add(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let mut constraints = Constraints::read(&app.store);
+    let id = constraints.next;
+    constraints.next += 1;
+    constraints.entries.push((id, args[0].clone()));
+    constraints.write(&mut app.store);
+    app.activate(id)
+}
+
+// '$chr_activate'(Id)
+pub(crate) fn activate(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let id = int_of(&app.store, &args[0]);
+    app.activate(id)
+}
+
+// find_chr_constraint(Constraint)
+pub(crate) fn find_constraint(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let start = app.store.trail();
+    let mut branches = Vec::new();
+    for (_, constraint) in Constraints::read(&app.store).entries {
+        if app.store.unify(&args[0], &constraint) {
+            branches.push(Branch {
+                trail: app.store.trail(),
+                goals: Vec::new(),
+            });
+        }
+        app.store.switch_to(&start);
+    }
+    Ok(branches)
+}
+
+impl App {
+    // tries the rules on the constraint `id`, if it is still in the store,
+    // and fires the first one that applies.
+    fn activate(&mut self, id: i64) -> Result<Vec<Branch>, Exception> {
+        let mut constraints = Constraints::read(&self.store);
+        let active = match constraints.entries.iter().find(|(i, _)| *i == id) {
+            Some((_, constraint)) => constraint.clone(),
+            None => return Ok(succeed(self)),
+        };
+        // variables bound to each other since it was added carry the ids of
+        // either.
+        let active = self.store.to_term(&active);
+        for var in active.free_vars() {
+            let var = match var {
+                Variable::Fresh(addr) => Cell::Ref(addr),
+                _ => continue,
+            };
+            let mut ids = match attr(&self.store, &var, chr()) {
+                Some(ids) => items(&self.store, &ids),
+                None => Vec::new(),
+            };
+            if !ids.iter().any(|other| int_of(&self.store, other) == id) {
+                ids.push(int_cell(id));
+                let ids = list(&mut self.store, ids);
+                set_attr(&mut self.store, &var, chr(), Some(ids));
+            }
+        }
+
+        let terms: Vec<(i64, Term)> = constraints
+            .entries
+            .iter()
+            .map(|(i, constraint)| (*i, self.store.to_term(constraint)))
+            .collect();
+        let rules = Rc::clone(&self.chr_rules);
+        for (r, rule) in rules.iter().enumerate() {
+            for (h, (head, _)) in rule.heads.iter().enumerate() {
+                let mut subst = Subst::new();
+                if !matches(head, &active, &mut subst) {
+                    continue;
+                }
+                let mut ids = vec![None; rule.heads.len()];
+                ids[h] = Some(id);
+                let history = &constraints.history;
+                let subst = match self.partners(r, rule, &terms, history, &mut ids, &subst)? {
+                    Some(subst) => subst,
+                    None => continue,
+                };
+                let ids: Vec<i64> = ids.into_iter().flatten().collect();
+                let removed: Vec<i64> = rule
+                    .heads
+                    .iter()
+                    .zip(&ids)
+                    .filter(|((_, removed), _)| *removed)
+                    .map(|(_, id)| *id)
+                    .collect();
+                constraints.entries.retain(|(i, _)| !removed.contains(i));
+                if rule.is_propagation() {
+                    constraints.history.push((r, ids));
+                }
+                constraints.write(&mut self.store);
+
+                let body = rule.body.subst(&subst);
+                let mut vars: HashMap<Variable, usize> = HashMap::new();
+                for var in body.free_vars() {
+                    if let Variable::Fresh(addr) = var {
+                        vars.insert(var, addr);
+                    }
+                }
+                let body = match rule.module {
+                    m if m == module::user() => body,
+                    m => qualify(m, body),
+                };
+                let mut goals = vec![self.store.from_term(&body, &mut vars)];
+                if !removed.contains(&id) {
+                    goals.push(
+                        self.store
+                            .compound(intern!("$chr_activate"), vec![int_cell(id)]),
+                    );
+                }
+                return Ok(vec![Branch {
+                    trail: self.store.trail(),
+                    goals,
+                }]);
+            }
+        }
+        Ok(succeed(self))
+    }
+
+    // the bindings of the variables of `rule` once the heads whose entry
+    // in `ids` is none match other constraints, not propagated from
+    // before, and the guard holds. Matched constraints are written in `ids`.
+    fn partners(
+        &mut self,
+        r: usize,
+        rule: &ChrRule,
+        terms: &[(i64, Term)],
+        history: &[(usize, Vec<i64>)],
+        ids: &mut Vec<Option<i64>>,
+        subst: &Subst,
+    ) -> Result<Option<Subst>, Exception> {
+        let k = match ids.iter().position(Option::is_none) {
+            Some(k) => k,
+            None => {
+                let matched: Vec<i64> = ids.iter().flatten().copied().collect();
+                if rule.is_propagation() && history.contains(&(r, matched)) {
+                    return Ok(None);
+                }
+                return Ok(if self.guard(rule, subst)? {
+                    Some(subst.clone())
+                } else {
+                    None
+                });
+            }
+        };
+        for (i, term) in terms {
+            if ids.contains(&Some(*i)) {
+                continue;
+            }
+            let mut extended = subst.clone();
+            if matches(&rule.heads[k].0, term, &mut extended) {
+                ids[k] = Some(*i);
+                if let Some(found) = self.partners(r, rule, terms, history, ids, &extended)? {
+                    return Ok(Some(found));
+                }
+                ids[k] = None;
+            }
+        }
+        Ok(None)
+    }
+
+    // whether the guard of `rule` holds without binding the variables of
+    // the matched constraints. It runs with the query set aside.
+    fn guard(&mut self, rule: &ChrRule, subst: &Subst) -> Result<bool, Exception> {
+        if rule.guard.is_empty() {
+            return Ok(true);
+        }
+        let guard: Vec<Term> = rule
+            .guard
+            .iter()
+            .map(|goal| match rule.module {
+                m if m == module::user() => goal.subst(subst),
+                m => qualify(m, goal.subst(subst)),
+            })
+            .collect();
+        let vars: Vec<Variable> = Term::free_vars_sum(&guard)
+            .into_iter()
+            .filter(|var| matches!(var, Variable::Fresh(_)))
+            .collect();
+        // a guard that is not instantiated enough does not hold yet.
+        let answers = match self.answers(vars, &guard) {
+            Ok(answers) => answers,
+            Err((e, _)) if e == Exception::instantiation_error() => return Ok(false),
+            Err((e, _)) => return Err(e),
+        };
+        Ok(answers.iter().any(|values| {
+            let mut seen = HashSet::new();
+            values
+                .iter()
+                .all(|value| matches!(value, Term::Var(var) if seen.insert(*var)))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{answers, consult};
+
+    #[test]
+    fn test_rules() {
+        let gcd = ":- chr_constraint gcd/1.\n\
+                   gcd(0) <=> true.\n\
+                   gcd(N) \\ gcd(M) <=> N =< M | L is M - N, gcd(L).\n";
+        assert_eq!(
+            answers(
+                &mut consult(gcd),
+                &["X"],
+                "gcd(9), gcd(6), find_chr_constraint(X)."
+            ),
+            vec!["gcd(3)"]
+        );
+
+        let leq = ":- chr_constraint leq/2.\n\
+                   reflexivity @ leq(X, X) <=> true.\n\
+                   antisymmetry @ leq(X, Y), leq(Y, X) <=> X = Y.\n\
+                   idempotence @ leq(X, Y) \\ leq(X, Y) <=> true.\n\
+                   transitivity @ leq(X, Y), leq(Y, Z) ==> leq(X, Z).\n";
+        assert_eq!(
+            answers(
+                &mut consult(leq),
+                &["X"],
+                "leq(A, B), leq(B, C), leq(C, A), A = 1, X = f(A, B, C)."
+            ),
+            vec!["f(1, 1, 1)"]
+        );
+        assert_eq!(
+            answers(
+                &mut consult(leq),
+                &["X"],
+                "leq(A, B), leq(B, C), A = 1, B = 2, C = 3, find_chr_constraint(X)."
+            ),
+            vec!["leq(1, 2)", "leq(2, 3)", "leq(1, 3)"]
+        );
+
+        let twice = ":- chr_constraint p/1, q/1.\n p(N) ==> N > 0 | q(N).\n";
+        assert_eq!(
+            answers(
+                &mut consult(twice),
+                &["X"],
+                "p(1), p(0), find_chr_constraint(X)."
+            ),
+            vec!["p(1)", "q(1)", "p(0)"]
+        );
+    }
+}
@@ -0,0 +1,1094 @@
+// Constraints over integers, in the style of `library(clpfd)`. A constrained
+// variable has a `clpfd` attribute `clpfd(Domain, Props)`: its domain,
+// written as for `in/2`, and the propagators it takes part in. Posting a
+// constraint runs the propagators of every variable whose domain shrinks
+// until nothing changes, and a variable left with a single value is bound
+// to it. Binding a constrained variable otherwise wakes its propagators.
+
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::slice;
+
+use crate::app::App;
+use crate::atom::{self, intern, Atom};
+use crate::bigint::BigInt;
+use crate::builtins::{succeed, Branch};
+use crate::coroutine::{attr, check, items, list, set_attr};
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::store::{Cell, Store};
+
+// unbounded ends of an interval.
+const INF: i64 = i64::MIN;
+const SUP: i64 = i64::MAX;
+
+fn clpfd() -> Atom {
+    intern!("clpfd")
+}
+
+fn int(n: i128) -> Term {
+    Term::Const(Constant::Int(BigInt::from(n as i64)))
+}
+
+fn int_cell(n: i64) -> Cell {
+    Cell::Int(Rc::new(BigInt::from(n)))
+}
+
+// the sorted, disjoint and non-adjacent intervals of a set of integers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Domain(Vec<(i64, i64)>);
+
+impl Domain {
+    pub fn full() -> Domain {
+        Domain(vec![(INF, SUP)])
+    }
+
+    pub fn range(min: i64, max: i64) -> Domain {
+        if min > max {
+            Domain(Vec::new())
+        } else {
+            Domain(vec![(min, max)])
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn min(&self) -> i64 {
+        self.0[0].0
+    }
+
+    pub fn max(&self) -> i64 {
+        self.0[self.0.len() - 1].1
+    }
+
+    // the only value in the domain, if so.
+    pub fn value(&self) -> Option<i64> {
+        match self.0[..] {
+            [(min, max)] if min == max && min != INF && max != SUP => Some(min),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, n: i64) -> bool {
+        self.0.iter().any(|&(min, max)| min <= n && n <= max)
+    }
+
+    // the number of values, or none if infinite.
+    pub fn size(&self) -> Option<u64> {
+        if self.is_empty() {
+            return Some(0);
+        }
+        if self.min() == INF || self.max() == SUP {
+            return None;
+        }
+        Some(
+            self.0
+                .iter()
+                .map(|&(min, max)| (max as i128 - min as i128 + 1) as u64)
+                .sum(),
+        )
+    }
+
+    pub fn intersect(&self, other: &Domain) -> Domain {
+        let mut intervals = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.0.len() && j < other.0.len() {
+            let (a, b) = (self.0[i], other.0[j]);
+            let (min, max) = (a.0.max(b.0), a.1.min(b.1));
+            if min <= max {
+                intervals.push((min, max));
+            }
+            if a.1 < b.1 {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        Domain(intervals)
+    }
+
+    pub fn union(&self, other: &Domain) -> Domain {
+        let mut all = [self.0.clone(), other.0.clone()].concat();
+        all.sort();
+        let mut intervals: Vec<(i64, i64)> = Vec::new();
+        for (min, max) in all {
+            match intervals.last_mut() {
+                Some(last) if min <= last.1.saturating_add(1) => last.1 = last.1.max(max),
+                _ => intervals.push((min, max)),
+            }
+        }
+        Domain(intervals)
+    }
+
+    pub fn remove(&self, n: i64) -> Domain {
+        let mut intervals = Vec::new();
+        for &(min, max) in &self.0 {
+            if n < min || n > max {
+                intervals.push((min, max));
+                continue;
+            }
+            if min < n {
+                intervals.push((min, n - 1));
+            }
+            if n < max {
+                intervals.push((n + 1, max));
+            }
+        }
+        Domain(intervals)
+    }
+
+    // the values of a finite domain.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = i64> + '_ {
+        self.0.iter().flat_map(|&(min, max)| min..=max)
+    }
+
+    // `Min..Max \/ ...`, as written for `in/2`.
+    pub fn to_term(&self) -> Term {
+        let bound = |n: i64, name: &str| match n {
+            INF | SUP => Term::atom(name),
+            n => int(n as i128),
+        };
+        let intervals = self.0.iter().map(|&(min, max)| {
+            if min == max {
+                int(min as i128)
+            } else {
+                Term::compound("..", vec![bound(min, "inf"), bound(max, "sup")])
+            }
+        });
+        intervals
+            .reduce(|union, interval| Term::compound("\\/", vec![union, interval]))
+            .unwrap_or_else(|| Term::compound("..", vec![int(1), int(0)]))
+    }
+}
+
+// a 64-bit integer from a cell holding an integer.
+fn small(store: &Store, cell: &Cell) -> Result<i64, Exception> {
+    match store.deref(cell) {
+        Cell::Int(n) => n
+            .to_i64()
+            .filter(|&n| n != INF && n != SUP)
+            .ok_or_else(|| Exception::domain_error("clpfd_integer", store.to_term(cell))),
+        Cell::Ref(_) => Err(Exception::instantiation_error()),
+        other => Err(Exception::type_error("integer", store.to_term(&other))),
+    }
+}
+
+// the domain written as `Domain`, e.g. `1..5 \/ 7`.
+fn parse_domain(store: &Store, cell: &Cell) -> Result<Domain, Exception> {
+    let bound = |cell: &Cell, name: &str, unbounded: i64| match store.deref(cell) {
+        Cell::Atom(atom) if atom.name() == name => Ok(unbounded),
+        _ => small(store, cell),
+    };
+    match store.key(cell) {
+        Some((f, 2)) if f == intern!("..") => {
+            let args = store.args(cell);
+            Ok(Domain::range(
+                bound(&args[0], "inf", INF)?,
+                bound(&args[1], "sup", SUP)?,
+            ))
+        }
+        Some((f, 2)) if f == intern!("\\/") => {
+            let args = store.args(cell);
+            Ok(parse_domain(store, &args[0])?.union(&parse_domain(store, &args[1])?))
+        }
+        _ => match store.deref(cell) {
+            Cell::Int(_) => {
+                let n = small(store, cell)?;
+                Ok(Domain::range(n, n))
+            }
+            Cell::Ref(_) => Err(Exception::instantiation_error()),
+            other => Err(Exception::type_error("clpfd_domain", store.to_term(&other))),
+        },
+    }
+}
+
+// the domain and propagators of the unbound variable `var`.
+fn fd_attr(store: &Store, var: &Cell) -> Result<(Domain, Vec<Cell>), Exception> {
+    match attr(store, var, clpfd()) {
+        Some(value) => {
+            let args = store.args(&value);
+            Ok((parse_domain(store, &args[0])?, items(store, &args[1])))
+        }
+        None => Ok((Domain::full(), Vec::new())),
+    }
+}
+
+// the values `cell` may take.
+fn domain(store: &Store, cell: &Cell) -> Result<Domain, Exception> {
+    match store.deref(cell) {
+        var @ Cell::Ref(_) => Ok(fd_attr(store, &var)?.0),
+        _ => {
+            let n = small(store, cell)?;
+            Ok(Domain::range(n, n))
+        }
+    }
+}
+
+// `n / d` rounded down and up, for `d` other than 0.
+fn floor_div(n: i128, d: i128) -> i128 {
+    let q = n / d;
+    if (n % d != 0) && ((n < 0) != (d < 0)) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn ceil_div(n: i128, d: i128) -> i128 {
+    -floor_div(-n, d)
+}
+
+// a bound computed in 128 bits back to a domain bound, or none if it does
+// not narrow anything.
+fn to_bound(n: i128) -> Option<i64> {
+    if n <= INF as i128 || n >= SUP as i128 {
+        None
+    } else {
+        Some(n as i64)
+    }
+}
+
+// lower and upper bounds, none when unbounded.
+type Bounds = (Option<i128>, Option<i128>);
+
+// `sum(Coefficient * Variable) + Constant`.
+#[derive(Default)]
+struct Linear {
+    terms: Vec<(i128, Cell)>,
+    constant: i128,
+}
+
+impl Linear {
+    fn add(&mut self, coefficient: i128, var: Cell) {
+        match self.terms.iter_mut().find(|(_, v)| *v == var) {
+            Some(term) => term.0 += coefficient,
+            None => self.terms.push((coefficient, var)),
+        }
+    }
+}
+
+// propagators waiting to run over the domains of `store`.
+struct Solver<'a> {
+    store: &'a mut Store,
+    queue: VecDeque<Cell>,
+}
+
+impl<'a> Solver<'a> {
+    fn new(store: &'a mut Store) -> Solver<'a> {
+        Solver {
+            store,
+            queue: VecDeque::new(),
+        }
+    }
+
+    // the domain and propagators of `var`, an unbound variable.
+    fn attr(&self, var: &Cell) -> Result<(Domain, Vec<Cell>), Exception> {
+        fd_attr(self.store, var)
+    }
+
+    fn set_attr(&mut self, var: &Cell, domain: &Domain, props: Vec<Cell>) {
+        let domain = self.store.from_term(&domain.to_term(), &mut HashMap::new());
+        let props = list(self.store, props);
+        let value = self.store.compound(intern!("clpfd"), vec![domain, props]);
+        set_attr(self.store, var, clpfd(), Some(value));
+    }
+
+    fn domain(&self, cell: &Cell) -> Result<Domain, Exception> {
+        domain(self.store, cell)
+    }
+
+    fn schedule(&mut self, props: &[Cell]) {
+        for prop in props {
+            let prop = self.store.deref(prop);
+            if !self.queue.contains(&prop) {
+                self.queue.push_back(prop);
+            }
+        }
+    }
+
+    // restricts `cell` to `domain`, scheduling the propagators of the
+    // variable if that changed anything. False if no value is left.
+    fn narrow(&mut self, cell: &Cell, domain: &Domain) -> Result<bool, Exception> {
+        let var = match self.store.deref(cell) {
+            var @ Cell::Ref(_) => var,
+            _ => return Ok(domain.contains(small(self.store, cell)?)),
+        };
+        let (current, props) = self.attr(&var)?;
+        let narrowed = current.intersect(domain);
+        if narrowed.is_empty() {
+            return Ok(false);
+        }
+        if narrowed == current {
+            return Ok(true);
+        }
+        self.schedule(&props);
+        match narrowed.value() {
+            // other attributes of the variable are woken as usual.
+            Some(n) => {
+                set_attr(self.store, &var, clpfd(), None);
+                Ok(self.store.unify(&var, &int_cell(n)))
+            }
+            None => {
+                self.set_attr(&var, &narrowed, props);
+                Ok(true)
+            }
+        }
+    }
+
+    // adds `props` to the propagators of `cell` if it is a variable.
+    fn attach(&mut self, cell: &Cell, props: &[Cell]) -> Result<(), Exception> {
+        let var = match self.store.deref(cell) {
+            var @ Cell::Ref(_) => var,
+            _ => return Ok(()),
+        };
+        let (domain, mut current) = self.attr(&var)?;
+        for prop in props {
+            let prop = self.store.deref(prop);
+            if !current.iter().any(|other| self.store.deref(other) == prop) {
+                current.push(prop);
+            }
+        }
+        self.set_attr(&var, &domain, current);
+        Ok(())
+    }
+
+    // adds the propagator `prop` to `vars` and runs it.
+    fn post(&mut self, prop: Cell, vars: &[Cell]) -> Result<bool, Exception> {
+        for var in vars {
+            self.attach(var, slice::from_ref(&prop))?;
+        }
+        self.schedule(&[prop]);
+        self.propagate()
+    }
+
+    fn propagate(&mut self) -> Result<bool, Exception> {
+        while let Some(prop) = self.queue.pop_front() {
+            if !self.run(&prop)? {
+                self.queue.clear();
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn run(&mut self, prop: &Cell) -> Result<bool, Exception> {
+        let args = self.store.args(prop);
+        match self.store.key(prop) {
+            Some((name, 4)) if name == intern!("$lin") => self.linear(&args),
+            Some((name, 3)) if name == intern!("$times") => self.times(&args),
+            Some((name, 1)) if name == intern!("$all_different") => self.all_different(&args[0]),
+            _ => Ok(true),
+        }
+    }
+
+    // '$lin'(Coefficients, Variables, Op, Constant): the sum of the
+    // products is `=`, `\=` or `=<` the constant.
+    fn linear(&mut self, args: &[Cell]) -> Result<bool, Exception> {
+        let coefficients = items(self.store, &args[0]);
+        let vars = items(self.store, &args[1]);
+        let op = match self.store.deref(&args[2]) {
+            Cell::Atom(op) => op,
+            _ => return Ok(true),
+        };
+        let mut constant = small(self.store, &args[3])? as i128;
+        let mut terms = Vec::new();
+        for (coefficient, var) in coefficients.iter().zip(vars) {
+            let coefficient = small(self.store, coefficient)? as i128;
+            match self.store.deref(&var) {
+                Cell::Ref(_) => {
+                    let domain = self.domain(&var)?;
+                    terms.push((coefficient, var, domain));
+                }
+                _ => constant -= coefficient * small(self.store, &var)? as i128,
+            }
+        }
+        if op == intern!("\\=") {
+            return match &terms[..] {
+                [] => Ok(constant != 0),
+                [(coefficient, var, domain)] => {
+                    if constant % coefficient != 0 {
+                        return Ok(true);
+                    }
+                    match to_bound(constant / coefficient) {
+                        Some(n) => self.narrow(var, &domain.remove(n)),
+                        None => Ok(true),
+                    }
+                }
+                _ => Ok(true),
+            };
+        }
+        // lower and upper bounds of each product, none when unbounded.
+        let bounds: Vec<Bounds> = terms
+            .iter()
+            .map(|(c, _, domain)| {
+                let (min, max) = (domain.min(), domain.max());
+                let min = Some(min).filter(|&n| n != INF).map(|n| c * n as i128);
+                let max = Some(max).filter(|&n| n != SUP).map(|n| c * n as i128);
+                if *c > 0 {
+                    (min, max)
+                } else {
+                    (max, min)
+                }
+            })
+            .collect();
+        let total = |pick: fn(&Bounds) -> Option<i128>| {
+            let unbounded = bounds.iter().filter(|b| pick(b).is_none()).count();
+            let sum: i128 = bounds.iter().filter_map(pick).sum();
+            (unbounded, sum)
+        };
+        let (lows_unbounded, lows) = total(|b| b.0);
+        let (highs_unbounded, highs) = total(|b| b.1);
+        if terms.is_empty() {
+            return Ok(if op == intern!("=") {
+                constant == 0
+            } else {
+                constant >= 0
+            });
+        }
+        for ((coefficient, var, _), (low, high)) in terms.iter().zip(&bounds) {
+            // bounds of the sum of the other products.
+            let others_low = match low {
+                Some(low) if lows_unbounded == 0 => Some(lows - low),
+                None if lows_unbounded == 1 => Some(lows),
+                _ => None,
+            };
+            let others_high = match high {
+                Some(high) if highs_unbounded == 0 => Some(highs - high),
+                None if highs_unbounded == 1 => Some(highs),
+                _ => None,
+            };
+            // bounds of this product.
+            let max = others_low.map(|low| constant - low);
+            let min = match op.name() {
+                "=" => others_high.map(|high| constant - high),
+                _ => None,
+            };
+            let (min, max) = if *coefficient > 0 {
+                (
+                    min.map(|n| ceil_div(n, *coefficient)),
+                    max.map(|n| floor_div(n, *coefficient)),
+                )
+            } else {
+                (
+                    max.map(|n| ceil_div(n, *coefficient)),
+                    min.map(|n| floor_div(n, *coefficient)),
+                )
+            };
+            let range = Domain::range(
+                min.and_then(to_bound).unwrap_or(INF),
+                max.and_then(to_bound).unwrap_or(SUP),
+            );
+            if !self.narrow(var, &range)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // '$times'(X, Y, Z): `X * Y = Z`, on the bounds of finite domains.
+    fn times(&mut self, args: &[Cell]) -> Result<bool, Exception> {
+        let finite = |d: &Domain| d.min() != INF && d.max() != SUP;
+        let corners = |a: &Domain, b: &Domain| {
+            let (a, b) = ([a.min(), a.max()], [b.min(), b.max()]);
+            let products: Vec<i128> = a
+                .iter()
+                .flat_map(|&x| b.iter().map(move |&y| x as i128 * y as i128))
+                .collect();
+            (
+                *products.iter().min().unwrap(),
+                *products.iter().max().unwrap(),
+            )
+        };
+        let (x, y) = (self.domain(&args[0])?, self.domain(&args[1])?);
+        if finite(&x) && finite(&y) {
+            let (min, max) = corners(&x, &y);
+            let range = Domain::range(to_bound(min).unwrap_or(INF), to_bound(max).unwrap_or(SUP));
+            if !self.narrow(&args[2], &range)? {
+                return Ok(false);
+            }
+        }
+        // a factor from the product and the other factor, when it does not
+        // change sign.
+        for (factor, other) in [(0, 1), (1, 0)] {
+            let z = self.domain(&args[2])?;
+            let divisor = self.domain(&args[other])?;
+            if !finite(&z) || !finite(&divisor) || (divisor.min() <= 0 && divisor.max() >= 0) {
+                continue;
+            }
+            let mut quotients = Vec::new();
+            for &n in &[z.min(), z.max()] {
+                for &d in &[divisor.min(), divisor.max()] {
+                    quotients.push((n as i128, d as i128));
+                }
+            }
+            let min = quotients
+                .iter()
+                .map(|&(n, d)| ceil_div(n, d))
+                .min()
+                .unwrap();
+            let max = quotients
+                .iter()
+                .map(|&(n, d)| floor_div(n, d))
+                .max()
+                .unwrap();
+            let range = Domain::range(to_bound(min).unwrap_or(INF), to_bound(max).unwrap_or(SUP));
+            if !self.narrow(&args[factor], &range)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // '$all_different'(List): values taken are removed from the other
+    // domains, and there must be enough values left for the variables.
+    fn all_different(&mut self, list: &Cell) -> Result<bool, Exception> {
+        let cells = items(self.store, list);
+        let mut taken = Vec::new();
+        let mut vars = Vec::new();
+        for cell in cells {
+            match self.store.deref(&cell) {
+                Cell::Ref(_) => vars.push(cell),
+                _ => {
+                    let n = small(self.store, &cell)?;
+                    if taken.contains(&n) {
+                        return Ok(false);
+                    }
+                    taken.push(n);
+                }
+            }
+        }
+        let mut union = Domain(Vec::new());
+        for var in &vars {
+            let mut domain = self.domain(var)?;
+            for &n in &taken {
+                domain = domain.remove(n);
+            }
+            if !self.narrow(var, &domain)? {
+                return Ok(false);
+            }
+            union = union.union(&self.domain(var)?);
+        }
+        Ok(union.size().is_none_or(|size| size >= vars.len() as u64))
+    }
+
+    // adds the linear form of `expr`, multiplied by `scale`, to `linear`.
+    // Products of two variables are replaced by new variables.
+    fn linearize(
+        &mut self,
+        expr: &Cell,
+        scale: i128,
+        linear: &mut Linear,
+    ) -> Result<(), Exception> {
+        let cell = self.store.deref(expr);
+        let args = self.store.args(&cell);
+        match (self.store.key(&cell), &cell) {
+            (_, Cell::Ref(_)) => linear.add(scale, cell),
+            (_, Cell::Int(_)) => linear.constant += scale * small(self.store, &cell)? as i128,
+            (Some((f, 2)), _) if f == intern!("+") => {
+                self.linearize(&args[0], scale, linear)?;
+                self.linearize(&args[1], scale, linear)?;
+            }
+            (Some((f, 2)), _) if f == intern!("-") => {
+                self.linearize(&args[0], scale, linear)?;
+                self.linearize(&args[1], -scale, linear)?;
+            }
+            (Some((f, 1)), _) if f == intern!("-") => self.linearize(&args[0], -scale, linear)?,
+            (Some((f, 1)), _) if f == intern!("+") => self.linearize(&args[0], scale, linear)?,
+            (Some((f, 2)), _) if f == intern!("*") => {
+                let (mut a, mut b) = (Linear::default(), Linear::default());
+                self.linearize(&args[0], 1, &mut a)?;
+                self.linearize(&args[1], 1, &mut b)?;
+                if a.terms.is_empty() || b.terms.is_empty() {
+                    let (factor, other) = if a.terms.is_empty() { (a, b) } else { (b, a) };
+                    for (coefficient, var) in other.terms {
+                        linear.add(scale * factor.constant * coefficient, var);
+                    }
+                    linear.constant += scale * factor.constant * other.constant;
+                } else {
+                    let z = self.store.var();
+                    self.post_times(a, b, &z)?;
+                    linear.add(scale, z);
+                }
+            }
+            _ => {
+                return Err(Exception::type_error(
+                    "clpfd_expression",
+                    self.store.to_term(&cell),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    // posts `a * b = z` without propagating.
+    fn post_times(&mut self, a: Linear, b: Linear, z: &Cell) -> Result<(), Exception> {
+        let x = self.var_of(a)?;
+        let y = self.var_of(b)?;
+        let prop = self
+            .store
+            .compound(intern!("$times"), vec![x.clone(), y.clone(), z.clone()]);
+        for var in &[x, y, z.clone()] {
+            self.attach(var, slice::from_ref(&prop))?;
+        }
+        self.schedule(&[prop]);
+        Ok(())
+    }
+
+    // posts `var = expr` if `expr` is the product of two expressions with
+    // variables, which needs no variable for the product.
+    fn post_product(&mut self, var: &Cell, expr: &Cell) -> Result<bool, Exception> {
+        let var = self.store.deref(var);
+        if !matches!(var, Cell::Ref(_)) || self.store.key(expr) != Some((intern!("*"), 2)) {
+            return Ok(false);
+        }
+        let args = self.store.args(expr);
+        let (mut a, mut b) = (Linear::default(), Linear::default());
+        self.linearize(&args[0], 1, &mut a)?;
+        self.linearize(&args[1], 1, &mut b)?;
+        if a.terms.is_empty() || b.terms.is_empty() {
+            return Ok(false);
+        }
+        self.post_times(a, b, &var)?;
+        Ok(true)
+    }
+
+    // a variable equal to `linear`.
+    fn var_of(&mut self, mut linear: Linear) -> Result<Cell, Exception> {
+        if let [(1, var)] = &linear.terms[..] {
+            if linear.constant == 0 {
+                return Ok(var.clone());
+            }
+        }
+        let var = self.store.var();
+        linear.add(-1, var.clone());
+        self.post_linear(linear, "=")?;
+        Ok(var)
+    }
+
+    // posts `linear op 0` without propagating.
+    fn post_linear(&mut self, linear: Linear, op: &str) -> Result<(), Exception> {
+        let terms: Vec<(i128, Cell)> = linear.terms.into_iter().filter(|t| t.0 != 0).collect();
+        let mut coefficients = Vec::new();
+        for (coefficient, _) in &terms {
+            let n =
+                to_bound(*coefficient).ok_or_else(|| Exception::resource_error("clpfd_integer"))?;
+            coefficients.push(int_cell(n));
+        }
+        let constant =
+            to_bound(-linear.constant).ok_or_else(|| Exception::resource_error("clpfd_integer"))?;
+        let vars: Vec<Cell> = terms.into_iter().map(|(_, var)| var).collect();
+        let coefficients = list(self.store, coefficients);
+        let var_list = list(self.store, vars.clone());
+        let prop = self.store.compound(
+            intern!("$lin"),
+            vec![
+                coefficients,
+                var_list,
+                Cell::Atom(Atom::new(op)),
+                int_cell(constant),
+            ],
+        );
+        for var in &vars {
+            self.attach(var, slice::from_ref(&prop))?;
+        }
+        self.schedule(&[prop]);
+        Ok(())
+    }
+}
+
+// `Left Relation Right` for the relations `#=`, `#\=`, `#<`, `#>`, `#=<`
+// and `#>=`.
+pub(crate) fn relation(
+    app: &mut App,
+    args: &[Cell],
+    relation: &str,
+) -> Result<Vec<Branch>, Exception> {
+    let mut solver = Solver::new(&mut app.store);
+    let mut linear = Linear::default();
+    // all relations are written as `Linear op 0`, with `op` one of `=`, `\=`
+    // and `=<`.
+    let (left, right, op, offset) = match relation {
+        "#=" => (0, 1, "=", 0),
+        "#\\=" => (0, 1, "\\=", 0),
+        "#=<" => (0, 1, "=<", 0),
+        "#<" => (0, 1, "=<", 1),
+        "#>=" => (1, 0, "=<", 0),
+        _ => (1, 0, "=<", 1),
+    };
+    let product = relation == "#="
+        && (solver.post_product(&args[0], &args[1])? || solver.post_product(&args[1], &args[0])?);
+    if !product {
+        solver.linearize(&args[left], 1, &mut linear)?;
+        solver.linearize(&args[right], -1, &mut linear)?;
+        linear.constant += offset;
+        solver.post_linear(linear, op)?;
+    }
+    let consistent = solver.propagate()?;
+    Ok(check(app, consistent))
+}
+
+// X in Domain
+pub(crate) fn in_(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    domains(app, &[args[0].clone()], &args[1])
+}
+
+// Xs ins Domain
+pub(crate) fn ins(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let vars = list_arg(&app.store, &args[0])?;
+    domains(app, &vars, &args[1])
+}
+
+fn domains(app: &mut App, vars: &[Cell], domain: &Cell) -> Result<Vec<Branch>, Exception> {
+    let domain = parse_domain(&app.store, domain)?;
+    let mut solver = Solver::new(&mut app.store);
+    for var in vars {
+        if !solver.narrow(var, &domain)? {
+            return Ok(check(app, false));
+        }
+    }
+    let consistent = solver.propagate()?;
+    Ok(check(app, consistent))
+}
+
+// the items of a proper list of variables and integers.
+fn list_arg(store: &Store, cell: &Cell) -> Result<Vec<Cell>, Exception> {
+    let mut items = Vec::new();
+    let mut rest = cell.clone();
+    loop {
+        match store.key(&rest) {
+            Some((f, 2)) if f == atom::DOT => {
+                let args = store.args(&rest);
+                match store.deref(&args[0]) {
+                    Cell::Ref(_) | Cell::Int(_) => items.push(args[0].clone()),
+                    other => return Err(Exception::type_error("integer", store.to_term(&other))),
+                }
+                rest = args[1].clone();
+            }
+            Some((f, 0)) if f == atom::NIL => return Ok(items),
+            _ => match store.deref(&rest) {
+                Cell::Ref(_) => return Err(Exception::instantiation_error()),
+                other => return Err(Exception::type_error("list", store.to_term(&other))),
+            },
+        }
+    }
+}
+
+// all_different(Xs)
+pub(crate) fn all_different(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let vars = list_arg(&app.store, &args[0])?;
+    let list = list(&mut app.store, vars.clone());
+    let prop = app.store.compound(intern!("$all_different"), vec![list]);
+    let consistent = Solver::new(&mut app.store).post(prop, &vars)?;
+    Ok(check(app, consistent))
+}
+
+// sum(Xs, Relation, Value)
+pub(crate) fn sum(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let vars = list_arg(&app.store, &args[0])?;
+    let op = match app.store.deref(&args[1]) {
+        Cell::Atom(name) if matches!(name.name(), "#=" | "#\\=" | "#<" | "#>" | "#=<" | "#>=") => {
+            name
+        }
+        Cell::Ref(_) => return Err(Exception::instantiation_error()),
+        other => {
+            return Err(Exception::domain_error(
+                "clpfd_relation",
+                app.store.to_term(&other),
+            ))
+        }
+    };
+    let total = vars
+        .into_iter()
+        .reduce(|total, var| app.store.compound(intern!("+"), vec![total, var]))
+        .unwrap_or_else(|| int_cell(0));
+    relation(app, &[total, args[2].clone()], op.name())
+}
+
+// labeling(Options, Xs) gives each variable of `Xs` the values of its
+// domain in turn. Options choose the variable, `leftmost`, `ff` (smallest
+// domain), `ffc`, `min` or `max`, and the order of values, `up` or `down`.
+pub(crate) fn labeling(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let (mut select, mut down) = ("leftmost", false);
+    let mut options = args[0].clone();
+    loop {
+        match app.store.key(&options) {
+            Some((f, 2)) if f == atom::DOT => {
+                let parts = app.store.args(&options);
+                match app.store.deref(&parts[0]) {
+                    Cell::Atom(name) => match name.name() {
+                        "leftmost" | "ff" | "ffc" | "min" | "max" => select = name.name(),
+                        "up" => down = false,
+                        "down" => down = true,
+                        "step" | "enum" | "bisect" => {}
+                        _ => {
+                            return Err(Exception::domain_error(
+                                "labeling_option",
+                                app.store.to_term(&parts[0]),
+                            ))
+                        }
+                    },
+                    Cell::Ref(_) => return Err(Exception::instantiation_error()),
+                    other => {
+                        return Err(Exception::domain_error(
+                            "labeling_option",
+                            app.store.to_term(&other),
+                        ))
+                    }
+                }
+                options = parts[1].clone();
+            }
+            Some((f, 0)) if f == atom::NIL => break,
+            _ => return Err(Exception::type_error("list", app.store.to_term(&options))),
+        }
+    }
+    let solver = Solver::new(&mut app.store);
+    let mut vars = Vec::new();
+    for var in list_arg(solver.store, &args[1])? {
+        if let Cell::Ref(_) = solver.store.deref(&var) {
+            let domain = solver.domain(&var)?;
+            if domain.size().is_none() {
+                return Err(Exception::instantiation_error());
+            }
+            vars.push((var, domain));
+        }
+    }
+    let key = |domain: &Domain| -> i128 {
+        match select {
+            "ff" | "ffc" => domain.size().unwrap() as i128,
+            "min" => domain.min() as i128,
+            "max" => -(domain.max() as i128),
+            _ => 0,
+        }
+    };
+    // the first variable with the smallest key.
+    let (var, domain) = match vars.iter().min_by_key(|(_, domain)| key(domain)) {
+        Some(chosen) => chosen.clone(),
+        None => return Ok(succeed(app)),
+    };
+    let values: Vec<i64> = if down {
+        domain.values().rev().collect()
+    } else {
+        domain.values().collect()
+    };
+    let goal = app.store.compound(intern!("labeling"), args.to_vec());
+    let start = app.store.trail();
+    let mut branches = Vec::new();
+    for n in values {
+        if app.store.unify(&var, &int_cell(n)) {
+            branches.push(Branch {
+                trail: app.store.trail(),
+                goals: vec![goal.clone()],
+            });
+        }
+        app.store.switch_to(&start);
+    }
+    Ok(branches)
+}
+
+// '$fd_unify'(Attribute, Other), woken when a variable with the attribute
+// `clpfd(Domain, Props)` is bound to `Other`.
+pub(crate) fn unify_hook(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let parts = app.store.args(&args[0]);
+    let domain = parse_domain(&app.store, &parts[0])?;
+    let props = items(&app.store, &parts[1]);
+    let mut solver = Solver::new(&mut app.store);
+    match solver.store.deref(&args[1]) {
+        Cell::Ref(_) | Cell::Int(_) => {}
+        other => {
+            return Err(Exception::type_error(
+                "integer",
+                solver.store.to_term(&other),
+            ))
+        }
+    }
+    solver.attach(&args[1], &props)?;
+    let consistent = solver.narrow(&args[1], &domain)? && {
+        solver.schedule(&props);
+        solver.propagate()?
+    };
+    Ok(check(app, consistent))
+}
+
+// the constraints on `var`, whose `clpfd` attribute is `value`, as shown
+// with answers.
+pub fn residual(store: &Store, var: &Cell, value: &Cell) -> Vec<Term> {
+    let args = store.args(value);
+    let mut goals: Vec<Term> = Vec::new();
+    if let Ok(domain) = parse_domain(store, &args[0]) {
+        if domain != Domain::full() {
+            goals.push(Term::compound(
+                "in",
+                vec![store.to_term(var), domain.to_term()],
+            ));
+        }
+    }
+    for prop in items(store, &args[1]) {
+        let args = store.args(&prop);
+        match store.key(&prop) {
+            Some((name, 4)) if name == intern!("$lin") => {
+                if let Some(goal) = linear_goal(store, &args) {
+                    goals.push(goal);
+                }
+            }
+            Some((name, 3)) if name == intern!("$times") => {
+                let product =
+                    Term::compound("*", vec![store.to_term(&args[0]), store.to_term(&args[1])]);
+                goals.push(Term::compound("#=", vec![product, store.to_term(&args[2])]))
+            }
+            Some((name, 1)) if name == intern!("$all_different") => goals.push(Term::compound(
+                "all_different",
+                vec![store.to_term(&args[0])],
+            )),
+            _ => {}
+        }
+    }
+    // constraints between bound variables hold.
+    goals.retain(|goal| goal.has_free_var());
+    goals
+}
+
+// `'$lin'(Coefficients, Variables, Op, Constant)` as a relation between the
+// variables still unbound, with positive coefficients on both sides, unless
+// the domains of the variables already imply it.
+fn linear_goal(store: &Store, args: &[Cell]) -> Option<Term> {
+    let mut constant = small(store, &args[3]).ok()? as i128;
+    let mut terms = Vec::new();
+    let (mut min, mut max) = (Some(0), Some(0));
+    for (coefficient, var) in items(store, &args[0]).iter().zip(items(store, &args[1])) {
+        let coefficient = small(store, coefficient).ok()? as i128;
+        if let Cell::Ref(_) = store.deref(&var) {
+            let domain = domain(store, &var).ok()?;
+            let (low, high) = (domain.min(), domain.max());
+            let low = Some(low)
+                .filter(|&n| n != INF)
+                .map(|n| coefficient * n as i128);
+            let high = Some(high)
+                .filter(|&n| n != SUP)
+                .map(|n| coefficient * n as i128);
+            let (low, high) = if coefficient > 0 {
+                (low, high)
+            } else {
+                (high, low)
+            };
+            min = min.zip(low).map(|(a, b)| a + b);
+            max = max.zip(high).map(|(a, b)| a + b);
+            terms.push((coefficient, store.to_term(&var), domain));
+        } else {
+            constant -= coefficient * small(store, &var).ok()? as i128;
+        }
+    }
+    let op = match store.deref(&args[2]) {
+        Cell::Atom(op) => op,
+        _ => return None,
+    };
+    let entailed = match op.name() {
+        "=" => terms.is_empty(),
+        "\\=" => match &terms[..] {
+            [(c, _, domain)] => constant % c != 0 || !domain.contains((constant / c) as i64),
+            _ => min.is_some_and(|min| constant < min) || max.is_some_and(|max| constant > max),
+        },
+        _ => max.is_some_and(|max| max <= constant),
+    };
+    if entailed {
+        return None;
+    }
+    let sum = |terms: Vec<Term>| {
+        terms
+            .into_iter()
+            .reduce(|sum, term| Term::compound("+", vec![sum, term]))
+    };
+    let product = |c: i128, var: &Term| match c {
+        1 => var.clone(),
+        c => Term::compound("*", vec![int(c), var.clone()]),
+    };
+    let left: Vec<Term> = terms
+        .iter()
+        .filter(|t| t.0 > 0)
+        .map(|(c, v, _)| product(*c, v))
+        .collect();
+    let right: Vec<Term> = terms
+        .iter()
+        .filter(|t| t.0 < 0)
+        .map(|(c, v, _)| product(-c, v))
+        .collect();
+    let name = match op.name() {
+        "=" => "#=",
+        "\\=" => "#\\=",
+        _ => "#=<",
+    };
+    // `Left - Right op Constant`.
+    Some(match (sum(left), sum(right)) {
+        (Some(left), None) => Term::compound(name, vec![left, int(constant)]),
+        (None, Some(right)) => {
+            let name = if name == "#=<" { "#>=" } else { name };
+            Term::compound(name, vec![right, int(-constant)])
+        }
+        (Some(left), Some(right)) => {
+            let right = match constant {
+                0 => right,
+                c if c > 0 => Term::compound("+", vec![right, int(c)]),
+                c => Term::compound("-", vec![right, int(-c)]),
+            };
+            Term::compound(name, vec![left, right])
+        }
+        (None, None) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::values_of_x;
+
+    #[test]
+    fn test_domain() {
+        let a = Domain::range(1, 10).remove(5);
+        assert_eq!(a, Domain(vec![(1, 4), (6, 10)]));
+        assert_eq!(a.size(), Some(9));
+        let b = a.intersect(&Domain::range(3, 7));
+        assert_eq!(b, Domain(vec![(3, 4), (6, 7)]));
+        assert_eq!(b.union(&Domain::range(5, 5)), Domain::range(3, 7));
+        assert_eq!(Domain::range(2, 2).value(), Some(2));
+        assert_eq!(Domain::full().size(), None);
+        assert_eq!(
+            b.to_term().to_string(),
+            "\\/(..(3, 4), ..(6, 7))".to_string()
+        );
+        assert_eq!(floor_div(-7, 2), -4);
+        assert_eq!(ceil_div(-7, 2), -3);
+        assert_eq!(ceil_div(7, -2), -3);
+    }
+
+    #[test]
+    fn test_constraints() {
+        assert_eq!(values_of_x("X #= 3 * 4 - 2."), vec!["10"]);
+        assert_eq!(
+            values_of_x("X in 1..5, X #> 3, label([X])."),
+            vec!["4", "5"]
+        );
+        assert_eq!(
+            values_of_x("X in 1..3, labeling([down], [X]), X #\\= 2."),
+            vec!["3", "1"]
+        );
+        assert_eq!(values_of_x("[X, Y] ins 0..3, X + Y #= 6."), vec!["3"]);
+        assert_eq!(
+            values_of_x("X in 1..3, Y in 2..5, X = Y, Y #< 3."),
+            vec!["2"]
+        );
+        assert_eq!(
+            values_of_x("[A, B, X] ins 1..3, all_different([A, B, X]), A #< B, B #< X."),
+            vec!["3"]
+        );
+        assert_eq!(
+            values_of_x("sum([A, B], #=, X), A in 1..2, B = 5, A #> 1."),
+            vec!["7"]
+        );
+        assert_eq!(
+            values_of_x("X * X #= 16, X in 0..9, label([X])."),
+            vec!["4"]
+        );
+        assert_eq!(values_of_x("X in 1..3, X = 4."), Vec::<String>::new());
+    }
+}
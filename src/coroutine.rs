@@ -0,0 +1,438 @@
+// Coroutining through attributed variables. A variable may carry one value
+// per module, set with `put_attr/3`. When it gets bound, the goals its
+// attributes wake run before the next goal: `Module:attr_unify_hook(Value,
+// Other)`, the goals `freeze/2`, `dif/2` and `when/2` suspended on it,
+// which they keep as a list in the attribute of their own module, or the
+// propagators of the constraints in `clpfd`.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::app::App;
+use crate::atom::{self, Atom};
+use crate::builtins::{atom_arg, fail, succeed, Branch};
+use crate::chr;
+use crate::clpfd;
+use crate::error::Exception;
+use crate::expr::{Constant, Term, Variable};
+use crate::module;
+use crate::store::{Cell, Store, Trail};
+
+// the items of a list of cells.
+pub(crate) fn items(store: &Store, list: &Cell) -> Vec<Cell> {
+    let mut items = Vec::new();
+    let mut list = list.clone();
+    while store
+        .key(&list)
+        .is_some_and(|(f, n)| f == atom::DOT && n == 2)
+    {
+        let args = store.args(&list);
+        items.push(args[0].clone());
+        list = args[1].clone();
+    }
+    items
+}
+
+pub(crate) fn list(store: &mut Store, items: Vec<Cell>) -> Cell {
+    items
+        .into_iter()
+        .rev()
+        .fold(Cell::Atom(atom::NIL), |tail, item| {
+            store.compound(atom::DOT, vec![item, tail])
+        })
+}
+
+// the attributes of `var` as `(Module, Value)` pairs.
+fn attrs(store: &Store, var: &Cell) -> Vec<(Atom, Cell)> {
+    let list = match store.attrs(var) {
+        Some(list) => list,
+        None => return Vec::new(),
+    };
+    items(store, &list)
+        .iter()
+        .filter_map(|pair| {
+            let args = store.args(pair);
+            match store.deref(&args[0]) {
+                Cell::Atom(module) => Some((module, args[1].clone())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn attr(store: &Store, var: &Cell, module: Atom) -> Option<Cell> {
+    attrs(store, var)
+        .into_iter()
+        .find(|(m, _)| *m == module)
+        .map(|(_, value)| value)
+}
+
+// sets the attribute of `var` for `module` to `value`, or removes it.
+pub(crate) fn set_attr(store: &mut Store, var: &Cell, module: Atom, value: Option<Cell>) {
+    let mut pairs = attrs(store, var);
+    let position = pairs.iter().position(|(m, _)| *m == module);
+    match (position, value) {
+        (Some(i), Some(value)) => pairs[i].1 = value,
+        (Some(i), None) => {
+            pairs.remove(i);
+        }
+        (None, Some(value)) => pairs.push((module, value)),
+        (None, None) => return,
+    }
+    if pairs.is_empty() {
+        store.set_attrs(var, None);
+        return;
+    }
+    let pairs = pairs
+        .into_iter()
+        .map(|(module, value)| store.compound(Atom::new("-"), vec![Cell::Atom(module), value]))
+        .collect();
+    let pairs = list(store, pairs);
+    store.set_attrs(var, Some(pairs));
+}
+
+// adds `goal` to the goals suspended on `var` by `module`, unless the same
+// goal already is.
+fn suspend(store: &mut Store, var: &Cell, module: &str, goal: Cell) {
+    let module = Atom::new(module);
+    let mut goals = match attr(store, var, module) {
+        Some(goals) => items(store, &goals),
+        None => Vec::new(),
+    };
+    let term = store.to_term(&goal);
+    if goals.iter().any(|other| store.to_term(other) == term) {
+        return;
+    }
+    goals.push(goal);
+    let goals = list(store, goals);
+    set_attr(store, var, module, Some(goals));
+}
+
+// the goals suspended on `var` as they are shown with answers.
+fn var_goals(store: &Store, var: &Cell) -> Vec<Term> {
+    let mut goals = Vec::new();
+    for (module, value) in attrs(store, var) {
+        match module.name() {
+            "freeze" => goals.extend(items(store, &value).iter().map(|goal| {
+                Term::compound("freeze", vec![store.to_term(var), store.to_term(goal)])
+            })),
+            "dif" => goals.extend(items(store, &value).iter().map(|goal| store.to_term(goal))),
+            "when" => {
+                for goal in items(store, &value) {
+                    let args = store.args(&goal);
+                    if let Cell::Ref(_) = store.deref(&args[0]) {
+                        let (condition, goal) = (store.to_term(&args[1]), store.to_term(&args[2]));
+                        goals.push(Term::compound("when", vec![condition, goal]));
+                    }
+                }
+            }
+            "clpfd" => goals.extend(clpfd::residual(store, var, &value)),
+            // constraints are shown from the store.
+            "chr" => {}
+            _ => goals.push(Term::compound(
+                "put_attr",
+                vec![
+                    store.to_term(var),
+                    Term::Const(Constant::Name(module)),
+                    store.to_term(&value),
+                ],
+            )),
+        }
+    }
+    goals
+}
+
+// the goals suspended on the variables of `cells`, or on the variables of
+// those goals.
+pub fn residual_goals(store: &Store, cells: &[Cell]) -> Vec<Term> {
+    let mut goals: Vec<Term> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending: Vec<Variable> = cells
+        .iter()
+        .flat_map(|cell| store.to_term(cell).free_vars())
+        .collect();
+    pending.reverse();
+    while let Some(var) = pending.pop() {
+        let addr = match var {
+            Variable::Fresh(addr) if seen.insert(addr) => addr,
+            _ => continue,
+        };
+        for goal in var_goals(store, &Cell::Ref(addr)) {
+            if !goals.contains(&goal) {
+                pending.extend(goal.free_vars().into_iter().rev());
+                goals.push(goal);
+            }
+        }
+    }
+    goals
+}
+
+impl App {
+    // the goals woken by the attributed variables bound since `from`, in
+    // the order they were bound.
+    pub(crate) fn wakeups(&mut self, from: &Trail) -> Vec<Cell> {
+        let mut bound = Vec::new();
+        let mut trail = &self.store.trail;
+        while trail.len() > from.len() {
+            let entry = trail.0.as_ref().unwrap();
+            match (&entry.old, &entry.value) {
+                (_, Cell::Attr(_)) => {}
+                (_, Cell::Ref(a)) if *a == entry.addr => {}
+                (Cell::Attr(b), value) => bound.push((*b, value.clone())),
+                _ => {}
+            }
+            trail = &entry.prev;
+        }
+        let mut goals = Vec::new();
+        for (attrs, other) in bound.into_iter().rev() {
+            for pair in items(&self.store, &Cell::Ref(attrs)) {
+                let args = self.store.args(&pair);
+                let module = match self.store.deref(&args[0]) {
+                    Cell::Atom(module) => module,
+                    _ => continue,
+                };
+                let value = args[1].clone();
+                match module.name() {
+                    // a frozen goal runs once `other` is bound, or is frozen
+                    // again on it.
+                    "freeze" => {
+                        for goal in items(&self.store, &value) {
+                            let freeze = self
+                                .store
+                                .compound(Atom::new("freeze"), vec![other.clone(), goal]);
+                            goals.push(freeze);
+                        }
+                    }
+                    "dif" | "when" => goals.extend(items(&self.store, &value)),
+                    "chr" => goals.extend(chr::wake(&mut self.store, &value)),
+                    "clpfd" => {
+                        let hook = self
+                            .store
+                            .compound(Atom::new("$fd_unify"), vec![value, other.clone()]);
+                        goals.push(hook);
+                    }
+                    _ => {
+                        let hook = self
+                            .store
+                            .compound(Atom::new("attr_unify_hook"), vec![value, other.clone()]);
+                        goals.push(self.qualify(module, hook));
+                    }
+                }
+            }
+        }
+        goals
+    }
+
+    // `goal` qualified with the module the running builtin was called from.
+    fn context_goal(&mut self, goal: &Cell) -> Cell {
+        if self.context == module::user() {
+            return goal.clone();
+        }
+        self.qualify(self.context, goal.clone())
+    }
+}
+
+// the single solution if `test` holds.
+pub(crate) fn check(app: &App, test: bool) -> Vec<Branch> {
+    if test {
+        succeed(app)
+    } else {
+        fail()
+    }
+}
+
+fn var_arg(app: &App, cell: &Cell) -> Result<Cell, Exception> {
+    match app.store.deref(cell) {
+        var @ Cell::Ref(_) => Ok(var),
+        other => Err(Exception::uninstantiation_error(app.store.to_term(&other))),
+    }
+}
+
+// put_attr(Var, Module, Value)
+pub(crate) fn put_attr(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let var = var_arg(app, &args[0])?;
+    let module = atom_arg(app, &args[1])?;
+    set_attr(&mut app.store, &var, module, Some(args[2].clone()));
+    Ok(succeed(app))
+}
+
+// get_attr(Var, Module, Value)
+pub(crate) fn get_attr(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let module = atom_arg(app, &args[1])?;
+    match attr(&app.store, &args[0], module) {
+        Some(value) => {
+            let unified = app.store.unify(&args[2], &value);
+            Ok(check(app, unified))
+        }
+        None => Ok(fail()),
+    }
+}
+
+// del_attr(Var, Module)
+pub(crate) fn del_attr(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let module = atom_arg(app, &args[1])?;
+    set_attr(&mut app.store, &args[0], module, None);
+    Ok(succeed(app))
+}
+
+// freeze(Var, Goal) runs `Goal` once `Var` is bound.
+pub(crate) fn freeze(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    match app.store.deref(&args[0]) {
+        var @ Cell::Ref(_) => {
+            let goal = app.context_goal(&args[1]);
+            suspend(&mut app.store, &var, "freeze", goal);
+            Ok(succeed(app))
+        }
+        _ => Ok(vec![Branch {
+            trail: app.store.trail(),
+            goals: vec![args[1].clone()],
+        }]),
+    }
+}
+
+// frozen(Term, Goal) unifies `Goal` with the conjunction of the goals
+// suspended on `Term` if it is a variable, or `true`.
+pub(crate) fn frozen(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let goals = var_goals(&app.store, &args[0]);
+    let goal = match goals.split_last() {
+        Some((last, init)) => init.iter().rev().fold(last.clone(), |rest, goal| {
+            Term::compound(",", vec![goal.clone(), rest])
+        }),
+        None => Term::atom("true"),
+    };
+    // the variables of the goals are the cells they were read from.
+    let mut vars: HashMap<Variable, usize> = HashMap::new();
+    for var in goal.free_vars() {
+        if let Variable::Fresh(addr) = var {
+            vars.insert(var, addr);
+        }
+    }
+    let goal = app.store.from_term(&goal, &mut vars);
+    let unified = app.store.unify(&args[1], &goal);
+    Ok(check(app, unified))
+}
+
+// dif(A, B) holds while `A` and `B` do not unify, and fails as soon as they
+// are identical.
+pub(crate) fn dif(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    match app.store.unifier(&args[0], &args[1]) {
+        None => Ok(succeed(app)),
+        Some(vars) if vars.is_empty() => Ok(fail()),
+        Some(vars) => {
+            let goal = app.store.compound(Atom::new("dif"), args.to_vec());
+            for var in vars {
+                suspend(&mut app.store, &var, "dif", goal.clone());
+            }
+            Ok(succeed(app))
+        }
+    }
+}
+
+// when(Condition, Goal) runs `Goal` once `Condition` holds.
+pub(crate) fn when(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let done = app.store.var();
+    let goal = app.context_goal(&args[1]);
+    wake_when(app, &[done, args[0].clone(), goal])
+}
+
+// '$when'(Done, Condition, Goal), the form in which `when/2` is suspended:
+// runs `Goal` and binds `Done` once `Condition` holds, unless `Done` is
+// already bound.
+pub(crate) fn wake_when(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    if !matches!(app.store.deref(&args[0]), Cell::Ref(_)) {
+        return Ok(succeed(app));
+    }
+    match condition(app, &args[1])? {
+        None => {
+            app.store.unify(&args[0], &Cell::Atom(Atom::new("true")));
+            Ok(vec![Branch {
+                trail: app.store.trail(),
+                goals: vec![args[2].clone()],
+            }])
+        }
+        Some(vars) => {
+            let goal = app.store.compound(Atom::new("$when"), args.to_vec());
+            for var in vars {
+                suspend(&mut app.store, &var, "when", goal.clone());
+            }
+            Ok(succeed(app))
+        }
+    }
+}
+
+// none if `condition` holds, or else variables whose binding may make it
+// hold. Conditions are `nonvar/1`, `ground/1`, `?=/2` and their
+// conjunctions and disjunctions.
+fn condition(app: &mut App, condition: &Cell) -> Result<Option<Vec<Cell>>, Exception> {
+    let invalid = |app: &App| match app.store.deref(condition) {
+        Cell::Ref(_) => Exception::instantiation_error(),
+        other => Exception::domain_error("when_condition", app.store.to_term(&other)),
+    };
+    let key = app.store.key(condition).ok_or_else(|| invalid(app))?;
+    let args = app.store.args(condition);
+    match (key.0.name(), key.1) {
+        ("nonvar", 1) => match app.store.deref(&args[0]) {
+            var @ Cell::Ref(_) => Ok(Some(vec![var])),
+            _ => Ok(None),
+        },
+        ("ground", 1) => match app.store.to_term(&args[0]).free_vars().first() {
+            Some(Variable::Fresh(addr)) => Ok(Some(vec![Cell::Ref(*addr)])),
+            _ => Ok(None),
+        },
+        ("?=", 2) => match app.store.unifier(&args[0], &args[1]) {
+            Some(vars) if !vars.is_empty() => Ok(Some(vars)),
+            _ => Ok(None),
+        },
+        (",", 2) => {
+            let first = self::condition(app, &args[0])?;
+            let second = self::condition(app, &args[1])?;
+            Ok(first.or(second))
+        }
+        (";", 2) => {
+            let first = self::condition(app, &args[0])?;
+            let second = self::condition(app, &args[1])?;
+            match (first, second) {
+                (Some(first), Some(second)) => Ok(Some([first, second].concat())),
+                _ => Ok(None),
+            }
+        }
+        _ => Err(invalid(app)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::values_of_x;
+
+    #[test]
+    fn test_coroutining() {
+        assert_eq!(values_of_x("freeze(Y, X = done), Y = 1."), vec!["done"]);
+        assert_eq!(
+            values_of_x("freeze(Y, X = done), Y = Z, Z = 1."),
+            vec!["done"]
+        );
+        assert_eq!(values_of_x("freeze(Y, fail), (Y = 1 ; X = 2)."), vec!["2"]);
+        assert_eq!(values_of_x("dif(X, a), X = a."), Vec::<String>::new());
+        assert_eq!(values_of_x("dif(X, a), (X = a ; X = b)."), vec!["b"]);
+        assert_eq!(
+            values_of_x("dif(f(X, Y), f(a, b)), X = a, Y = c."),
+            vec!["a"]
+        );
+        assert_eq!(
+            values_of_x("dif(X, Y), X = Z, Y = Z."),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            values_of_x("when(ground(f(A, B)), X = done), A = 1, B = 2."),
+            vec!["done"]
+        );
+        assert_eq!(
+            values_of_x("when((nonvar(A) ; nonvar(B)), X = 1), B = 2."),
+            vec!["1"]
+        );
+        assert_eq!(
+            values_of_x("freeze(Y, go), frozen(Y, freeze(_, X))."),
+            vec!["go"]
+        );
+        assert_eq!(values_of_x("frozen(a, X)."), vec!["true"]);
+    }
+}
@@ -0,0 +1,214 @@
+// Definite clause grammars. A grammar rule `Head --> Body` is translated
+// into a clause whose nonterminals take two more arguments: the list to
+// parse and what is left of it once they are parsed.
+
+use std::collections::HashSet;
+use std::mem;
+
+use crate::atom::Atom;
+use crate::error::Exception;
+use crate::expr::{Constant, Term, Variable};
+use crate::module;
+
+fn unify(a: Term, b: Term) -> Term {
+    Term::compound("=", vec![a, b])
+}
+
+fn and(a: Term, b: Term) -> Term {
+    Term::compound(",", vec![a, b])
+}
+
+// `term` with two more arguments.
+fn extend(term: &Term, s0: Term, s: Term) -> Option<Term> {
+    match term {
+        Term::Const(Constant::Name(name)) => Some(Term::Combined {
+            functor: *name,
+            args: vec![s0, s],
+        }),
+        Term::Combined { functor, args } => Some(Term::Combined {
+            functor: *functor,
+            args: [args.clone(), vec![s0, s]].concat(),
+        }),
+        _ => None,
+    }
+}
+
+// the items of a proper list of terminals, or of a string as codes.
+fn terminals(term: &Term) -> Option<Vec<Term>> {
+    let mut items = Vec::new();
+    let mut term = term;
+    loop {
+        match term {
+            Term::Combined { functor, args } if functor.name() == "." && args.len() == 2 => {
+                items.push(args[0].clone());
+                term = &args[1];
+            }
+            Term::Const(Constant::Name(name)) if name.name() == "[]" => return Some(items),
+            Term::Const(Constant::Str(text)) if items.is_empty() => {
+                let codes = text
+                    .chars()
+                    .map(|c| Term::Const(Constant::Int((c as i64).into())))
+                    .collect();
+                return Some(codes);
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn not_callable(term: &Term) -> Exception {
+    Exception::type_error("callable", term.clone())
+}
+
+// the goals of a conjunction, nested or not.
+pub fn goals(mut term: Term) -> Vec<Term> {
+    match term {
+        Term::Combined {
+            functor,
+            ref mut args,
+        } if functor.name() == "," && args.len() == 2 => {
+            mem::take(args).into_iter().flat_map(goals).collect()
+        }
+        _ => vec![term],
+    }
+}
+
+// translates grammar bodies, naming the lists between their parts with
+// variables that do not occur in the rule.
+pub struct Translator {
+    used: HashSet<Variable>,
+    next: usize,
+}
+
+impl Translator {
+    pub fn new(terms: &[&Term]) -> Translator {
+        Translator {
+            used: terms.iter().flat_map(|term| term.free_vars()).collect(),
+            next: 0,
+        }
+    }
+
+    pub fn fresh(&mut self) -> Term {
+        loop {
+            let var = Variable::Named(Atom::new(&format!("_S{}", self.next)));
+            self.next += 1;
+            if !self.used.contains(&var) {
+                return Term::Var(var);
+            }
+        }
+    }
+
+    // the clause `Head :- Body` for `Head --> Body`, where `Head` may be
+    // followed by a list pushed back onto the input. It is an error for a
+    // part not to be callable, or to be a cut, which the engine lacks.
+    pub fn rule(&mut self, head: &Term, body: &Term) -> Result<(Term, Term), Exception> {
+        let (s0, s) = (self.fresh(), self.fresh());
+        match head {
+            Term::Combined { functor, args } if functor.name() == "," && args.len() == 2 => {
+                let pushback = terminals(&args[1]).ok_or_else(|| not_callable(&args[1]))?;
+                let nonterminal = extend(&args[0], s0.clone(), s.clone())
+                    .ok_or_else(|| not_callable(&args[0]))?;
+                let mid = self.fresh();
+                let body = self.body(body, s0, mid.clone())?;
+                Ok((nonterminal, and(body, unify(s, Term::list(pushback, mid)))))
+            }
+            _ => {
+                let nonterminal =
+                    extend(head, s0.clone(), s.clone()).ok_or_else(|| not_callable(head))?;
+                Ok((nonterminal, self.body(body, s0, s)?))
+            }
+        }
+    }
+
+    // the goal parsing `body` from `s0`, leaving `s`.
+    pub fn body(&mut self, body: &Term, s0: Term, s: Term) -> Result<Term, Exception> {
+        if let Some(items) = terminals(body) {
+            return Ok(unify(s0, Term::list(items, s)));
+        }
+        match body {
+            Term::Var(_) => Ok(Term::compound("phrase", vec![body.clone(), s0, s])),
+            Term::Combined { functor, args } => match (functor.name(), args.len()) {
+                (",", 2) => {
+                    let mid = self.fresh();
+                    let first = self.body(&args[0], s0, mid.clone())?;
+                    Ok(and(first, self.body(&args[1], mid, s)?))
+                }
+                (";", 2) | ("|", 2) => {
+                    let left = self.body(&args[0], s0.clone(), s.clone())?;
+                    Ok(Term::compound(";", vec![left, self.body(&args[1], s0, s)?]))
+                }
+                ("->", 2) => {
+                    let mid = self.fresh();
+                    let condition = self.body(&args[0], s0, mid.clone())?;
+                    Ok(Term::compound(
+                        "->",
+                        vec![condition, self.body(&args[1], mid, s)?],
+                    ))
+                }
+                ("\\+", 1) => {
+                    let rest = self.fresh();
+                    let goal = self.body(&args[0], s0.clone(), rest)?;
+                    Ok(and(Term::compound("\\+", vec![goal]), unify(s0, s)))
+                }
+                ("{}", 1) => Ok(and(args[0].clone(), unify(s0, s))),
+                (":", 2) => {
+                    let goal = self.body(&args[1], s0, s)?;
+                    Ok(Term::compound(":", vec![args[0].clone(), goal]))
+                }
+                _ => Ok(extend(body, s0, s).unwrap()),
+            },
+            Term::Const(Constant::Name(name)) if name.name() == "!" => {
+                let cut = module::indicator((*name, 0));
+                Err(Exception::unsupported_error(cut))
+            }
+            Term::Const(Constant::Name(_)) => Ok(extend(body, s0, s).unwrap()),
+            _ => Err(not_callable(body)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_file_content;
+
+    fn clause(source: &str) -> String {
+        let (rules, errors) = parse_file_content("test.pl", source);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let rule = &rules[0];
+        let body: Vec<String> = rule.rhs.iter().map(|goal| goal.to_string()).collect();
+        format!("{} :- {}", rule.lhs, body.join(", "))
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            clause("greeting --> [hello], name."),
+            "greeting(_S0, _S1) :- =(_S0, [hello|_S2]), name(_S2, _S1)"
+        );
+        assert_eq!(
+            clause("digits([D|T]) --> digit(D), {D > 0}, digits(T)."),
+            "digits([D|T], _S0, _S1) :- digit(D, _S0, _S2), >(D, 0), =(_S2, _S3), \
+             digits(T, _S3, _S1)"
+        );
+        assert_eq!(
+            clause("sign(S) --> ([-] -> {S = -1} ; \\+ [+], {S = 1})."),
+            "sign(S, _S0, _S1) :- ;(->(=(_S0, [-|_S2]), ','(=(S, -1), =(_S2, _S1))), \
+             ','(','(\\+(=(_S0, [+|_S4])), =(_S0, _S3)), ','(=(S, 1), =(_S3, _S1))))"
+        );
+        assert_eq!(
+            clause("ab --> \"ab\" | []."),
+            "ab(_S0, _S1) :- ;(=(_S0, [97, 98|_S1]), =(_S0, _S1))"
+        );
+        assert_eq!(
+            clause("look, [X] --> [X]."),
+            "look(_S0, _S1) :- =(_S0, [X|_S2]), =(_S1, [X|_S2])"
+        );
+        assert_eq!(
+            clause("any(G) --> call(G, x), X."),
+            "any(G, _S0, _S1) :- call(G, x, _S0, _S2), phrase(X, _S2, _S1)"
+        );
+
+        let (_, errors) = parse_file_content("test.pl", "a --> b, 1.\nX --> b.\nc --> b, !.");
+        assert_eq!(errors.len(), 3);
+    }
+}
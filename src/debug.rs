@@ -0,0 +1,420 @@
+// The debugger, following the box model: a goal is entered by its call
+// port and left by its exit port once proved, or by its fail port when it
+// has no solution; its redo port is passed when another solution is tried.
+// With breadth-first resolution the alternatives of a goal are all queued
+// at once, so the ports after the call are marked by goals added to the
+// queue items while debugging: `'$trace_redo'(Depth, Goal)` before the
+// goals of every alternative but the first and `'$trace_exit'(Depth, Goal)`
+// after them. The depth of a goal is the number of exit marks below it.
+//
+// In trace mode every port is shown, and in debug mode only those of the
+// predicates having a spy point. Leashed ports then wait for a command.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{stdin, stdout, Write};
+use std::rc::Rc;
+
+use crate::app::{App, Goals};
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::builtins::{atom_arg, fail, succeed, Branch};
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::load::pred_indicators;
+use crate::store::Cell;
+use crate::wam::PredKey;
+
+const HELP: &str = "Options:\n\
+    c, <return>: creep to the next port\n\
+    s: skip to the exit or fail port of this goal\n\
+    l: leap to the next spy point\n\
+    f: fail this goal\n\
+    a: abort the query\n\
+    n: stop debugging\n";
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Port {
+    Call,
+    Exit,
+    Redo,
+    Fail,
+    Exception,
+}
+
+const PORTS: [Port; 5] = [
+    Port::Call,
+    Port::Exit,
+    Port::Redo,
+    Port::Fail,
+    Port::Exception,
+];
+
+impl Port {
+    fn parse(name: &str) -> Option<Port> {
+        PORTS
+            .iter()
+            .copied()
+            .find(|port| port.to_string().to_lowercase() == name)
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Port::Call => "Call",
+            Port::Exit => "Exit",
+            Port::Redo => "Redo",
+            Port::Fail => "Fail",
+            Port::Exception => "Exception",
+        };
+        f.pad(name)
+    }
+}
+
+pub struct Debugger {
+    // whether every port is shown.
+    pub(crate) tracing: bool,
+    // whether the ports of spy points are shown.
+    pub(crate) debugging: bool,
+    // predicates given by name and arity, or by name for every arity.
+    spy_points: HashSet<(Atom, Option<usize>)>,
+    // ports waiting for a command.
+    leashed: HashSet<Port>,
+    // depth below which ports are hidden after `skip`.
+    skip: Option<usize>,
+    // reads a line with a command, none at the end of the input.
+    pub input: Box<dyn FnMut() -> Option<String>>,
+    pub output: Box<dyn Write>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            tracing: false,
+            debugging: false,
+            spy_points: HashSet::new(),
+            leashed: PORTS.iter().copied().collect(),
+            skip: None,
+            input: Box::new(|| {
+                let mut line = String::new();
+                match stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line),
+                }
+            }),
+            output: Box::new(stdout()),
+        }
+    }
+
+    // whether goals are marked for the exit and redo ports.
+    pub(crate) fn is_active(&self) -> bool {
+        self.tracing || self.debugging
+    }
+
+    fn is_spied(&self, key: PredKey) -> bool {
+        self.debugging
+            && (self.spy_points.contains(&(key.0, Some(key.1)))
+                || self.spy_points.contains(&(key.0, None)))
+    }
+
+    fn show(&mut self, text: &str) {
+        // the debugger has no way to report that its output failed.
+        let _ = self.output.write_all(text.as_bytes());
+        let _ = self.output.flush();
+    }
+}
+
+// whether `key` is one of the goals marking ports.
+pub(crate) fn is_mark(key: PredKey) -> bool {
+    key.1 == 2 && (key.0.name() == "$trace_exit" || key.0.name() == "$trace_redo")
+}
+
+fn int_cell(n: usize) -> Cell {
+    Cell::Int(Rc::new(BigInt::from(n as i64)))
+}
+
+impl App {
+    // the depth of the goal on top of `rest`, counting from 1 for the
+    // goals of the query.
+    pub(crate) fn depth(&self, rest: &Goals) -> usize {
+        let mut depth = 1;
+        let mut goals = rest;
+        while let Some(node) = &goals.0 {
+            if let Some((name, 2)) = self.store.key(&node.0) {
+                if name.name() == "$trace_exit" {
+                    depth += 1;
+                }
+            }
+            goals = &node.2;
+        }
+        depth
+    }
+
+    // `goals` of the `n`th alternative of `goal`, with the ports after the
+    // call marked.
+    pub(crate) fn mark_ports(
+        &mut self,
+        mut goals: Vec<Cell>,
+        n: usize,
+        depth: usize,
+        goal: &Cell,
+    ) -> Vec<Cell> {
+        let exit = self.store.compound(
+            Atom::new("$trace_exit"),
+            vec![int_cell(depth), goal.clone()],
+        );
+        goals.push(exit);
+        if n > 0 {
+            let redo = self.store.compound(
+                Atom::new("$trace_redo"),
+                vec![int_cell(depth), goal.clone()],
+            );
+            goals.insert(0, redo);
+        }
+        goals
+    }
+
+    // shows `goal` passing `port` if it is traced or spied, and waits for a
+    // command if the port is leashed. Returns whether the goal is to fail.
+    pub(crate) fn port(
+        &mut self,
+        port: Port,
+        depth: usize,
+        goal: &Cell,
+        error: Option<&Exception>,
+    ) -> Result<bool, Exception> {
+        let spied = self
+            .store
+            .key(goal)
+            .is_some_and(|key| self.debugger.is_spied(key));
+        let debugger = &mut self.debugger;
+        if !debugger.tracing && !spied {
+            return Ok(false);
+        }
+        match debugger.skip {
+            Some(skip) if depth > skip => return Ok(false),
+            _ => debugger.skip = None,
+        }
+        let mut line = format!("{:>9}: ({}) {}", port, depth, self.store.to_term(goal));
+        if let Some(e) = error {
+            line += &format!(" : {}", e);
+        }
+        if !debugger.leashed.contains(&port) {
+            debugger.show(&format!("{}\n", line));
+            return Ok(false);
+        }
+        loop {
+            debugger.show(&format!("{} ? ", line));
+            let command = (debugger.input)();
+            if command.is_none() {
+                debugger.show("\n");
+            }
+            let command = command.as_deref().map_or("", str::trim);
+            match command.chars().next() {
+                None | Some('c') => {
+                    debugger.tracing = true;
+                    return Ok(false);
+                }
+                Some('s') => {
+                    if port == Port::Call || port == Port::Redo {
+                        debugger.skip = Some(depth);
+                    }
+                    debugger.tracing = true;
+                    return Ok(false);
+                }
+                Some('l') => {
+                    debugger.tracing = false;
+                    return Ok(false);
+                }
+                Some('f') => return Ok(true),
+                Some('a') => {
+                    debugger.tracing = false;
+                    debugger.skip = None;
+                    return Err(Exception::aborted());
+                }
+                Some('n') => {
+                    debugger.tracing = false;
+                    debugger.debugging = false;
+                    return Ok(false);
+                }
+                _ => debugger.show(HELP),
+            }
+        }
+    }
+}
+
+// '$trace_exit'(Depth, Goal) and '$trace_redo'(Depth, Goal)
+pub(crate) fn mark(app: &mut App, args: &[Cell], port: Port) -> Result<Vec<Branch>, Exception> {
+    let depth = match app.store.deref(&args[0]) {
+        Cell::Int(n) => n.to_i64().unwrap_or(0) as usize,
+        _ => 0,
+    };
+    if app.port(port, depth, &args[1], None)? {
+        if port == Port::Redo {
+            app.port(Port::Fail, depth, &args[1], None)?;
+        }
+        return Ok(fail());
+    }
+    Ok(succeed(app))
+}
+
+// trace/0, notrace/0, debug/0 and nodebug/0
+pub(crate) fn set_mode(app: &mut App, tracing: bool, debugging: bool) -> Vec<Branch> {
+    app.debugger.tracing = tracing;
+    app.debugger.debugging = debugging;
+    app.debugger.skip = None;
+    succeed(app)
+}
+
+// the predicates of `Name/Arity`, `Name` or a list of them.
+fn spy_specs(app: &App, cell: &Cell) -> Result<Vec<(Atom, Option<usize>)>, Exception> {
+    match app.store.to_term(cell) {
+        Term::Const(Constant::Name(name)) if name.name() != "[]" => Ok(vec![(name, None)]),
+        spec => Ok(pred_indicators(&spec)?
+            .into_iter()
+            .map(|(name, arity)| (name, Some(arity)))
+            .collect()),
+    }
+}
+
+fn spec_name(spec: &(Atom, Option<usize>)) -> String {
+    match spec {
+        (name, Some(arity)) => format!("{}/{}", name, arity),
+        (name, None) => name.to_string(),
+    }
+}
+
+// spy(Spec)
+pub(crate) fn spy(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    for spec in spy_specs(app, &args[0])? {
+        app.debugger
+            .show(&format!("% Spy point on {}\n", spec_name(&spec)));
+        app.debugger.spy_points.insert(spec);
+    }
+    app.debugger.debugging = true;
+    Ok(succeed(app))
+}
+
+// nospy(Spec)
+pub(crate) fn nospy(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    for spec in spy_specs(app, &args[0])? {
+        if app.debugger.spy_points.remove(&spec) {
+            app.debugger
+                .show(&format!("% Spy point removed from {}\n", spec_name(&spec)));
+        }
+    }
+    Ok(succeed(app))
+}
+
+// leash(Ports): a port, `+Port` or `-Port` to add or remove one, a list of
+// ports, or one of `none`, `loose`, `half`, `tight` and `full`.
+pub(crate) fn leash(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let spec = app.store.deref(&args[0]);
+    let (sign, spec) = match app.store.key(&spec) {
+        Some((sign, 1)) if sign.name() == "+" || sign.name() == "-" => {
+            (Some(sign.name() == "+"), app.store.args(&spec)[0].clone())
+        }
+        _ => (None, spec),
+    };
+    let ports = match app.store.key(&spec) {
+        Some((name, 0)) if name.name() != "[]" => match atom_arg(app, &spec)?.name() {
+            "none" => Vec::new(),
+            "loose" => vec![Port::Call],
+            "half" => vec![Port::Call, Port::Redo],
+            "tight" => vec![Port::Call, Port::Redo, Port::Fail, Port::Exception],
+            "full" | "all" => PORTS.to_vec(),
+            name => vec![Port::parse(name)
+                .ok_or_else(|| Exception::domain_error("port", app.store.to_term(&spec)))?],
+        },
+        _ => {
+            let mut ports = Vec::new();
+            for item in crate::coroutine::items(&app.store, &spec) {
+                let name = atom_arg(app, &item)?;
+                ports
+                    .push(Port::parse(name.name()).ok_or_else(|| {
+                        Exception::domain_error("port", app.store.to_term(&item))
+                    })?);
+            }
+            ports
+        }
+    };
+    let leashed = &mut app.debugger.leashed;
+    match sign {
+        Some(true) => leashed.extend(ports),
+        Some(false) => leashed.retain(|port| !ports.contains(port)),
+        None => *leashed = ports.into_iter().collect(),
+    }
+    Ok(succeed(app))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{answers, consult, Shared};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    // the answers to `query`, run with `commands` typed at the leashed
+    // ports, and what the debugger showed.
+    fn trace(query: &str, commands: &[&str]) -> (Vec<String>, String) {
+        let mut app = consult(
+            "parent(kobo, koji).\nparent(koji, iwao).\nparent(kobo, sanae).\n\
+             grandparent(X, Z) :- parent(X, Y), parent(Y, Z).\n",
+        );
+        let mut commands: VecDeque<String> = commands.iter().map(|c| c.to_string()).collect();
+        app.debugger.input = Box::new(move || commands.pop_front());
+        let output = Rc::new(RefCell::new(Vec::new()));
+        app.debugger.output = Box::new(Shared(Rc::clone(&output)));
+        let answers = answers(&mut app, &["X"], query);
+        let shown = String::from_utf8(output.borrow().clone()).unwrap();
+        (answers, shown)
+    }
+
+    #[test]
+    fn test_ports() {
+        // breadth-first, the ports of the alternatives interleave.
+        let (answers, shown) = trace("leash(none), trace, grandparent(kobo, X).", &[]);
+        assert_eq!(answers, vec!["iwao"]);
+        assert_eq!(
+            shown,
+            "     Call: (1) grandparent(kobo, _G2)\n\
+             \x20    Call: (2) parent(kobo, _G13)\n\
+             \x20    Exit: (2) parent(kobo, koji)\n\
+             \x20    Redo: (2) parent(kobo, sanae)\n\
+             \x20    Call: (2) parent(koji, _G2)\n\
+             \x20    Exit: (2) parent(kobo, sanae)\n\
+             \x20    Exit: (2) parent(koji, iwao)\n\
+             \x20    Call: (2) parent(sanae, _G2)\n\
+             \x20    Fail: (2) parent(sanae, _G2)\n\
+             \x20    Exit: (1) grandparent(kobo, iwao)\n"
+        );
+
+        // fail the first call, then abort at the first port of the second.
+        let (answers, _) = trace("trace, parent(kobo, X).", &["f"]);
+        assert!(answers.is_empty());
+        let (answers, _) = trace("trace, parent(kobo, X).", &["a"]);
+        assert_eq!(answers, vec!["Execution Aborted"]);
+
+        // skip the goals below the first.
+        let (answers, shown) = trace("trace, grandparent(kobo, X).", &["s"]);
+        assert_eq!(answers, vec!["iwao"]);
+        assert_eq!(
+            shown,
+            "     Call: (1) grandparent(kobo, _G2) ? \
+             \x20    Exit: (1) grandparent(kobo, iwao) ? \n"
+        );
+
+        // only the ports of spy points are shown in debug mode.
+        let (answers, shown) = trace("leash(none), spy(parent/2), grandparent(kobo, X).", &[]);
+        assert_eq!(answers, vec!["iwao"]);
+        assert!(shown.starts_with("% Spy point on parent/2\n     Call: (2) parent(kobo, "));
+        assert!(!shown.contains("grandparent"));
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+
+use crate::expr::{Constant, Term};
+use crate::write::{term_to_string, WriteOptions};
+
+// a term thrown while solving a query. Builtins raise ISO error terms of
+// the form `error(Formal, Context)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exception(pub Term);
+
+impl Exception {
+    fn error(formal: Term, context: Term) -> Exception {
+        Exception(Term::compound("error", vec![formal, context]))
+    }
+
+    pub fn resource_error(resource: &str) -> Exception {
+        Exception::error(
+            Term::compound("resource_error", vec![Term::atom(resource)]),
+            Term::atom("none"),
+        )
+    }
+
+    // thrown when the user aborts the query from the debugger.
+    pub fn aborted() -> Exception {
+        Exception(Term::atom("$aborted"))
+    }
+
+    // thrown by `call_with_time_limit/2` when its goal runs out of time.
+    pub fn time_limit_exceeded() -> Exception {
+        Exception(Term::atom("time_limit_exceeded"))
+    }
+
+    pub fn instantiation_error() -> Exception {
+        Exception::error(Term::atom("instantiation_error"), Term::atom("none"))
+    }
+
+    pub fn uninstantiation_error(culprit: Term) -> Exception {
+        Exception::error(
+            Term::compound("uninstantiation_error", vec![culprit]),
+            Term::atom("none"),
+        )
+    }
+
+    pub fn type_error(expected: &str, culprit: Term) -> Exception {
+        Exception::error(
+            Term::compound("type_error", vec![Term::atom(expected), culprit]),
+            Term::atom("none"),
+        )
+    }
+
+    pub fn domain_error(domain: &str, culprit: Term) -> Exception {
+        Exception::error(
+            Term::compound("domain_error", vec![Term::atom(domain), culprit]),
+            Term::atom("none"),
+        )
+    }
+
+    pub fn existence_error(kind: &str, culprit: Term) -> Exception {
+        Exception::error(
+            Term::compound("existence_error", vec![Term::atom(kind), culprit]),
+            Term::atom("none"),
+        )
+    }
+
+    // a builtin called on an engine that does not implement it.
+    pub fn unsupported_error(culprit: Term) -> Exception {
+        Exception::error(
+            Term::compound("unsupported_error", vec![culprit]),
+            Term::atom("none"),
+        )
+    }
+
+    pub fn permission_error(action: &str, kind: &str, culprit: Term) -> Exception {
+        Exception::error(
+            Term::compound(
+                "permission_error",
+                vec![Term::atom(action), Term::atom(kind), culprit],
+            ),
+            Term::atom("none"),
+        )
+    }
+
+    pub fn evaluation_error(error: &str) -> Exception {
+        Exception::error(
+            Term::compound("evaluation_error", vec![Term::atom(error)]),
+            Term::atom("none"),
+        )
+    }
+
+    // a malformed `format/2` directive or arguments not matching it.
+    pub fn format_error(message: &str) -> Exception {
+        Exception::error(
+            Term::compound(
+                "format",
+                vec![Term::Const(Constant::Str(message.to_string()))],
+            ),
+            Term::atom("none"),
+        )
+    }
+
+    // a term read by `read_term/2,3` that is not valid syntax.
+    pub fn syntax_error(message: &str) -> Exception {
+        Exception::error(
+            Term::compound("syntax_error", vec![Term::atom(message)]),
+            Term::atom("none"),
+        )
+    }
+
+    // the formal part of an `error/2` term.
+    fn formal(&self) -> Option<&Term> {
+        match &self.0 {
+            Term::Combined { functor, args } if functor.name() == "error" && args.len() == 2 => {
+                Some(&args[0])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // culprits are written as `writeq/1` would, with their operators.
+        let q = |term: &Term| term_to_string(term, WriteOptions::WRITEQ);
+        match self.formal() {
+            Some(Term::Combined { functor, args }) if args.len() == 1 => match functor.name() {
+                "resource_error" => write!(f, "Not enough resources: {}", q(&args[0])),
+                "evaluation_error" => {
+                    write!(f, "Arithmetic: evaluation error: {}", q(&args[0]))
+                }
+                "format" => match &args[0] {
+                    Term::Const(Constant::Str(message)) => write!(f, "Format error: {}", message),
+                    message => write!(f, "Format error: {}", q(message)),
+                },
+                "syntax_error" => write!(f, "Syntax error: {}", q(&args[0])),
+                "unsupported_error" => {
+                    write!(f, "`{}' is not supported on this engine", q(&args[0]))
+                }
+                "uninstantiation_error" => {
+                    write!(f, "Cannot represent due to `{}' being bound", q(&args[0]))
+                }
+                _ => write!(f, "{}", q(self.formal().unwrap())),
+            },
+            Some(Term::Combined { functor, args }) if args.len() == 2 => match functor.name() {
+                "type_error" => write!(
+                    f,
+                    "Type error: `{}' expected, found `{}'",
+                    q(&args[0]),
+                    q(&args[1])
+                ),
+                "existence_error" => match &args[1] {
+                    Term::Const(Constant::Name(name)) => {
+                        write!(f, "{} `{}' does not exist", q(&args[0]), name)
+                    }
+                    culprit => write!(f, "{} `{}' does not exist", q(&args[0]), q(culprit)),
+                },
+                "domain_error" => write!(
+                    f,
+                    "Domain error: `{}' expected, found `{}'",
+                    q(&args[0]),
+                    q(&args[1])
+                ),
+                _ => write!(f, "{}", q(self.formal().unwrap())),
+            },
+            Some(Term::Combined { functor, args })
+                if functor.name() == "permission_error" && args.len() == 3 =>
+            {
+                write!(
+                    f,
+                    "No permission to {} {} `{}'",
+                    q(&args[0]),
+                    q(&args[1]),
+                    q(&args[2])
+                )
+            }
+            Some(Term::Const(Constant::Name(name))) if name.name() == "instantiation_error" => {
+                write!(f, "Arguments are not sufficiently instantiated")
+            }
+            Some(formal) => write!(f, "{}", q(formal)),
+            None if *self == Exception::aborted() => write!(f, "Execution Aborted"),
+            None if *self == Exception::time_limit_exceeded() => write!(f, "Time limit exceeded"),
+            None => write!(f, "Unhandled exception: {}", q(&self.0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::goals;
+
+    #[test]
+    fn test_display() {
+        let culprit = goals("f('A b', 1 + 2, (=)/2).").remove(0);
+        assert_eq!(
+            Exception::type_error("integer", culprit.clone()).to_string(),
+            "Type error: `integer' expected, found `f('A b',1+2,(=)/2)'"
+        );
+        assert_eq!(
+            Exception(culprit).to_string(),
+            "Unhandled exception: f('A b',1+2,(=)/2)"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// Expansion hooks run while a file is consulted. Each clause read is
+// passed to `term_expansion/2`, which may replace it with another clause, a
+// list of clauses or none, and each goal in the body of a clause or in a
+// directive to `goal_expansion/2`. Hooks are looked up in the module being
+// loaded and in `user`.
+
+use std::mem;
+
+use crate::app::App;
+use crate::atom::Atom;
+use crate::error::Exception;
+use crate::expr::{Constant, Term, Variable};
+use crate::module::{self, qualify};
+use crate::unifier::Subst;
+
+impl App {
+    // the clauses `term` expands to.
+    pub(crate) fn expand_term(&mut self, term: Term, module: Atom) -> Result<Vec<Term>, Exception> {
+        match self.call_hook("term_expansion", &term, module)? {
+            Some(expanded) => Ok(items(expanded)),
+            None => Ok(vec![term]),
+        }
+    }
+
+    // `goal` expanded until `goal_expansion/2` fails or leaves it as it is,
+    // and then the goals inside it if it is a control construct.
+    pub(crate) fn expand_goal(&mut self, goal: Term, module: Atom) -> Result<Term, Exception> {
+        let mut goal = goal;
+        while !matches!(goal, Term::Var(_)) {
+            match self.call_hook("goal_expansion", &goal, module)? {
+                Some(expanded) if expanded != goal => goal = expanded,
+                _ => break,
+            }
+        }
+        match goal {
+            Term::Combined {
+                functor,
+                ref mut args,
+            } if matches!(
+                (functor.name(), args.len()),
+                (",", 2) | (";", 2) | ("->", 2) | ("\\+", 1)
+            ) =>
+            {
+                let args = mem::take(args)
+                    .into_iter()
+                    .map(|arg| self.expand_goal(arg, module))
+                    .collect::<Result<Vec<Term>, Exception>>()?;
+                Ok(Term::Combined { functor, args })
+            }
+            goal => Ok(goal),
+        }
+    }
+
+    // the output of the first answer to `Module:Hook(Input, Output)`, for the
+    // first of `module` and `user` defining the hook.
+    fn call_hook(
+        &mut self,
+        hook: &str,
+        input: &Term,
+        module: Atom,
+    ) -> Result<Option<Term>, Exception> {
+        let key = (Atom::new(hook), 2);
+        let mut modules = vec![module];
+        if module != module::user() {
+            modules.push(module::user());
+        }
+        for module in modules {
+            if !self.hooks.contains(&(module, key)) {
+                continue;
+            }
+            let mut vars: Vec<Variable> = input
+                .free_vars()
+                .into_iter()
+                .filter(|v| !v.is_anonymous())
+                .collect();
+            vars.sort();
+            vars.dedup();
+            let output = Variable::Named(Atom::new("$Output"));
+            let goal = Term::Combined {
+                functor: key.0,
+                args: vec![input.clone(), Term::Var(output)],
+            };
+            let answers = self
+                .answers(
+                    [vars.clone(), vec![output]].concat(),
+                    &[qualify(module, goal)],
+                )
+                .map_err(|(e, _)| e)?;
+            if let Some(mut values) = answers.into_iter().next() {
+                let output = values.pop().unwrap();
+                // variables of `input` left unbound keep their names.
+                let names: Subst = vars
+                    .iter()
+                    .zip(values)
+                    .filter_map(|(var, value)| match value {
+                        Term::Var(fresh) => Some((fresh, Term::Var(*var))),
+                        _ => None,
+                    })
+                    .collect();
+                return Ok(Some(output.subst(&names)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// the items of a list, or the term itself if it is not one.
+fn items(term: Term) -> Vec<Term> {
+    let mut items = Vec::new();
+    let mut rest = term.clone();
+    loop {
+        match rest {
+            Term::Combined {
+                functor,
+                ref mut args,
+            } if functor.name() == "." && args.len() == 2 => {
+                let tail = args.pop().unwrap();
+                items.push(args.pop().unwrap());
+                rest = tail;
+            }
+            Term::Const(Constant::Name(name)) if name.name() == "[]" => return items,
+            _ => return vec![term],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_expansion() {
+        let dir = std::env::temp_dir().join(format!("rslog-expand-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("macros.pl");
+        let source = "term_expansion(pair(X), [left(X), right(X)]).\n\
+                      term_expansion(hidden, []).\n\
+                      goal_expansion(double(X, Y), Y is X * 2).\n\
+                      pair(a).\n\
+                      hidden.\n\
+                      p(X, Y) :- double(X, Y), q(Y).\n";
+        fs::write(&file, source).unwrap();
+
+        let mut app = App::new();
+        app.consult(&file.to_string_lossy()).unwrap();
+        let clauses: Vec<String> = app.rules[3..]
+            .iter()
+            .map(|rule| {
+                let body: Vec<String> = rule.rhs.iter().map(|goal| goal.to_string()).collect();
+                format!("{} :- {}", rule.lhs, body.join(", "))
+            })
+            .collect();
+        assert_eq!(
+            clauses,
+            vec![
+                "left(a) :- ",
+                "right(a) :- ",
+                "p(X, Y) :- is(Y, *(X, 2)), q(Y)"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
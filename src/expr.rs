@@ -1,47 +1,144 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
 
+use crate::atom::{self, Atom};
+use crate::bigint::BigInt;
+use crate::parser::{escape, quote_atom};
+use crate::rational::Rational;
 use crate::unifier::{compose, Subst};
 
 #[derive(Debug)]
 pub enum Query {
-    File(String),
+    // consults the files of a query written as a list, e.g. `['rules.pl'].`
+    Files(Vec<String>),
     Terms(Vec<Term>),
 }
 
-pub type Variable = String;
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Variable {
+    // variable written in the source. The name is only used for printing.
+    Named(Atom),
+    // variable created when renaming a rule.
+    Fresh(usize),
+}
+
+impl Variable {
+    // `_`, which stands for a different variable at each occurrence.
+    pub fn is_anonymous(&self) -> bool {
+        matches!(self, Variable::Named(name) if name.name() == "_")
+    }
+
+    // `_` or a variable written as `_Name`, neither of which is reported in
+    // answers or singleton warnings.
+    pub fn is_hidden(&self) -> bool {
+        matches!(self, Variable::Named(name) if name.name().starts_with('_'))
+    }
+}
 
-#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variable::Named(name) => write!(f, "{}", name),
+            Variable::Fresh(id) => write!(f, "_G{}", id),
+        }
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq)]
 pub enum Term {
     Const(Constant),
     Var(Variable),
-    Combined { functor: String, args: Vec<Term> },
+    Combined { functor: Atom, args: Vec<Term> },
+}
+
+// the last arguments of structures are copied and dropped in a loop rather
+// than by recursion, as lists can be long.
+impl Clone for Term {
+    fn clone(&self) -> Term {
+        self.map_leaves(&mut |term| match term {
+            Term::Const(c) => Term::Const(c.clone()),
+            Term::Var(v) => Term::Var(*v),
+            Term::Combined { functor, .. } => Term::Combined {
+                functor: *functor,
+                args: Vec::new(),
+            },
+        })
+    }
+}
+
+impl Drop for Term {
+    fn drop(&mut self) {
+        let mut last = match self {
+            Term::Combined { args, .. } => args.pop(),
+            _ => None,
+        };
+        while let Some(mut term) = last {
+            last = match &mut term {
+                Term::Combined { args, .. } => args.pop(),
+                _ => None,
+            };
+        }
+    }
 }
 
 impl Term {
+    pub fn atom(name: &str) -> Term {
+        Term::Const(Constant::Name(Atom::new(name)))
+    }
+
+    pub fn compound(functor: &str, args: Vec<Term>) -> Term {
+        Term::Combined {
+            functor: Atom::new(functor),
+            args,
+        }
+    }
+
+    // the empty list `[]`.
+    pub fn nil() -> Term {
+        Term::Const(Constant::Name(atom::NIL))
+    }
+
+    // the list of `items` followed by `tail`, built from `'.'/2` cells.
+    pub fn list(items: Vec<Term>, tail: Term) -> Term {
+        items
+            .into_iter()
+            .rev()
+            .fold(tail, |tail, item| Term::Combined {
+                functor: atom::DOT,
+                args: vec![item, tail],
+            })
+    }
+
+    // a stack rather than recursion, as lists can be long.
     pub fn free_vars(&self) -> Vec<Variable> {
-        match self {
-            Term::Const(_) => Vec::new(),
-            Term::Var(v) => vec![v.clone()],
-            Term::Combined { functor: _, args } => args
-                .iter()
-                .fold(Vec::new(), |ret, term| [ret, term.free_vars()].concat()),
+        let mut vars = Vec::new();
+        let mut terms = vec![self];
+        while let Some(term) = terms.pop() {
+            match term {
+                Term::Const(_) => {}
+                Term::Var(v) => vars.push(*v),
+                Term::Combined { functor: _, args } => terms.extend(args.iter().rev()),
+            }
         }
+        vars
     }
 
-    pub fn free_vars_sum(terms: &Vec<Term>) -> Vec<Variable> {
-        terms
-            .iter()
-            .map(|term| term.free_vars())
-            .flatten()
-            .collect()
+    pub fn free_vars_sum(terms: &[Term]) -> Vec<Variable> {
+        terms.iter().flat_map(|term| term.free_vars()).collect()
     }
 
     pub fn has_free_var(&self) -> bool {
-        match self {
-            Term::Const(_) => false,
-            Term::Var(_) => true,
-            Term::Combined { functor: _, args } => args.iter().any(|arg| arg.has_free_var()),
+        let mut terms = vec![self];
+        while let Some(term) = terms.pop() {
+            match term {
+                Term::Const(_) => {}
+                Term::Var(_) => return true,
+                Term::Combined { functor: _, args } => terms.extend(args),
+            }
         }
+        false
     }
 
     // map of vars in self to form term.
@@ -49,7 +146,7 @@ impl Term {
         match self {
             Term::Var(v) => {
                 let mut map = HashMap::new();
-                map.insert(v.clone(), term.clone());
+                map.insert(*v, term.clone());
                 Some(map)
             }
             Term::Const(_) => Some(HashMap::new()),
@@ -61,47 +158,43 @@ impl Term {
                     if functor != functor2 {
                         None
                     } else {
-                        args.iter()
-                            .zip(args2.iter())
-                            .fold(Some(HashMap::new()), |ret, (t1, t2)| {
-                                let mut sub_acum = ret?;
-                                match t1.var_to_term_map(t2) {
-                                    None => Some(sub_acum),
-                                    Some(sub) => {
-                                        let mut valid = true;
-                                        sub.iter().for_each(|(v, t)| {
-                                            match sub_acum.clone().get(v) {
-                                                None => {
-                                                    sub_acum.insert(v.clone(), t.clone());
+                        args.iter().zip(args2.iter()).try_fold(
+                            HashMap::new(),
+                            |mut sub_acum, (t1, t2)| match t1.var_to_term_map(t2) {
+                                None => Some(sub_acum),
+                                Some(sub) => {
+                                    let mut valid = true;
+                                    sub.iter().for_each(|(v, t)| match sub_acum.clone().get(v) {
+                                        None => {
+                                            sub_acum.insert(*v, t.clone());
+                                        }
+                                        Some(val) => match (val, t) {
+                                            (Term::Var(_), Term::Var(_)) => {
+                                                if val != t {
+                                                    valid = false;
                                                 }
-                                                Some(val) => match (val, t) {
-                                                    (Term::Var(_), Term::Var(_)) => {
-                                                        if val != t {
-                                                            valid = false;
-                                                        }
-                                                    }
-                                                    (Term::Var(_), _) => {
-                                                        sub_acum.insert(v.clone(), t.clone());
-                                                    }
-                                                    (_, Term::Var(_)) => {
-                                                        sub_acum.insert(v.clone(), val.clone());
-                                                    }
-                                                    _ => {
-                                                        if val != t {
-                                                            valid = false;
-                                                        }
-                                                    }
-                                                },
                                             }
-                                        });
-                                        if valid {
-                                            Some(sub_acum)
-                                        } else {
-                                            None
-                                        }
+                                            (Term::Var(_), _) => {
+                                                sub_acum.insert(*v, t.clone());
+                                            }
+                                            (_, Term::Var(_)) => {
+                                                sub_acum.insert(*v, val.clone());
+                                            }
+                                            _ => {
+                                                if val != t {
+                                                    valid = false;
+                                                }
+                                            }
+                                        },
+                                    });
+                                    if valid {
+                                        Some(sub_acum)
+                                    } else {
+                                        None
                                     }
                                 }
-                            })
+                            },
+                        )
                     }
                 }
                 _ => Some(HashMap::new()),
@@ -109,109 +202,254 @@ impl Term {
         }
     }
 
+    // replaces each occurrence of `_` with a variable of its own.
+    pub fn rename_anonymous(&self, vars_count: &mut usize) -> Term {
+        self.map_leaves(&mut |term| match term {
+            Term::Var(v) if v.is_anonymous() => {
+                *vars_count += 1;
+                Term::Var(Variable::Fresh(*vars_count))
+            }
+            _ => term.clone(),
+        })
+    }
+
     pub fn subst(&self, map: &Subst) -> Term {
-        match self {
-            Term::Const(_) => self.clone(),
-            Term::Var(v) => match map.get(v) {
-                Some(term) => term.clone(),
-                None => self.clone(),
-            },
-            Term::Combined { functor, args } => Term::Combined {
-                functor: functor.clone(),
-                args: args.iter().map(|term| term.subst(&map)).collect(),
-            },
+        self.map_leaves(&mut |term| match term {
+            Term::Var(v) => map.get(v).unwrap_or(term).clone(),
+            _ => term.clone(),
+        })
+    }
+
+    // the term with each variable, constant and structure without arguments
+    // replaced by `leaf` of it, in the order they are written. The last arguments of structures are
+    // followed in a loop, as lists can be long.
+    fn map_leaves<F: FnMut(&Term) -> Term>(&self, leaf: &mut F) -> Term {
+        let mut outer = Vec::new();
+        let mut term = self;
+        let mut mapped = loop {
+            match term {
+                Term::Combined { functor, args } if !args.is_empty() => {
+                    let (last, args) = args.split_last().unwrap();
+                    let args: Vec<Term> = args.iter().map(|arg| arg.map_leaves(leaf)).collect();
+                    outer.push((*functor, args));
+                    term = last;
+                }
+                _ => break leaf(term),
+            }
+        };
+        while let Some((functor, mut args)) = outer.pop() {
+            args.push(mapped);
+            mapped = Term::Combined { functor, args };
         }
+        mapped
     }
+}
 
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Term::Const(c) => match c {
-                Constant::Int(i) => format!("{}", i),
-                Constant::Str(s) => format!("\"{}\"", s),
-                Constant::Name(name) => name.clone(),
+                Constant::Int(i) => write!(f, "{}", i),
+                Constant::Rational(r) => write!(f, "{}", r),
+                Constant::Float(x) => write!(f, "{}", format_float(*x)),
+                Constant::Str(s) => write!(f, "\"{}\"", escape(s, '"')),
+                Constant::Name(name) => write!(f, "{}", quote_atom(name.name())),
             },
-            Term::Var(v) => v.clone(),
+            Term::Var(v) => write!(f, "{}", v),
+            Term::Combined { functor, args } if functor.name() == "." && args.len() == 2 => {
+                write!(f, "[{}", args[0])?;
+                let mut tail = &args[1];
+                loop {
+                    match tail {
+                        Term::Combined { functor, args }
+                            if functor.name() == "." && args.len() == 2 =>
+                        {
+                            write!(f, ", {}", args[0])?;
+                            tail = &args[1];
+                        }
+                        Term::Const(Constant::Name(name)) if name.name() == "[]" => {
+                            return write!(f, "]")
+                        }
+                        _ => return write!(f, "|{}]", tail),
+                    }
+                }
+            }
+            Term::Combined { functor, args } if functor.name() == "{}" && args.len() == 1 => {
+                write!(f, "{{{}}}", args[0])
+            }
             Term::Combined { functor, args } => {
                 let args_str = args
                     .iter()
                     .map(|arg| arg.to_string())
                     .collect::<Vec<String>>()
                     .join(", ");
-                format!("{}({})", functor, args_str)
+                write!(f, "{}({})", quote_atom(functor.name()), args_str)
             }
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug)]
 pub enum Constant {
-    Int(i32),
+    Int(BigInt),
+    Rational(Rational),
+    Float(f64),
     Str(String),
-    Name(String),
+    Name(Atom),
+}
+
+// floats are compared bit by bit, so that `nan` equals itself and `0.0` and
+// `-0.0` are different constants, as in standard order.
+impl PartialEq for Constant {
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::Int(a), Constant::Int(b)) => a == b,
+            (Constant::Rational(a), Constant::Rational(b)) => a == b,
+            (Constant::Float(a), Constant::Float(b)) => a.to_bits() == b.to_bits(),
+            (Constant::Str(a), Constant::Str(b)) => a == b,
+            (Constant::Name(a), Constant::Name(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Constant::Int(i) => i.hash(state),
+            Constant::Rational(r) => r.hash(state),
+            Constant::Float(f) => f.to_bits().hash(state),
+            Constant::Str(s) => s.hash(state),
+            Constant::Name(name) => name.hash(state),
+        }
+    }
+}
+
+// shortest text that reads back as the same float, always with a fraction,
+// e.g. `1.0`, `0.1` or `1.0e-10`.
+pub fn format_float(f: f64) -> String {
+    if f.is_nan() {
+        return "1.5NaN".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "1.0Inf" } else { "-1.0Inf" }.to_string();
+    }
+    let s = format!("{:?}", f);
+    match s.find('e') {
+        Some(i) if !s[..i].contains('.') => format!("{}.0{}", &s[..i], &s[i..]),
+        _ => s,
+    }
+}
+
+// where a clause starts in its source file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Location {
+    pub file: Atom,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Rule {
     pub lhs: Term,
     pub rhs: Vec<Term>,
+    // none for rules that were not read from a file.
+    pub location: Option<Location>,
+    // module the rule belongs to, `user` unless read from a module file.
+    pub module: Atom,
 }
 
 impl Rule {
-    pub fn instantiate(&self, vars_count: &mut i32, map: &Subst) -> Rule {
-        let mut free_vars = vec![self.lhs.free_vars(), Term::free_vars_sum(&self.rhs)].concat();
+    pub fn instantiate(&self, vars_count: &mut usize, map: &Subst) -> Rule {
+        let lhs = self.lhs.rename_anonymous(vars_count);
+        let rhs: Vec<Term> = self
+            .rhs
+            .iter()
+            .map(|term| term.rename_anonymous(vars_count))
+            .collect();
+        let mut free_vars = [lhs.free_vars(), Term::free_vars_sum(&rhs)].concat();
         free_vars.sort();
         free_vars.dedup();
         let number_map: Subst = free_vars
             .iter()
             .map(|v| {
-                *vars_count = *vars_count + 1;
-                (v.clone(), Term::Var(format!("{}", vars_count)))
+                *vars_count += 1;
+                (*v, Term::Var(Variable::Fresh(*vars_count)))
             })
             .collect();
         let sub = compose(&number_map, map);
         Rule {
-            lhs: self.lhs.subst(&sub),
-            rhs: self.rhs.iter().map(|term| term.subst(&sub)).collect(),
+            lhs: lhs.subst(&sub),
+            rhs: rhs.iter().map(|term| term.subst(&sub)).collect(),
+            location: self.location,
+            module: self.module,
         }
     }
+
+    // named variables occurring only once in the rule, except for those
+    // written as `_Name`.
+    pub fn singletons(&self) -> Vec<Variable> {
+        let vars = [self.lhs.free_vars(), Term::free_vars_sum(&self.rhs)].concat();
+        let mut singletons: Vec<Variable> = vars
+            .iter()
+            .filter(|v| matches!(v, Variable::Named(_)) && !v.is_hidden())
+            .filter(|v| vars.iter().filter(|w| w == v).count() == 1)
+            .copied()
+            .collect();
+        singletons.dedup();
+        singletons
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn var(name: &str) -> Variable {
+        Variable::Named(Atom::new(name))
+    }
+
     #[test]
     fn test_var_to_term_map() {
         // rule: add(s(X), Y, s(Z)) :- add(X, Y, Z). query: add(s(z), s(z), X).
-        let const_z = Term::Const(Constant::Name("z".to_string()));
+        let const_z = Term::Const(Constant::Name(Atom::new("z")));
         let s_z = Term::Combined {
-            functor: "s".to_string(),
+            functor: Atom::new("s"),
             args: vec![const_z.clone()],
         };
         let rule_lhs = Term::Combined {
-            functor: "add".to_string(),
+            functor: Atom::new("add"),
             args: vec![
                 Term::Combined {
-                    functor: "s".to_string(),
-                    args: vec![Term::Var("X".to_string())],
+                    functor: Atom::new("s"),
+                    args: vec![Term::Var(var("X"))],
                 },
-                Term::Var("Y".to_string()),
+                Term::Var(var("Y")),
                 Term::Combined {
-                    functor: "s".to_string(),
-                    args: vec![Term::Var("Z".to_string())],
+                    functor: Atom::new("s"),
+                    args: vec![Term::Var(var("Z"))],
                 },
             ],
         };
         let query = Term::Combined {
-            functor: "add".to_string(),
-            args: vec![s_z.clone(), s_z.clone(), Term::Var("X".to_string())],
+            functor: Atom::new("add"),
+            args: vec![s_z.clone(), s_z.clone(), Term::Var(var("X"))],
         };
         let expected = [
-            ("X".to_string(), const_z.clone()),
+            (var("X"), const_z.clone()),
             (
-                "Y".to_string(),
+                var("Y"),
                 Term::Combined {
-                    functor: "s".to_string(),
+                    functor: Atom::new("s"),
                     args: vec![const_z.clone()],
                 },
             ),
@@ -223,21 +461,17 @@ mod tests {
 
         // rule: add(z, Y, Y). query: add(z, X, s(z)).
         let fact = Term::Combined {
-            functor: "add".to_string(),
-            args: vec![
-                const_z.clone(),
-                Term::Var("Y".to_string()),
-                Term::Var("Y".to_string()),
-            ],
+            functor: Atom::new("add"),
+            args: vec![const_z.clone(), Term::Var(var("Y")), Term::Var(var("Y"))],
         };
         let query = Term::Combined {
-            functor: "add".to_string(),
-            args: vec![const_z.clone(), Term::Var("X".to_string()), s_z.clone()],
+            functor: Atom::new("add"),
+            args: vec![const_z.clone(), Term::Var(var("X")), s_z.clone()],
         };
         let expected = [(
-            "Y".to_string(),
+            var("Y"),
             Term::Combined {
-                functor: "s".to_string(),
+                functor: Atom::new("s"),
                 args: vec![const_z.clone()],
             },
         )]
@@ -248,4 +482,20 @@ mod tests {
 
         // TODO: test for father(X, Y) :- parent(X, Y), male(Y).
     }
+
+    #[test]
+    fn test_anonymous_and_singletons() {
+        let source = "p(X, _) :- q(_, Y).\n\n% comment\nr(_A, Z, Z).";
+        let (rules, _) = crate::parser::parse_file_content("test.pl", source);
+        let lines: Vec<usize> = rules.iter().map(|r| r.location.unwrap().line).collect();
+        assert_eq!(lines, vec![1, 4]);
+        assert_eq!(rules[0].singletons(), vec![var("X"), var("Y")]);
+        assert!(rules[1].singletons().is_empty());
+
+        let rule = rules[0].instantiate(&mut 0, &HashMap::new());
+        let mut vars = [rule.lhs.free_vars(), Term::free_vars_sum(&rule.rhs)].concat();
+        vars.sort();
+        vars.dedup();
+        assert_eq!(vars.len(), 4);
+    }
 }
@@ -0,0 +1,150 @@
+// Filtering of the answers of a query. Breadth-first resolution finds an
+// answer once for each of its derivations; depending on the `answer_mode`
+// flag, an answer whose bindings are a variant of those of an answer already
+// reported (`variant`), or an instance of them (`subsumption`), is dropped.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::expr::{Term, Variable};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnswerMode {
+    All,
+    Variant,
+    Subsumption,
+}
+
+// the answers reported so far.
+#[derive(Default)]
+pub struct AnswerFilter {
+    // the bindings of each, with their variables numbered in order.
+    variants: HashSet<Vec<Term>>,
+    reported: Vec<Vec<Term>>,
+}
+
+impl AnswerFilter {
+    // whether an answer binding the variables of the query to `values` is
+    // to be reported, in which case it is remembered.
+    pub fn admit(&mut self, mode: AnswerMode, values: &[Term]) -> bool {
+        if mode == AnswerMode::All {
+            return true;
+        }
+        let mut numbers = HashMap::new();
+        let values: Vec<Term> = values
+            .iter()
+            .map(|value| numbered(value, &mut numbers))
+            .collect();
+        if self.variants.contains(&values) {
+            return false;
+        }
+        if mode == AnswerMode::Subsumption {
+            if self
+                .reported
+                .iter()
+                .any(|general| subsumes(general, &values))
+            {
+                return false;
+            }
+            self.reported.push(values.clone());
+        }
+        self.variants.insert(values);
+        true
+    }
+}
+
+// `term` with its variables renamed `_G0`, `_G1`, ... in the order they
+// appear, so that variants are equal.
+fn numbered(term: &Term, numbers: &mut HashMap<Variable, usize>) -> Term {
+    match term {
+        Term::Var(var) => {
+            let next = numbers.len();
+            Term::Var(Variable::Fresh(*numbers.entry(*var).or_insert(next)))
+        }
+        Term::Const(_) => term.clone(),
+        Term::Combined { functor, args } => Term::Combined {
+            functor: *functor,
+            args: args.iter().map(|arg| numbered(arg, numbers)).collect(),
+        },
+    }
+}
+
+// whether some substitution of the variables of `general` makes it equal
+// to `specific`, whose variables are left as they are.
+pub fn subsumes(general: &[Term], specific: &[Term]) -> bool {
+    let mut bound: HashMap<Variable, &Term> = HashMap::new();
+    let mut pending: Vec<(&Term, &Term)> = general.iter().zip(specific).collect();
+    while let Some((general, specific)) = pending.pop() {
+        match (general, specific) {
+            (Term::Var(var), _) => match bound.get(var) {
+                Some(value) if *value != specific => return false,
+                Some(_) => {}
+                None => {
+                    bound.insert(*var, specific);
+                }
+            },
+            (
+                Term::Combined { functor, args },
+                Term::Combined {
+                    functor: other,
+                    args: other_args,
+                },
+            ) if functor == other && args.len() == other_args.len() => {
+                pending.extend(args.iter().zip(other_args));
+            }
+            (Term::Const(a), Term::Const(b)) if a == b => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::atom::Atom;
+    use crate::testing::{consult, goals};
+
+    // the values of `X` and `Y` in the answers to `query`.
+    fn answers(mode: &str, query: &str) -> Vec<String> {
+        let mut app = consult(
+            "edge(a, b).\nedge(b, c).\nedge(a, c).\n\
+             path(X, Y) :- edge(X, Y).\n\
+             path(X, Y) :- edge(X, Z), path(Z, Y).\n\
+             p(_, _).\np(a, _).\np(X, X).\np(b, _).\np(_, _).\n",
+        );
+        app.flags
+            .set(Atom::new("answer_mode"), Term::atom(mode))
+            .unwrap();
+        let vars = vec![
+            Variable::Named(Atom::new("X")),
+            Variable::Named(Atom::new("Y")),
+        ];
+        let values = app.answers(vars, &goals(query)).unwrap();
+        values
+            .iter()
+            .map(|values| {
+                let mut numbers = HashMap::new();
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| numbered(value, &mut numbers).to_string())
+                    .collect();
+                values.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_modes() {
+        assert_eq!(
+            answers("all", "path(a, X)."),
+            vec!["b _G0", "c _G0", "c _G0"]
+        );
+        assert_eq!(answers("variant", "path(a, X)."), vec!["b _G0", "c _G0"]);
+        assert_eq!(
+            answers("variant", "p(X, Y)."),
+            vec!["_G0 _G1", "a _G0", "_G0 _G0", "b _G0"]
+        );
+        assert_eq!(answers("subsumption", "p(X, Y)."), vec!["_G0 _G1"]);
+        assert_eq!(answers("subsumption", "p(X, Y), X = Y."), vec!["_G0 _G0"]);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::filter::AnswerMode;
+
+// flags describing the system that `set_prolog_flag/2` cannot change.
+const READ_ONLY: &[&str] = &["bounded"];
+
+// flags limiting the resources of a query, whose value is a non-negative
+// integer and 0 for no limit.
+const LIMITS: &[&str] = &["max_memory", "max_queue_length"];
+
+// flags whose value is one of a few atoms, the first being the default.
+const CHOICES: &[(&str, &[&str])] = &[
+    // whether `/` and `**` on integers give rationals rather than floats.
+    ("prefer_rationals", &["true", "false"]),
+    // what float arithmetic gives instead of raising an evaluation error.
+    ("float_overflow", &["error", "infinity"]),
+    ("float_zero_div", &["error", "infinity"]),
+    ("float_undefined", &["error", "nan"]),
+    // which answers of a query are reported: all, all but variants of those
+    // already reported, or all but instances of them.
+    ("answer_mode", &["all", "variant", "subsumption"]),
+];
+
+// values of the flags read by `current_prolog_flag/2` and changed by
+// `set_prolog_flag/2`.
+pub struct Flags {
+    values: HashMap<Atom, Term>,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Flags {
+    pub fn new() -> Flags {
+        let mut values = HashMap::new();
+        // bytes the engine may use for a query, and queue items it may keep.
+        for name in LIMITS {
+            values.insert(Atom::new(name), Term::Const(Constant::Int(BigInt::zero())));
+        }
+        // integers are unbounded, so there is no max_integer/min_integer.
+        values.insert(Atom::new("bounded"), Term::atom("false"));
+        for (name, choices) in CHOICES {
+            values.insert(Atom::new(name), Term::atom(choices[0]));
+        }
+        Flags { values }
+    }
+
+    pub fn get(&self, name: Atom) -> Option<&Term> {
+        self.values.get(&name)
+    }
+
+    pub fn set(&mut self, name: Atom, value: Term) -> Result<(), Exception> {
+        let flag = Term::Const(Constant::Name(name));
+        if !self.values.contains_key(&name) {
+            return Err(Exception::domain_error("prolog_flag", flag));
+        }
+        if READ_ONLY.contains(&name.name()) {
+            return Err(Exception::permission_error("modify", "flag", flag));
+        }
+        if let Some((_, choices)) = CHOICES.iter().find(|(n, _)| *n == name.name()) {
+            match &value {
+                Term::Const(Constant::Name(v)) if choices.contains(&v.name()) => {}
+                Term::Var(_) => return Err(Exception::instantiation_error()),
+                _ => return Err(Exception::domain_error("flag_value", value)),
+            }
+        }
+        if LIMITS.contains(&name.name()) {
+            match &value {
+                Term::Const(Constant::Int(i)) if i.is_negative() => {
+                    return Err(Exception::domain_error("not_less_than_zero", value))
+                }
+                Term::Const(Constant::Int(_)) => {}
+                Term::Var(_) => return Err(Exception::instantiation_error()),
+                _ => return Err(Exception::type_error("integer", value)),
+            }
+        }
+        self.values.insert(name, value);
+        Ok(())
+    }
+
+    // all flags sorted by name.
+    pub fn iter(&self) -> Vec<(Atom, Term)> {
+        let mut flags: Vec<(Atom, Term)> = self
+            .values
+            .iter()
+            .map(|(name, value)| (*name, value.clone()))
+            .collect();
+        flags.sort_by_key(|(name, _)| name.name());
+        flags
+    }
+
+    // whether an atom valued flag is set to `value`.
+    pub fn is(&self, name: &str, value: &str) -> bool {
+        match self.get(Atom::new(name)) {
+            Some(Term::Const(Constant::Name(v))) => v.name() == value,
+            _ => false,
+        }
+    }
+
+    pub fn max_memory(&self) -> Option<usize> {
+        self.limit("max_memory")
+    }
+
+    pub fn max_queue_length(&self) -> Option<usize> {
+        self.limit("max_queue_length")
+    }
+
+    pub fn answer_mode(&self) -> AnswerMode {
+        if self.is("answer_mode", "variant") {
+            AnswerMode::Variant
+        } else if self.is("answer_mode", "subsumption") {
+            AnswerMode::Subsumption
+        } else {
+            AnswerMode::All
+        }
+    }
+
+    fn limit(&self, name: &str) -> Option<usize> {
+        match self.get(Atom::new(name)) {
+            Some(Term::Const(Constant::Int(i))) if !i.is_zero() => {
+                Some(i.to_i64().map_or(usize::MAX, |i| i as usize))
+            }
+            _ => None,
+        }
+    }
+}
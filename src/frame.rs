@@ -0,0 +1,513 @@
+// Frames of the goals run by `catch/3`, `call_with_inference_limit/3`,
+// `call_with_time_limit/2`, `profile/1,2`, `with_output_to/2` and of the
+// conditions of `->/2` and `\+/1`. Such a goal
+// runs in the queue of the query like any other, followed by the mark
+// `'$exit_frame'`, and each queue item knows the frames it runs in, the
+// innermost first. An item reaching the mark leaves the innermost frame.
+//
+// An error raised by an item is caught by the innermost of its `catch/3`
+// frames whose catcher unifies with it: the items still running in that
+// frame are dropped and the recovery goal runs from the state the frame was
+// entered in. A frame running out of inferences or time is left the same
+// way, with `Result = inference_limit_exceeded` or by raising
+// `time_limit_exceeded` where it was entered. A condition is left at its
+// first answer, dropping the others, or once none of its items is left.
+
+use std::cell::{self, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::app::{App, Goals, QueueItem};
+use crate::atom::{self, Atom};
+use crate::builtins::Branch;
+use crate::coroutine;
+use crate::error::Exception;
+use crate::profile::{self, Profiler};
+use crate::proof::Steps;
+use crate::store::{Cell, Trail};
+use crate::write::text_value;
+
+pub(crate) enum Kind {
+    // `args` are the catcher and the recovery goal.
+    Catch,
+    // `args` are the result. Inferences are the steps of the items in the
+    // frame.
+    InferenceLimit {
+        limit: u64,
+        used: cell::Cell<u64>,
+    },
+    TimeLimit {
+        deadline: Instant,
+    },
+    // the profiler counts the steps of the items in the frame, and the
+    // profile is printed and written to `files` (JSON or not, name) once
+    // none is left.
+    Profile {
+        profiler: RefCell<Option<Profiler>>,
+        files: Vec<(bool, String)>,
+    },
+    // `args` are the sink and a variable whose `$output` attribute holds
+    // the text written by the items in the frame, as a list of strings, the
+    // last written first. The frame is left at the first answer.
+    Output,
+    // `args` are the goals run after the first answer of the condition and
+    // instead of it if it has none.
+    Condition,
+}
+
+pub(crate) struct Frame {
+    pub(crate) kind: Kind,
+    pub(crate) saved: RefCell<Saved>,
+    // queue items running in the frame, counting the one being resolved.
+    live: cell::Cell<usize>,
+    // whether the items running in the frame were dropped.
+    dropped: cell::Cell<bool>,
+    // the frames the goal was called in.
+    pub(crate) outer: Frames,
+}
+
+// the state the frame was entered in, which the garbage collector rewrites.
+pub(crate) struct Saved {
+    // the goals after the call.
+    pub(crate) goals: Goals,
+    pub(crate) trail: Trail,
+    pub(crate) args: Vec<Cell>,
+}
+
+// the frames of a queue item, the innermost first. Items share the frames
+// around their own.
+#[derive(Clone, Default)]
+pub struct Frames(pub(crate) Option<Rc<Frame>>);
+
+impl Frames {
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Rc<Frame>> {
+        std::iter::successors(self.0.as_ref(), |frame| frame.outer.0.as_ref())
+    }
+
+    fn contains(&self, frame: &Rc<Frame>) -> bool {
+        self.iter().any(|f| Rc::ptr_eq(f, frame))
+    }
+
+    // the innermost frame of the kind `is_kind` holds for.
+    fn find(&self, is_kind: impl Fn(&Kind) -> bool) -> Option<Rc<Frame>> {
+        self.iter().find(|frame| is_kind(&frame.kind)).cloned()
+    }
+
+    // the innermost frame run by `profile/1,2`.
+    pub(crate) fn profiled(&self) -> Option<Rc<Frame>> {
+        self.find(|kind| matches!(kind, Kind::Profile { .. }))
+    }
+
+    // the innermost frame run by `with_output_to/2`.
+    pub(crate) fn captured(&self) -> Option<Rc<Frame>> {
+        self.find(|kind| matches!(kind, Kind::Output))
+    }
+}
+
+impl Frame {
+    // whether the items of the frame or of one around it were dropped.
+    fn abandoned(&self) -> bool {
+        self.dropped.get() || self.outer.iter().any(|frame| frame.dropped.get())
+    }
+
+    fn saved(&self) -> (Goals, Trail, Vec<Cell>) {
+        let saved = self.saved.borrow();
+        (saved.goals.clone(), saved.trail.clone(), saved.args.clone())
+    }
+
+    // replaces the profiler of a profiled frame by `profiler`.
+    pub(crate) fn swap_profiler(&self, profiler: Option<Profiler>) -> Option<Profiler> {
+        match &self.kind {
+            Kind::Profile { profiler: own, .. } => own.replace(profiler),
+            _ => profiler,
+        }
+    }
+}
+
+impl Drop for Frames {
+    // drops deeply nested frames iteratively to avoid overflowing the stack.
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(frame) = next {
+            match Rc::try_unwrap(frame) {
+                Ok(mut frame) => next = frame.outer.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// the single branch running `goal` in a new frame of `kind` around the
+// goals after the call.
+pub(crate) fn call(app: &mut App, kind: Kind, goal: Cell, args: Vec<Cell>) -> Vec<Branch> {
+    app.entered = Some((kind, args));
+    vec![Branch {
+        trail: app.store.trail(),
+        goals: vec![goal, Cell::Atom(Atom::new("$exit_frame"))],
+    }]
+}
+
+pub(crate) fn is_exit(key: (Atom, usize)) -> bool {
+    key.1 == 0 && key.0.name() == "$exit_frame"
+}
+
+impl App {
+    // the frames of the branches of a builtin called in `frames`, with the
+    // goals `rest` after it and the bindings `trail` before it: a new frame
+    // if it entered one.
+    pub(crate) fn branch_frames(&mut self, frames: &Frames, rest: &Goals, trail: &Trail) -> Frames {
+        match self.entered.take() {
+            Some((kind, args)) => Frames(Some(Rc::new(Frame {
+                kind,
+                saved: RefCell::new(Saved {
+                    goals: rest.clone(),
+                    trail: trail.clone(),
+                    args,
+                }),
+                live: cell::Cell::new(0),
+                dropped: cell::Cell::new(false),
+                outer: frames.clone(),
+            }))),
+            None => frames.clone(),
+        }
+    }
+
+    pub(crate) fn enqueue(&mut self, item: QueueItem) {
+        for frame in item.frames.iter() {
+            frame.live.set(frame.live.get() + 1);
+        }
+        self.queue.push_back(item);
+    }
+
+    // notes that an item running in `frames` for `answer` was resolved or
+    // dropped, finishing the frames it was the last item of.
+    pub(crate) fn leave(
+        &mut self,
+        frames: &Frames,
+        answer: &Cell,
+        steps: &Steps,
+    ) -> Result<(), Exception> {
+        for frame in frames.iter() {
+            frame.live.set(frame.live.get() - 1);
+            if frame.live.get() > 0 {
+                continue;
+            }
+            match &frame.kind {
+                Kind::Profile { profiler, files } => {
+                    if let Some(profiler) = profiler.borrow_mut().take() {
+                        profile::report(self, &profiler, files)?;
+                    }
+                }
+                // a condition without answers.
+                Kind::Condition if !frame.abandoned() => {
+                    let (goals, trail, args) = frame.saved();
+                    self.store.switch_to(&trail);
+                    let resumed = (&frame.outer, answer, steps);
+                    self.resume(&goals, &trail, vec![args[1].clone()], resumed);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // drops the items still running in `frame`.
+    fn drop_items(&mut self, frame: &Rc<Frame>) -> Result<(), Exception> {
+        frame.dropped.set(true);
+        let (dropped, kept): (VecDeque<QueueItem>, VecDeque<QueueItem>) = self
+            .queue
+            .drain(..)
+            .partition(|item| item.frames.contains(frame));
+        self.queue = kept;
+        for item in dropped {
+            self.leave(&item.frames, &item.answer, &item.steps)?;
+        }
+        Ok(())
+    }
+
+    // the outermost of `frames` that ran out of inferences or time, counting
+    // an inference in each.
+    fn exceeded(&self, frames: &Frames) -> Option<Rc<Frame>> {
+        let mut exceeded = None;
+        for frame in frames.iter() {
+            let out = match &frame.kind {
+                Kind::InferenceLimit { limit, used } => {
+                    used.set(used.get() + 1);
+                    used.get() > *limit
+                }
+                Kind::TimeLimit { deadline } => Instant::now() >= *deadline,
+                _ => false,
+            };
+            if out {
+                exceeded = Some(frame.clone());
+            }
+        }
+        exceeded
+    }
+
+    // leaves the frame of `item` that ran out of inferences or time, if
+    // any, instead of resolving it. Returns whether it did.
+    pub(crate) fn leave_exceeded(&mut self, item: &QueueItem) -> Result<bool, Exception> {
+        let frame = match self.exceeded(&item.frames) {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        self.drop_items(&frame)?;
+        match frame.kind {
+            Kind::TimeLimit { .. } => {
+                let e = Exception::time_limit_exceeded();
+                self.raise(e, &frame.outer, &item.answer, &item.steps)?;
+            }
+            _ => {
+                let (goals, trail, args) = frame.saved();
+                self.store.switch_to(&trail);
+                let exceeded = Cell::Atom(Atom::new("inference_limit_exceeded"));
+                if self.store.unify(&args[0], &exceeded) {
+                    let resumed = (&frame.outer, &item.answer, &item.steps);
+                    self.resume(&goals, &trail, Vec::new(), resumed);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    // queues `goals` before `rest` with the current bindings, waking the
+    // goals of the variables bound since `trail`, to run in `frames` for
+    // `answer`.
+    fn resume(
+        &mut self,
+        rest: &Goals,
+        trail: &Trail,
+        goals: Vec<Cell>,
+        (frames, answer, steps): (&Frames, &Cell, &Steps),
+    ) {
+        let goals = [self.wakeups(trail), goals].concat();
+        self.enqueue(QueueItem {
+            goals: rest.push(goals, None),
+            trail: self.store.trail(),
+            answer: answer.clone(),
+            steps: steps.clone(),
+            frames: frames.clone(),
+        });
+        self.store.switch_to(trail);
+    }
+
+    // catches `e`, raised by an item running in `frames`, with the innermost
+    // `catch/3` whose catcher unifies with it. Returns the error if there is
+    // none.
+    pub(crate) fn raise(
+        &mut self,
+        e: Exception,
+        frames: &Frames,
+        answer: &Cell,
+        steps: &Steps,
+    ) -> Result<(), Exception> {
+        // an aborted query cannot be caught.
+        if e == Exception::aborted() {
+            return Err(e);
+        }
+        for frame in frames.iter() {
+            if !matches!(frame.kind, Kind::Catch) {
+                continue;
+            }
+            let (goals, trail, args) = frame.saved();
+            self.store.switch_to(&trail);
+            let ball = self.store.from_term(&e.0, &mut HashMap::new());
+            if self.store.unify(&args[0], &ball) {
+                self.drop_items(frame)?;
+                let resumed = (&frame.outer, answer, steps);
+                self.resume(&goals, &trail, vec![args[1].clone()], resumed);
+                return Ok(());
+            }
+            self.store.switch_to(&trail);
+        }
+        Err(e)
+    }
+
+    // resolves the mark `'$exit_frame'` of an item running in `frames`,
+    // with `rest` after it, leaving the innermost frame.
+    pub(crate) fn exit_frame(
+        &mut self,
+        rest: &Goals,
+        trail: &Trail,
+        (frames, answer, steps): (&Frames, &Cell, &Steps),
+    ) -> Result<(), Exception> {
+        let frame = match &frames.0 {
+            Some(frame) => frame.clone(),
+            None => return Ok(()),
+        };
+        let (_, _, args) = frame.saved();
+        let mut goals = Vec::new();
+        match &frame.kind {
+            Kind::InferenceLimit { .. } => {
+                // `!` when no other item runs in the frame.
+                let result = match frame.live.get() {
+                    1 => "!",
+                    _ => "true",
+                };
+                if !self.store.unify(&args[0], &Cell::Atom(Atom::new(result))) {
+                    self.store.switch_to(trail);
+                    return Ok(());
+                }
+            }
+            Kind::Output => {
+                let text = self.output_text(&args[1]);
+                self.drop_items(&frame)?;
+                if !self.unify_sink(&args[0], &text) {
+                    self.store.switch_to(trail);
+                    return Ok(());
+                }
+            }
+            Kind::Condition => {
+                self.drop_items(&frame)?;
+                goals.push(args[0].clone());
+            }
+            Kind::Catch | Kind::TimeLimit { .. } | Kind::Profile { .. } => {}
+        }
+        self.resume(rest, trail, goals, (&frame.outer, answer, steps));
+        Ok(())
+    }
+
+    // makes a new stream the current output of a builtin run in `frames`,
+    // if one of them is run by `with_output_to/2`.
+    pub(crate) fn capture_output(&mut self, frames: &Frames) -> Option<Capture> {
+        let frame = frames.captured()?;
+        let (stream, outer) = self.streams.capture();
+        Some(Capture {
+            frame,
+            stream,
+            outer,
+        })
+    }
+
+    // gives back the output taken by `capture`, adding what the builtin
+    // wrote to the text of its frame in each of its branches.
+    pub(crate) fn captured(
+        &mut self,
+        capture: Capture,
+        result: &mut Result<Vec<Branch>, Exception>,
+    ) {
+        let text = self.streams.release(capture.stream, capture.outer);
+        let branches = match result {
+            Ok(branches) if !text.is_empty() => branches,
+            _ => return,
+        };
+        let (_, _, args) = capture.frame.saved();
+        let output = Atom::new("$output");
+        for branch in branches {
+            self.store.switch_to(&branch.trail);
+            let written = coroutine::attr(&self.store, &args[1], output);
+            let written = written.unwrap_or(Cell::Atom(atom::NIL));
+            let text = Cell::Str(text.as_str().into());
+            let written = self.store.compound(atom::DOT, vec![text, written]);
+            coroutine::set_attr(&mut self.store, &args[1], output, Some(written));
+            branch.trail = self.store.trail();
+        }
+    }
+
+    // the text written in the frame of `with_output_to/2` whose buffer is
+    // `buffer`.
+    fn output_text(&self, buffer: &Cell) -> String {
+        let written = match coroutine::attr(&self.store, buffer, Atom::new("$output")) {
+            Some(written) => coroutine::items(&self.store, &written),
+            None => return String::new(),
+        };
+        let mut text = String::new();
+        for chunk in written.iter().rev() {
+            if let Cell::Str(chunk) = self.store.deref(chunk) {
+                text += &chunk;
+            }
+        }
+        text
+    }
+
+    // unifies the text of `Sink`, `atom(A)`, `string(S)`, `codes(Cs)` or
+    // `chars(Cs)`, with `text`.
+    fn unify_sink(&mut self, sink: &Cell, text: &str) -> bool {
+        let kind = match self.store.key(sink) {
+            Some((kind, 1)) => kind,
+            _ => return false,
+        };
+        let value = match text_value(self, kind.name(), text) {
+            Some(value) => value,
+            None => return false,
+        };
+        let arg = self.store.args(sink)[0].clone();
+        self.store.unify(&arg, &value)
+    }
+}
+
+// the output of a builtin taken by `App::capture_output`.
+pub(crate) struct Capture {
+    frame: Rc<Frame>,
+    stream: usize,
+    outer: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{answers, consult};
+
+    #[test]
+    fn test_catch() {
+        let mut app = consult("nat(z).\nnat(s(X)) :- nat(X).\n");
+        // the bindings of the goal are undone before the recovery runs,
+        // and its answers before the error are kept.
+        let query = "catch((X = 1 ; X = 2, throw(oops)), E, (X = 3, E = oops)).";
+        assert_eq!(answers(&mut app, &["X"], query), vec!["1", "3"]);
+        let query = "catch(catch(throw(a), b, X = inner), a, X = outer).";
+        assert_eq!(answers(&mut app, &["X"], query), vec!["outer"]);
+        assert_eq!(
+            answers(&mut app, &["X"], "catch(throw(a), b, true)."),
+            vec!["Unhandled exception: a"]
+        );
+        // the answers of a goal with infinitely many of them are found as
+        // they come.
+        let query = "call_with_inference_limit(\
+                     (catch(nat(X), _, true), X = s(s(z))), 1000, R).";
+        assert_eq!(answers(&mut app, &["X", "R"], query)[0], "s(s(z)) true");
+        // delayed goals stay on the variables.
+        let query = "catch(dif(X, a), _, true), X = a.";
+        assert!(answers(&mut app, &["X"], query).is_empty());
+        // a goal woken after the call is not in it.
+        let query = "catch(freeze(X, throw(woken)), E, true), X = 1, Y = E.";
+        assert_eq!(
+            answers(&mut app, &["Y"], query),
+            vec!["Unhandled exception: woken"]
+        );
+    }
+
+    #[test]
+    fn test_condition() {
+        let source = "nat(z).\nnat(s(X)) :- nat(X).\n\
+                      sign(S) --> ([-] -> {S = -1} ; \\+ [+], {S = 1}).\n";
+        let mut app = consult(source);
+        let query = "((X = 1 ; X = 2) -> Y = X ; Y = none).";
+        assert_eq!(answers(&mut app, &["X", "Y"], query), vec!["1 1"]);
+        let query = "(fail -> Y = a ; Y = b).";
+        assert_eq!(answers(&mut app, &["Y"], query), vec!["b"]);
+        let query = "(nat(X) -> true), Y = X.";
+        assert_eq!(answers(&mut app, &["Y"], query), vec!["z"]);
+        assert!(answers(&mut app, &[], "\\+ nat(_).").is_empty());
+        assert_eq!(
+            answers(&mut app, &[], "\\+ ((X = b ; X = c), X = a)."),
+            vec![""]
+        );
+        let query = "\\+ \\+ X = a, X = b.";
+        assert_eq!(answers(&mut app, &["X"], query), vec!["b"]);
+        let query = "catch(\\+ throw(oops), E, true), X = E.";
+        assert_eq!(answers(&mut app, &["X"], query), vec!["oops"]);
+        let query = "(L = [-] ; L = [] ; L = [+]), phrase(sign(S), L).";
+        assert_eq!(answers(&mut app, &["S"], query), vec!["-1", "1"]);
+    }
+
+    #[test]
+    fn test_profile_runs_with_the_query() {
+        let mut app = consult("nat(z).\nnat(s(X)) :- nat(X).\n");
+        let query = "profile(dif(X, a)), X = a.";
+        assert!(answers(&mut app, &["X"], query).is_empty());
+        let query = "call_with_inference_limit((profile(nat(X)), X = s(z)), 100, _).";
+        assert_eq!(answers(&mut app, &["X"], query)[0], "s(z)");
+    }
+}
@@ -0,0 +1,365 @@
+// Garbage collection of the store used by the breadth-first engine.
+//
+// Cells only referenced by queue items that were already dropped are
+// reclaimed by compacting the store. Bindings on the trails of the
+// remaining items, and of the frames they run in, are kept only for
+// variables that are still reachable. Sharing between queue items is
+// preserved.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+
+use crate::app::{GoalNode, Goals, QueueItem};
+use crate::frame::Frame;
+use crate::proof::{StepNode, Steps};
+use crate::store::{Addr, Cell, Store, Trail, TrailEntry};
+
+pub struct Collected {
+    // goal list nodes that survived the collection.
+    pub goal_nodes: usize,
+}
+
+// collects the garbage of the items in `queue` and of `resolved`, the item
+// whose step is done but which was not left yet.
+pub fn collect(
+    store: &mut Store,
+    queue: &mut VecDeque<QueueItem>,
+    resolved: Option<&mut QueueItem>,
+) -> Collected {
+    // with no bindings applied, the store only holds the terms as written.
+    store.switch_to(&Trail::default());
+    let mut items: Vec<&mut QueueItem> = queue.iter_mut().chain(resolved).collect();
+
+    // the frames of the items, each once.
+    let mut frames: Vec<Rc<Frame>> = Vec::new();
+    let mut seen: HashSet<*const Frame> = HashSet::new();
+    for item in items.iter() {
+        for frame in item.frames.iter() {
+            if !seen.insert(Rc::as_ptr(frame)) {
+                break;
+            }
+            frames.push(frame.clone());
+        }
+    }
+    let saved: Vec<(Trail, Goals, Vec<Cell>)> = frames
+        .iter()
+        .map(|frame| {
+            let saved = frame.saved.borrow();
+            (saved.trail.clone(), saved.goals.clone(), saved.args.clone())
+        })
+        .collect();
+
+    let mut bindings: HashMap<Addr, Vec<Cell>> = HashMap::new();
+    let mut seen: HashSet<*const TrailEntry> = HashSet::new();
+    let trails = items.iter().map(|item| &item.trail);
+    for trail in trails.chain(saved.iter().map(|(trail, _, _)| trail)) {
+        let mut trail = trail;
+        while let Some(entry) = &trail.0 {
+            if !seen.insert(Rc::as_ptr(entry)) {
+                break;
+            }
+            let values = bindings.entry(entry.addr).or_default();
+            values.push(entry.value.clone());
+            values.push(entry.old.clone());
+            trail = &entry.prev;
+        }
+    }
+
+    let mut marker = Marker {
+        store,
+        bindings: &bindings,
+        marked: vec![false; store.cells.len()],
+        stack: Vec::new(),
+    };
+    // the first cell holds the state of the query, and stays first.
+    if !store.cells.is_empty() {
+        marker.visit(&Cell::Ref(0));
+    }
+    for (_, _, args) in &saved {
+        for arg in args {
+            marker.visit(arg);
+        }
+    }
+    let mut seen: HashSet<*const GoalNode> = HashSet::new();
+    for item in items.iter() {
+        marker.visit(&item.answer);
+    }
+    let goal_lists = items.iter().map(|item| &item.goals);
+    for goals in goal_lists.chain(saved.iter().map(|(_, goals, _)| goals)) {
+        let mut goals = goals;
+        while let Some(node) = &goals.0 {
+            if !seen.insert(Rc::as_ptr(node)) {
+                break;
+            }
+            marker.visit(&node.0);
+            goals = &node.2;
+        }
+    }
+    let mut seen: HashSet<*const StepNode> = HashSet::new();
+    for item in items.iter() {
+        let mut steps = &item.steps;
+        while let Some(node) = &steps.0 {
+            if !seen.insert(Rc::as_ptr(node)) {
+                break;
+            }
+            marker.visit(&node.0);
+            steps = &node.3;
+        }
+    }
+    marker.run();
+    let marked = marker.marked;
+
+    let mut forward = vec![0; marked.len()];
+    let mut next = 0;
+    for (addr, m) in marked.iter().enumerate() {
+        if *m {
+            forward[addr] = next;
+            next += 1;
+        }
+    }
+    let relocate = |cell: &Cell| match cell {
+        Cell::Ref(a) => Cell::Ref(forward[*a]),
+        Cell::Struct(a) => Cell::Struct(forward[*a]),
+        Cell::Attr(a) => Cell::Attr(forward[*a]),
+        _ => cell.clone(),
+    };
+    store.cells = store
+        .cells
+        .iter()
+        .zip(marked.iter())
+        .filter(|(_, m)| **m)
+        .map(|(cell, _)| relocate(cell))
+        .collect();
+
+    // the old lists are kept alive until the end so that the addresses used
+    // as keys below are not reused.
+    let old: Vec<(Trail, Goals, Steps)> = items
+        .iter()
+        .map(|item| (item.trail.clone(), item.goals.clone(), item.steps.clone()))
+        .collect();
+    let mut trails = HashMap::new();
+    let mut goal_lists = HashMap::new();
+    let mut derivations = HashMap::new();
+    let mut bindings = 0;
+    for (item, (trail, goals, steps)) in items.iter_mut().zip(old.iter()) {
+        item.trail = rebuild_trail(trail, &marked, &relocate, &mut trails, &mut bindings);
+        item.goals = rebuild_goals(goals, &relocate, &mut goal_lists);
+        item.steps = rebuild_steps(steps, &relocate, &mut derivations);
+        item.answer = relocate(&item.answer);
+    }
+    for (frame, (trail, goals, args)) in frames.iter().zip(saved.iter()) {
+        let mut saved = frame.saved.borrow_mut();
+        saved.trail = rebuild_trail(trail, &marked, &relocate, &mut trails, &mut bindings);
+        saved.goals = rebuild_goals(goals, &relocate, &mut goal_lists);
+        saved.args = args.iter().map(&relocate).collect();
+    }
+    store.trail = Trail::default();
+    store.bindings = bindings;
+    Collected {
+        goal_nodes: goal_lists.len(),
+    }
+}
+
+struct Marker<'a> {
+    store: &'a Store,
+    bindings: &'a HashMap<Addr, Vec<Cell>>,
+    marked: Vec<bool>,
+    stack: Vec<Addr>,
+}
+
+impl<'a> Marker<'a> {
+    fn visit(&mut self, cell: &Cell) {
+        match cell {
+            Cell::Ref(a) | Cell::Struct(a) | Cell::Attr(a) => self.stack.push(*a),
+            _ => {}
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(addr) = self.stack.pop() {
+            if self.marked[addr] {
+                continue;
+            }
+            self.marked[addr] = true;
+            match &self.store.cells[addr] {
+                Cell::Ref(a) if *a != addr => self.stack.push(*a),
+                Cell::Struct(a) | Cell::Attr(a) => self.stack.push(*a),
+                Cell::Functor(_, n) => self.stack.extend(addr + 1..=addr + n),
+                _ => {}
+            }
+            // a reachable variable keeps whatever any trail binds it to,
+            // and the attributes it had before.
+            if let Some(values) = self.bindings.get(&addr) {
+                for value in values.clone() {
+                    self.visit(&value);
+                }
+            }
+        }
+    }
+}
+
+fn rebuild_trail(
+    trail: &Trail,
+    marked: &[bool],
+    relocate: &impl Fn(&Cell) -> Cell,
+    memo: &mut HashMap<*const TrailEntry, Trail>,
+    count: &mut usize,
+) -> Trail {
+    // entries down to the first one already rebuilt, newest first.
+    let mut pending: Vec<&Rc<TrailEntry>> = Vec::new();
+    let mut current = trail;
+    let mut base = Trail::default();
+    while let Some(entry) = &current.0 {
+        if let Some(rebuilt) = memo.get(&Rc::as_ptr(entry)) {
+            base = rebuilt.clone();
+            break;
+        }
+        pending.push(entry);
+        current = &entry.prev;
+    }
+    for entry in pending.into_iter().rev() {
+        if marked[entry.addr] {
+            if let Cell::Ref(addr) = relocate(&Cell::Ref(entry.addr)) {
+                base = base.push(addr, relocate(&entry.value), relocate(&entry.old));
+                *count += 1;
+            }
+        }
+        memo.insert(Rc::as_ptr(entry), base.clone());
+    }
+    base
+}
+
+fn rebuild_goals(
+    goals: &Goals,
+    relocate: &impl Fn(&Cell) -> Cell,
+    memo: &mut HashMap<*const GoalNode, Goals>,
+) -> Goals {
+    let mut pending: Vec<&Rc<GoalNode>> = Vec::new();
+    let mut current = goals;
+    let mut base = Goals::default();
+    while let Some(node) = &current.0 {
+        if let Some(rebuilt) = memo.get(&Rc::as_ptr(node)) {
+            base = rebuilt.clone();
+            break;
+        }
+        pending.push(node);
+        current = &node.2;
+    }
+    for node in pending.into_iter().rev() {
+        base = base.push(vec![relocate(&node.0)], node.1);
+        memo.insert(Rc::as_ptr(node), base.clone());
+    }
+    base
+}
+
+fn rebuild_steps(
+    steps: &Steps,
+    relocate: &impl Fn(&Cell) -> Cell,
+    memo: &mut HashMap<*const StepNode, Steps>,
+) -> Steps {
+    let mut pending: Vec<&Rc<StepNode>> = Vec::new();
+    let mut current = steps;
+    let mut base = Steps::default();
+    while let Some(node) = &current.0 {
+        if let Some(rebuilt) = memo.get(&Rc::as_ptr(node)) {
+            base = rebuilt.clone();
+            break;
+        }
+        pending.push(node);
+        current = &node.3;
+    }
+    for node in pending.into_iter().rev() {
+        base = base.push(relocate(&node.0), node.1, node.2);
+        memo.insert(Rc::as_ptr(node), base.clone());
+    }
+    base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use crate::atom::Atom;
+    use crate::expr::{Term, Variable};
+    use crate::frame::Frames;
+    use crate::testing::{answers, temp_dir};
+    use std::fs;
+
+    #[test]
+    fn test_collect() {
+        let mut store = Store::new();
+        let mut vars = HashMap::new();
+        let x = Term::Var(Variable::Named(Atom::new("X")));
+        let answer = store.from_term(&Term::compound("$answer", vec![x.clone()]), &mut vars);
+        let goal = store.from_term(&Term::compound("p", vec![x]), &mut vars);
+        let root = store.trail();
+
+        // garbage only referenced by a dropped queue item.
+        store.from_term(
+            &Term::compound("big", vec![Term::atom("a"); 100]),
+            &mut HashMap::new(),
+        );
+        let value = store.from_term(
+            &Term::compound("f", vec![Term::atom("b")]),
+            &mut HashMap::new(),
+        );
+        let c = store.from_term(
+            &Term::compound("$answer", vec![Term::atom("c")]),
+            &mut HashMap::new(),
+        );
+        assert!(store.unify(&answer, &c));
+        let bound_c = store.trail();
+        store.switch_to(&root);
+        let arg = store.args(&answer)[0].clone();
+        assert!(store.unify(&arg, &value));
+        let bound_f = store.trail();
+        store.switch_to(&root);
+
+        let len = store.len();
+        let mut queue = VecDeque::new();
+        queue.push_back(QueueItem {
+            goals: Goals::default().push(vec![goal], None),
+            trail: bound_f,
+            answer,
+            steps: Steps::default(),
+            frames: Frames::default(),
+        });
+        drop(bound_c);
+        collect(&mut store, &mut queue, None);
+        assert!(store.len() < len);
+
+        let item = &queue[0];
+        store.switch_to(&item.trail);
+        assert_eq!(
+            store.to_term(&item.answer),
+            Term::compound("$answer", vec![Term::compound("f", vec![Term::atom("b")])])
+        );
+        let (goal, _, _) = item.goals.pop().unwrap();
+        assert_eq!(
+            store.to_term(&goal),
+            Term::compound("p", vec![Term::compound("f", vec![Term::atom("b")])])
+        );
+    }
+
+    #[test]
+    fn test_collect_in_frames() {
+        // the read term is garbage once `read_term/3` fails, so the limit
+        // collects it while the condition and the negation are left.
+        let list: Vec<String> = (0..2000).map(|n| format!("a{}", n)).collect();
+        let dir = temp_dir(&[("big.pl", &format!("big({}).\n", list.join(", ")))]);
+        let file = dir.join("big.pl");
+        let mut app = App::new();
+        let query = format!(
+            "set_prolog_flag(max_memory, 20000), open('{}', read, S), \
+             (read_term(S, foo, []) -> X = yes ; X = no), close(S).",
+            file.display()
+        );
+        assert_eq!(answers(&mut app, &["X"], &query), vec!["no"]);
+        let query = format!(
+            "open('{}', read, S), (\\+ read_term(S, foo, []) -> X = yes ; X = no), close(S).",
+            file.display()
+        );
+        assert_eq!(answers(&mut app, &["X"], &query), vec!["yes"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
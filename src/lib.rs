@@ -1,7 +1,34 @@
 extern crate nom;
 
 pub mod app;
+pub mod arith;
+pub mod atom;
+pub mod bigint;
+pub mod builtins;
+pub mod chr;
+pub mod clpfd;
+pub mod coroutine;
+pub mod dcg;
+pub mod debug;
+pub mod error;
+pub mod expand;
 pub mod expr;
+pub mod filter;
+pub mod flags;
+pub mod frame;
+pub mod gc;
+pub mod limits;
+pub mod load;
+pub mod module;
 pub mod parser;
+pub mod profile;
+pub mod proof;
+pub mod rational;
+pub mod store;
+pub mod stream;
+#[cfg(test)]
+mod testing;
 pub mod unifier;
 pub mod util;
+pub mod wam;
+pub mod write;
@@ -0,0 +1,209 @@
+// Limits on the resources of a query. `call_with_inference_limit/3` and
+// `call_with_time_limit/2` run their goal in a frame (see `frame`) that is
+// left when it runs out of resolution steps, counted as inferences, or
+// time, and the `max_queue_length` flag bounds the queue items a query may
+// keep. An interrupt (Ctrl-C) aborts the running query.
+
+use std::cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::app::App;
+use crate::arith::{self, Number};
+use crate::builtins::Branch;
+use crate::error::Exception;
+use crate::frame::{self, Kind};
+use crate::store::Cell;
+
+// set when the user interrupts the running query.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// makes Ctrl-C interrupt the running query instead of ending the process.
+#[cfg(unix)]
+pub fn catch_interrupts() {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    // the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        signal(SIGINT, on_interrupt);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_interrupts() {}
+
+// forgets an interrupt that came while no query was running.
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+// whether the running query was interrupted since last asked.
+pub fn interrupted() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+impl App {
+    // raises an error if the running query was interrupted or keeps too
+    // many alternatives.
+    pub(crate) fn check_limits(&mut self) -> Result<(), Exception> {
+        if interrupted() {
+            return Err(Exception::aborted());
+        }
+        match self.flags.max_queue_length() {
+            Some(max) if self.queue.len() > max => Err(Exception::resource_error("queue")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// call_with_inference_limit(Goal, Limit, Result): the answers of `Goal`
+// found within `Limit` inferences, `Result` being `!` for an answer after
+// which no alternative is left and `true` for the others. If `Goal` runs out
+// of inferences, its alternatives left are dropped and it succeeds once more
+// with `Result = inference_limit_exceeded`. The inferences of an enclosing
+// call count those of `Goal` too.
+pub(crate) fn call_with_inference_limit(
+    app: &mut App,
+    args: &[Cell],
+) -> Result<Vec<Branch>, Exception> {
+    let limit = match arith::eval(&app.store, &app.flags, &args[1])? {
+        Number::Int(n) if !n.is_negative() => n.to_i64().unwrap_or(i64::MAX) as u64,
+        Number::Int(_) => {
+            let culprit = app.store.to_term(&args[1]);
+            return Err(Exception::domain_error("not_less_than_zero", culprit));
+        }
+        _ => {
+            return Err(Exception::type_error(
+                "integer",
+                app.store.to_term(&args[1]),
+            ))
+        }
+    };
+    let kind = Kind::InferenceLimit {
+        limit,
+        used: cell::Cell::new(0),
+    };
+    Ok(frame::call(
+        app,
+        kind,
+        args[0].clone(),
+        vec![args[2].clone()],
+    ))
+}
+
+// call_with_time_limit(Time, Goal): the answers of `Goal` found within
+// `Time` seconds, after which its alternatives left are dropped and it
+// raises `time_limit_exceeded`.
+pub(crate) fn call_with_time_limit(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let seconds = match arith::eval(&app.store, &app.flags, &args[0])? {
+        Number::Int(n) => n.to_i64().unwrap_or(i64::MAX) as f64,
+        Number::Rational(r) => r.to_f64(),
+        Number::Float(f) => f,
+    };
+    if seconds.is_nan() || seconds <= 0.0 {
+        return Err(Exception::domain_error(
+            "positive_number",
+            app.store.to_term(&args[0]),
+        ));
+    }
+    let deadline = Duration::try_from_secs_f64(seconds)
+        .ok()
+        .and_then(|time| Instant::now().checked_add(time));
+    Ok(match deadline {
+        Some(deadline) => frame::call(
+            app,
+            Kind::TimeLimit { deadline },
+            args[1].clone(),
+            Vec::new(),
+        ),
+        // a time too long to reach.
+        None => vec![Branch {
+            trail: app.store.trail(),
+            goals: vec![args[1].clone()],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{answers, consult};
+
+    // the last of `answers`, after checking that the others are `earlier`.
+    fn last(answers: Vec<String>, earlier: &str) -> String {
+        let (last, others) = answers.split_last().unwrap();
+        assert!(
+            others.iter().all(|answer| answer == earlier),
+            "{:?}",
+            others
+        );
+        last.clone()
+    }
+
+    #[test]
+    fn test_limits() {
+        // `path/2` has infinitely many answers on a cyclic graph.
+        let mut app = consult(
+            "edge(a, b).\nedge(b, a).\n\
+             path(X, Y) :- edge(X, Y).\n\
+             path(X, Y) :- path(X, Z), edge(Z, Y).\n",
+        );
+
+        // the answers found within the limit come first.
+        let query = "call_with_inference_limit(path(a, _), 1000, X).";
+        let results = answers(&mut app, &["X"], query);
+        assert!(results.len() > 1);
+        assert_eq!(last(results, "true"), "inference_limit_exceeded");
+        let query = "call_with_inference_limit(edge(X, _), 1000, R).";
+        assert_eq!(answers(&mut app, &["X"], query), vec!["a", "b"]);
+        let query = "call_with_inference_limit(edge(_, _), 1000, X).";
+        assert_eq!(answers(&mut app, &["X"], query), vec!["true", "!"]);
+        // the enclosing limit is the one exceeded.
+        let query = "call_with_inference_limit(\
+                     call_with_inference_limit(path(a, _), 10000, _), 100, X).";
+        let results = answers(&mut app, &["X"], query);
+        assert_eq!(last(results, "true"), "inference_limit_exceeded");
+
+        let query = "catch(call_with_time_limit(0.05, path(a, _)), E, true), X = E.";
+        let results = answers(&mut app, &["X"], query);
+        assert_eq!(results.last().unwrap(), "time_limit_exceeded");
+        assert_eq!(
+            answers(
+                &mut app,
+                &["X"],
+                "call_with_time_limit(0.2, path(a, _)), X = 1."
+            ),
+            vec!["Time limit exceeded"]
+        );
+
+        let query = "set_prolog_flag(max_queue_length, 3), path(a, X).";
+        assert_eq!(
+            answers(&mut app, &["X"], query),
+            vec!["Not enough resources: queue"]
+        );
+        let query = "catch(path(a, _), error(E, _), true), X = E.";
+        let results = answers(&mut app, &["X"], query);
+        assert_eq!(results.last().unwrap(), "resource_error(queue)");
+    }
+
+    #[test]
+    fn test_limited_goals_run_with_the_query() {
+        let mut app = consult("nat(z).\nnat(s(X)) :- nat(X).\n");
+        // answers of a goal with infinitely many of them are found as
+        // they come.
+        let query = "call_with_inference_limit(\
+                     (call_with_time_limit(10, nat(X)), X = s(s(z))), 1000, R).";
+        assert_eq!(answers(&mut app, &["X", "R"], query)[0], "s(s(z)) true");
+        // delayed goals stay on the variables.
+        let query = "call_with_time_limit(5, freeze(X, fail)), X = 1.";
+        assert!(answers(&mut app, &["X"], query).is_empty());
+        let query = "call_with_inference_limit(dif(X, a), 10, _), X = a.";
+        assert!(answers(&mut app, &["X"], query).is_empty());
+    }
+}
@@ -0,0 +1,629 @@
+// Consulting source files. Clauses are added to the program as they are
+// read and directives (`:- Goal.`) are run at that point, except for the
+// declarations handled here and `initialization/1` goals, which run once the
+// file is loaded. Consulting a file again replaces the clauses it defined.
+// A file starting with `:- module(Name, Exports)` defines a module whose
+// exports are imported by the module loading it.
+
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use crate::app::App;
+use crate::atom::Atom;
+use crate::builtins;
+use crate::chr;
+use crate::dcg;
+use crate::error::Exception;
+use crate::expr::{Constant, Location, Rule, Term, Variable};
+use crate::module::{self, indicator, meta_specs, qualify, Module};
+use crate::parser::{clause_rule, parse_file_terms};
+use crate::wam::{key_of, PredKey};
+
+// libraries that are part of the system, which need no loading.
+const LIBRARIES: [&str; 2] = ["library(chr)", "library(clpfd)"];
+
+// a consulted file.
+pub struct SourceFile {
+    path: PathBuf,
+    // names of the file and the files it includes, as used in the locations
+    // of their clauses.
+    names: HashSet<Atom>,
+    // modification time when it was consulted.
+    modified: Option<SystemTime>,
+    // module defined by the file.
+    module: Option<Atom>,
+}
+
+// state of the file being consulted, shared with the files it includes.
+struct Load {
+    // module the clauses are added to.
+    module: Atom,
+    // module declared by the file.
+    defines: Option<Atom>,
+    names: HashSet<Atom>,
+    // predicate of the previous clause.
+    last: Option<PredKey>,
+    // predicates having clauses in the file.
+    defined: HashSet<PredKey>,
+    discontiguous: HashSet<PredKey>,
+    // `initialization/1` goals and their modules, run after loading.
+    initialization: Vec<(Term, Location, Atom)>,
+}
+
+impl Load {
+    fn new(module: Atom) -> Load {
+        Load {
+            module,
+            defines: None,
+            names: HashSet::new(),
+            last: None,
+            defined: HashSet::new(),
+            discontiguous: HashSet::new(),
+            initialization: Vec::new(),
+        }
+    }
+}
+
+impl App {
+    // consults `file`, replacing the clauses read from it before. Syntax
+    // errors, warnings and errors raised by directives are printed.
+    pub fn consult(&mut self, file: &str) -> Result<(), Exception> {
+        if LIBRARIES.contains(&file) {
+            return Ok(());
+        }
+        let importer = self.context;
+        if let Some(module) = self.load(file, false)? {
+            self.import_exports(importer, module);
+        }
+        Ok(())
+    }
+
+    // consults `file` unless it was already loaded.
+    pub fn ensure_loaded(&mut self, file: &str) -> Result<(), Exception> {
+        if LIBRARIES.contains(&file) {
+            return Ok(());
+        }
+        let importer = self.context;
+        if let Some(module) = self.load(file, true)? {
+            self.import_exports(importer, module);
+        }
+        Ok(())
+    }
+
+    // loads the module file `file` unless it was already loaded and imports
+    // the predicates it exports, or only `imports`.
+    pub fn use_module(&mut self, file: &str, imports: Option<&[PredKey]>) -> Result<(), Exception> {
+        if LIBRARIES.contains(&file) {
+            return Ok(());
+        }
+        let importer = self.context;
+        let module = self
+            .load(file, true)?
+            .ok_or_else(|| Exception::domain_error("module_file", Term::atom(file)))?;
+        match imports {
+            Some(keys) => self.import(importer, module, keys),
+            None => self.import_exports(importer, module),
+        }
+        Ok(())
+    }
+
+    fn import_exports(&mut self, into: Atom, from: Atom) {
+        let exports = self
+            .modules
+            .get(&from)
+            .map_or(Vec::new(), |m| m.exports.clone());
+        self.import(into, from, &exports);
+    }
+
+    // loads `file` into the module of the running goal and returns the
+    // module it defines. Files already loaded are loaded again unless
+    // `if_not_loaded`.
+    fn load(&mut self, file: &str, if_not_loaded: bool) -> Result<Option<Atom>, Exception> {
+        let path = self.resolve(file);
+        let key = canonical(&path);
+        if if_not_loaded {
+            if let Some(source) = self.loaded.get(&key) {
+                return Ok(source.module);
+            }
+        }
+        if let Some(source) = self.loaded.remove(&key) {
+            self.rules.retain(|rule| {
+                rule.location
+                    .is_none_or(|location| !source.names.contains(&location.file))
+            });
+            Rc::make_mut(&mut self.chr_rules)
+                .retain(|rule| !source.names.contains(&rule.location.file));
+            self.hooks = self
+                .rules
+                .iter()
+                .filter_map(|rule| key_of(&rule.lhs).map(|key| (rule.module, key)))
+                .filter(|(_, key)| is_hook(*key))
+                .collect();
+            self.rules_changed();
+        }
+        let mut load = Load::new(self.context);
+        self.load_file(&path, &mut load)?;
+        let source = SourceFile {
+            path: path.clone(),
+            names: mem::take(&mut load.names),
+            modified: modified(&path),
+            module: load.defines,
+        };
+        self.loaded.insert(key, source);
+        for (goal, location, module) in mem::take(&mut load.initialization) {
+            self.run_directive(&goal, location, module);
+        }
+        Ok(load.defines)
+    }
+
+    // consults again the files modified since they were loaded.
+    pub fn make(&mut self) -> Result<(), Exception> {
+        let mut changed: Vec<PathBuf> = self
+            .loaded
+            .values()
+            .filter(|source| modified(&source.path) != source.modified)
+            .map(|source| source.path.clone())
+            .collect();
+        changed.sort();
+        for path in changed {
+            self.consult(&path.to_string_lossy())?;
+        }
+        Ok(())
+    }
+
+    // `file`, relative to the directory of the file being consulted, with
+    // the `.pl` extension added if only that exists.
+    fn resolve(&self, file: &str) -> PathBuf {
+        let path = match self.loading.last().and_then(|current| current.parent()) {
+            Some(dir) if Path::new(file).is_relative() => dir.join(file),
+            _ => PathBuf::from(file),
+        };
+        let with_extension = path.with_extension("pl");
+        if !path.exists() && path.extension().is_none() && with_extension.exists() {
+            with_extension
+        } else {
+            path
+        }
+    }
+
+    fn load_file(&mut self, path: &Path, load: &mut Load) -> Result<(), Exception> {
+        let name = path.to_string_lossy();
+        let source = fs::read_to_string(path)
+            .map_err(|_| Exception::existence_error("source_sink", Term::atom(&name)))?;
+        load.names.insert(Atom::new(&name));
+        let (terms, errors) = parse_file_terms(&name, &source);
+        for e in errors {
+            self.message(format!("ERROR: {}", e));
+        }
+        self.loading.push(path.to_path_buf());
+        for (term, location) in terms {
+            let expanded = self
+                .expand_term(term.clone(), load.module)
+                .unwrap_or_else(|e| {
+                    self.message(format!("ERROR: {}: {}", location, e));
+                    vec![term]
+                });
+            for term in expanded {
+                self.load_clause(term, location, load);
+            }
+        }
+        self.loading.pop();
+        Ok(())
+    }
+
+    fn load_clause(&mut self, term: Term, location: Location, load: &mut Load) {
+        if chr::is_rule(&term) {
+            return match chr::rule(term, location, load.module, &self.chr_constraints) {
+                Ok(rule) => Rc::make_mut(&mut self.chr_rules).push(rule),
+                Err(e) => self.message(format!("ERROR: {}: {}", location, e)),
+            };
+        }
+        let mut rule = match clause_rule(term) {
+            Ok(rule) => rule,
+            Err(e) => return self.message(format!("ERROR: {}: {}", location, e)),
+        };
+        rule.location = Some(location);
+        rule.module = load.module;
+        if let Some(goal) = directive(&rule) {
+            let goal = self.expand(goal.clone(), location, load.module);
+            return self.handle_directive(&goal, location, load);
+        }
+        let body = mem::take(&mut rule.rhs);
+        for goal in body {
+            let goal = self.expand(goal, location, load.module);
+            rule.rhs.extend(dcg::goals(goal));
+        }
+        self.add_clause(rule, location, load);
+    }
+
+    // `goal` expanded, or as it is if expanding it raised an error.
+    fn expand(&mut self, goal: Term, location: Location, module: Atom) -> Term {
+        self.expand_goal(goal.clone(), module).unwrap_or_else(|e| {
+            self.message(format!("ERROR: {}: {}", location, e));
+            goal
+        })
+    }
+
+    fn add_clause(&mut self, rule: Rule, location: Location, load: &mut Load) {
+        let singletons = rule.singletons();
+        if !singletons.is_empty() {
+            let names: Vec<String> = singletons.iter().map(|v| v.to_string()).collect();
+            self.message(format!(
+                "Warning: {}:{}: Singleton variables: [{}]",
+                location.file,
+                location.line,
+                names.join(",")
+            ));
+        }
+        let key = key_of(&rule.lhs);
+        if let Some(key) = key {
+            if builtins::lookup(key).is_some() {
+                let e = Exception::permission_error("modify", "static_procedure", indicator(key));
+                self.message(format!("ERROR: {}: {}", location, e));
+                return;
+            }
+            if load.last != Some(key)
+                && !load.defined.insert(key)
+                && !load.discontiguous.contains(&key)
+                && !self.dynamic.contains(&key)
+            {
+                self.message(format!(
+                    "Warning: {}:{}: Clauses of {}/{} are not together in the source-file",
+                    location.file, location.line, key.0, key.1
+                ));
+                // warn only once for each predicate.
+                load.discontiguous.insert(key);
+            }
+        }
+        load.last = key;
+        if let Some(key) = key.filter(|key| is_hook(*key)) {
+            self.hooks.insert((rule.module, key));
+        }
+        self.rules.push(rule);
+        self.rules_changed();
+    }
+
+    fn handle_directive(&mut self, goal: &Term, location: Location, load: &mut Load) {
+        let result = match goal {
+            Term::Combined { functor, args } => match (functor.name(), args.len()) {
+                ("dynamic", 1) => pred_indicators(&args[0]).map(|keys| self.dynamic.extend(keys)),
+                ("discontiguous", 1) => {
+                    pred_indicators(&args[0]).map(|keys| load.discontiguous.extend(keys))
+                }
+                ("initialization", 1) => {
+                    load.initialization
+                        .push((args[0].clone(), location, load.module));
+                    Ok(())
+                }
+                ("include", 1) => file_arg(&args[0]).and_then(|file| {
+                    let path = self.resolve(&file);
+                    self.load_file(&path, load)
+                }),
+                ("module", 2) => self.declare_module(&args[0], &args[1], load),
+                ("chr_constraint", 1) => chr::constraint_keys(&args[0])
+                    .map(|keys| self.declare_constraints(keys, location, load)),
+                ("chr_option", 2) => Ok(()),
+                ("meta_predicate", 1) => meta_specs(&args[0]).map(|specs| {
+                    let module = self.modules.entry(load.module).or_default();
+                    module.meta.extend(specs)
+                }),
+                _ => return self.run_directive(goal, location, load.module),
+            },
+            _ => return self.run_directive(goal, location, load.module),
+        };
+        if let Err(e) = result {
+            self.message(format!("ERROR: {}: {}", location, e));
+        }
+    }
+
+    // `:- module(Name, Exports)`: the clauses that follow belong to `Name`.
+    fn declare_module(
+        &mut self,
+        name: &Term,
+        exports: &Term,
+        load: &mut Load,
+    ) -> Result<(), Exception> {
+        let name = match name {
+            Term::Const(Constant::Name(name)) => *name,
+            Term::Var(_) => return Err(Exception::instantiation_error()),
+            other => return Err(Exception::type_error("atom", other.clone())),
+        };
+        let exports = pred_indicators(exports)?;
+        if name == module::user() || name == module::system() {
+            return Err(Exception::permission_error(
+                "redefine",
+                "module",
+                Term::atom(name.name()),
+            ));
+        }
+        // declared again when the file is consulted again.
+        self.modules.insert(
+            name,
+            Module {
+                exports,
+                ..Module::default()
+            },
+        );
+        load.module = name;
+        load.defines = Some(name);
+        Ok(())
+    }
+
+    // defines each constraint `c/n` by the clause
+    // `c(A1, ..., An) :- '$chr_add'(c(A1, ..., An))`.
+    fn declare_constraints(&mut self, keys: Vec<PredKey>, location: Location, load: &mut Load) {
+        for (name, arity) in keys {
+            let args = (1..=arity)
+                .map(|i| Term::Var(Variable::Named(Atom::new(&format!("A{}", i)))))
+                .collect();
+            let head = match arity {
+                0 => Term::Const(Constant::Name(name)),
+                _ => Term::Combined {
+                    functor: name,
+                    args,
+                },
+            };
+            let add = Term::Combined {
+                functor: Atom::new("$chr_add"),
+                args: vec![head.clone()],
+            };
+            self.chr_constraints.insert((name, arity));
+            let rule = Rule {
+                lhs: head,
+                rhs: vec![add],
+                location: Some(location),
+                module: load.module,
+            };
+            self.add_clause(rule, location, load);
+        }
+    }
+
+    // writes a warning or error found while loading where `user_output`
+    // writes.
+    fn message(&mut self, text: String) {
+        self.fresh_line();
+        self.print(&format!("{}\n", text));
+    }
+
+    // runs `goal` in `module` as a query whose answers are not printed.
+    fn run_directive(&mut self, goal: &Term, location: Location, module: Atom) {
+        let mut query = goal.clone();
+        if module != module::user() {
+            query = qualify(module, query);
+        }
+        let result = self.aside(|app| app.solve(&[query]));
+        match result {
+            Ok(0) => self.message(format!(
+                "Warning: {}:{}: Goal (directive) failed: {}",
+                location.file, location.line, goal
+            )),
+            Ok(_) => {}
+            Err((e, _)) => self.message(format!("ERROR: {}: {}", location, e)),
+        }
+    }
+}
+
+// the path identifying a file, whichever way it was named.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn is_hook(key: PredKey) -> bool {
+    key.1 == 2 && ["term_expansion", "goal_expansion"].contains(&key.0.name())
+}
+
+// the goal of a clause written as `:- Goal.` or `?- Goal.`.
+fn directive(rule: &Rule) -> Option<&Term> {
+    match &rule.lhs {
+        Term::Combined { functor, args }
+            if rule.rhs.is_empty()
+                && args.len() == 1
+                && (functor.name() == ":-" || functor.name() == "?-") =>
+        {
+            Some(&args[0])
+        }
+        _ => None,
+    }
+}
+
+// the files named by an atom or a list of atoms.
+pub fn file_args(term: &Term) -> Result<Vec<String>, Exception> {
+    match term {
+        Term::Const(Constant::Name(name)) if name.name() == "[]" => Ok(Vec::new()),
+        Term::Combined { functor, args } if functor.name() == "." && args.len() == 2 => {
+            Ok([vec![file_arg(&args[0])?], file_args(&args[1])?].concat())
+        }
+        _ => Ok(vec![file_arg(term)?]),
+    }
+}
+
+fn file_arg(term: &Term) -> Result<String, Exception> {
+    match term {
+        Term::Combined { functor, args } if functor.name() == "library" && args.len() == 1 => {
+            Ok(format!("library({})", file_arg(&args[0])?))
+        }
+        Term::Const(Constant::Name(name)) => Ok(name.name().to_string()),
+        Term::Const(Constant::Str(text)) => Ok(text.clone()),
+        Term::Var(_) => Err(Exception::instantiation_error()),
+        other => Err(Exception::type_error("atom", other.clone())),
+    }
+}
+
+// the predicates of `Name/Arity` or `Name//Arity`, a sequence `(A, B)` or a list of them.
+pub fn pred_indicators(term: &Term) -> Result<Vec<PredKey>, Exception> {
+    match term {
+        Term::Var(_) => Err(Exception::instantiation_error()),
+        Term::Const(Constant::Name(name)) if name.name() == "[]" => Ok(Vec::new()),
+        Term::Combined { functor, args }
+            if args.len() == 2 && (functor.name() == "," || functor.name() == ".") =>
+        {
+            Ok([pred_indicators(&args[0])?, pred_indicators(&args[1])?].concat())
+        }
+        // `Name//Arity` is a nonterminal, taking two more arguments.
+        Term::Combined { functor, args }
+            if args.len() == 2 && (functor.name() == "/" || functor.name() == "//") =>
+        {
+            let extra = if functor.name() == "//" { 2 } else { 0 };
+            match (&args[0], &args[1]) {
+                (Term::Const(Constant::Name(name)), Term::Const(Constant::Int(arity))) => {
+                    match arity.to_i64() {
+                        Some(arity) if arity >= 0 => Ok(vec![(*name, arity as usize + extra)]),
+                        _ => Err(Exception::domain_error(
+                            "not_less_than_zero",
+                            args[1].clone(),
+                        )),
+                    }
+                }
+                (Term::Var(_), _) | (_, Term::Var(_)) => Err(Exception::instantiation_error()),
+                _ => Err(Exception::type_error("predicate_indicator", term.clone())),
+            }
+        }
+        _ => Err(Exception::type_error("predicate_indicator", term.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file_content;
+    use crate::testing::{answers, temp_dir, Shared};
+    use std::cell::RefCell;
+
+    fn directives(source: &str) -> Vec<Term> {
+        let (rules, errors) = parse_file_content("test.pl", source);
+        assert!(errors.is_empty());
+        rules.iter().filter_map(directive).cloned().collect()
+    }
+
+    #[test]
+    fn test_pred_indicators() {
+        let goals =
+            directives(":- dynamic foo/1, bar/2.\n:- dynamic([baz/0, greeting//1]).\nfoo(1).");
+        assert_eq!(goals.len(), 2);
+        let keys: Vec<Vec<PredKey>> = goals
+            .iter()
+            .map(|goal| match goal {
+                Term::Combined { args, .. } => pred_indicators(&args[0]).unwrap(),
+                _ => panic!("not a declaration"),
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                vec![(Atom::new("foo"), 1), (Atom::new("bar"), 2)],
+                vec![(Atom::new("baz"), 0), (Atom::new("greeting"), 3)]
+            ]
+        );
+
+        let goals = directives(":- dynamic foo.\n:- dynamic X/1.");
+        for goal in goals {
+            if let Term::Combined { args, .. } = &goal {
+                assert!(pred_indicators(&args[0]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_reconsult() {
+        let dir = std::env::temp_dir().join(format!("rslog-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("facts.pl");
+        fs::write(&file, "p(1).\np(2).\n").unwrap();
+        let name = file.to_string_lossy().to_string();
+
+        let mut app = App::new();
+        app.consult(&name).unwrap();
+        app.consult(&name).unwrap();
+        assert_eq!(app.rules.len(), 2);
+        app.ensure_loaded(&dir.join("facts").to_string_lossy())
+            .unwrap();
+        assert_eq!(app.rules.len(), 2);
+
+        fs::write(&file, "p(3).\n").unwrap();
+        app.consult(&name).unwrap();
+        assert_eq!(app.rules.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // an interpreter that consulted `main.pl` in a directory holding `files`,
+    // and what loading it wrote.
+    fn load(files: &[(&str, &str)]) -> (App, String) {
+        let dir = temp_dir(files);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut app = App::new();
+        app.output = Box::new(Shared(Rc::clone(&output)));
+        app.consult(&dir.join("main.pl").to_string_lossy()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let written = String::from_utf8(output.borrow().clone()).unwrap();
+        (app, written)
+    }
+
+    #[test]
+    fn test_initialization() {
+        // the goal runs once the clauses after it are loaded.
+        let source = ":- initialization((p(X), write(X), nl)).\n\
+                      :- p(_).\n\
+                      p(loaded).\n";
+        let (_, written) = load(&[("main.pl", source)]);
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("Goal (directive) failed"), "{}", written);
+        assert_eq!(lines[1], "loaded");
+
+        // an answer starts a new line after the output of the goal.
+        let dir = temp_dir(&[("main.pl", ":- initialization(write(done)).\n")]);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut app = App::new();
+        app.output = Box::new(Shared(Rc::clone(&output)));
+        let file = dir.join("main.pl");
+        app.handle_query(&format!("consult('{}'), X = 1.", file.display()));
+        fs::remove_dir_all(&dir).unwrap();
+        let written = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(written, "done\nX = 1.\n");
+    }
+
+    #[test]
+    fn test_include() {
+        let (mut app, written) = load(&[
+            ("main.pl", "p(main).\n:- include(part).\nq(X) :- p(X).\n"),
+            ("part.pl", "p(part).\n"),
+        ]);
+        assert_eq!(written, "");
+        // included clauses belong to the including file.
+        assert_eq!(answers(&mut app, &["X"], "q(X)."), vec!["main", "part"]);
+        assert_eq!(app.loaded.len(), 1);
+    }
+
+    #[test]
+    fn test_ensure_loaded() {
+        let (mut app, written) = load(&[
+            (
+                "main.pl",
+                ":- ensure_loaded(part).\n:- ensure_loaded(part).\n",
+            ),
+            ("part.pl", "p(part).\n:- write(loading), nl.\n"),
+        ]);
+        assert_eq!(written, "loading\n");
+        assert_eq!(answers(&mut app, &["X"], "p(X)."), vec!["part"]);
+    }
+
+    #[test]
+    fn test_discontiguous() {
+        let source = ":- discontiguous q/1.\n\
+                      p(1).\nq(1).\np(2).\nq(2).\np(3).\n";
+        let (mut app, written) = load(&[("main.pl", source)]);
+        let lines: Vec<&str> = written.lines().collect();
+        // one warning, for p/1 only.
+        assert_eq!(lines.len(), 1, "{}", written);
+        assert!(lines[0].ends_with(":4: Clauses of p/1 are not together in the source-file"));
+        assert_eq!(answers(&mut app, &["X"], "p(X)."), vec!["1", "2", "3"]);
+    }
+}
@@ -1,39 +1,127 @@
 pub mod app;
+pub mod arith;
+pub mod atom;
+pub mod bigint;
+pub mod builtins;
+pub mod chr;
+pub mod clpfd;
+pub mod coroutine;
+pub mod dcg;
+pub mod debug;
+pub mod error;
+pub mod expand;
 pub mod expr;
+pub mod filter;
+pub mod flags;
+pub mod frame;
+pub mod gc;
+pub mod limits;
+pub mod load;
+pub mod module;
 pub mod parser;
+pub mod profile;
+pub mod proof;
+pub mod rational;
+pub mod store;
+pub mod stream;
+#[cfg(test)]
+mod testing;
 pub mod unifier;
 pub mod util;
+pub mod wam;
+pub mod write;
 
-use std::io::{stdin, stdout, Write};
+use std::env;
+use std::io::stdin;
 
-use crate::app::{App, Status};
-use crate::expr::Term;
+use crate::app::{App, Engine, Status};
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::expr::{Constant, Term};
 use crate::unifier::search;
 
-const PROMPT: &'static str = "?- ";
-const HALT_MESSAGE: &'static str = "halt.";
+const PROMPT: &str = "?- ";
+const HALT_MESSAGE: &str = "halt.";
+
+fn parse_engine(name: &str) -> Engine {
+    match name {
+        "bfs" => Engine::Bfs,
+        "wam" => Engine::Wam,
+        _ => panic!("Unknown engine: {}", name),
+    }
+}
+
+// parses sizes such as `512k`, `64m` or `1g` into bytes.
+fn parse_size(size: &str) -> Term {
+    let (digits, unit) = match size.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    let bytes = digits
+        .parse::<i64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .unwrap_or_else(|| panic!("Invalid memory size: {}", size));
+    Term::Const(Constant::Int(BigInt::from(bytes)))
+}
 
 fn main() {
     let mut app = App::new();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--engine" => {
+                i += 1;
+                app.engine = parse_engine(args.get(i).expect("--engine needs a value"));
+            }
+            "--max-memory" => {
+                i += 1;
+                let size = parse_size(args.get(i).expect("--max-memory needs a value"));
+                app.flags.set(Atom::new("max_memory"), size).unwrap();
+            }
+            arg => {
+                if let Some(name) = arg.strip_prefix("--engine=") {
+                    app.engine = parse_engine(name);
+                } else if let Some(size) = arg.strip_prefix("--max-memory=") {
+                    app.flags
+                        .set(Atom::new("max_memory"), parse_size(size))
+                        .unwrap();
+                } else {
+                    panic!("Unknown option: {}", arg);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    limits::catch_interrupts();
     loop {
-        print!("\n{}", PROMPT);
+        app.print(&format!("\n{}", PROMPT));
         let mut input = String::new();
-        stdout().flush().unwrap();
-        stdin().read_line(&mut input).expect("Invalid input");
-        input.retain(|c| !c.is_whitespace());
-
+        if stdin().read_line(&mut input).expect("Invalid input") == 0 {
+            break;
+        }
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
         if input == HALT_MESSAGE {
             break;
         }
 
-        let Status { done, subst } = app.handle_query(&input);
+        let Status { done, subst } = app.handle_query(input);
         if done {
-            println!("true.");
-            app.asked_vars.iter().for_each(|var| {
-                search(&Term::Var(var.clone()), &subst)
-                    .map(|t| println!("{} = {term}.", var, term = t.to_string()));
-            });
+            app.fresh_line();
+            app.print("true.\n");
+            for var in app.asked_vars.clone() {
+                if let Some(t) = search(&Term::Var(var), &subst) {
+                    app.print(&format!("{} = {}.\n", var, t));
+                }
+            }
             app.asked_vars.clear();
         } else {
             //println!("subst {:?}", subst);
@@ -0,0 +1,200 @@
+// Modules. Clauses belong to the module of the file they were read from,
+// `user` unless the file starts with `:- module(Name, Exports)`. A goal is
+// looked up in the module it is called from, then among the predicates that
+// module imported and finally in `user`. Builtins belong to `system` and are
+// visible from every module.
+
+use std::collections::HashMap;
+
+use crate::app::App;
+use crate::atom::{self, Atom};
+use crate::bigint::BigInt;
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::wam::{key_of, PredKey};
+
+pub fn user() -> Atom {
+    atom::USER
+}
+
+pub fn system() -> Atom {
+    atom::SYSTEM
+}
+
+#[derive(Default)]
+pub struct Module {
+    pub exports: Vec<PredKey>,
+    // imported predicates and the module defining them.
+    pub imports: HashMap<PredKey, Atom>,
+    // for each meta-predicate, which arguments are goals (or other terms
+    // depending on the module) to be qualified with the caller's module.
+    pub meta: HashMap<PredKey, Vec<bool>>,
+}
+
+impl App {
+    // makes the `keys` exported by `from` visible in `into`. Importing the
+    // same predicate from two modules is an error.
+    pub(crate) fn import(&mut self, into: Atom, from: Atom, keys: &[PredKey]) {
+        if into == from {
+            return;
+        }
+        let exports = self
+            .modules
+            .get(&from)
+            .map_or(Vec::new(), |m| m.exports.clone());
+        for key in keys {
+            if !exports.contains(key) {
+                self.print(&format!(
+                    "Warning: {}:{}/{} is not exported (still imported into {})\n",
+                    from, key.0, key.1, into
+                ));
+            }
+            let module = self.modules.entry(into).or_default();
+            match module.imports.get(key) {
+                Some(other) if *other != from => {
+                    let e = Exception::permission_error(
+                        "import_into",
+                        "procedure",
+                        qualify(from, indicator(*key)),
+                    );
+                    self.print(&format!("ERROR: {}\n", e));
+                }
+                _ => {
+                    module.imports.insert(*key, from);
+                }
+            }
+        }
+    }
+}
+
+// `module:term`.
+pub fn qualify(module: Atom, term: Term) -> Term {
+    Term::Combined {
+        functor: Atom::new(":"),
+        args: vec![Term::Const(Constant::Name(module)), term],
+    }
+}
+
+// `Name/Arity`.
+pub fn indicator(key: PredKey) -> Term {
+    Term::Combined {
+        functor: Atom::new("/"),
+        args: vec![
+            Term::Const(Constant::Name(key.0)),
+            Term::Const(Constant::Int(BigInt::from(key.1 as i64))),
+        ],
+    }
+}
+
+// the predicates declared by `meta_predicate foo(0, ?), bar(:)` and which of
+// their arguments depend on the module: goals, possibly missing some
+// arguments (`0`..`9`), `:`, `^` and `//`.
+pub fn meta_specs(term: &Term) -> Result<Vec<(PredKey, Vec<bool>)>, Exception> {
+    match term {
+        Term::Var(_) => Err(Exception::instantiation_error()),
+        Term::Combined { functor, args } if functor.name() == "," && args.len() == 2 => {
+            Ok([meta_specs(&args[0])?, meta_specs(&args[1])?].concat())
+        }
+        Term::Combined { args, .. } => {
+            let spec = args
+                .iter()
+                .map(|arg| match arg {
+                    Term::Var(_) => Err(Exception::instantiation_error()),
+                    Term::Const(Constant::Int(n)) => match n.to_i64() {
+                        Some(0..=9) => Ok(true),
+                        _ => Err(Exception::domain_error(
+                            "meta_argument_specifier",
+                            arg.clone(),
+                        )),
+                    },
+                    Term::Const(Constant::Name(name)) => {
+                        Ok(matches!(name.name(), ":" | "^" | "//"))
+                    }
+                    _ => Err(Exception::domain_error(
+                        "meta_argument_specifier",
+                        arg.clone(),
+                    )),
+                })
+                .collect::<Result<Vec<bool>, Exception>>()?;
+            Ok(vec![(key_of(term).unwrap(), spec)])
+        }
+        _ => Err(Exception::type_error("compound", term.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use crate::parser::parse_file_content;
+    use crate::testing::{answers, temp_dir};
+
+    #[test]
+    fn test_meta_specs() {
+        let (rules, errors) = parse_file_content(
+            "test.pl",
+            ":- meta_predicate maplist(2, ?, ?), once(0), m(:, +).",
+        );
+        assert!(errors.is_empty());
+        let spec = match &rules[0].lhs {
+            Term::Combined { args, .. } => match &args[0] {
+                Term::Combined { args, .. } => meta_specs(&args[0]).unwrap(),
+                _ => panic!("not a declaration"),
+            },
+            _ => panic!("not a directive"),
+        };
+        assert_eq!(
+            spec,
+            vec![
+                ((Atom::new("maplist"), 3), vec![true, false, false]),
+                ((Atom::new("once"), 1), vec![true]),
+                ((Atom::new("m"), 2), vec![true, false]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_modules() {
+        let dir = temp_dir(&[
+            (
+                "m1.pl",
+                ":- module(m1, [p/1]).\np(X) :- helper(X, _).\nhelper(one, m1).\n",
+            ),
+            (
+                "m2.pl",
+                ":- module(m2, [q/1]).\nq(X) :- helper(X, _).\nhelper(two, m2).\n",
+            ),
+            ("m3.pl", ":- module(m3, [r/1, s/1]).\nr(r).\ns(s).\n"),
+            (
+                "m4.pl",
+                ":- module(m4, [apply/1, run/1]).\n:- meta_predicate apply(0).\n\
+                 apply(G) :- call(G).\nrun(G) :- call(G).\nwhere(m4).\n",
+            ),
+            ("user.pl", "where(user).\n"),
+        ]);
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let mut app = App::new();
+        for name in ["m1.pl", "m2.pl", "m4.pl"] {
+            app.consult(&path(name)).unwrap();
+        }
+        let query = format!("use_module('{}', [r/1]).", path("m3.pl"));
+        assert_eq!(answers(&mut app, &[], &query), vec![""]);
+
+        // each module has its own helper/2, which is not visible outside.
+        assert_eq!(answers(&mut app, &["X"], "p(X)."), vec!["one"]);
+        assert_eq!(answers(&mut app, &["X"], "q(X)."), vec!["two"]);
+        assert!(answers(&mut app, &["X"], "helper(X, _).").is_empty());
+        // a module-qualified goal runs in that module.
+        assert_eq!(answers(&mut app, &["M"], "m2:helper(_, M)."), vec!["m2"]);
+        // only the predicates in the import list are imported.
+        assert_eq!(answers(&mut app, &["X"], "r(X)."), vec!["r"]);
+        assert!(answers(&mut app, &["X"], "s(X).").is_empty());
+        assert_eq!(answers(&mut app, &["X"], "m3:s(X)."), vec!["s"]);
+        // a meta-argument runs in the module of the caller, other arguments
+        // in the module of the predicate.
+        app.consult(&path("user.pl")).unwrap();
+        assert_eq!(answers(&mut app, &["M"], "apply(where(M))."), vec!["user"]);
+        assert_eq!(answers(&mut app, &["M"], "run(where(M))."), vec!["m4"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
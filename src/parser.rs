@@ -1,135 +1,831 @@
-use nom::character::complete::{alpha1, alphanumeric1, digit1};
-use nom::error::ErrorKind;
-use nom::{delimited, do_parse, eof, is_not, many_till, map, map_res, named, opt, tag};
+// Reads clauses and queries. Terms are parsed by operator precedence over
+// the standard operator table below; layout between tokens is skipped.
+
+use std::fmt;
+
+use nom::bytes::complete::take_while1;
+use nom::character::complete::digit1;
+use nom::error::{ErrorKind, ParseError};
 use nom::{Err, IResult};
 
-use crate::expr::{Constant, Query, Rule, Term};
-use crate::util::first_char;
-
-named!(
-    parse_const_int<&str, Constant>,
-    do_parse!(
-        i: map_res!(digit1, |s: &str| s.parse::<i32>()) >>
-        (Constant::Int(i))
-    )
-);
-
-named!(
-    parse_const_str<&str, Constant>,
-    do_parse!(
-        s: delimited!(tag!("\""), is_not!("\""), tag!("\"")) >>
-        (Constant::Str(s.to_string()))
-    )
-);
-
-fn parse_const_name(s: &str) -> IResult<&str, Constant> {
-    if first_char(s).is_lowercase() {
-        alphanumeric1(s).and_then(|(i, n)| Ok((i, Constant::Name(n.to_string()))))
-    } else {
-        Err(Err::Error(("var", ErrorKind::AlphaNumeric)))
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::dcg::{self, Translator};
+use crate::error::Exception;
+use crate::expr::{Constant, Location, Query, Rule, Term, Variable};
+use crate::rational::Rational;
+
+const SYMBOL_CHARS: &str = "+-*/\\^<>=~:.?@#&$";
+
+// priority and maximum argument priorities of an infix operator.
+pub(crate) fn infix_op(name: &str) -> Option<(u32, u32, u32)> {
+    let (priority, assoc) = match name {
+        ":-" | "-->" | "@" => (1200, "xfx"),
+        "pragma" => (1190, "xfx"),
+        "<=>" | "==>" => (1180, "xfx"),
+        "\\" => (1100, "xfx"),
+        ";" | "|" => (1100, "xfy"),
+        "->" | "*->" => (1050, "xfy"),
+        "," => (1000, "xfy"),
+        "=" | "\\=" | "==" | "\\==" | "@<" | "@>" | "@=<" | "@>=" | "=.." | "is" | "=:="
+        | "=\\=" | "<" | ">" | "=<" | ">=" => (700, "xfx"),
+        "#=" | "#\\=" | "#<" | "#>" | "#=<" | "#>=" | "in" | "ins" => (700, "xfx"),
+        ":" => (200, "xfy"),
+        ".." => (450, "xfx"),
+        "+" | "-" | "/\\" | "\\/" | "xor" => (500, "yfx"),
+        "*" | "/" | "//" | "rem" | "mod" | "div" | "rdiv" | "<<" | ">>" => (400, "yfx"),
+        "**" => (200, "xfx"),
+        "^" => (200, "xfy"),
+        _ => return None,
+    };
+    Some(match assoc {
+        "xfx" => (priority, priority - 1, priority - 1),
+        "xfy" => (priority, priority - 1, priority),
+        _ => (priority, priority, priority - 1),
+    })
+}
+
+// priority and maximum argument priority of a prefix operator.
+pub(crate) fn prefix_op(name: &str) -> Option<(u32, u32)> {
+    match name {
+        ":-" | "?-" => Some((1200, 1199)),
+        "dynamic" | "discontiguous" | "initialization" | "meta_predicate" | "chr_constraint" => {
+            Some((1150, 1149))
+        }
+        "\\+" => Some((900, 900)),
+        "-" | "+" | "\\" => Some((200, 200)),
+        _ => None,
     }
 }
 
-fn parse_const(s: &str) -> IResult<&str, Term> {
-    parse_const_int(s)
-        .or(parse_const_str(s))
-        .or(parse_const_name(s))
-        .and_then(|(i, c)| Ok((i, Term::Const(c))))
+// what the parser expected at `rest`, the input left when it failed.
+#[derive(Debug, PartialEq)]
+pub struct Expected<'a> {
+    rest: &'a str,
+    message: &'static str,
 }
 
-fn parse_var(s: &str) -> IResult<&str, Term> {
-    if first_char(s).is_uppercase() {
-        alpha1(s).and_then(|(i, s)| Ok((i, Term::Var(s.to_string()))))
-    } else {
-        Err(Err::Error(("var", ErrorKind::Alpha)))
+impl<'a> ParseError<&'a str> for Expected<'a> {
+    fn from_error_kind(rest: &'a str, _: ErrorKind) -> Self {
+        Expected {
+            rest,
+            message: "unexpected character",
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type PResult<'a, O> = IResult<&'a str, O, Expected<'a>>;
+
+// a syntax error in a clause or query.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub location: Location,
+    pub message: &'static str,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn error<'a, O>(s: &'a str, message: &'static str) -> PResult<'a, O> {
+    Err(Err::Error(Expected { rest: s, message }))
+}
+
+// an error that alternatives are not tried after, e.g. a missing `)`.
+fn failure<'a, O>(s: &'a str, message: &'static str) -> PResult<'a, O> {
+    Err(Err::Failure(Expected { rest: s, message }))
+}
+
+// turns errors into failures once the input can only be read one way.
+fn cut<O>(result: PResult<'_, O>) -> PResult<'_, O> {
+    result.map_err(|e| match e {
+        Err::Error(e) => Err::Failure(e),
+        e => e,
+    })
+}
+
+fn expected(token: &str) -> &'static str {
+    match token {
+        "(" => "expected '('",
+        ")" => "expected ')'",
+        "[" => "expected '['",
+        "]" => "expected ']'",
+        "{" => "expected '{'",
+        "}" => "expected '}'",
+        "|" => "expected '|'",
+        "," => "expected ','",
+        _ => "expected '.'",
     }
 }
 
-fn parse_term(s: &str) -> IResult<&str, Term> {
-    parse_combined(s).or(parse_const(s)).or(parse_var(s))
+// line and column of `rest` in `source`, counted from 1.
+fn locate(file: Atom, source: &str, rest: &str) -> Location {
+    let before = &source[..source.len() - rest.len()];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Location {
+        file,
+        line: before.matches('\n').count() + 1,
+        col: before[line_start..].chars().count() + 1,
+    }
 }
 
-// TODO: bad code. Clean up with do_parse! and opt!
-fn parse_terms(s: &str) -> IResult<&str, Vec<Term>> {
-    let mut v = Vec::new();
-    let mut i = s;
+fn syntax_error(file: Atom, source: &str, e: Err<Expected>) -> SyntaxError {
+    match e {
+        Err::Error(e) | Err::Failure(e) => SyntaxError {
+            location: locate(file, source, e.rest),
+            message: e.message,
+        },
+        Err::Incomplete(_) => SyntaxError {
+            location: locate(file, source, ""),
+            message: "unexpected end of file",
+        },
+    }
+}
+
+// skips whitespace and comments.
+fn layout(s: &str) -> &str {
+    let mut s = s.trim_start();
     loop {
-        match parse_term(i) {
-            Ok((ni, term)) => {
-                i = ni;
-                v.push(term);
+        if s.starts_with('%') {
+            s = s.find('\n').map_or("", |i| &s[i..]).trim_start();
+        } else if let Some(rest) = s.strip_prefix("/*") {
+            s = rest.find("*/").map_or("", |i| &rest[i + 2..]).trim_start();
+        } else {
+            return s;
+        }
+    }
+}
+
+fn punct<'a>(s: &'a str, p: &'static str) -> PResult<'a, ()> {
+    match layout(s).strip_prefix(p) {
+        Some(rest) => Ok((rest, ())),
+        None => error(layout(s), expected(p)),
+    }
+}
+
+// the `.` ending a clause or query.
+fn parse_end(s: &str) -> PResult<'_, ()> {
+    let (rest, _) = punct(s, ".")?;
+    match rest.chars().next() {
+        Some(c) if SYMBOL_CHARS.contains(c) || c == '(' => error(s, "expected an operator or '.'"),
+        _ => Ok((rest, ())),
+    }
+}
+
+// letters are classified as in SWI-Prolog: uppercase and titlecase letters
+// start a variable, other letters (including those without case, such as
+// CJK ideographs) start an atom.
+fn is_var_start(c: char) -> bool {
+    c == '_'
+        || c.is_uppercase()
+        || (c.is_alphabetic() && !c.is_lowercase() && c.to_lowercase().next() != Some(c))
+}
+
+fn is_atom_start(c: char) -> bool {
+    c.is_alphabetic() && !is_var_start(c)
+}
+
+fn is_alnum(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// `name` as written by `writeq/1`: quoted unless it reads back as the same
+// atom.
+pub fn quote_atom(name: &str) -> String {
+    let plain = match name.chars().next() {
+        Some(c) if is_atom_start(c) => name.chars().all(is_alnum),
+        Some(_) => {
+            name.chars().all(|c| SYMBOL_CHARS.contains(c)) || ["[]", "{}", "!", ";"].contains(&name)
+        }
+        None => false,
+    };
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", escape(name, '\''))
+    }
+}
+
+// text to write between `quote`s, with the quote and control characters
+// escaped.
+pub fn escape(text: &str, quote: char) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
             }
-            Err(e) => return Err(e),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:x}\\", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// the character written after a backslash in quoted text, or none for a
+// backslash before a newline, which continues the text on the next line.
+fn parse_escape(s: &str) -> PResult<'_, Option<char>> {
+    let c = match s.chars().next() {
+        Some(c) => c,
+        None => return failure(s, "undefined escape sequence"),
+    };
+    let rest = &s[c.len_utf8()..];
+    let escaped = match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'a' => '\x07',
+        'b' => '\x08',
+        'f' => '\x0c',
+        'v' => '\x0b',
+        'e' => '\x1b',
+        's' => ' ',
+        '0'..='7' | 'x' => {
+            // `\123\` in octal or `\x7f\` in hexadecimal.
+            let (radix, digits) = if c == 'x' { (16, rest) } else { (8, s) };
+            let end = digits
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(digits.len());
+            let code = u32::from_str_radix(&digits[..end], radix).ok();
+            let rest = &digits[end..];
+            return match code.and_then(std::char::from_u32) {
+                Some(c) => Ok((rest.strip_prefix('\\').unwrap_or(rest), Some(c))),
+                None => failure(s, "undefined escape sequence"),
+            };
         }
-        match parse_comma(i) {
-            Ok((ni, _)) => i = ni,
-            Err(_) => return Ok((i, v)),
+        '\\' | '\'' | '"' | '`' => c,
+        '\n' => return Ok((rest, None)),
+        _ => return failure(s, "undefined escape sequence"),
+    };
+    Ok((rest, Some(escaped)))
+}
+
+// text between `quote`s, with escape sequences replaced and a doubled quote
+// read as one.
+fn parse_quoted(s: &str, quote: char) -> PResult<'_, String> {
+    let mut rest = match s.strip_prefix(quote) {
+        Some(rest) => rest,
+        None => return error(s, "expected a quote"),
+    };
+    let mut text = String::new();
+    loop {
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None if quote == '"' => return failure(s, "unterminated string"),
+            None => return failure(s, "unterminated quoted atom"),
+        };
+        rest = &rest[c.len_utf8()..];
+        if c == quote {
+            match rest.strip_prefix(quote) {
+                Some(after) => rest = after,
+                None => return Ok((rest, text)),
+            }
+            text.push(quote);
+        } else if c == '\\' {
+            let (after, escaped) = parse_escape(rest)?;
+            text.extend(escaped);
+            rest = after;
+        } else {
+            text.push(c);
         }
     }
 }
 
-named!(parse_comma<&str, &str>, tag!(","));
-named!(parse_dot<&str, &str>, tag!("."));
+fn digits(s: &str) -> Option<&str> {
+    digit1::<_, (&str, ErrorKind)>(s).ok().map(|(rest, _)| rest)
+}
 
-fn parse_functor(s: &str) -> IResult<&str, &str> {
-    if first_char(s).is_lowercase() {
-        alpha1(s)
+// a float such as `1.5`, `1.0e-3`, `1.0Inf` or `1.5NaN`.
+fn parse_float(s: &str) -> PResult<'_, Constant> {
+    let rest = digits(s).and_then(|rest| digits(rest.strip_prefix('.')?));
+    let mut rest = match rest {
+        Some(rest) => rest,
+        None => return error(s, "expected a float"),
+    };
+    let exponent = rest
+        .strip_prefix(|c| c == 'e' || c == 'E')
+        .map(|e| e.strip_prefix(|c| c == '+' || c == '-').unwrap_or(e))
+        .and_then(digits);
+    if let Some(after) = exponent {
+        rest = after;
+    }
+    let text = &s[..s.len() - rest.len()];
+    if let Some(after) = rest.strip_prefix("Inf") {
+        return Ok((after, Constant::Float(f64::INFINITY)));
+    }
+    if let Some(after) = rest.strip_prefix("NaN") {
+        return Ok((after, Constant::Float(f64::NAN)));
+    }
+    Ok((rest, Constant::Float(text.parse().unwrap())))
+}
+
+// a character code such as `0'a`, `0'\n` or `0'''`.
+fn parse_char_code(s: &str) -> PResult<'_, Constant> {
+    let rest = match s.strip_prefix("0'") {
+        Some(rest) => rest,
+        None => return error(s, "expected a character code"),
+    };
+    let (rest, c) = if let Some(after) = rest.strip_prefix("''") {
+        (after, '\'')
+    } else if let Some(after) = rest.strip_prefix('\\') {
+        match parse_escape(after)? {
+            (after, Some(c)) => (after, c),
+            (_, None) => return error(s, "expected a character code"),
+        }
     } else {
-        Err(Err::Error(("functor", ErrorKind::Alpha)))
+        match rest.chars().next() {
+            Some(c) => (&rest[c.len_utf8()..], c),
+            None => return error(s, "expected a character code"),
+        }
+    };
+    Ok((rest, Constant::Int(BigInt::from(c as i64))))
+}
+
+// an integer written as `0x1f`, `0o17` or `0b101`.
+fn parse_radix_int(s: &str) -> PResult<'_, Constant> {
+    for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+        if let Some(rest) = s.strip_prefix(prefix) {
+            let end = rest
+                .find(|c: char| !c.is_digit(radix))
+                .unwrap_or(rest.len());
+            if end > 0 {
+                let i = BigInt::parse_radix(&rest[..end], radix).unwrap();
+                return Ok((&rest[end..], Constant::Int(i)));
+            }
+        }
     }
+    error(s, "expected an integer")
 }
 
-named!(parse_paren_args<&str, Vec<Term>>, delimited!(tag!("("), parse_terms, tag!(")")));
+// an integer, a float, or a rational written as `1r3`.
+fn parse_number(s: &str) -> PResult<'_, Constant> {
+    if let Ok(number) = parse_float(s)
+        .or_else(|_| parse_char_code(s))
+        .or_else(|_| parse_radix_int(s))
+    {
+        return Ok(number);
+    }
+    let (rest, digits) = digit1(s)?;
+    let num = BigInt::parse(digits).unwrap();
+    if let Some(after) = rest.strip_prefix('r') {
+        if let Ok((rest, den)) = digit1::<_, (&str, ErrorKind)>(after) {
+            return match Rational::new(num, BigInt::parse(den).unwrap()) {
+                Some(r) if r.is_integer() => Ok((rest, Constant::Int(r.numerator().clone()))),
+                Some(r) => Ok((rest, Constant::Rational(r))),
+                None => failure(s, "illegal number"),
+            };
+        }
+    }
+    Ok((rest, Constant::Int(num)))
+}
 
-named!(
-    parse_combined<&str, Term>,
-    do_parse!(
-        functor: parse_functor >>
-        args: parse_paren_args >>
-        (Term::Combined{functor: functor.to_string(), args: args})
-    )
-);
+fn negate(c: Constant) -> Constant {
+    match c {
+        Constant::Int(i) => Constant::Int(i.neg()),
+        Constant::Rational(r) => Constant::Rational(r.neg()),
+        Constant::Float(f) => Constant::Float(-f),
+        c => c,
+    }
+}
 
-named!(parse_file_name<&str, &str>, delimited!(tag!("['"), is_not!("']"), tag!("']")));
+fn parse_const_str(s: &str) -> PResult<'_, Constant> {
+    parse_quoted(s, '"').map(|(rest, text)| (rest, Constant::Str(text)))
+}
 
-pub fn parse_query(input: &str) -> Result<Query, Err<(&str, ErrorKind)>> {
-    if let Ok((i, o)) = parse_file_name(input) {
-        match parse_dot(i) {
-            Ok(_) => Ok(Query::File(o.to_string())),
-            Err(e) => Err(e),
+// an atom: a quoted atom, an identifier starting with a lowercase letter, a
+// sequence of symbol characters or one of the solo atoms.
+fn parse_name(s: &str) -> PResult<'_, Atom> {
+    let s = layout(s);
+    let (rest, name) = match s.chars().next() {
+        Some('\'') => {
+            let (rest, name) = parse_quoted(s, '\'')?;
+            return Ok((rest, Atom::new(&name)));
         }
-    } else if let Ok((i, terms)) = parse_terms(input) {
-        match parse_dot(i) {
-            Ok(_) => Ok(Query::Terms(terms)),
-            Err(e) => Err(e),
+        Some(c) if is_atom_start(c) => take_while1(is_alnum)(s)?,
+        Some(c) if SYMBOL_CHARS.contains(c) => {
+            if parse_end(s).is_ok() {
+                return error(s, "expected an atom");
+            }
+            take_while1(|c: char| SYMBOL_CHARS.contains(c))(s)?
         }
-    } else {
-        Err(Err::Error(("parse_query", ErrorKind::NoneOf)))
-    }
-}
-
-named!(
-    parse_rhs<&str, Vec<Term>>,
-    map!(
-        opt!(
-            do_parse!(
-                tag!(":-") >>
-                terms: parse_terms >>
-                (terms)
-            )
-        ),
-        |t| t.unwrap_or(Vec::new())
-    )
-);
-
-named!(
-    parse_rule<&str, Rule>,
-    do_parse!(
-        lhs: parse_term >>
-        rhs: parse_rhs >>
-        parse_dot >>
-        (Rule { lhs, rhs })
-    )
-);
-
-named!(pub parse_file_content<&str, (Vec<Rule>, &str)>, many_till!(parse_rule, eof!()));
+        Some('!') | Some(';') => (&s[1..], &s[..1]),
+        _ => return error(s, "expected an atom"),
+    };
+    Ok((rest, Atom::new(name)))
+}
+
+fn parse_var(s: &str) -> PResult<'_, Term> {
+    match s.chars().next() {
+        Some(c) if is_var_start(c) => {
+            take_while1(is_alnum)(s).map(|(i, s)| (i, Term::Var(Variable::Named(Atom::new(s)))))
+        }
+        _ => error(s, "expected a variable"),
+    }
+}
+
+fn parse_args(s: &str) -> PResult<'_, Vec<Term>> {
+    let (mut s, first) = parse_term(s, 999)?;
+    let mut args = vec![first];
+    while let Ok((rest, _)) = punct(s, ",") {
+        let (rest, arg) = cut(parse_term(rest, 999))?;
+        args.push(arg);
+        s = rest;
+    }
+    Ok((s, args))
+}
+
+// a term that is not an infix operator application, with its priority.
+fn parse_primary(s: &str, max: u32) -> PResult<'_, (Term, u32)> {
+    let s = layout(s);
+    match s.chars().next() {
+        Some(c) if c.is_ascii_digit() => {
+            let (rest, c) = parse_number(s)?;
+            return Ok((rest, (Term::Const(c), 0)));
+        }
+        Some('"') => {
+            let (rest, c) = parse_const_str(s)?;
+            return Ok((rest, (Term::Const(c), 0)));
+        }
+        Some(c) if is_var_start(c) => {
+            let (rest, var) = parse_var(s)?;
+            return Ok((rest, (var, 0)));
+        }
+        Some('(') => {
+            let (rest, term) = cut(parse_term(&s[1..], 1200))?;
+            let (rest, _) = cut(punct(rest, ")"))?;
+            return Ok((rest, (term, 0)));
+        }
+        Some('[') => {
+            if let Ok((rest, _)) = punct(&s[1..], "]") {
+                return Ok((rest, (Term::nil(), 0)));
+            }
+            let (rest, items) = cut(parse_args(&s[1..]))?;
+            let (rest, tail) = match punct(rest, "|") {
+                Ok((rest, _)) => cut(parse_term(rest, 999))?,
+                Err(_) => (rest, Term::nil()),
+            };
+            let (rest, _) = cut(punct(rest, "]"))?;
+            return Ok((rest, (Term::list(items, tail), 0)));
+        }
+        Some('{') => {
+            let functor = Atom::new("{}");
+            if let Ok((rest, _)) = punct(&s[1..], "}") {
+                return Ok((rest, (Term::Const(Constant::Name(functor)), 0)));
+            }
+            let (rest, term) = cut(parse_term(&s[1..], 1200))?;
+            let (rest, _) = cut(punct(rest, "}"))?;
+            let args = vec![term];
+            return Ok((rest, (Term::Combined { functor, args }, 0)));
+        }
+        _ => {}
+    }
+
+    let (rest, functor) = match parse_name(s) {
+        Err(Err::Error(_)) => return error(s, "expected a term"),
+        result => result?,
+    };
+    if let Some(after) = rest.strip_prefix('(') {
+        let (rest, args) = cut(parse_args(after))?;
+        let (rest, _) = cut(punct(rest, ")"))?;
+        return Ok((rest, (Term::Combined { functor, args }, 0)));
+    }
+    // a minus sign directly before a number is part of it.
+    if functor.name() == "-" && s.starts_with('-') && rest.starts_with(|c: char| c.is_ascii_digit())
+    {
+        let (rest, c) = parse_number(rest)?;
+        return Ok((rest, (Term::Const(negate(c)), 0)));
+    }
+    if let Some((priority, arg_max)) = prefix_op(functor.name()) {
+        if priority <= max {
+            match parse_term(rest, arg_max) {
+                Ok((rest, arg)) => {
+                    let term = Term::Combined {
+                        functor,
+                        args: vec![arg],
+                    };
+                    return Ok((rest, (term, priority)));
+                }
+                Err(Err::Error(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok((rest, (Term::Const(Constant::Name(functor)), 0)))
+}
+
+fn parse_infix(s: &str) -> PResult<'_, Atom> {
+    if let Ok((rest, _)) = punct(s, ",") {
+        return Ok((rest, Atom::new(",")));
+    }
+    match punct(s, "|") {
+        Ok((rest, _)) => Ok((rest, Atom::new("|"))),
+        Err(_) => parse_name(s),
+    }
+}
+
+// a term of priority at most `max`.
+fn parse_term(s: &str, max: u32) -> PResult<'_, Term> {
+    let (mut s, (mut left, mut priority)) = parse_primary(s, max)?;
+    while let Ok((rest, name)) = parse_infix(s) {
+        let (op_priority, right_max) = match infix_op(name.name()) {
+            Some((p, left_max, right_max)) if p <= max && priority <= left_max => (p, right_max),
+            _ => break,
+        };
+        match parse_term(rest, right_max) {
+            Ok((rest, right)) => {
+                left = Term::Combined {
+                    functor: name,
+                    args: vec![left, right],
+                };
+                priority = op_priority;
+                s = rest;
+            }
+            Err(Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((s, left))
+}
+
+// goals of a conjunction `A, B, ...`.
+fn conjunction(term: Term) -> Vec<Term> {
+    let mut goals = Vec::new();
+    let mut term = term;
+    loop {
+        match term {
+            Term::Combined {
+                functor,
+                ref mut args,
+            } if functor.name() == "," && args.len() == 2 => {
+                let right = args.pop().unwrap();
+                goals.push(args.pop().unwrap());
+                term = right;
+            }
+            _ => {
+                goals.push(term);
+                return goals;
+            }
+        }
+    }
+}
+
+// the atoms of a non-empty list such as `['rules.pl', lists]`.
+fn file_list(term: &Term) -> Option<Vec<String>> {
+    let mut files = Vec::new();
+    let mut term = term;
+    loop {
+        match term {
+            Term::Combined { functor, args } if functor.name() == "." && args.len() == 2 => {
+                match &args[0] {
+                    Term::Const(Constant::Name(name)) => files.push(name.name().to_string()),
+                    _ => return None,
+                }
+                term = &args[1];
+            }
+            Term::Const(Constant::Name(name)) if name.name() == "[]" && !files.is_empty() => {
+                return Some(files)
+            }
+            _ => return None,
+        }
+    }
+}
+
+pub fn parse_query(input: &str) -> Result<Query, SyntaxError> {
+    let query = parse_term(input, 1200).and_then(|(rest, term)| {
+        parse_end(rest)?;
+        Ok(match file_list(&term) {
+            Some(files) => Query::Files(files),
+            None => Query::Terms(conjunction(term)),
+        })
+    });
+    query.map_err(|e| syntax_error(Atom::new("user"), input, e))
+}
+
+// the rule written as the clause `term`: `Head :- Body`, a grammar rule
+// `Head --> Body` or a fact, or the error in the grammar rule.
+pub fn clause_rule(mut term: Term) -> Result<Rule, Exception> {
+    let (lhs, rhs) = match term {
+        Term::Combined { functor, ref args } if functor.name() == "-->" && args.len() == 2 => {
+            let (head, body) = Translator::new(&[&args[0], &args[1]]).rule(&args[0], &args[1])?;
+            (head, dcg::goals(body))
+        }
+        Term::Combined {
+            functor,
+            ref mut args,
+        } if functor.name() == ":-" && args.len() == 2 => {
+            let body = args.pop().unwrap();
+            (args.pop().unwrap(), conjunction(body))
+        }
+        lhs => (lhs, Vec::new()),
+    };
+    Ok(Rule {
+        lhs,
+        rhs,
+        location: None,
+        module: Atom::new("user"),
+    })
+}
+
+fn parse_clause(s: &str) -> PResult<'_, Term> {
+    let (s, term) = parse_term(s, 1200)?;
+    let (s, _) = parse_end(s)?;
+    Ok((s, term))
+}
+
+// the clauses of `source`, read from `file`, as terms with their locations,
+// and the syntax errors found in it. Reading resumes after the end of a
+// clause that has an error.
+pub fn parse_file_terms(file: &str, source: &str) -> (Vec<(Term, Location)>, Vec<SyntaxError>) {
+    let file = Atom::new(file);
+    let mut terms = Vec::new();
+    let mut errors = Vec::new();
+    let mut s = layout(source);
+    while !s.is_empty() {
+        match parse_clause(s) {
+            Ok((rest, term)) => {
+                terms.push((term, locate(file, source, s)));
+                s = rest;
+            }
+            Err(e) => {
+                errors.push(syntax_error(file, source, e));
+                s = skip_clause(s);
+            }
+        }
+        s = layout(s);
+    }
+    (terms, errors)
+}
+
+// the next clause of `source` as a term, for `read_term/2,3`, with the
+// input after it, or `None` at the end of the input. After a syntax error
+// the input resumes after the end of the clause.
+pub fn read_term(source: &str) -> Result<Option<(Term, &str)>, (SyntaxError, &str)> {
+    let s = layout(source);
+    if s.is_empty() {
+        return Ok(None);
+    }
+    match parse_clause(s) {
+        Ok((rest, term)) => Ok(Some((term, rest))),
+        Err(e) => Err((syntax_error(Atom::new("user"), source, e), skip_clause(s))),
+    }
+}
+
+// the clauses of `source`, read from `file`, and the errors found in it.
+pub fn parse_file_content(file: &str, source: &str) -> (Vec<Rule>, Vec<SyntaxError>) {
+    let (terms, mut errors) = parse_file_terms(file, source);
+    let mut rules = Vec::new();
+    for (term, location) in terms {
+        match clause_rule(term) {
+            Ok(mut rule) => {
+                rule.location = Some(location);
+                rules.push(rule);
+            }
+            Err(_) => errors.push(SyntaxError {
+                location,
+                message: "expected a grammar rule",
+            }),
+        }
+    }
+    (rules, errors)
+}
+
+// the input after the next `.` followed by layout.
+fn skip_clause(s: &str) -> &str {
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '.'
+            && chars
+                .peek()
+                .is_none_or(|(_, c)| c.is_whitespace() || *c == '%')
+        {
+            return &s[i + 1..];
+        }
+    }
+    ""
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(s: &str) -> String {
+        match parse_query(s).unwrap() {
+            Query::Terms(goals) => goals
+                .iter()
+                .map(|g| g.to_string())
+                .collect::<Vec<_>>()
+                .join(" & "),
+            Query::Files(files) => files.join(" & "),
+        }
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(read("X is 1 + 2 * 3."), "is(X, +(1, *(2, 3)))");
+        assert_eq!(read("X = 1 - 2 - 3."), "=(X, -(-(1, 2), 3))");
+        assert_eq!(read("X = 2 ^ 3 ^ 4."), "=(X, ^(2, ^(3, 4)))");
+        assert_eq!(
+            read("X = - 1, Y = -1, Z = -(1)."),
+            "=(X, -(1)) & =(Y, -1) & =(Z, -(1))"
+        );
+        assert_eq!(read("X = (a :- b, c)."), "=(X, :-(a, ','(b, c)))");
+        assert_eq!(read("f(a, -)."), "f(a, -)");
+        assert_eq!(read(" ['rules.pl'] ."), "rules.pl");
+        assert_eq!(read("[a, 'b.pl']."), "a & b.pl");
+        assert_eq!(read("[a, X]."), "[a, X]");
+        assert!(parse_query("X = a = b.").is_err());
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            read("X = 123456789012345678901234567890."),
+            "=(X, 123456789012345678901234567890)"
+        );
+        assert_eq!(read("X = 2r6, Y = 4r2."), "=(X, 1r3) & =(Y, 2)");
+        assert!(parse_query("X = 1r0.").is_err());
+        assert_eq!(
+            read("X = 1.5, Y = 1.0e-3, Z = -2.5E2."),
+            "=(X, 1.5) & =(Y, 0.001) & =(Z, -250.0)"
+        );
+        assert_eq!(
+            read("X = 1.0e-10, Y = 1.0Inf."),
+            "=(X, 1.0e-10) & =(Y, 1.0Inf)"
+        );
+        assert_eq!(read("X = 1.0e22."), "=(X, 1.0e22)");
+    }
+
+    #[test]
+    fn test_lexical_syntax() {
+        assert_eq!(
+            read(r"X = 'New York', Y = 'it''s', Z = 'a\nb\x41\\\\101\'."),
+            r"=(X, 'New York') & =(Y, 'it\'s') & =(Z, 'a\nbA\\A')"
+        );
+        assert_eq!(read(r#"X = "say \"hi\""."#), r#"=(X, "say \"hi\"")"#);
+        assert_eq!(read("foo_bar(X1, _, _Y)."), "foo_bar(X1, _, _Y)");
+        assert_eq!(
+            read("X = f(=>, @), Y = [], Z = {}."),
+            "=(X, f(=>, @)) & =(Y, []) & =(Z, {})"
+        );
+        assert_eq!(read("X = f(!, ;, 'hello')."), "=(X, f(!, ;, hello))");
+        assert_eq!(read("X = 日本, Ärger = ä."), "=(X, 日本) & =(Ärger, ä)");
+        assert_eq!(
+            read("X = 0'a, Y = 0''', Z = 0'\\n. % comment\n"),
+            "=(X, 97) & =(Y, 39) & =(Z, 10)"
+        );
+        assert_eq!(
+            read("/* block */ X = 0x1F /* inner */ + 0o17 + 0b101."),
+            "=(X, +(+(31, 15), 5))"
+        );
+        assert!(parse_query("X = 'unterminated.").is_err());
+        assert!(parse_query(r"X = 'bad \q escape'.").is_err());
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(read("X = [a, 'B', 1]."), "=(X, [a, 'B', 1])");
+        assert_eq!(
+            read("X = [H|T], Y = [a, b | c]."),
+            "=(X, [H|T]) & =(Y, [a, b|c])"
+        );
+        assert_eq!(read("X = '.'(a, [])."), "=(X, [a])");
+        assert_eq!(read("X = {a, b}."), "=(X, {','(a, b)})");
+        assert!(parse_query("X = [a|b|c].").is_err());
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let source = "a.\nb :- c(d, e.\nf(.\n  g.";
+        let (rules, errors) = parse_file_content("rules.pl", source);
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "rules.pl:2:12: expected ')'",
+                "rules.pl:3:3: expected a term"
+            ]
+        );
+        let locations: Vec<String> = rules
+            .iter()
+            .map(|r| r.location.unwrap().to_string())
+            .collect();
+        assert_eq!(locations, vec!["rules.pl:1:1", "rules.pl:4:3"]);
+
+        let e = parse_query("X = [a, b.").unwrap_err();
+        assert_eq!((e.message, e.location.col), ("expected ']'", 10));
+        assert_eq!(
+            parse_query("X = \"ab.").unwrap_err().message,
+            "unterminated string"
+        );
+    }
+}
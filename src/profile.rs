@@ -0,0 +1,284 @@
+// The profiler of `profile/1,2`. While the goal runs, each resolution step
+// is counted, along with the calls of each predicate, the alternatives they
+// were resolved into, the clause heads that did not unify and the time spent
+// resolving them.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::app::App;
+use crate::builtins::Branch;
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::frame::{self, Kind};
+use crate::store::Cell;
+use crate::wam::PredKey;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PredStats {
+    // goals of the predicate that were resolved.
+    pub calls: u64,
+    // alternatives they were replaced by: matching clauses or solutions of
+    // a builtin.
+    pub inferences: u64,
+    // clause heads that did not unify with them.
+    pub failures: u64,
+    pub time: Duration,
+}
+
+pub struct Profiler {
+    // iterations of the resolution loop.
+    pub steps: u64,
+    pub preds: HashMap<PredKey, PredStats>,
+    // the predicate of the goal being resolved.
+    current: Option<PredKey>,
+    started: Instant,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            steps: 0,
+            preds: HashMap::new(),
+            current: None,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn call(&mut self, key: PredKey) {
+        self.preds.entry(key).or_default().calls += 1;
+        self.current = Some(key);
+    }
+
+    pub(crate) fn inferences(&mut self, key: PredKey, n: usize) {
+        self.preds.entry(key).or_default().inferences += n as u64;
+    }
+
+    pub(crate) fn unify_failure(&mut self, key: PredKey) {
+        self.preds.entry(key).or_default().failures += 1;
+    }
+
+    // counts a resolution step that took `time`.
+    pub(crate) fn step(&mut self, time: Duration) {
+        self.steps += 1;
+        if let Some(key) = self.current.take() {
+            self.preds.entry(key).or_default().time += time;
+        }
+    }
+
+    // the predicates, those that took the longest first.
+    fn sorted(&self) -> Vec<(String, PredStats)> {
+        let mut preds: Vec<(String, PredStats)> = self
+            .preds
+            .iter()
+            .map(|((name, arity), stats)| (format!("{}/{}", name, arity), *stats))
+            .collect();
+        preds.sort_by(|a, b| (b.1.time, b.1.calls, &a.0).cmp(&(a.1.time, a.1.calls, &b.0)));
+        preds
+    }
+
+    fn failures(&self) -> u64 {
+        self.preds.values().map(|stats| stats.failures).sum()
+    }
+
+    pub fn table(&self, elapsed: Duration) -> String {
+        let mut text = format!(
+            "% {} resolution steps, {} unification failures in {:.3} seconds\n",
+            self.steps,
+            self.failures(),
+            elapsed.as_secs_f64()
+        );
+        let _ = writeln!(
+            text,
+            "{:<32}{:>10}{:>12}{:>10}{:>12}",
+            "Predicate", "Calls", "Inferences", "Failures", "Time (ms)"
+        );
+        for (name, stats) in self.sorted() {
+            let _ = writeln!(
+                text,
+                "{:<32}{:>10}{:>12}{:>10}{:>12.3}",
+                name,
+                stats.calls,
+                stats.inferences,
+                stats.failures,
+                stats.time.as_secs_f64() * 1000.0
+            );
+        }
+        text
+    }
+
+    pub fn json(&self, elapsed: Duration) -> String {
+        let preds: Vec<String> = self
+            .sorted()
+            .iter()
+            .map(|(name, stats)| {
+                format!(
+                    "{{\"predicate\":\"{}\",\"calls\":{},\"inferences\":{},\"unification_failures\":{},\"time\":{}}}",
+                    escape_json(name),
+                    stats.calls,
+                    stats.inferences,
+                    stats.failures,
+                    stats.time.as_secs_f64()
+                )
+            })
+            .collect();
+        format!(
+            "{{\"steps\":{},\"unification_failures\":{},\"time\":{},\"predicates\":[{}]}}\n",
+            self.steps,
+            self.failures(),
+            elapsed.as_secs_f64(),
+            preds.join(",")
+        )
+    }
+
+    // the profile in the format read by callgrind_annotate and KCachegrind,
+    // with times in microseconds.
+    pub fn callgrind(&self, elapsed: Duration) -> String {
+        let mut text = String::from("# callgrind format\nversion: 1\ncreator: rslog\n");
+        text += "events: Calls Inferences Failures Time\n";
+        let _ = writeln!(
+            text,
+            "summary: {} {} {} {}",
+            self.preds.values().map(|stats| stats.calls).sum::<u64>(),
+            self.preds
+                .values()
+                .map(|stats| stats.inferences)
+                .sum::<u64>(),
+            self.failures(),
+            elapsed.as_micros()
+        );
+        for (name, stats) in self.sorted() {
+            let _ = write!(
+                text,
+                "\nfn={}\n0 {} {} {} {}\n",
+                name,
+                stats.calls,
+                stats.inferences,
+                stats.failures,
+                stats.time.as_micros()
+            );
+        }
+        text
+    }
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// profile(Goal) and profile(Goal, Options): runs `Goal` like `call/1` and
+// prints the profile of finding all its answers once none is left. `json(File)` and
+// `callgrind(File)` also write it to a file.
+pub(crate) fn profile(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let mut files = Vec::new();
+    if let Some(options) = args.get(1) {
+        let mut rest = app.store.to_term(options);
+        loop {
+            let option = match rest {
+                Term::Const(Constant::Name(name)) if name.name() == "[]" => break,
+                Term::Combined {
+                    functor,
+                    ref mut args,
+                } if functor.name() == "." && args.len() == 2 => {
+                    let tail = args.pop().unwrap();
+                    let option = args.pop().unwrap();
+                    rest = tail;
+                    option
+                }
+                Term::Var(_) => return Err(Exception::instantiation_error()),
+                other => return Err(Exception::type_error("list", other)),
+            };
+            match &option {
+                Term::Combined { functor, args }
+                    if args.len() == 1 && ["json", "callgrind"].contains(&functor.name()) =>
+                {
+                    let file = match &args[0] {
+                        Term::Const(Constant::Name(name)) => name.name().to_string(),
+                        Term::Const(Constant::Str(text)) => text.clone(),
+                        Term::Var(_) => return Err(Exception::instantiation_error()),
+                        other => return Err(Exception::type_error("atom", other.clone())),
+                    };
+                    files.push((functor.name() == "json", file));
+                }
+                Term::Var(_) => return Err(Exception::instantiation_error()),
+                _ => return Err(Exception::domain_error("profile_option", option)),
+            }
+        }
+    }
+
+    let kind = Kind::Profile {
+        profiler: RefCell::new(Some(Profiler::new())),
+        files,
+    };
+    Ok(frame::call(app, kind, args[0].clone(), Vec::new()))
+}
+
+// prints the profile of `profiler` and writes it to `files`.
+pub(crate) fn report(
+    app: &mut App,
+    profiler: &Profiler,
+    files: &[(bool, String)],
+) -> Result<(), Exception> {
+    let elapsed = profiler.started.elapsed();
+    app.print(&profiler.table(elapsed));
+    for (json, file) in files {
+        let text = match json {
+            true => profiler.json(elapsed),
+            false => profiler.callgrind(elapsed),
+        };
+        fs::write(file, text).map_err(|_| {
+            let file = Term::Const(Constant::Str(file.clone()));
+            Exception::permission_error("open", "source_sink", file)
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{answers, consult};
+    use std::fs;
+
+    #[test]
+    fn test_profile() {
+        let mut app = consult(
+            "parent(kobo, koji).\nparent(koji, iwao).\n\
+             ancestor(X, Y) :- parent(X, Y).\n\
+             ancestor(X, Y) :- parent(X, Z), ancestor(Z, Y).\n",
+        );
+        let file = std::env::temp_dir().join(format!("rslog-profile-{}.json", std::process::id()));
+        let query = format!("profile(ancestor(kobo, X), [json('{}')]).", file.display());
+        assert_eq!(answers(&mut app, &["X"], &query), vec!["koji", "iwao"]);
+
+        let json = fs::read_to_string(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        // 3 calls of ancestor/2, 6 of parent/2 and the 2 answers.
+        assert!(json.starts_with("{\"steps\":11,\"unification_failures\":8,"));
+        assert!(json.contains(
+            "{\"predicate\":\"ancestor/2\",\"calls\":3,\"inferences\":6,\"unification_failures\":0,"
+        ));
+        assert!(json.contains(
+            "{\"predicate\":\"parent/2\",\"calls\":6,\"inferences\":4,\"unification_failures\":8,"
+        ));
+    }
+}
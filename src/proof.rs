@@ -0,0 +1,302 @@
+// Proof trees of answers. While a query is explained, each queue item keeps
+// the resolution steps that led to it, the last first: the goal resolved,
+// the clause used, none for builtins, and the number of goals it was
+// replaced by. Goals are resolved depth-first from left to right, so in the
+// order they were taken the steps list the nodes of the proof tree in
+// preorder.
+//
+// The items whose next goal fails are kept too, as partial proofs ending
+// with that goal, so that a query without answers can be explained by the
+// ones that got the furthest.
+
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use crate::app::App;
+use crate::expr::{Rule, Term};
+use crate::store::Cell;
+use crate::wam::PredKey;
+
+// number of failed derivations kept while explaining a query.
+const MAX_FAILURES: usize = 3;
+
+// resolution steps leading to a queue item, shared with the items it was
+// derived from.
+#[derive(Clone, Default)]
+pub struct Steps(pub(crate) Option<Rc<StepNode>>);
+
+// a resolved goal, the index of the clause that resolved it, the number of
+// goals it was replaced by and the steps before.
+pub(crate) type StepNode = (Cell, Option<usize>, usize, Steps);
+
+impl Steps {
+    pub(crate) fn push(&self, goal: Cell, clause: Option<usize>, subgoals: usize) -> Steps {
+        Steps(Some(Rc::new((goal, clause, subgoals, self.clone()))))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        let mut len = 0;
+        let mut current = self;
+        while let Some(node) = &current.0 {
+            len += 1;
+            current = &node.3;
+        }
+        len
+    }
+}
+
+impl Drop for Steps {
+    // drops long derivations iteratively to avoid overflowing the stack.
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(node) = next {
+            match Rc::try_unwrap(node) {
+                Ok((_, _, _, mut rest)) => next = rest.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// how a goal of an answer was proved.
+#[derive(Clone, Debug)]
+pub struct ProofTree {
+    // the goal with the bindings of the answer.
+    pub goal: Term,
+    // the clause that resolved it, none for builtins.
+    pub rule: Option<Rule>,
+    pub subproofs: Vec<ProofTree>,
+    // why the goal failed, for the last goal of a partial proof.
+    pub failure: Option<Failure>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    // the predicate has no clauses.
+    NoClauses(PredKey),
+    // no clause head unifies with the goal.
+    NoMatch,
+    // a builtin found no solution, e.g. a unification or a comparison.
+    Builtin,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::NoClauses((name, arity)) => write!(f, "no clauses for {}/{}", name, arity),
+            Failure::NoMatch => write!(f, "no clause head matches"),
+            Failure::Builtin => write!(f, "builtin fails"),
+        }
+    }
+}
+
+// a derivation that could not go on, as it was when its goal failed.
+#[derive(Clone, Debug)]
+pub struct PartialProof {
+    // the proofs of the goals of the query, the failed goal last.
+    pub proofs: Vec<ProofTree>,
+    // number of goals resolved before.
+    pub steps: usize,
+}
+
+impl Drop for ProofTree {
+    // drops deep proofs iteratively to avoid overflowing the stack.
+    fn drop(&mut self) {
+        let mut pending = mem::take(&mut self.subproofs);
+        while let Some(mut proof) = pending.pop() {
+            pending.append(&mut proof.subproofs);
+        }
+    }
+}
+
+impl fmt::Display for ProofTree {
+    // the derivation, one goal per line indented below the goal it proves.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut pending = vec![(self, 0)];
+        while let Some((proof, depth)) = pending.pop() {
+            write!(f, "{:width$}{}", "", proof.goal, width = 2 * depth)?;
+            match &proof.rule {
+                _ if proof.failure.is_some() => {
+                    writeln!(f, "  % FAILED: {}", proof.failure.unwrap())?
+                }
+                Some(Rule {
+                    location: Some(location),
+                    ..
+                }) => writeln!(f, "  % {}", location)?,
+                Some(_) => writeln!(f)?,
+                None => writeln!(f, "  % builtin")?,
+            }
+            pending.extend(proof.subproofs.iter().rev().map(|sub| (sub, depth + 1)));
+        }
+        Ok(())
+    }
+}
+
+impl App {
+    // the proof trees of the goals of the query, from the steps of an item
+    // whose goals are all resolved. The first step stands for the query.
+    pub(crate) fn proof(&self, steps: &Steps, answer: &Cell) -> Vec<ProofTree> {
+        self.trees(steps, answer, None)
+    }
+
+    // keeps the derivation of `steps` ending with `goal`, which failed, if
+    // it is among the deepest ones.
+    pub(crate) fn note_failure(
+        &mut self,
+        steps: &Steps,
+        answer: &Cell,
+        goal: &Cell,
+        failure: Failure,
+    ) {
+        let failures = match &self.failures {
+            Some(failures) => failures,
+            None => return,
+        };
+        // the step standing for the query is not counted.
+        let depth = steps.len() - 1;
+        let replaced = if failures.len() < MAX_FAILURES {
+            None
+        } else {
+            match failures.iter().enumerate().min_by_key(|(_, f)| f.steps) {
+                Some((i, shallowest)) if shallowest.steps < depth => Some(i),
+                _ => return,
+            }
+        };
+        let failed = ProofTree {
+            goal: self.store.to_term(goal).subst(&self.var_names(answer)),
+            rule: None,
+            subproofs: Vec::new(),
+            failure: Some(failure),
+        };
+        let partial = PartialProof {
+            proofs: self.trees(steps, answer, Some(failed)),
+            steps: depth,
+        };
+        let failures = self.failures.as_mut().unwrap();
+        match replaced {
+            Some(i) => failures[i] = partial,
+            None => failures.push(partial),
+        }
+    }
+
+    // the trees built from `steps`, followed by `last` if given, with the
+    // variables of the query unbound in `answer` named. Proofs still missing
+    // subproofs are kept as they are.
+    fn trees(&self, steps: &Steps, answer: &Cell, last: Option<ProofTree>) -> Vec<ProofTree> {
+        let names = self.var_names(answer);
+        let mut preorder = Vec::new();
+        let mut current = steps;
+        while let Some(node) = &current.0 {
+            preorder.push(node);
+            current = &node.3;
+        }
+        let mut preorder = preorder.into_iter().rev();
+        let mut left = match preorder.next() {
+            Some(query) => query.2,
+            None => return Vec::new(),
+        };
+        let nodes = preorder.map(|node| {
+            let (goal, clause, subgoals, _) = &**node;
+            let proof = ProofTree {
+                goal: self.store.to_term(goal).subst(&names),
+                rule: clause.and_then(|i| self.rules.get(i).cloned()),
+                subproofs: Vec::new(),
+                failure: None,
+            };
+            (proof, *subgoals)
+        });
+        // proofs whose subproofs are being built, with the number missing.
+        let mut open: Vec<(ProofTree, usize)> = Vec::new();
+        let mut proofs = Vec::new();
+        for (proof, subgoals) in nodes.chain(last.map(|proof| (proof, 0))) {
+            match open.last_mut() {
+                Some((_, missing)) => *missing -= 1,
+                None if left == 0 => break,
+                None => left -= 1,
+            }
+            open.push((proof, subgoals));
+            while let Some((_, 0)) = open.last() {
+                let (proof, _) = open.pop().unwrap();
+                match open.last_mut() {
+                    Some((parent, _)) => parent.subproofs.push(proof),
+                    None => proofs.push(proof),
+                }
+            }
+        }
+        while let Some((proof, _)) = open.pop() {
+            match open.last_mut() {
+                Some((parent, _)) => parent.subproofs.push(proof),
+                None => proofs.push(proof),
+            }
+        }
+        proofs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Failure;
+    use crate::app::App;
+    use crate::parser::parse_file_content;
+    use crate::testing::goals;
+
+    fn family() -> App {
+        let (rules, errors) = parse_file_content(
+            "family.pl",
+            "parent(kobo, koji).\nparent(koji, iwao).\n\
+             ancestor(X, Y) :- parent(X, Y).\n\
+             ancestor(X, Y) :- parent(X, Z), ancestor(Z, Y).\n",
+        );
+        assert!(errors.is_empty());
+        let mut app = App::new();
+        app.rules = rules;
+        app
+    }
+
+    #[test]
+    fn test_explain() {
+        let mut app = family();
+        let proofs = app
+            .explain(&goals("ancestor(kobo, iwao), X is 1 + 1."))
+            .unwrap();
+        assert_eq!(proofs.len(), 1);
+        let text: String = proofs[0].iter().map(|proof| proof.to_string()).collect();
+        assert_eq!(
+            text,
+            "ancestor(kobo, iwao)  % family.pl:4:1\n\
+             \x20 parent(kobo, koji)  % family.pl:1:1\n\
+             \x20 ancestor(koji, iwao)  % family.pl:3:1\n\
+             \x20   parent(koji, iwao)  % family.pl:2:1\n\
+             is(2, +(1, 1))  % builtin\n"
+        );
+    }
+
+    #[test]
+    fn test_why_not() {
+        let mut app = family();
+        let failures = app.why_not(&goals("ancestor(iwao, kobo).")).unwrap();
+        assert_eq!(failures.len(), 2);
+        let text: String = failures[0]
+            .proofs
+            .iter()
+            .map(|proof| proof.to_string())
+            .collect();
+        assert_eq!(
+            text,
+            "ancestor(iwao, kobo)  % family.pl:3:1\n\
+             \x20 parent(iwao, kobo)  % FAILED: no clause head matches\n"
+        );
+        let failures = app.why_not(&goals("ancestor(kobo, X), X = kobo.")).unwrap();
+        assert_eq!(failures.len(), 3);
+        assert_eq!(failures[0].steps, 5);
+        assert!(failures
+            .iter()
+            .any(|f| f.proofs.len() == 2 && f.proofs[1].failure == Some(Failure::Builtin)));
+        assert!(
+            app.why_not(&goals("sister(kobo, X).")).unwrap()[0].proofs[0]
+                .failure
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Exact fractions of arbitrary-precision integers, written `1r3`.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::bigint::BigInt;
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub struct Rational {
+    // in lowest terms with a positive denominator.
+    num: BigInt,
+    den: BigInt,
+}
+
+impl Rational {
+    // None when `den` is zero.
+    pub fn new(num: BigInt, den: BigInt) -> Option<Rational> {
+        if den.is_zero() {
+            return None;
+        }
+        let gcd = num.gcd(&den);
+        let (mut num, mut den) = (num.div_rem(&gcd).unwrap().0, den.div_rem(&gcd).unwrap().0);
+        if den.is_negative() {
+            num = num.neg();
+            den = den.neg();
+        }
+        Some(Rational { num, den })
+    }
+
+    pub fn from_int(i: BigInt) -> Rational {
+        Rational {
+            num: i,
+            den: BigInt::one(),
+        }
+    }
+
+    pub fn numerator(&self) -> &BigInt {
+        &self.num
+    }
+
+    pub fn denominator(&self) -> &BigInt {
+        &self.den
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.den == BigInt::one()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.num.to_f64() / self.den.to_f64()
+    }
+
+    pub fn add(&self, other: &Rational) -> Rational {
+        Rational::new(
+            self.num.mul(&other.den).add(&other.num.mul(&self.den)),
+            self.den.mul(&other.den),
+        )
+        .unwrap()
+    }
+
+    pub fn sub(&self, other: &Rational) -> Rational {
+        self.add(&other.neg())
+    }
+
+    pub fn neg(&self) -> Rational {
+        Rational {
+            num: self.num.neg(),
+            den: self.den.clone(),
+        }
+    }
+
+    pub fn mul(&self, other: &Rational) -> Rational {
+        Rational::new(self.num.mul(&other.num), self.den.mul(&other.den)).unwrap()
+    }
+
+    // None when dividing by zero.
+    pub fn div(&self, other: &Rational) -> Option<Rational> {
+        Rational::new(self.num.mul(&other.den), self.den.mul(&other.num))
+    }
+
+    pub fn pow(&self, exp: u32) -> Rational {
+        Rational {
+            num: self.num.pow(exp),
+            den: self.den.pow(exp),
+        }
+    }
+
+    // the largest integer not greater than self.
+    pub fn floor(&self) -> BigInt {
+        self.num.div_mod_floor(&self.den).unwrap().0
+    }
+
+    pub fn ceiling(&self) -> BigInt {
+        self.neg().floor().neg()
+    }
+
+    pub fn truncate(&self) -> BigInt {
+        self.num.div_rem(&self.den).unwrap().0
+    }
+
+    // nearest integer, rounding halves away from zero.
+    pub fn round(&self) -> BigInt {
+        let half = Rational::new(BigInt::one(), BigInt::from(2)).unwrap();
+        if self.num.is_negative() {
+            self.sub(&half).ceiling()
+        } else {
+            self.add(&half).floor()
+        }
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Rational) -> Ordering {
+        self.num.mul(&other.den).cmp(&other.num.mul(&self.den))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Rational) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}r{}", self.num, self.den)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rat(num: i64, den: i64) -> Rational {
+        Rational::new(BigInt::from(num), BigInt::from(den)).unwrap()
+    }
+
+    #[test]
+    fn test_rational() {
+        assert_eq!(rat(2, -6).to_string(), "-1r3");
+        assert_eq!(rat(1, 3).add(&rat(1, 6)), rat(1, 2));
+        assert_eq!(rat(1, 3).mul(&rat(3, 1)), rat(1, 1));
+        assert_eq!(rat(1, 2).div(&rat(0, 1)), None);
+        assert!(rat(1, 3) < rat(1, 2) && rat(-1, 2) < rat(-1, 3));
+        assert_eq!(rat(-7, 2).floor(), BigInt::from(-4));
+        assert_eq!(rat(-7, 2).ceiling(), BigInt::from(-3));
+        assert_eq!(rat(-7, 2).truncate(), BigInt::from(-3));
+        assert_eq!(rat(-7, 2).round(), BigInt::from(-4));
+        assert_eq!(rat(7, 3).round(), BigInt::from(2));
+    }
+}
@@ -0,0 +1,637 @@
+// Heap representation of runtime terms. Terms are written once into `Store`
+// and shared afterwards; bindings are recorded on a persistent trail so that
+// every `QueueItem` of the breadth-first engine can have its own view of
+// which variables are bound without copying any term.
+
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::expr::{Constant, Rule, Term, Variable};
+use crate::rational::Rational;
+use crate::wam::PredKey;
+
+pub type Addr = usize;
+
+#[derive(Clone, Debug)]
+pub enum Cell {
+    // reference to another cell. An unbound variable refers to itself.
+    Ref(Addr),
+    // structure whose functor cell is at the given address, followed by args.
+    Struct(Addr),
+    Functor(Atom, usize),
+    // unbound variable with attributes, a list of `Module-Value` pairs
+    // held by the cell at the given address.
+    Attr(Addr),
+    Atom(Atom),
+    Int(Rc<BigInt>),
+    Rational(Rc<Rational>),
+    Float(f64),
+    Str(Rc<str>),
+}
+
+// identical cells; floats are compared bit by bit like `Constant`.
+impl PartialEq for Cell {
+    fn eq(&self, other: &Cell) -> bool {
+        match (self, other) {
+            (Cell::Ref(a), Cell::Ref(b))
+            | (Cell::Struct(a), Cell::Struct(b))
+            | (Cell::Attr(a), Cell::Attr(b)) => a == b,
+            (Cell::Functor(f, n), Cell::Functor(g, m)) => f == g && n == m,
+            (Cell::Atom(a), Cell::Atom(b)) => a == b,
+            (Cell::Int(a), Cell::Int(b)) => a == b,
+            (Cell::Rational(a), Cell::Rational(b)) => a == b,
+            (Cell::Float(a), Cell::Float(b)) => a.to_bits() == b.to_bits(),
+            (Cell::Str(a), Cell::Str(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Cell {
+    fn relocate(&self, offset: Addr) -> Cell {
+        match self {
+            Cell::Ref(a) => Cell::Ref(a + offset),
+            Cell::Struct(a) => Cell::Struct(a + offset),
+            Cell::Attr(a) => Cell::Attr(a + offset),
+            _ => self.clone(),
+        }
+    }
+
+    fn from_constant(c: &Constant) -> Cell {
+        match c {
+            Constant::Int(i) => Cell::Int(Rc::new(i.clone())),
+            Constant::Rational(r) => Cell::Rational(Rc::new(r.clone())),
+            Constant::Float(f) => Cell::Float(*f),
+            Constant::Str(s) => Cell::Str(Rc::from(s.as_str())),
+            Constant::Name(name) => Cell::Atom(*name),
+        }
+    }
+}
+
+pub(crate) struct TrailEntry {
+    pub(crate) addr: Addr,
+    pub(crate) value: Cell,
+    // what the cell held before: itself when unbound, or its attributes.
+    pub(crate) old: Cell,
+    len: usize,
+    pub(crate) prev: Trail,
+}
+
+// bindings made since the start of a query, newest first. Trails of
+// different queue items share their common prefix.
+#[derive(Clone, Default)]
+pub struct Trail(pub(crate) Option<Rc<TrailEntry>>);
+
+impl Trail {
+    pub fn len(&self) -> usize {
+        self.0.as_ref().map_or(0, |e| e.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    fn same(&self, other: &Trail) -> bool {
+        match (&self.0, &other.0) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    fn prev(&self) -> Trail {
+        self.0.as_ref().map(|e| e.prev.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn push(&self, addr: Addr, value: Cell, old: Cell) -> Trail {
+        Trail(Some(Rc::new(TrailEntry {
+            addr,
+            value,
+            old,
+            len: self.len() + 1,
+            prev: self.clone(),
+        })))
+    }
+}
+
+impl Drop for Trail {
+    // drops long trails iteratively to avoid overflowing the stack.
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(entry) = next {
+            match Rc::try_unwrap(entry) {
+                Ok(mut entry) => next = entry.prev.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// a rule written into cells once, copied into the store on each use.
+pub struct Clause {
+    cells: Vec<Cell>,
+    pub head: Cell,
+    pub body: Vec<Cell>,
+    // no variable occurs twice in the head.
+    linear: bool,
+}
+
+impl Clause {
+    pub fn new(rule: &Rule) -> Clause {
+        let mut cells = Vec::new();
+        let mut vars = HashMap::new();
+        let head = build(&mut cells, &rule.lhs, &mut vars);
+        let body = rule
+            .rhs
+            .iter()
+            .map(|goal| build(&mut cells, goal, &mut vars))
+            .collect();
+        let mut vars = rule.lhs.free_vars();
+        vars.retain(|v| !v.is_anonymous());
+        let count = vars.len();
+        vars.sort();
+        vars.dedup();
+        Clause {
+            cells,
+            head,
+            body,
+            linear: vars.len() == count,
+        }
+    }
+}
+
+// writes `term` at the end of `cells` and returns the cell referring to it.
+fn build(cells: &mut Vec<Cell>, term: &Term, vars: &mut HashMap<Variable, Addr>) -> Cell {
+    let root = match term {
+        Term::Const(c) => return Cell::from_constant(c),
+        Term::Var(v) => match vars.get(v) {
+            Some(addr) => return Cell::Ref(*addr),
+            None => {
+                let addr = cells.len();
+                cells.push(Cell::Ref(addr));
+                if !v.is_anonymous() {
+                    vars.insert(*v, addr);
+                }
+                return Cell::Ref(addr);
+            }
+        },
+        Term::Combined { .. } => Cell::Struct(cells.len()),
+    };
+    // the last argument of a structure is written next by this loop rather
+    // than by recursion, as lists can be long.
+    let mut term = term;
+    while let Term::Combined { functor, args } = term {
+        let addr = cells.len();
+        cells.push(Cell::Functor(*functor, args.len()));
+        cells.extend(args.iter().map(|_| Cell::Ref(0)));
+        let (last, args) = match args.split_last() {
+            Some(split) => split,
+            None => break,
+        };
+        for (i, arg) in args.iter().enumerate() {
+            cells[addr + 1 + i] = build_arg(cells, addr + 1 + i, arg, vars);
+        }
+        let slot = addr + 1 + args.len();
+        cells[slot] = match last {
+            Term::Combined { .. } => Cell::Struct(cells.len()),
+            _ => build_arg(cells, slot, last, vars),
+        };
+        term = last;
+    }
+    root
+}
+
+// writes `arg`, an argument of a structure in `slot`, and returns the cell
+// referring to it.
+fn build_arg(
+    cells: &mut Vec<Cell>,
+    slot: Addr,
+    arg: &Term,
+    vars: &mut HashMap<Variable, Addr>,
+) -> Cell {
+    match arg {
+        // a variable occurring first here lives in the argument slot.
+        Term::Var(v) if !vars.contains_key(v) => {
+            if !v.is_anonymous() {
+                vars.insert(*v, slot);
+            }
+            Cell::Ref(slot)
+        }
+        _ => build(cells, arg, vars),
+    }
+}
+
+#[derive(Default)]
+pub struct Store {
+    pub(crate) cells: Vec<Cell>,
+    pub(crate) trail: Trail,
+    // trail entries created since the last garbage collection, plus the
+    // ones that survived it.
+    pub(crate) bindings: usize,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    // drops cells allocated after `len`. Only valid when nothing older
+    // refers to them.
+    pub fn truncate(&mut self, len: usize) {
+        self.cells.truncate(len);
+    }
+
+    pub fn trail(&self) -> Trail {
+        self.trail.clone()
+    }
+
+    pub fn deref(&self, cell: &Cell) -> Cell {
+        let mut cell = cell.clone();
+        while let Cell::Ref(a) = cell {
+            match &self.cells[a] {
+                Cell::Ref(b) if *b == a => return cell,
+                Cell::Attr(_) => return cell,
+                next => cell = next.clone(),
+            }
+        }
+        cell
+    }
+
+    pub fn key(&self, cell: &Cell) -> Option<PredKey> {
+        match self.deref(cell) {
+            Cell::Atom(name) => Some((name, 0)),
+            Cell::Struct(a) => match self.cells[a] {
+                Cell::Functor(f, n) => Some((f, n)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn bind(&mut self, addr: Addr, cell: Cell) {
+        let old = mem::replace(&mut self.cells[addr], cell.clone());
+        self.trail = self.trail.push(addr, cell, old);
+        self.bindings += 1;
+    }
+
+    // the attributes of the variable `cell` refers to, if it has any.
+    pub fn attrs(&self, cell: &Cell) -> Option<Cell> {
+        match self.deref(cell) {
+            Cell::Ref(a) => match self.cells[a] {
+                Cell::Attr(b) => Some(Cell::Ref(b)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // replaces the attributes of the unbound variable `var` by `attrs`, or
+    // makes it a plain variable again.
+    pub fn set_attrs(&mut self, var: &Cell, attrs: Option<Cell>) {
+        if let Cell::Ref(a) = self.deref(var) {
+            match attrs {
+                Some(attrs) => {
+                    let b = self.cells.len();
+                    self.cells.push(attrs);
+                    self.bind(a, Cell::Attr(b));
+                }
+                None => self.bind(a, Cell::Ref(a)),
+            }
+        }
+    }
+
+    // a new unbound variable.
+    pub fn var(&mut self) -> Cell {
+        let addr = self.cells.len();
+        self.cells.push(Cell::Ref(addr));
+        Cell::Ref(addr)
+    }
+
+    // the variables bound by unifying `a` and `b`, both sides of bindings
+    // between variables, or none if they do not unify. The bindings are
+    // undone.
+    pub fn unifier(&mut self, a: &Cell, b: &Cell) -> Option<Vec<Cell>> {
+        let start = self.trail();
+        let unified = self.unify(a, b);
+        let mut vars = Vec::new();
+        let mut trail = &self.trail;
+        while trail.len() > start.len() {
+            let entry = trail.0.as_ref().unwrap();
+            vars.push(Cell::Ref(entry.addr));
+            if let Cell::Ref(other) = entry.value {
+                vars.push(Cell::Ref(other));
+            }
+            trail = &entry.prev;
+        }
+        self.switch_to(&start);
+        Some(vars).filter(|_| unified)
+    }
+
+    // approximate number of bytes used by cells and bindings.
+    pub fn memory_used(&self) -> usize {
+        self.cells.len() * mem::size_of::<Cell>() + self.bindings * mem::size_of::<TrailEntry>()
+    }
+
+    // arguments of a structure, or none for other terms.
+    pub fn args(&self, cell: &Cell) -> Vec<Cell> {
+        match self.deref(cell) {
+            Cell::Struct(a) => match self.cells[a] {
+                Cell::Functor(_, n) => (1..=n).map(|i| Cell::Ref(a + i)).collect(),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    // makes the bindings in `target` the current ones, undoing bindings not
+    // in it and redoing the missing ones.
+    pub fn switch_to(&mut self, target: &Trail) {
+        let mut undo = self.trail.clone();
+        let mut redo = target.clone();
+        let mut redo_entries = Vec::new();
+        while undo.len() > redo.len() {
+            self.unbind(&undo);
+            undo = undo.prev();
+        }
+        while redo.len() > undo.len() {
+            redo_entries.push(redo.clone());
+            redo = redo.prev();
+        }
+        while !undo.same(&redo) {
+            self.unbind(&undo);
+            undo = undo.prev();
+            redo_entries.push(redo.clone());
+            redo = redo.prev();
+        }
+        for entry in redo_entries.iter().rev() {
+            let entry = entry.0.as_ref().unwrap();
+            self.cells[entry.addr] = entry.value.clone();
+        }
+        self.trail = target.clone();
+    }
+
+    fn unbind(&mut self, trail: &Trail) {
+        if let Some(entry) = &trail.0 {
+            if entry.addr < self.cells.len() {
+                self.cells[entry.addr] = entry.old.clone();
+            }
+        }
+    }
+
+    pub fn unify(&mut self, c1: &Cell, c2: &Cell) -> bool {
+        self.unify_with(c1, c2, true)
+    }
+
+    // unifies `goal` with `head`, the head of a copy of `clause`. The goal
+    // shares no variable with the copy, so a linear head cannot make a
+    // cyclic term and needs no occurs check, which would walk every list
+    // passed to it.
+    pub fn unify_head(&mut self, goal: &Cell, head: &Cell, clause: &Clause) -> bool {
+        self.unify_with(goal, head, !clause.linear)
+    }
+
+    fn unify_with(&mut self, c1: &Cell, c2: &Cell, occurs_check: bool) -> bool {
+        let mut stack = vec![(c1.clone(), c2.clone())];
+        while let Some((c1, c2)) = stack.pop() {
+            let (d1, d2) = (self.deref(&c1), self.deref(&c2));
+            if d1 == d2 {
+                continue;
+            }
+            match (d1, d2) {
+                (Cell::Ref(a), Cell::Ref(b)) => {
+                    // a variable with attributes is only bound to another
+                    // one, whose attribute hooks then run.
+                    let attributed = |c| matches!(self.cells[c], Cell::Attr(_));
+                    let bind_b = match (attributed(a), attributed(b)) {
+                        (true, false) => true,
+                        (false, true) => false,
+                        _ => a < b,
+                    };
+                    if bind_b {
+                        self.bind(b, Cell::Ref(a));
+                    } else {
+                        self.bind(a, Cell::Ref(b));
+                    }
+                }
+                (Cell::Ref(a), other) | (other, Cell::Ref(a)) => {
+                    if occurs_check && self.occurs(a, &other) {
+                        return false;
+                    }
+                    self.bind(a, other)
+                }
+                (Cell::Struct(a), Cell::Struct(b)) => {
+                    if self.cells[a] != self.cells[b] {
+                        return false;
+                    }
+                    if let Cell::Functor(_, n) = self.cells[a] {
+                        for i in 1..=n {
+                            stack.push((Cell::Ref(a + i), Cell::Ref(b + i)));
+                        }
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // whether the variable at `addr` occurs in `cell`, which it cannot be
+    // bound to without making a cyclic term.
+    fn occurs(&self, addr: Addr, cell: &Cell) -> bool {
+        let mut stack = vec![cell.clone()];
+        while let Some(cell) = stack.pop() {
+            match self.deref(&cell) {
+                Cell::Ref(a) if a == addr => return true,
+                Cell::Struct(a) => {
+                    if let Cell::Functor(_, n) = self.cells[a] {
+                        stack.extend((1..=n).map(|i| Cell::Ref(a + i)));
+                    }
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    // copies a clause into the store with fresh variables.
+    pub fn instantiate(&mut self, clause: &Clause) -> (Cell, Vec<Cell>) {
+        let offset = self.cells.len();
+        self.cells
+            .extend(clause.cells.iter().map(|c| c.relocate(offset)));
+        let head = clause.head.relocate(offset);
+        let body = clause.body.iter().map(|c| c.relocate(offset)).collect();
+        (head, body)
+    }
+
+    // writes the structure `functor(args...)` built from existing cells.
+    pub fn compound(&mut self, functor: Atom, args: Vec<Cell>) -> Cell {
+        let addr = self.cells.len();
+        self.cells.push(Cell::Functor(functor, args.len()));
+        self.cells.extend(args);
+        Cell::Struct(addr)
+    }
+
+    pub fn from_term(&mut self, term: &Term, vars: &mut HashMap<Variable, Addr>) -> Cell {
+        build(&mut self.cells, term, vars)
+    }
+
+    // the term `cell` refers to. The last arguments of structures, such as
+    // the tails of lists, are followed in a loop so that long lists do not
+    // overflow the stack.
+    pub fn to_term(&self, cell: &Cell) -> Term {
+        let mut outer = Vec::new();
+        let mut cell = cell.clone();
+        let mut term = loop {
+            match self.deref(&cell) {
+                Cell::Ref(a) => break Term::Var(Variable::Fresh(a)),
+                Cell::Struct(a) => match self.cells[a] {
+                    Cell::Functor(f, 0) => {
+                        break Term::Combined {
+                            functor: f,
+                            args: Vec::new(),
+                        }
+                    }
+                    Cell::Functor(f, n) => {
+                        let args: Vec<Term> =
+                            (1..n).map(|i| self.to_term(&Cell::Ref(a + i))).collect();
+                        outer.push((f, args));
+                        cell = Cell::Ref(a + n);
+                    }
+                    _ => panic!("Structure without functor cell"),
+                },
+                Cell::Atom(name) => break Term::Const(Constant::Name(name)),
+                Cell::Int(i) => break Term::Const(Constant::Int((*i).clone())),
+                Cell::Rational(r) => break Term::Const(Constant::Rational((*r).clone())),
+                Cell::Float(f) => break Term::Const(Constant::Float(f)),
+                Cell::Str(s) => break Term::Const(Constant::Str(s.to_string())),
+                Cell::Functor(..) | Cell::Attr(_) => panic!("Dangling functor cell"),
+            }
+        };
+        while let Some((functor, mut args)) = outer.pop() {
+            args.push(term);
+            term = Term::Combined { functor, args };
+        }
+        term
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str) -> Term {
+        Term::Var(Variable::Named(Atom::new(name)))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut store = Store::new();
+        let term = Term::compound(
+            "f",
+            vec![
+                Term::atom("a"),
+                Term::compound("g", vec![Term::Const(Constant::Int(BigInt::from(1)))]),
+            ],
+        );
+        let cell = store.from_term(&term, &mut HashMap::new());
+        assert_eq!(store.to_term(&cell), term);
+    }
+
+    #[test]
+    fn test_switch_trail() {
+        let mut store = Store::new();
+        let mut vars = HashMap::new();
+        let t = store.from_term(&Term::compound("f", vec![var("X"), var("Y")]), &mut vars);
+        let root = store.trail();
+
+        let a = store.from_term(
+            &Term::compound("f", vec![Term::atom("a"), var("Y")]),
+            &mut vars,
+        );
+        assert!(store.unify(&t, &a));
+        let with_a = store.trail();
+        store.switch_to(&root);
+
+        let b = store.from_term(
+            &Term::compound("f", vec![Term::atom("b"), Term::atom("c")]),
+            &mut HashMap::new(),
+        );
+        assert!(store.unify(&t, &b));
+        let with_b = store.trail();
+        assert_eq!(
+            store.to_term(&t),
+            Term::compound("f", vec![Term::atom("b"), Term::atom("c")])
+        );
+
+        store.switch_to(&with_a);
+        let x = Cell::Ref(vars[&Variable::Named(Atom::new("X"))]);
+        assert_eq!(store.to_term(&x), Term::atom("a"));
+        store.switch_to(&with_b);
+        assert_eq!(store.to_term(&x), Term::atom("b"));
+        store.switch_to(&root);
+        assert!(matches!(store.deref(&x), Cell::Ref(_)));
+    }
+
+    #[test]
+    fn test_anonymous_variables() {
+        let mut store = Store::new();
+        let t = store.from_term(
+            &Term::compound("f", vec![var("_"), var("_"), var("X"), var("X")]),
+            &mut HashMap::new(),
+        );
+        let a = store.from_term(
+            &Term::compound(
+                "f",
+                vec![
+                    Term::atom("a"),
+                    Term::atom("b"),
+                    Term::atom("c"),
+                    Term::atom("c"),
+                ],
+            ),
+            &mut HashMap::new(),
+        );
+        assert!(store.unify(&t, &a));
+    }
+
+    #[test]
+    fn test_occurs_check() {
+        let mut store = Store::new();
+        let mut vars = HashMap::new();
+        let x = store.from_term(&var("X"), &mut vars);
+        let fx = store.from_term(&Term::compound("f", vec![var("X")]), &mut vars);
+        assert!(!store.unify(&x, &fx));
+
+        // p(Y, Y) against the head p(Z, f(Z)).
+        let goal = store.from_term(&Term::compound("p", vec![var("Y"), var("Y")]), &mut vars);
+        let rule = Rule {
+            lhs: Term::compound("p", vec![var("Z"), Term::compound("f", vec![var("Z")])]),
+            rhs: vec![],
+            location: None,
+            module: crate::atom::USER,
+        };
+        let clause = Clause::new(&rule);
+        let (head, _) = store.instantiate(&clause);
+        assert!(!store.unify_head(&goal, &head, &clause));
+    }
+
+    #[test]
+    fn test_long_list() {
+        let mut store = Store::new();
+        let items = (0..10_000).map(|i| Term::Const(Constant::Int(BigInt::from(i))));
+        let list = Term::list(items.collect(), Term::nil());
+        let cell = store.from_term(&list, &mut HashMap::new());
+        assert_eq!(store.to_term(&cell).to_string(), list.to_string());
+    }
+}
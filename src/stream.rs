@@ -0,0 +1,660 @@
+// Streams for reading and writing text. The table of open streams starts
+// with `user_input`, `user_output` and `user_error`, and `open/3,4` adds one
+// per file, referred to as `'$stream'(N)` or by its alias. Files opened for
+// reading are read whole when they are opened; `user_input` reads standard
+// input a line at a time. `user_output` writes to `App::output`.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{stderr, stdin, BufWriter, ErrorKind, Write};
+use std::mem;
+
+use crate::app::App;
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::builtins::{fail, succeed, Branch};
+use crate::coroutine;
+use crate::error::Exception;
+use crate::expr::{Constant, Term, Variable};
+use crate::frame::{self, Kind};
+use crate::parser;
+use crate::store::Cell;
+use crate::write::text_of;
+
+const USER_INPUT: usize = 0;
+const USER_OUTPUT: usize = 1;
+const USER_ERROR: usize = 2;
+
+// where an output stream writes.
+enum Sink {
+    // `App::output`.
+    User,
+    Error,
+    File(BufWriter<File>),
+    // text kept for `with_output_to/2`.
+    Memory(String),
+}
+
+struct Input {
+    text: String,
+    // byte offset of the next character to read.
+    pos: usize,
+    // whether more of `text` is read from standard input as needed.
+    stdin: bool,
+}
+
+impl Input {
+    // reads another line of standard input, returning whether there was one.
+    fn fill(&mut self) -> bool {
+        if !self.stdin {
+            return false;
+        }
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(n) if n > 0 => {
+                self.text.replace_range(..self.pos, "");
+                self.pos = 0;
+                self.text += &line;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+struct Stream {
+    alias: Option<Atom>,
+    input: Option<Input>,
+    output: Option<Sink>,
+    // the column the output is at, for the column stops of `format/2`.
+    column: usize,
+}
+
+pub struct Streams {
+    streams: HashMap<usize, Stream>,
+    aliases: HashMap<Atom, usize>,
+    pub(crate) input: usize,
+    pub(crate) output: usize,
+    next: usize,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Streams {
+        let mut streams = Streams {
+            streams: HashMap::new(),
+            aliases: HashMap::new(),
+            input: USER_INPUT,
+            output: USER_OUTPUT,
+            next: 0,
+        };
+        let input = Input {
+            text: String::new(),
+            pos: 0,
+            stdin: true,
+        };
+        streams.add(Some(Atom::new("user_input")), Some(input), None);
+        streams.add(Some(Atom::new("user_output")), None, Some(Sink::User));
+        streams.add(Some(Atom::new("user_error")), None, Some(Sink::Error));
+        streams
+    }
+
+    fn add(&mut self, alias: Option<Atom>, input: Option<Input>, output: Option<Sink>) -> usize {
+        let id = self.next;
+        self.next += 1;
+        if let Some(alias) = alias {
+            self.aliases.insert(alias, id);
+        }
+        let stream = Stream {
+            alias,
+            input,
+            output,
+            column: 0,
+        };
+        self.streams.insert(id, stream);
+        id
+    }
+
+    // the input of stream `id`, which is an input stream.
+    fn input(&mut self, id: usize) -> &mut Input {
+        self.streams
+            .get_mut(&id)
+            .and_then(|stream| stream.input.as_mut())
+            .unwrap()
+    }
+
+    // makes a new stream kept in memory the current output, returning it
+    // and the previous one.
+    pub(crate) fn capture(&mut self) -> (usize, usize) {
+        let id = self.add(None, None, Some(Sink::Memory(String::new())));
+        (id, mem::replace(&mut self.output, id))
+    }
+
+    // makes `outer` the current output again, instead of the stream `id`
+    // made by `capture`, and returns what was written to the latter.
+    pub(crate) fn release(&mut self, id: usize, outer: usize) -> String {
+        self.output = outer;
+        self.close(id).unwrap_or_default()
+    }
+
+    // closes the stream, unless it is one of the standard ones, returning
+    // the text written to it if it was kept.
+    fn close(&mut self, id: usize) -> Option<String> {
+        if id <= USER_ERROR {
+            return None;
+        }
+        let stream = self.streams.remove(&id)?;
+        if let Some(alias) = stream.alias {
+            self.aliases.remove(&alias);
+        }
+        if self.input == id {
+            self.input = USER_INPUT;
+        }
+        if self.output == id {
+            self.output = USER_OUTPUT;
+        }
+        match stream.output {
+            Some(Sink::Memory(text)) => Some(text),
+            Some(Sink::File(mut file)) => {
+                let _ = file.flush();
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+fn stream_term(id: usize) -> Term {
+    Term::Combined {
+        functor: Atom::new("$stream"),
+        args: vec![Term::Const(Constant::Int(BigInt::from(id as i64)))],
+    }
+}
+
+impl App {
+    // the stream `cell` refers to, by alias or as `'$stream'(N)`.
+    fn stream(&self, cell: &Cell) -> Result<usize, Exception> {
+        let term = self.store.to_term(cell);
+        let id = match &term {
+            Term::Var(_) => return Err(Exception::instantiation_error()),
+            Term::Const(Constant::Name(alias)) => self.streams.aliases.get(alias).copied(),
+            Term::Combined { functor, args } if functor.name() == "$stream" && args.len() == 1 => {
+                match &args[0] {
+                    Term::Const(Constant::Int(n)) => n.to_i64().map(|n| n as usize),
+                    _ => None,
+                }
+            }
+            _ => return Err(Exception::domain_error("stream_or_alias", term)),
+        };
+        match id {
+            Some(id) if self.streams.streams.contains_key(&id) => Ok(id),
+            _ => Err(Exception::existence_error("stream", term)),
+        }
+    }
+
+    pub(crate) fn output_stream(&self, cell: &Cell) -> Result<usize, Exception> {
+        let id = self.stream(cell)?;
+        match self.streams.streams[&id].output {
+            Some(_) => Ok(id),
+            None => Err(Exception::permission_error(
+                "output",
+                "stream",
+                self.store.to_term(cell),
+            )),
+        }
+    }
+
+    fn input_stream(&self, cell: &Cell) -> Result<usize, Exception> {
+        let id = self.stream(cell)?;
+        match self.streams.streams[&id].input {
+            Some(_) => Ok(id),
+            None => Err(Exception::permission_error(
+                "input",
+                "stream",
+                self.store.to_term(cell),
+            )),
+        }
+    }
+
+    pub(crate) fn column(&self, id: usize) -> usize {
+        self.streams
+            .streams
+            .get(&id)
+            .map_or(0, |stream| stream.column)
+    }
+
+    // writes `text` to `user_output`, as the top level does with its prompt,
+    // answers and errors, so that the column of `format/2` stays right.
+    pub(crate) fn print(&mut self, text: &str) {
+        let _ = self.write_to(USER_OUTPUT, text);
+    }
+
+    // ends the line of `user_output` unless it is empty, so that the answers
+    // and messages printed next start their own.
+    pub(crate) fn fresh_line(&mut self) {
+        if self.column(USER_OUTPUT) > 0 {
+            self.print("\n");
+        }
+    }
+
+    // the user ended the line of a query typed at the prompt, so
+    // `user_output` is on a new line.
+    pub(crate) fn line_typed(&mut self) {
+        if let Some(stream) = self.streams.streams.get_mut(&USER_OUTPUT) {
+            stream.column = 0;
+        }
+    }
+
+    pub(crate) fn write_to(&mut self, id: usize, text: &str) -> Result<(), Exception> {
+        let stream = match self.streams.streams.get_mut(&id) {
+            Some(stream) => stream,
+            None => return Err(Exception::existence_error("stream", stream_term(id))),
+        };
+        stream.column = match text.rfind('\n') {
+            Some(i) => text[i + 1..].chars().count(),
+            None => stream.column + text.chars().count(),
+        };
+        let written = match &mut stream.output {
+            Some(Sink::User) => self
+                .output
+                .write_all(text.as_bytes())
+                .and_then(|_| self.output.flush()),
+            Some(Sink::Error) => stderr().write_all(text.as_bytes()),
+            Some(Sink::File(file)) => file.write_all(text.as_bytes()),
+            Some(Sink::Memory(kept)) => {
+                kept.push_str(text);
+                Ok(())
+            }
+            None => {
+                return Err(Exception::permission_error(
+                    "output",
+                    "stream",
+                    stream_term(id),
+                ))
+            }
+        };
+        written.map_err(|_| Exception::permission_error("output", "stream", stream_term(id)))
+    }
+}
+
+// `'$stream'(N)` for stream `id`.
+fn stream_cell(app: &mut App, id: usize) -> Cell {
+    let n = Cell::Int(BigInt::from(id as i64).into());
+    app.store.compound(Atom::new("$stream"), vec![n])
+}
+
+fn unify(app: &mut App, a: &Cell, b: &Cell) -> Vec<Branch> {
+    match app.store.unify(a, b) {
+        true => succeed(app),
+        false => fail(),
+    }
+}
+
+// the atom `cell` is bound to.
+fn atom_arg(app: &App, cell: &Cell) -> Result<Atom, Exception> {
+    match app.store.deref(cell) {
+        Cell::Atom(name) => Ok(name),
+        Cell::Ref(_) | Cell::Attr(_) => Err(Exception::instantiation_error()),
+        other => Err(Exception::type_error("atom", app.store.to_term(&other))),
+    }
+}
+
+// the options in the list `cell`, each with its name and argument.
+fn options(app: &App, cell: &Cell, domain: &str) -> Result<Vec<(Atom, Cell)>, Exception> {
+    let mut options = Vec::new();
+    for option in coroutine::items(&app.store, cell) {
+        let option = app.store.deref(&option);
+        match app.store.key(&option) {
+            Some((name, 1)) => options.push((name, app.store.args(&option)[0].clone())),
+            None => return Err(Exception::instantiation_error()),
+            _ => return Err(Exception::domain_error(domain, app.store.to_term(&option))),
+        }
+    }
+    Ok(options)
+}
+
+fn option_term(app: &App, name: Atom, arg: &Cell) -> Term {
+    Term::Combined {
+        functor: name,
+        args: vec![app.store.to_term(arg)],
+    }
+}
+
+// open(File, Mode, Stream) and open(File, Mode, Stream, Options): opens
+// `File` for reading, writing or appending. The option `alias(A)` names the
+// stream; the others of ISO are accepted and have no effect.
+pub(crate) fn open(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let source = match app.store.to_term(&args[0]) {
+        Term::Var(_) => return Err(Exception::instantiation_error()),
+        term => match text_of(&term) {
+            Some(path) => (path, term),
+            None => return Err(Exception::domain_error("source_sink", term)),
+        },
+    };
+    let mode = atom_arg(app, &args[1])?;
+    match app.store.deref(&args[2]) {
+        Cell::Ref(_) => {}
+        stream => return Err(Exception::uninstantiation_error(app.store.to_term(&stream))),
+    }
+    let mut alias = None;
+    if let Some(list) = args.get(3) {
+        for (name, arg) in options(app, list, "stream_option")? {
+            match name.name() {
+                "alias" => {
+                    let name = atom_arg(app, &arg)?;
+                    if app.streams.aliases.contains_key(&name) {
+                        let option = app.store.to_term(&arg);
+                        return Err(Exception::permission_error("open", "source_sink", option));
+                    }
+                    alias = Some(name);
+                }
+                "type" | "encoding" | "eof_action" | "reposition" | "bom" | "create" | "lock" => {}
+                _ => {
+                    let option = option_term(app, name, &arg);
+                    return Err(Exception::domain_error("stream_option", option));
+                }
+            }
+        }
+    }
+
+    let (path, term) = source;
+    let opened = match mode.name() {
+        "read" => fs::read_to_string(&path).map(|text| {
+            let input = Input {
+                text,
+                pos: 0,
+                stdin: false,
+            };
+            (Some(input), None)
+        }),
+        "write" | "append" => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(mode.name() == "append")
+            .truncate(mode.name() == "write")
+            .open(&path)
+            .map(|file: File| (None, Some(Sink::File(BufWriter::new(file))))),
+        _ => {
+            let mode = Term::Const(Constant::Name(mode));
+            return Err(Exception::domain_error("io_mode", mode));
+        }
+    };
+    let (input, output) = match opened {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Exception::existence_error("source_sink", term))
+        }
+        Err(_) => return Err(Exception::permission_error("open", "source_sink", term)),
+    };
+    let id = app.streams.add(alias, input, output);
+    let stream = stream_cell(app, id);
+    Ok(unify(app, &args[2], &stream))
+}
+
+// close(Stream) and close(Stream, Options). Closing a standard stream does
+// nothing.
+pub(crate) fn close(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let id = app.stream(&args[0])?;
+    app.streams.close(id);
+    Ok(succeed(app))
+}
+
+// current_input(Stream) and current_output(Stream)
+pub(crate) fn current(
+    app: &mut App,
+    args: &[Cell],
+    output: bool,
+) -> Result<Vec<Branch>, Exception> {
+    let id = match output {
+        true => app.streams.output,
+        false => app.streams.input,
+    };
+    let stream = stream_cell(app, id);
+    Ok(unify(app, &args[0], &stream))
+}
+
+// set_input(Stream) and set_output(Stream)
+pub(crate) fn set(app: &mut App, args: &[Cell], output: bool) -> Result<Vec<Branch>, Exception> {
+    match output {
+        true => app.streams.output = app.output_stream(&args[0])?,
+        false => app.streams.input = app.input_stream(&args[0])?,
+    }
+    Ok(succeed(app))
+}
+
+// the stream read from: the first of `args` if there are `arity + 1` of
+// them, else the current input.
+fn input_of(app: &App, args: &[Cell], arity: usize) -> Result<usize, Exception> {
+    match args.len() > arity {
+        true => app.input_stream(&args[0]),
+        false => Ok(app.streams.input),
+    }
+}
+
+// put_char(Char) and put_char(Stream, Char)
+pub(crate) fn put_char(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let id = match args.len() {
+        2 => app.output_stream(&args[0])?,
+        _ => app.streams.output,
+    };
+    let char = atom_arg(app, &args[args.len() - 1])?;
+    if char.name().chars().count() != 1 {
+        let culprit = Term::Const(Constant::Name(char));
+        return Err(Exception::type_error("character", culprit));
+    }
+    app.write_to(id, char.name())?;
+    Ok(succeed(app))
+}
+
+// get_char(Char) and get_char(Stream, Char): the next character of the
+// input, or `end_of_file` after the last.
+pub(crate) fn get_char(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let id = input_of(app, args, 1)?;
+    let input = app.streams.input(id);
+    if input.pos == input.text.len() {
+        input.fill();
+    }
+    let char = match input.text[input.pos..].chars().next() {
+        Some(c) => {
+            input.pos += c.len_utf8();
+            c.to_string()
+        }
+        None => "end_of_file".to_string(),
+    };
+    let char = Cell::Atom(Atom::new(&char));
+    Ok(unify(app, &args[args.len() - 1], &char))
+}
+
+// read_term(Term, Options) and read_term(Stream, Term, Options): the next
+// clause of the input, or `end_of_file` after the last. The options
+// `variable_names(Vs)` and `variables(Vs)` give the variables of the term,
+// as `Name = Var` pairs for those with names.
+pub(crate) fn read_term(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let id = input_of(app, args, 2)?;
+    let options = options(app, &args[args.len() - 1], "read_option")?;
+    if let Some((name, arg)) = options
+        .iter()
+        .find(|(name, _)| !["variable_names", "variables"].contains(&name.name()))
+    {
+        let option = option_term(app, *name, arg);
+        return Err(Exception::domain_error("read_option", option));
+    }
+
+    let input = app.streams.input(id);
+    // standard input is read up to the end of a clause.
+    while !input.text[input.pos..].trim_end().ends_with('.') && input.fill() {}
+    let source = &input.text[input.pos..];
+    let read = match parser::read_term(source) {
+        Ok(Some((term, rest))) => Ok((Some(term), rest.len())),
+        Ok(None) => Ok((None, 0)),
+        Err((e, rest)) => Err((e, rest.len())),
+    };
+    let (term, rest) = match read {
+        Ok(read) => read,
+        Err((e, rest)) => {
+            input.pos = input.text.len() - rest;
+            return Err(Exception::syntax_error(e.message));
+        }
+    };
+    input.pos = input.text.len() - rest;
+
+    let term = match term {
+        Some(term) => term.rename_anonymous(&mut 0),
+        None => Term::atom("end_of_file"),
+    };
+    let mut vars = HashMap::new();
+    let cell = app.store.from_term(&term, &mut vars);
+    let mut order = term.free_vars();
+    let mut seen = HashSet::new();
+    order.retain(|var| seen.insert(*var));
+    let variables: Vec<Cell> = order.iter().map(|var| Cell::Ref(vars[var])).collect();
+    let mut names = Vec::new();
+    for var in &order {
+        if let Variable::Named(name) = var {
+            let pair = vec![Cell::Atom(*name), Cell::Ref(vars[var])];
+            names.push(app.store.compound(Atom::new("="), pair));
+        }
+    }
+    let names = coroutine::list(&mut app.store, names);
+    let variables = coroutine::list(&mut app.store, variables);
+    let mut unified = app.store.unify(&args[args.len() - 2], &cell);
+    for (name, arg) in options {
+        unified = unified
+            && match name.name() {
+                "variable_names" => app.store.unify(&arg, &names),
+                _ => app.store.unify(&arg, &variables),
+            };
+    }
+    Ok(match unified {
+        true => succeed(app),
+        false => fail(),
+    })
+}
+
+// with_output_to(Sink, Goal): the first answer of `Goal`, with `Sink` being
+// `atom(A)`, `string(S)`, `codes(Cs)` or `chars(Cs)` for the text it wrote
+// to the current output until then.
+pub(crate) fn with_output_to(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let sink = app.store.deref(&args[0]);
+    match app.store.key(&sink) {
+        Some((kind, 1)) if ["atom", "string", "codes", "chars"].contains(&kind.name()) => {}
+        None => return Err(Exception::instantiation_error()),
+        _ => {
+            return Err(Exception::domain_error(
+                "output_sink",
+                app.store.to_term(&sink),
+            ))
+        }
+    };
+    // holds what the goal writes in each of its branches.
+    let buffer = app.store.var();
+    Ok(frame::call(
+        app,
+        Kind::Output,
+        args[1].clone(),
+        vec![sink, buffer],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::App;
+    use crate::testing::{answers, consult, temp_dir, Shared};
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_streams() {
+        let mut app = App::new();
+        let file = std::env::temp_dir().join(format!("rslog-stream-{}.pl", std::process::id()));
+        let file = file.display();
+        let query = format!(
+            "open('{}', write, S, [alias(out)]), put_char(S, a), \
+             format(out, \"(~w, _, Y).~nb.~n\", [1]), close(S), X = Y.",
+            file
+        );
+        assert_eq!(answers(&mut app, &["X", "Y"], &query).len(), 1);
+        assert_eq!(
+            fs::read_to_string(file.to_string()).unwrap(),
+            "a(1, _, Y).\nb.\n"
+        );
+
+        let query = format!(
+            "open('{}', read, S), read_term(S, X, [variables([z, V]), variable_names(Y)]), \
+             V = y, get_char(S, C), read(S, b), read(S, E), close(S), C = '\\n', E = end_of_file.",
+            file
+        );
+        assert_eq!(
+            answers(&mut app, &["X", "Y"], &query),
+            vec!["a(1, z, y) [=('Y', y)]"]
+        );
+        fs::remove_file(file.to_string()).unwrap();
+
+        let query = "with_output_to(atom(X), (write(f(a)), nl, put_char(b))), \
+                     with_output_to(codes(Y), format(\"~a\", [c])).";
+        assert_eq!(
+            answers(&mut app, &["X", "Y"], query),
+            vec!["'f(a)\\nb' [99]"]
+        );
+        assert_eq!(
+            answers(
+                &mut app,
+                &["X", "Y"],
+                "open('/nonexistent/rslog', read, _)."
+            ),
+            vec!["source_sink `/nonexistent/rslog' does not exist"]
+        );
+        assert_eq!(
+            answers(&mut app, &["X", "Y"], "put_char(user_input, a)."),
+            vec!["No permission to output stream `user_input'"]
+        );
+    }
+
+    #[test]
+    fn test_read_long_list() {
+        let items: Vec<&str> = (0..50000).map(|n| ["a", "_"][n % 2]).collect();
+        let dir = temp_dir(&[("long.pl", &format!("l([{}]).\n", items.join(", ")))]);
+        let query = format!(
+            "open('{}', read, S), read_term(S, l([X, _, Y|_]), [variables(Vs)]), \
+             close(S), Vs = [_|_].",
+            dir.join("long.pl").display()
+        );
+        assert_eq!(answers(&mut App::new(), &["X", "Y"], &query), vec!["a a"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_top_level_output() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut app = App::new();
+        app.output = Box::new(Shared(Rc::clone(&output)));
+        // answers and errors are written to `user_output`, whose column
+        // starts again with each query.
+        app.handle_query("write(ok), X = 'A'.");
+        app.handle_query("format(\"~w~t~4|~w~n\", [a, b]).");
+        app.handle_query("X is Y + 1.");
+        let written = String::from_utf8(output.borrow().clone()).unwrap();
+        assert_eq!(
+            written,
+            "ok\nX = 'A'.\na   b\nERROR: Arguments are not sufficiently instantiated\n"
+        );
+    }
+
+    #[test]
+    fn test_output_until_first_answer() {
+        let mut app = consult("nat(z).\nnat(s(X)) :- nat(X).\n");
+        let query = "with_output_to(atom(X), ((Y = a ; Y = b), write(Y))).";
+        assert_eq!(answers(&mut app, &["X", "Y"], query), vec!["a a"]);
+        // a goal with infinitely many answers stops at the first.
+        let query = "with_output_to(atom(X), (nat(Y), write(Y), Y = s(_))).";
+        assert_eq!(answers(&mut app, &["X", "Y"], query), vec!["'s(z)' s(z)"]);
+        let query = "with_output_to(atom(_), dif(X, a)), X = a.";
+        assert!(answers(&mut app, &["X"], query).is_empty());
+    }
+}
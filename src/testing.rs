@@ -0,0 +1,85 @@
+// Helpers shared by the tests of the other modules.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::app::App;
+use crate::atom::Atom;
+use crate::expr::{Query, Term, Variable};
+use crate::parser::parse_query;
+
+// output kept for the test to read.
+pub struct Shared(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// the goals of `query`, which is not a list of files to consult.
+pub fn goals(query: &str) -> Vec<Term> {
+    match parse_query(query) {
+        Ok(Query::Terms(goals)) => goals,
+        _ => panic!("not a query: {}", query),
+    }
+}
+
+// an interpreter that consulted `source` from a file, removed afterwards.
+pub fn consult(source: &str) -> App {
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+    let n = FILES.fetch_add(1, Ordering::SeqCst);
+    let file = std::env::temp_dir().join(format!("rslog-test-{}-{}.pl", std::process::id(), n));
+    fs::write(&file, source).unwrap();
+    let mut app = App::new();
+    let consulted = app.consult(&file.to_string_lossy());
+    fs::remove_file(&file).unwrap();
+    consulted.unwrap();
+    app
+}
+
+// a new directory holding `files`, given by name and text, which the test
+// removes.
+pub fn temp_dir(files: &[(&str, &str)]) -> PathBuf {
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+    let n = DIRS.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir().join(format!("rslog-dir-{}-{}", std::process::id(), n));
+    fs::create_dir_all(&dir).unwrap();
+    for (name, text) in files {
+        fs::write(dir.join(name), text).unwrap();
+    }
+    dir
+}
+
+// the values of `vars` in each answer to `query`, separated by spaces, or
+// the error it raised.
+pub fn answers(app: &mut App, vars: &[&str], query: &str) -> Vec<String> {
+    let vars = vars
+        .iter()
+        .map(|name| Variable::Named(Atom::new(name)))
+        .collect();
+    match app.answers(vars, &goals(query)) {
+        Ok(answers) => answers
+            .iter()
+            .map(|values| {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                values.join(" ")
+            })
+            .collect(),
+        Err((e, _)) => vec![e.to_string()],
+    }
+}
+
+// the value of `X` in each answer to `query`, asked to a new interpreter.
+pub fn values_of_x(query: &str) -> Vec<String> {
+    answers(&mut App::new(), &["X"], query)
+}
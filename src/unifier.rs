@@ -7,8 +7,8 @@ pub type Subst = HashMap<Variable, Term>;
 pub type Constraint = (Term, Term);
 
 pub fn compose(s1: &Subst, s2: &Subst) -> Subst {
-    let mut ret: Subst = s1.iter().map(|(v, t)| (v.clone(), t.subst(s2))).collect();
-    ret.extend(s2.clone().into_iter());
+    let mut ret: Subst = s1.iter().map(|(v, t)| (*v, t.subst(s2))).collect();
+    ret.extend(s2.clone());
     ret
 }
 
@@ -28,14 +28,13 @@ pub fn unify(constraints: &mut Vec<Constraint>) -> Result<Subst, String> {
                     } else {
                         panic!("This case won't happend.");
                     };
-                    let sub: Subst = [(v.clone(), t.clone())].iter().cloned().collect();
+                    let sub: Subst = [(v, t.clone())].iter().cloned().collect();
                     let mut new_constraints: Vec<Constraint> = constraints
                         .iter()
-                        .cloned()
                         .map(|(l, r)| (l.subst(&sub), r.subst(&sub)))
                         .collect();
                     unify(&mut new_constraints).map(|mut sub| {
-                        sub.insert(v.clone(), t.clone());
+                        sub.insert(v, t.clone());
                         sub
                     })
                 }
@@ -67,15 +66,15 @@ pub fn unify(constraints: &mut Vec<Constraint>) -> Result<Subst, String> {
 pub fn search(target: &Term, subst: &Subst) -> Option<Term> {
     match target {
         Term::Const(_) => Some(target.clone()),
-        Term::Var(v) => subst.get(v).map(|val| search(&val, subst)).flatten(),
+        Term::Var(v) => subst.get(v).and_then(|val| search(val, subst)),
         Term::Combined { functor, args } => {
             let new_args: Vec<Option<Term>> = args.iter().map(|arg| search(arg, subst)).collect();
             if new_args.iter().any(|t| t.is_none()) {
                 None
             } else {
                 Some(Term::Combined {
-                    functor: functor.clone(),
-                    args: args.iter().map(|arg| search(arg, subst).unwrap()).collect(),
+                    functor: *functor,
+                    args: new_args.into_iter().map(Option::unwrap).collect(),
                 })
             }
         }
@@ -85,27 +84,35 @@ pub fn search(target: &Term, subst: &Subst) -> Option<Term> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::expr::Constant;
+    use crate::atom::Atom;
+    use crate::expr::{Constant, Variable};
 
     #[test]
     fn test_unify() {
-        let const_z = Term::Const(Constant::Name("z".to_string()));
+        let const_z = Term::Const(Constant::Name(Atom::new("z")));
         let s_z = Term::Combined {
-            functor: "s".to_string(),
+            functor: Atom::new("s"),
             args: vec![const_z.clone()],
         };
 
         let mut constraints = vec![(
             Term::Combined {
-                functor: "add".to_string(),
-                args: vec![const_z.clone(), s_z.clone(), Term::Var("C".to_string())],
+                functor: Atom::new("add"),
+                args: vec![
+                    const_z.clone(),
+                    s_z.clone(),
+                    Term::Var(Variable::Named(Atom::new("C"))),
+                ],
             },
             Term::Combined {
-                functor: "add".to_string(),
+                functor: Atom::new("add"),
                 args: vec![const_z.clone(), s_z.clone(), s_z.clone()],
             },
         )];
-        let expected: Subst = [("C".to_string(), s_z.clone())].iter().cloned().collect();
+        let expected: Subst = [(Variable::Named(Atom::new("C")), s_z.clone())]
+            .iter()
+            .cloned()
+            .collect();
         assert_eq!(unify(&mut constraints), Ok(expected));
     }
 }
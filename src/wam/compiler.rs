@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::bigint::BigInt;
+use crate::error::Exception;
+use crate::expr::{Constant, Rule, Term, Variable};
+use crate::wam::{args_of, key_of, Instruction, Label, PredKey, QueryVars, Reg};
+
+// groups clauses by name/arity, keeping the order of first definition.
+pub fn group_by_predicate(rules: &[Rule]) -> Result<Vec<(PredKey, Vec<&Rule>)>, Exception> {
+    let mut order: Vec<PredKey> = Vec::new();
+    let mut groups: HashMap<PredKey, Vec<&Rule>> = HashMap::new();
+    for rule in rules {
+        let key =
+            key_of(&rule.lhs).ok_or_else(|| Exception::type_error("callable", rule.lhs.clone()))?;
+        if !groups.contains_key(&key) {
+            order.push(key);
+        }
+        groups.entry(key).or_default().push(rule);
+    }
+    Ok(order
+        .into_iter()
+        .map(|key| {
+            let clauses = groups.remove(&key).unwrap();
+            (key, clauses)
+        })
+        .collect())
+}
+
+// compiles all clauses of one predicate and returns its entry label.
+pub fn compile_predicate(
+    clauses: &[&Rule],
+    code: &mut Vec<Instruction>,
+) -> Result<Label, Exception> {
+    if clauses.len() == 1 {
+        let entry = code.len();
+        compile_clause(clauses[0], code)?;
+        return Ok(entry);
+    }
+
+    let indexed = !args_of(&clauses[0].lhs).is_empty();
+    let switch = code.len();
+    if indexed {
+        code.push(Instruction::Proceed); // patched below
+    }
+
+    let mut chain: Vec<Label> = Vec::new();
+    let mut starts: Vec<Label> = Vec::new();
+    for (i, clause) in clauses.iter().enumerate() {
+        chain.push(code.len());
+        code.push(if i == 0 {
+            Instruction::TryMeElse(0)
+        } else if i + 1 < clauses.len() {
+            Instruction::RetryMeElse(0)
+        } else {
+            Instruction::TrustMe
+        });
+        starts.push(code.len());
+        compile_clause(clause, code)?;
+    }
+    for i in 0..clauses.len() - 1 {
+        code[chain[i]] = match code[chain[i]] {
+            Instruction::TryMeElse(_) => Instruction::TryMeElse(chain[i + 1]),
+            _ => Instruction::RetryMeElse(chain[i + 1]),
+        };
+    }
+    if !indexed {
+        return Ok(chain[0]);
+    }
+
+    // first-argument indexing. Clauses whose first argument is a variable
+    // belong to every bucket.
+    let firsts: Vec<&Term> = clauses.iter().map(|c| &args_of(&c.lhs)[0]).collect();
+    let var_clauses: Vec<Label> = (0..clauses.len())
+        .filter(|&i| matches!(firsts[i], Term::Var(_)))
+        .map(|i| starts[i])
+        .collect();
+
+    let mut constants: Vec<Constant> = Vec::new();
+    let mut structures: Vec<PredKey> = Vec::new();
+    for first in firsts.iter() {
+        match first {
+            Term::Const(c) if !constants.contains(c) => constants.push(c.clone()),
+            Term::Combined { .. } => {
+                let key = key_of(first).unwrap();
+                if !structures.contains(&key) {
+                    structures.push(key);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let default = emit_alternatives(&var_clauses, code);
+    let constant = if constants.is_empty() {
+        default
+    } else {
+        let mut table = HashMap::new();
+        for c in constants {
+            let targets: Vec<Label> = (0..clauses.len())
+                .filter(|&i| match firsts[i] {
+                    Term::Var(_) => true,
+                    Term::Const(c2) => *c2 == c,
+                    _ => false,
+                })
+                .map(|i| starts[i])
+                .collect();
+            if let Some(label) = emit_alternatives(&targets, code) {
+                table.insert(c, label);
+            }
+        }
+        code.push(Instruction::SwitchOnConstant(table, default));
+        Some(code.len() - 1)
+    };
+    let structure = if structures.is_empty() {
+        default
+    } else {
+        let mut table = HashMap::new();
+        for key in structures {
+            let targets: Vec<Label> = (0..clauses.len())
+                .filter(|&i| match firsts[i] {
+                    Term::Var(_) => true,
+                    t @ Term::Combined { .. } => key_of(t) == Some(key),
+                    _ => false,
+                })
+                .map(|i| starts[i])
+                .collect();
+            if let Some(label) = emit_alternatives(&targets, code) {
+                table.insert(key, label);
+            }
+        }
+        code.push(Instruction::SwitchOnStructure(table, default));
+        Some(code.len() - 1)
+    };
+    code[switch] = Instruction::SwitchOnTerm {
+        var: Some(chain[0]),
+        constant,
+        structure,
+    };
+    Ok(switch)
+}
+
+// emits a try/retry/trust block over the given clause starts.
+fn emit_alternatives(targets: &[Label], code: &mut Vec<Instruction>) -> Option<Label> {
+    match targets.len() {
+        0 => None,
+        1 => Some(targets[0]),
+        n => {
+            let label = code.len();
+            for (i, target) in targets.iter().enumerate() {
+                code.push(if i == 0 {
+                    Instruction::Try(*target)
+                } else if i + 1 < n {
+                    Instruction::Retry(*target)
+                } else {
+                    Instruction::Trust(*target)
+                });
+            }
+            Some(label)
+        }
+    }
+}
+
+fn compile_clause(rule: &Rule, code: &mut Vec<Instruction>) -> Result<(), Exception> {
+    let mut compiler = ClauseCompiler::new(&rule.lhs, &rule.rhs, false);
+    compiler.allocate();
+    compiler.compile_head(&rule.lhs);
+    compiler.compile_body(&rule.rhs)?;
+    code.append(&mut compiler.code);
+    Ok(())
+}
+
+// compiles the query as a clause body ending with `Yield`. Every query
+// variable is permanent so that its binding can be read at the end.
+pub fn compile_query(goals: &[Term], code: &mut Vec<Instruction>) -> Result<QueryVars, Exception> {
+    let head = Term::Const(Constant::Int(BigInt::zero()));
+    let mut compiler = ClauseCompiler::new(&head, goals, true);
+    compiler.allocate();
+    compiler.compile_body(goals)?;
+    code.append(&mut compiler.code);
+    let mut vars: QueryVars = compiler
+        .perm
+        .iter()
+        .map(|(v, i)| (*v, *i))
+        .filter(|(v, _)| matches!(v, Variable::Named(_)))
+        .collect();
+    vars.sort_by_key(|(_, i)| *i);
+    Ok(vars)
+}
+
+struct ClauseCompiler {
+    perm: HashMap<Variable, usize>,
+    temp: HashMap<Variable, usize>,
+    seen: HashSet<Variable>,
+    next_x: usize,
+    is_query: bool,
+    with_env: bool,
+    code: Vec<Instruction>,
+}
+
+impl ClauseCompiler {
+    fn new(head: &Term, body: &[Term], is_query: bool) -> ClauseCompiler {
+        // head and the first goal form one chunk. A variable occurring in
+        // more than one chunk must survive calls, so it lives in the
+        // environment.
+        let mut chunks: HashMap<Variable, HashSet<usize>> = HashMap::new();
+        for v in head.free_vars() {
+            chunks.entry(v).or_default().insert(0);
+        }
+        for (i, goal) in body.iter().enumerate() {
+            for v in goal.free_vars() {
+                chunks.entry(v).or_default().insert(i);
+            }
+        }
+        let mut perm_vars: Vec<Variable> = chunks
+            .iter()
+            .filter(|(_, c)| is_query || (body.len() > 1 && c.len() > 1))
+            .map(|(v, _)| *v)
+            .collect();
+        perm_vars.sort();
+
+        let next_x = body
+            .iter()
+            .chain(std::iter::once(head))
+            .map(|t| args_of(t).len())
+            .max()
+            .unwrap_or(0);
+        ClauseCompiler {
+            perm: perm_vars
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| (v, i))
+                .collect(),
+            temp: HashMap::new(),
+            seen: HashSet::new(),
+            next_x,
+            is_query,
+            with_env: is_query || body.len() > 1,
+            code: Vec::new(),
+        }
+    }
+
+    fn fresh_x(&mut self) -> usize {
+        self.next_x += 1;
+        self.next_x - 1
+    }
+
+    // returns the register of `v` and whether this is its first occurrence.
+    fn reg(&mut self, v: Variable) -> (Reg, bool) {
+        let first = self.seen.insert(v);
+        if let Some(i) = self.perm.get(&v) {
+            return (Reg::Y(*i), first);
+        }
+        let reg = match self.temp.get(&v) {
+            Some(i) => *i,
+            None => {
+                let i = self.fresh_x();
+                self.temp.insert(v, i);
+                i
+            }
+        };
+        (Reg::X(reg), first)
+    }
+
+    fn compile_head(&mut self, head: &Term) {
+        let mut pending: Vec<(usize, &Term)> = Vec::new();
+        for (i, arg) in args_of(head).iter().enumerate() {
+            match arg {
+                Term::Var(v) => {
+                    let (reg, first) = self.reg(*v);
+                    self.code.push(if first {
+                        Instruction::GetVariable(reg, i)
+                    } else {
+                        Instruction::GetValue(reg, i)
+                    });
+                }
+                Term::Const(c) => self.code.push(Instruction::GetConstant(c.clone(), i)),
+                Term::Combined { .. } => pending.push((i, arg)),
+            }
+        }
+        while !pending.is_empty() {
+            let (i, term) = pending.remove(0);
+            if let Term::Combined { functor, args } = term {
+                self.code
+                    .push(Instruction::GetStructure(*functor, args.len(), i));
+                for arg in args {
+                    match arg {
+                        Term::Var(v) => {
+                            let (reg, first) = self.reg(*v);
+                            self.code.push(if first {
+                                Instruction::UnifyVariable(reg)
+                            } else {
+                                Instruction::UnifyValue(reg)
+                            });
+                        }
+                        Term::Const(c) => self.code.push(Instruction::UnifyConstant(c.clone())),
+                        Term::Combined { .. } => {
+                            let x = self.fresh_x();
+                            self.code.push(Instruction::UnifyVariable(Reg::X(x)));
+                            pending.push((x, arg));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // the environment has to exist before the head binds permanent variables.
+    fn allocate(&mut self) {
+        if self.with_env {
+            self.code.push(Instruction::Allocate(self.perm.len()));
+        }
+    }
+
+    fn compile_body(&mut self, body: &[Term]) -> Result<(), Exception> {
+        for (i, goal) in body.iter().enumerate() {
+            // a variable goal is `call/1`, which the machine reports as
+            // unsupported if it is reached.
+            let call;
+            let goal = match goal {
+                Term::Var(_) => {
+                    call = Term::compound("call", vec![goal.clone()]);
+                    &call
+                }
+                _ => goal,
+            };
+            let (functor, arity) =
+                key_of(goal).ok_or_else(|| Exception::type_error("callable", goal.clone()))?;
+            for (j, arg) in args_of(goal).iter().enumerate() {
+                self.put_argument(arg, j);
+            }
+            if self.is_query || i + 1 < body.len() {
+                self.code.push(Instruction::Call(functor, arity));
+            } else {
+                if self.with_env {
+                    self.code.push(Instruction::Deallocate);
+                }
+                self.code.push(Instruction::Execute(functor, arity));
+            }
+        }
+        if self.is_query {
+            self.code.push(Instruction::Yield);
+        } else if body.is_empty() {
+            self.code.push(Instruction::Proceed);
+        }
+        Ok(())
+    }
+
+    fn put_argument(&mut self, arg: &Term, i: usize) {
+        match arg {
+            Term::Var(v) => {
+                let (reg, first) = self.reg(*v);
+                self.code.push(if first {
+                    Instruction::PutVariable(reg, i)
+                } else {
+                    Instruction::PutValue(reg, i)
+                });
+            }
+            Term::Const(c) => self.code.push(Instruction::PutConstant(c.clone(), i)),
+            Term::Combined { .. } => self.build_structure(arg, i),
+        }
+    }
+
+    // builds nested structures bottom-up into register `target`.
+    fn build_structure(&mut self, term: &Term, target: usize) {
+        if let Term::Combined { functor, args } = term {
+            let inner: Vec<Option<usize>> = args
+                .iter()
+                .map(|arg| match arg {
+                    Term::Combined { .. } => {
+                        let x = self.fresh_x();
+                        self.build_structure(arg, x);
+                        Some(x)
+                    }
+                    _ => None,
+                })
+                .collect();
+            self.code
+                .push(Instruction::PutStructure(*functor, args.len(), target));
+            for (arg, x) in args.iter().zip(inner) {
+                match arg {
+                    Term::Var(v) => {
+                        let (reg, first) = self.reg(*v);
+                        self.code.push(if first {
+                            Instruction::SetVariable(reg)
+                        } else {
+                            Instruction::SetValue(reg)
+                        });
+                    }
+                    Term::Const(c) => self.code.push(Instruction::SetConstant(c.clone())),
+                    Term::Combined { .. } => {
+                        self.code.push(Instruction::SetValue(Reg::X(x.unwrap())))
+                    }
+                }
+            }
+        }
+    }
+}
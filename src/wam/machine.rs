@@ -0,0 +1,426 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::builtins;
+use crate::error::Exception;
+use crate::expr::{Constant, Term, Variable};
+use crate::limits;
+use crate::module;
+use crate::unifier::Subst;
+use crate::wam::{Instruction, Label, PredKey, QueryVars, Reg};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Cell {
+    // reference to a heap cell. An unbound variable refers to itself.
+    Ref(usize),
+    // structure whose functor cell is at the given address.
+    Str(usize),
+    Functor(Atom, usize),
+    Con(Constant),
+}
+
+struct Environment {
+    prev: Option<Rc<Environment>>,
+    cont: Label,
+    ys: RefCell<Vec<Cell>>,
+}
+
+struct ChoicePoint {
+    args: Vec<Cell>,
+    env: Option<Rc<Environment>>,
+    cont: Label,
+    next: Label,
+    trail_len: usize,
+    heap_len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Read,
+    Write,
+}
+
+pub struct Machine<'a> {
+    code: &'a [Instruction],
+    preds: &'a HashMap<PredKey, Label>,
+    heap: Vec<Cell>,
+    xs: Vec<Cell>,
+    env: Option<Rc<Environment>>,
+    choices: Vec<ChoicePoint>,
+    trail: Vec<usize>,
+    p: Label,
+    cont: Label,
+    arity: usize,
+    mode: Mode,
+    s: usize,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(code: &'a [Instruction], preds: &'a HashMap<PredKey, Label>) -> Machine<'a> {
+        Machine {
+            code,
+            preds,
+            heap: Vec::new(),
+            xs: Vec::new(),
+            env: None,
+            choices: Vec::new(),
+            trail: Vec::new(),
+            p: 0,
+            cont: 0,
+            arity: 0,
+            mode: Mode::Read,
+            s: 0,
+        }
+    }
+
+    pub fn run(mut self, start: Label, vars: &QueryVars) -> Result<Vec<Subst>, Exception> {
+        let mut answers = Vec::new();
+        self.p = start;
+        let code = self.code;
+        loop {
+            if limits::interrupted() {
+                return Err(Exception::aborted());
+            }
+            let ok = match &code[self.p] {
+                Instruction::Yield => {
+                    answers.push(self.answer(vars));
+                    false
+                }
+                // builtins only run on the breadth-first engine.
+                Instruction::Call(f, n) | Instruction::Execute(f, n)
+                    if !self.preds.contains_key(&(*f, *n))
+                        && builtins::lookup((*f, *n)).is_some() =>
+                {
+                    let culprit = module::indicator((*f, *n));
+                    return Err(Exception::unsupported_error(culprit));
+                }
+                instruction => self.step(instruction),
+            };
+            if !ok && !self.backtrack() {
+                return Ok(answers);
+            }
+        }
+    }
+
+    fn answer(&self, vars: &QueryVars) -> Subst {
+        let env = self.env.as_ref().unwrap();
+        let ys = env.ys.borrow();
+        vars.iter()
+            .map(|(v, i)| (*v, self.to_term(&ys[*i])))
+            .collect()
+    }
+
+    fn to_term(&self, cell: &Cell) -> Term {
+        match self.deref(cell) {
+            Cell::Ref(a) => Term::Var(Variable::Fresh(a)),
+            Cell::Con(c) => Term::Const(c),
+            Cell::Str(a) => match &self.heap[a] {
+                Cell::Functor(f, n) => Term::Combined {
+                    functor: *f,
+                    args: (1..=*n).map(|i| self.to_term(&Cell::Ref(a + i))).collect(),
+                },
+                _ => panic!("Structure without functor cell"),
+            },
+            Cell::Functor(..) => panic!("Dangling functor cell"),
+        }
+    }
+
+    fn deref(&self, cell: &Cell) -> Cell {
+        let mut cell = cell.clone();
+        while let Cell::Ref(a) = cell {
+            match &self.heap[a] {
+                Cell::Ref(b) if *b == a => return cell,
+                next => cell = next.clone(),
+            }
+        }
+        cell
+    }
+
+    fn new_var(&mut self) -> Cell {
+        let cell = Cell::Ref(self.heap.len());
+        self.heap.push(cell.clone());
+        cell
+    }
+
+    fn bind(&mut self, addr: usize, cell: Cell) {
+        self.trail.push(addr);
+        self.heap[addr] = cell;
+    }
+
+    fn unify(&mut self, c1: &Cell, c2: &Cell) -> bool {
+        let mut stack = vec![(c1.clone(), c2.clone())];
+        while let Some((c1, c2)) = stack.pop() {
+            let (d1, d2) = (self.deref(&c1), self.deref(&c2));
+            if d1 == d2 {
+                continue;
+            }
+            match (d1, d2) {
+                (Cell::Ref(a), Cell::Ref(b)) => {
+                    // bind the younger variable to the older one.
+                    if a < b {
+                        self.bind(b, Cell::Ref(a));
+                    } else {
+                        self.bind(a, Cell::Ref(b));
+                    }
+                }
+                (Cell::Ref(a), other) | (other, Cell::Ref(a)) => self.bind(a, other),
+                (Cell::Str(a), Cell::Str(b)) => {
+                    if self.heap[a] != self.heap[b] {
+                        return false;
+                    }
+                    if let Cell::Functor(_, n) = self.heap[a] {
+                        for i in 1..=n {
+                            stack.push((Cell::Ref(a + i), Cell::Ref(b + i)));
+                        }
+                    }
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn get(&self, reg: Reg) -> Cell {
+        match reg {
+            Reg::X(i) => self.xs[i].clone(),
+            Reg::Y(i) => self.env.as_ref().unwrap().ys.borrow()[i].clone(),
+        }
+    }
+
+    fn set(&mut self, reg: Reg, cell: Cell) {
+        match reg {
+            Reg::X(i) => self.set_x(i, cell),
+            Reg::Y(i) => self.env.as_ref().unwrap().ys.borrow_mut()[i] = cell,
+        }
+    }
+
+    fn set_x(&mut self, i: usize, cell: Cell) {
+        if self.xs.len() <= i {
+            self.xs
+                .resize(i + 1, Cell::Con(Constant::Int(BigInt::zero())));
+        }
+        self.xs[i] = cell;
+    }
+
+    fn jump_to(&mut self, key: PredKey) -> bool {
+        match self.preds.get(&key) {
+            Some(label) => {
+                self.arity = key.1;
+                self.p = *label;
+                true
+            }
+            // undefined predicates fail as in the breadth-first engine.
+            None => false,
+        }
+    }
+
+    fn push_choice(&mut self, next: Label) {
+        let mut args = self.xs.clone();
+        args.truncate(self.arity);
+        self.choices.push(ChoicePoint {
+            args,
+            env: self.env.clone(),
+            cont: self.cont,
+            next,
+            trail_len: self.trail.len(),
+            heap_len: self.heap.len(),
+        });
+    }
+
+    fn restore_choice(&mut self) {
+        let choice = self.choices.last().unwrap();
+        let args = choice.args.clone();
+        self.env = choice.env.clone();
+        self.cont = choice.cont;
+        let (trail_len, heap_len) = (choice.trail_len, choice.heap_len);
+        for (i, arg) in args.into_iter().enumerate() {
+            self.set_x(i, arg);
+        }
+        for addr in self.trail.drain(trail_len..) {
+            self.heap[addr] = Cell::Ref(addr);
+        }
+        self.heap.truncate(heap_len);
+    }
+
+    fn backtrack(&mut self) -> bool {
+        match self.choices.last() {
+            None => false,
+            Some(choice) => {
+                self.p = choice.next;
+                true
+            }
+        }
+    }
+
+    // executes one instruction. Returns false on failure.
+    fn step(&mut self, instruction: &Instruction) -> bool {
+        self.p += 1;
+        match instruction {
+            Instruction::PutVariable(reg, i) => {
+                let var = self.new_var();
+                self.set(*reg, var.clone());
+                self.set_x(*i, var);
+            }
+            Instruction::PutValue(reg, i) => {
+                let cell = self.get(*reg);
+                self.set_x(*i, cell);
+            }
+            Instruction::PutStructure(f, n, i) => {
+                self.heap.push(Cell::Functor(*f, *n));
+                self.set_x(*i, Cell::Str(self.heap.len() - 1));
+            }
+            Instruction::PutConstant(c, i) => self.set_x(*i, Cell::Con(c.clone())),
+            Instruction::GetVariable(reg, i) => {
+                let cell = self.xs[*i].clone();
+                self.set(*reg, cell);
+            }
+            Instruction::GetValue(reg, i) => {
+                let (c1, c2) = (self.get(*reg), self.xs[*i].clone());
+                return self.unify(&c1, &c2);
+            }
+            Instruction::GetStructure(f, n, i) => match self.deref(&self.xs[*i]) {
+                Cell::Ref(a) => {
+                    self.heap.push(Cell::Functor(*f, *n));
+                    self.bind(a, Cell::Str(self.heap.len() - 1));
+                    self.mode = Mode::Write;
+                }
+                Cell::Str(a) if self.heap[a] == Cell::Functor(*f, *n) => {
+                    self.s = a + 1;
+                    self.mode = Mode::Read;
+                }
+                _ => return false,
+            },
+            Instruction::GetConstant(c, i) => match self.deref(&self.xs[*i]) {
+                Cell::Ref(a) => self.bind(a, Cell::Con(c.clone())),
+                Cell::Con(c2) => return *c == c2,
+                _ => return false,
+            },
+            Instruction::SetVariable(reg) => {
+                let var = self.new_var();
+                self.set(*reg, var);
+            }
+            Instruction::SetValue(reg) => {
+                let cell = self.get(*reg);
+                self.heap.push(cell);
+            }
+            Instruction::SetConstant(c) => self.heap.push(Cell::Con(c.clone())),
+            Instruction::UnifyVariable(reg) => {
+                match self.mode {
+                    Mode::Read => self.set(*reg, Cell::Ref(self.s)),
+                    Mode::Write => {
+                        let var = self.new_var();
+                        self.set(*reg, var);
+                    }
+                }
+                self.s += 1;
+            }
+            Instruction::UnifyValue(reg) => {
+                let cell = self.get(*reg);
+                match self.mode {
+                    Mode::Read => {
+                        if !self.unify(&cell, &Cell::Ref(self.s)) {
+                            return false;
+                        }
+                    }
+                    Mode::Write => self.heap.push(cell),
+                }
+                self.s += 1;
+            }
+            Instruction::UnifyConstant(c) => {
+                match self.mode {
+                    Mode::Read => match self.deref(&Cell::Ref(self.s)) {
+                        Cell::Ref(a) => self.bind(a, Cell::Con(c.clone())),
+                        Cell::Con(c2) if *c == c2 => {}
+                        _ => return false,
+                    },
+                    Mode::Write => self.heap.push(Cell::Con(c.clone())),
+                }
+                self.s += 1;
+            }
+            Instruction::Allocate(n) => {
+                self.env = Some(Rc::new(Environment {
+                    prev: self.env.take(),
+                    cont: self.cont,
+                    ys: RefCell::new(vec![Cell::Con(Constant::Int(BigInt::zero())); *n]),
+                }));
+            }
+            Instruction::Deallocate => {
+                let env = self.env.take().unwrap();
+                self.cont = env.cont;
+                self.env = env.prev.clone();
+            }
+            Instruction::Call(f, n) => {
+                self.cont = self.p;
+                return self.jump_to((*f, *n));
+            }
+            Instruction::Execute(f, n) => return self.jump_to((*f, *n)),
+            Instruction::Proceed => self.p = self.cont,
+            Instruction::TryMeElse(next) => self.push_choice(*next),
+            Instruction::RetryMeElse(next) => {
+                self.restore_choice();
+                self.choices.last_mut().unwrap().next = *next;
+            }
+            Instruction::TrustMe => {
+                self.restore_choice();
+                self.choices.pop();
+            }
+            Instruction::Try(label) => {
+                self.push_choice(self.p);
+                self.p = *label;
+            }
+            Instruction::Retry(label) => {
+                self.restore_choice();
+                self.choices.last_mut().unwrap().next = self.p;
+                self.p = *label;
+            }
+            Instruction::Trust(label) => {
+                self.restore_choice();
+                self.choices.pop();
+                self.p = *label;
+            }
+            Instruction::SwitchOnTerm {
+                var,
+                constant,
+                structure,
+            } => {
+                let target = match self.deref(&self.xs[0]) {
+                    Cell::Ref(_) => var,
+                    Cell::Con(_) => constant,
+                    _ => structure,
+                };
+                match target {
+                    Some(label) => self.p = *label,
+                    None => return false,
+                }
+            }
+            Instruction::SwitchOnConstant(table, default) => {
+                let target = match self.deref(&self.xs[0]) {
+                    Cell::Con(c) => table.get(&c).or(default.as_ref()),
+                    _ => None,
+                };
+                match target {
+                    Some(label) => self.p = *label,
+                    None => return false,
+                }
+            }
+            Instruction::SwitchOnStructure(table, default) => {
+                let target = match self.deref(&self.xs[0]) {
+                    Cell::Str(a) => match self.heap[a] {
+                        Cell::Functor(f, n) => table.get(&(f, n)).or(default.as_ref()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match target {
+                    Some(label) => self.p = *label,
+                    None => return false,
+                }
+            }
+            Instruction::Yield => {}
+        }
+        true
+    }
+}
@@ -0,0 +1,209 @@
+// Warren Abstract Machine backend. Rules are compiled into the instructions
+// below and run by `machine::Machine` with depth-first search and
+// backtracking, unlike the breadth-first engine in `app.rs`.
+
+pub mod compiler;
+pub mod machine;
+
+use std::collections::HashMap;
+
+use crate::atom::Atom;
+use crate::error::Exception;
+use crate::expr::{Constant, Rule, Term, Variable};
+use crate::unifier::Subst;
+
+pub type Label = usize;
+
+pub type PredKey = (Atom, usize);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reg {
+    // temporary (argument) register.
+    X(usize),
+    // permanent variable in the current environment.
+    Y(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    PutVariable(Reg, usize),
+    PutValue(Reg, usize),
+    PutStructure(Atom, usize, usize),
+    PutConstant(Constant, usize),
+    GetVariable(Reg, usize),
+    GetValue(Reg, usize),
+    GetStructure(Atom, usize, usize),
+    GetConstant(Constant, usize),
+    SetVariable(Reg),
+    SetValue(Reg),
+    SetConstant(Constant),
+    UnifyVariable(Reg),
+    UnifyValue(Reg),
+    UnifyConstant(Constant),
+    Allocate(usize),
+    Deallocate,
+    Call(Atom, usize),
+    Execute(Atom, usize),
+    Proceed,
+    TryMeElse(Label),
+    RetryMeElse(Label),
+    TrustMe,
+    Try(Label),
+    Retry(Label),
+    Trust(Label),
+    SwitchOnTerm {
+        var: Option<Label>,
+        constant: Option<Label>,
+        structure: Option<Label>,
+    },
+    SwitchOnConstant(HashMap<Constant, Label>, Option<Label>),
+    SwitchOnStructure(HashMap<PredKey, Label>, Option<Label>),
+    // reports the bindings of the query variables and backtracks for more.
+    Yield,
+}
+
+pub struct Program {
+    pub code: Vec<Instruction>,
+    pub preds: HashMap<PredKey, Label>,
+}
+
+impl Program {
+    pub fn compile(rules: &[Rule]) -> Result<Program, Exception> {
+        let mut program = Program {
+            code: Vec::new(),
+            preds: HashMap::new(),
+        };
+        // renaming gives each `_` a variable of its own.
+        let rules: Vec<Rule> = rules
+            .iter()
+            .map(|rule| rule.instantiate(&mut 0, &HashMap::new()))
+            .collect();
+        for (key, clauses) in compiler::group_by_predicate(&rules)? {
+            let entry = compiler::compile_predicate(&clauses, &mut program.code)?;
+            program.preds.insert(key, entry);
+        }
+        Ok(program)
+    }
+
+    // runs the query to exhaustion and returns one substitution of the query
+    // variables per answer, or the error that stopped it.
+    pub fn run_query(&mut self, goals: &[Term]) -> Result<Vec<Subst>, Exception> {
+        let start = self.code.len();
+        let mut anonymous = 0;
+        let goals: Vec<Term> = goals
+            .iter()
+            .map(|goal| goal.rename_anonymous(&mut anonymous))
+            .collect();
+        let answers = compiler::compile_query(&goals, &mut self.code)
+            .and_then(|vars| machine::Machine::new(&self.code, &self.preds).run(start, &vars));
+        self.code.truncate(start);
+        answers
+    }
+}
+
+pub fn key_of(term: &Term) -> Option<PredKey> {
+    match term {
+        Term::Const(Constant::Name(name)) => Some((*name, 0)),
+        Term::Combined { functor, args } => Some((*functor, args.len())),
+        _ => None,
+    }
+}
+
+pub fn args_of(term: &Term) -> &[Term] {
+    match term {
+        Term::Combined { args, .. } => args,
+        _ => &[],
+    }
+}
+
+pub type QueryVars = Vec<(Variable, usize)>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_file_content;
+    use crate::testing::goals;
+
+    fn answers(source: &str, query: &str) -> Vec<String> {
+        let (rules, errors) = parse_file_content("test.pl", source);
+        assert!(errors.is_empty());
+        let goals = goals(query);
+        let vars = Term::free_vars_sum(&goals);
+        let mut program = Program::compile(&rules).unwrap();
+        program
+            .run_query(&goals)
+            .unwrap()
+            .iter()
+            .map(|subst| {
+                vars.iter()
+                    .map(|v| {
+                        format!(
+                            "{}={}",
+                            v,
+                            crate::unifier::search(&Term::Var(*v), subst).unwrap()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_facts_and_rules() {
+        let source = "parent(kobo,koji).parent(kobo,sanae).parent(koji,iwao).\
+                      male(kobo).male(koji).female(sanae).\
+                      father(X,Y):-parent(X,Y),male(Y).\
+                      ancestor(X,Y):-parent(X,Y).\
+                      ancestor(X,Y):-parent(X,Z),ancestor(Z,Y).";
+        assert_eq!(answers(source, "father(kobo,X)."), vec!["X=koji"]);
+        assert_eq!(
+            answers(source, "ancestor(kobo,X)."),
+            vec!["X=koji", "X=sanae", "X=iwao"]
+        );
+        assert!(answers(source, "father(kobo,sanae).").is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_goals() {
+        let (rules, _) = parse_file_content("test.pl", "p(G) :- G.\nq(X) :- write(X).\nr.\n");
+        let mut program = Program::compile(&rules).unwrap();
+        let error = |program: &mut Program, query| match program.run_query(&goals(query)) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("no error for {}", query),
+        };
+        assert_eq!(
+            error(&mut program, "p(r)."),
+            "`call/1' is not supported on this engine"
+        );
+        assert_eq!(
+            error(&mut program, "X."),
+            "`call/1' is not supported on this engine"
+        );
+        assert_eq!(
+            error(&mut program, "q(X)."),
+            "`write/1' is not supported on this engine"
+        );
+        // the query still runs afterwards.
+        assert_eq!(program.run_query(&goals("r.")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_debugger_is_unsupported() {
+        let (rules, _) = parse_file_content("test.pl", "r.\n");
+        let mut program = Program::compile(&rules).unwrap();
+        for query in ["trace, r.", "spy(r/0).", "r, nodebug."] {
+            assert!(program.run_query(&goals(query)).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_structures() {
+        let source = "add(z,Y,Y).add(s(X),Y,s(Z)):-add(X,Y,Z).";
+        assert_eq!(answers(source, "add(s(z),s(z),X)."), vec!["X=s(s(z))"]);
+        assert_eq!(
+            answers(source, "add(X,Y,s(s(z)))."),
+            vec!["X=z,Y=s(s(z))", "X=s(z),Y=s(z)", "X=s(s(z)),Y=z"]
+        );
+    }
+}
@@ -0,0 +1,613 @@
+// Writing terms as text: `write/1`, `print/1`, `writeq/1`,
+// `write_canonical/1`, `nl/0` and `format/2,3`. Terms are written with the
+// operators of the parser, and atoms and strings are quoted when the text is
+// to be read back. The output goes to the current output stream, or to the
+// stream given as the first argument.
+
+use crate::app::App;
+use crate::atom::Atom;
+use crate::bigint::BigInt;
+use crate::builtins::{fail, succeed, Branch};
+use crate::error::Exception;
+use crate::expr::{Constant, Term};
+use crate::parser::{escape, infix_op, prefix_op, quote_atom};
+use crate::store::Cell;
+
+const SYMBOL_CHARS: &str = "+-*/\\^<>=~:.?@#&$";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WriteOptions {
+    // whether atoms and strings are quoted so that the text reads back.
+    pub quoted: bool,
+    // whether operators are written in functional notation.
+    pub ignore_ops: bool,
+    // whether `'$VAR'(N)` is written as a variable name.
+    pub numbervars: bool,
+}
+
+impl WriteOptions {
+    pub const WRITE: WriteOptions = WriteOptions {
+        quoted: false,
+        ignore_ops: false,
+        numbervars: true,
+    };
+    pub const WRITEQ: WriteOptions = WriteOptions {
+        quoted: true,
+        ignore_ops: false,
+        numbervars: true,
+    };
+    pub const CANONICAL: WriteOptions = WriteOptions {
+        quoted: true,
+        ignore_ops: true,
+        numbervars: false,
+    };
+}
+
+// `term` as written with `options`.
+pub fn term_to_string(term: &Term, options: WriteOptions) -> String {
+    write_term(term, options, 1200)
+}
+
+fn write_term(term: &Term, options: WriteOptions, max: u32) -> String {
+    match term {
+        Term::Var(var) => var.to_string(),
+        Term::Const(Constant::Name(name)) => atom_text(*name, options),
+        Term::Const(Constant::Str(text)) if options.quoted => format!("\"{}\"", escape(text, '"')),
+        Term::Const(Constant::Str(text)) => text.clone(),
+        Term::Const(_) => term.to_string(),
+        Term::Combined { functor, args } if functor.name() == "." && args.len() == 2 => {
+            let mut text = format!("[{}", write_term(&args[0], options, 999));
+            let mut tail = &args[1];
+            loop {
+                match tail {
+                    Term::Combined { functor, args }
+                        if functor.name() == "." && args.len() == 2 =>
+                    {
+                        text += ",";
+                        text += &write_term(&args[0], options, 999);
+                        tail = &args[1];
+                    }
+                    Term::Const(Constant::Name(name)) if name.name() == "[]" => break,
+                    _ => {
+                        text += "|";
+                        text += &write_term(tail, options, 999);
+                        break;
+                    }
+                }
+            }
+            text + "]"
+        }
+        Term::Combined { functor, args } if functor.name() == "{}" && args.len() == 1 => {
+            format!("{{{}}}", write_term(&args[0], options, 1200))
+        }
+        Term::Combined { functor, args } => {
+            if options.numbervars && functor.name() == "$VAR" && args.len() == 1 {
+                if let Term::Const(Constant::Int(n)) = &args[0] {
+                    if let Some(n) = n.to_i64().filter(|n| *n >= 0) {
+                        let letter = (b'A' + (n % 26) as u8) as char;
+                        return match n / 26 {
+                            0 => letter.to_string(),
+                            suffix => format!("{}{}", letter, suffix),
+                        };
+                    }
+                }
+            }
+            if !options.ignore_ops {
+                if let Some(text) = write_op(*functor, args, options, max) {
+                    return text;
+                }
+            }
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| write_term(arg, options, 999))
+                .collect();
+            format!("{}({})", atom_text(*functor, options), args.join(","))
+        }
+    }
+}
+
+fn atom_text(name: Atom, options: WriteOptions) -> String {
+    match options.quoted {
+        true => quote_atom(name.name()),
+        false => name.name().to_string(),
+    }
+}
+
+fn is_symbolic(text: &str) -> bool {
+    text.chars()
+        .next()
+        .is_some_and(|c| SYMBOL_CHARS.contains(c))
+}
+
+// an operator term written in operator notation, in parentheses if its
+// priority is above `max`.
+fn write_op(functor: Atom, args: &[Term], options: WriteOptions, max: u32) -> Option<String> {
+    let name = functor.name();
+    let (priority, text) = match (args, infix_op(name), prefix_op(name)) {
+        ([left, right], Some((priority, left_max, right_max)), _) => {
+            let left = operand(left, options, left_max);
+            let right = operand(right, options, right_max);
+            let op = atom_text(functor, options);
+            let text = if name == "," {
+                format!("{},{}", left, right)
+            } else if name.starts_with(char::is_alphabetic) {
+                format!("{} {} {}", left, op, right)
+            } else {
+                // symbol characters on both sides would read as one atom.
+                let before = match left.chars().last() {
+                    Some(c) if SYMBOL_CHARS.contains(c) => " ",
+                    _ => "",
+                };
+                let after = if is_symbolic(&right) { " " } else { "" };
+                format!("{}{}{}{}{}", left, before, op, after, right)
+            };
+            (priority, text)
+        }
+        ([arg], _, Some((priority, arg_max))) => {
+            let operand = operand(arg, options, arg_max);
+            let op = atom_text(functor, options);
+            // `- 1` is the compound, `-1` the number.
+            let number = matches!(arg, Term::Const(Constant::Int(_)))
+                || matches!(arg, Term::Const(Constant::Rational(_)))
+                || matches!(arg, Term::Const(Constant::Float(_)));
+            let space = name.starts_with(char::is_alphabetic) || is_symbolic(&operand) || number;
+            let text = match space {
+                true => format!("{} {}", op, operand),
+                false => format!("{}{}", op, operand),
+            };
+            (priority, text)
+        }
+        _ => return None,
+    };
+    Some(match priority > max {
+        true => format!("({})", text),
+        false => text,
+    })
+}
+
+// an argument of an operator. An atom that is an operator itself is put in
+// parentheses, as in `(=)/2`, so that the text reads back.
+fn operand(term: &Term, options: WriteOptions, max: u32) -> String {
+    match term {
+        Term::Const(Constant::Name(name))
+            if infix_op(name.name()).is_some() || prefix_op(name.name()).is_some() =>
+        {
+            format!("({})", atom_text(*name, options))
+        }
+        _ => write_term(term, options, max),
+    }
+}
+
+// the text of an atom, a string, a number, or a list of codes or chars.
+pub(crate) fn text_of(term: &Term) -> Option<String> {
+    match term {
+        Term::Const(Constant::Name(name)) if name.name() == "[]" => Some(String::new()),
+        Term::Const(Constant::Name(name)) => Some(name.name().to_string()),
+        Term::Const(Constant::Str(text)) => Some(text.clone()),
+        Term::Const(_) => Some(term.to_string()),
+        Term::Combined { .. } => {
+            let mut text = String::new();
+            let mut rest = term;
+            loop {
+                match rest {
+                    Term::Combined { functor, args }
+                        if functor.name() == "." && args.len() == 2 =>
+                    {
+                        text.push(match &args[0] {
+                            Term::Const(Constant::Int(code)) => {
+                                char::from_u32(code.to_i64()? as u32)?
+                            }
+                            Term::Const(Constant::Name(c)) if c.name().chars().count() == 1 => {
+                                c.name().chars().next()?
+                            }
+                            _ => return None,
+                        });
+                        rest = &args[1];
+                    }
+                    Term::Const(Constant::Name(name)) if name.name() == "[]" => return Some(text),
+                    _ => return None,
+                }
+            }
+        }
+        Term::Var(_) => None,
+    }
+}
+
+// the text `format/2` writes for `directives` with `args`, the output
+// starting at `column`.
+pub fn format(directives: &str, args: &[Term], column: usize) -> Result<String, Exception> {
+    let mut out = String::new();
+    let mut args = args.iter();
+    // where the current line starts in `out`, and the column of the last
+    // column stop.
+    let mut line_start = 0;
+    let mut stop = column;
+    // the fill points since the last column stop, as byte offsets in `out`
+    // and the character to fill with.
+    let mut fills: Vec<(usize, char)> = Vec::new();
+    let mut chars = directives.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            if c == '\n' {
+                line_start = out.len();
+                stop = 0;
+                fills.clear();
+            }
+            continue;
+        }
+        // the numeric argument: digits, `*` for the next argument or a
+        // backquote and the character itself.
+        let mut numeric: Option<usize> = None;
+        match chars.peek() {
+            Some('*') => {
+                chars.next();
+                numeric = match args.next() {
+                    Some(Term::Const(Constant::Int(n))) => n.to_i64().map(|n| n.max(0) as usize),
+                    Some(_) => {
+                        return Err(Exception::format_error("* expects an integer argument"))
+                    }
+                    None => return Err(Exception::format_error("not enough arguments")),
+                };
+            }
+            Some('`') => {
+                chars.next();
+                numeric = chars.next().map(|c| c as usize);
+            }
+            _ => {
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    chars.next();
+                    numeric = Some(numeric.unwrap_or(0) * 10 + digit as usize);
+                }
+            }
+        }
+        let directive = match chars.next() {
+            Some(directive) => directive,
+            None => return Err(Exception::format_error("truncated format directive")),
+        };
+        let mut next_arg = || {
+            args.next()
+                .ok_or_else(|| Exception::format_error("not enough arguments"))
+        };
+        match directive {
+            '~' => out.push('~'),
+            'w' => out += &term_to_string(next_arg()?, WriteOptions::WRITE),
+            'p' | 'q' => out += &term_to_string(next_arg()?, WriteOptions::WRITEQ),
+            'a' => match next_arg()? {
+                Term::Var(_) => return Err(Exception::instantiation_error()),
+                Term::Combined { .. } => {
+                    return Err(Exception::format_error("~a expects an atomic argument"))
+                }
+                arg => out += &text_of(arg).unwrap_or_default(),
+            },
+            'd' => {
+                let n = match next_arg()? {
+                    Term::Const(Constant::Int(n)) => n.clone(),
+                    Term::Var(_) => return Err(Exception::instantiation_error()),
+                    other => return Err(Exception::type_error("integer", other.clone())),
+                };
+                out += &group_digits(&n, numeric.unwrap_or(0));
+            }
+            's' => match text_of(next_arg()?) {
+                Some(text) => out += &text,
+                None => {
+                    return Err(Exception::format_error(
+                        "~s expects a string or a list of codes",
+                    ))
+                }
+            },
+            'c' => {
+                let code = match next_arg()? {
+                    Term::Const(Constant::Int(n)) => {
+                        n.to_i64().and_then(|n| char::from_u32(n as u32))
+                    }
+                    Term::Var(_) => return Err(Exception::instantiation_error()),
+                    _ => None,
+                };
+                match code {
+                    Some(c) => out.extend(std::iter::repeat_n(c, numeric.unwrap_or(1))),
+                    None => return Err(Exception::format_error("~c expects a character code")),
+                }
+            }
+            'e' | 'f' | 'g' => {
+                let x = match next_arg()? {
+                    Term::Const(Constant::Int(n)) => n.to_f64(),
+                    Term::Const(Constant::Rational(r)) => r.to_f64(),
+                    Term::Const(Constant::Float(x)) => *x,
+                    Term::Var(_) => return Err(Exception::instantiation_error()),
+                    other => return Err(Exception::type_error("number", other.clone())),
+                };
+                let digits = numeric.unwrap_or(6);
+                out += &match directive {
+                    'e' => exponent(x, digits),
+                    'f' => format!("{:.*}", digits, x),
+                    _ => format!("{}", x),
+                };
+            }
+            'n' => {
+                for _ in 0..numeric.unwrap_or(1) {
+                    out.push('\n');
+                }
+                line_start = out.len();
+                stop = 0;
+                fills.clear();
+            }
+            't' => fills.push((
+                out.len(),
+                numeric
+                    .and_then(|c| char::from_u32(c as u32))
+                    .unwrap_or(' '),
+            )),
+            '|' | '+' => {
+                let current = column_of(&out[line_start..], line_start, column);
+                let target = match directive {
+                    '|' => numeric.unwrap_or(current),
+                    _ => stop + numeric.unwrap_or(8),
+                };
+                if current < target {
+                    let pad = target - current;
+                    if fills.is_empty() {
+                        // text is left aligned unless a fill point says
+                        // otherwise.
+                        fills.push((out.len(), ' '));
+                    }
+                    // the padding is shared between the fill points, the
+                    // last ones taking what is left.
+                    let n = fills.len();
+                    for (i, (at, fill)) in fills.iter().enumerate().rev() {
+                        let share = pad / n + usize::from(i >= n - pad % n);
+                        let padding: String = std::iter::repeat_n(*fill, share).collect();
+                        out.insert_str(*at, &padding);
+                    }
+                }
+                stop = target;
+                fills.clear();
+            }
+            other => {
+                return Err(Exception::format_error(&format!(
+                    "unknown directive ~{}",
+                    other
+                )));
+            }
+        }
+    }
+    if args.next().is_some() {
+        return Err(Exception::format_error("too many arguments"));
+    }
+    Ok(out)
+}
+
+// the column reached after `line`, which starts at column `column` if it is
+// the first line of the output.
+fn column_of(line: &str, line_start: usize, column: usize) -> usize {
+    let start = if line_start == 0 { column } else { 0 };
+    start + line.chars().count()
+}
+
+// `n` with a decimal point inserted `decimals` digits from the right.
+fn group_digits(n: &BigInt, decimals: usize) -> String {
+    if decimals == 0 {
+        return n.to_string();
+    }
+    let text = n.to_string();
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", text.as_str()),
+    };
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    format!("{}{}.{}", sign, whole, fraction)
+}
+
+// `x` in the exponent notation of C's `%e`, such as `1.500000e+02`.
+fn exponent(x: f64, digits: usize) -> String {
+    let text = format!("{:.*e}", digits, x);
+    match text.split_once('e') {
+        Some((mantissa, exp)) => {
+            let (sign, exp) = match exp.strip_prefix('-') {
+                Some(exp) => ('-', exp),
+                None => ('+', exp),
+            };
+            format!("{}e{}{:0>2}", mantissa, sign, exp)
+        }
+        None => text,
+    }
+}
+
+// `text` as the argument of an `atom(A)`, `string(S)`, `codes(Cs)` or
+// `chars(Cs)` sink of kind `kind`, if it is one.
+pub(crate) fn text_value(app: &mut App, kind: &str, text: &str) -> Option<Cell> {
+    Some(match kind {
+        "atom" => Cell::Atom(Atom::new(text)),
+        "string" => Cell::Str(text.into()),
+        "codes" | "chars" => {
+            let items = text
+                .chars()
+                .map(|c| match kind {
+                    "codes" => Cell::Int(BigInt::from(c as i64).into()),
+                    _ => Cell::Atom(Atom::new(&c.to_string())),
+                })
+                .collect();
+            crate::coroutine::list(&mut app.store, items)
+        }
+        _ => return None,
+    })
+}
+
+// the stream written to: the first of `args` if there are `arity + 1` of
+// them, else the current output.
+fn output_of(app: &App, args: &[Cell], arity: usize) -> Result<usize, Exception> {
+    match args.len() > arity {
+        true => app.output_stream(&args[0]),
+        false => Ok(app.streams.output),
+    }
+}
+
+// write/1,2, print/1,2, writeq/1,2 and write_canonical/1,2
+pub(crate) fn write(
+    app: &mut App,
+    args: &[Cell],
+    options: WriteOptions,
+) -> Result<Vec<Branch>, Exception> {
+    let id = output_of(app, args, 1)?;
+    let text = term_to_string(&app.store.to_term(&args[args.len() - 1]), options);
+    app.write_to(id, &text)?;
+    Ok(succeed(app))
+}
+
+// nl/0,1
+pub(crate) fn nl(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let id = output_of(app, args, 0)?;
+    app.write_to(id, "\n")?;
+    Ok(succeed(app))
+}
+
+// the directives and arguments of `format/2,3`. Arguments that are not a
+// list stand for the list of that one argument.
+fn format_args(app: &App, args: &[Cell]) -> Result<(String, Vec<Term>), Exception> {
+    let directives = match app.store.to_term(&args[0]) {
+        Term::Var(_) => return Err(Exception::instantiation_error()),
+        term => text_of(&term).ok_or_else(|| Exception::type_error("text", term))?,
+    };
+    let mut items = Vec::new();
+    let mut rest = app.store.to_term(&args[1]);
+    loop {
+        match rest {
+            Term::Combined {
+                functor,
+                ref mut args,
+            } if functor.name() == "." && args.len() == 2 => {
+                let tail = args.pop().unwrap();
+                items.push(args.pop().unwrap());
+                rest = tail;
+            }
+            Term::Const(Constant::Name(name)) if name.name() == "[]" => break,
+            other if items.is_empty() => {
+                items.push(other);
+                break;
+            }
+            other => return Err(Exception::type_error("list", other)),
+        }
+    }
+    Ok((directives, items))
+}
+
+// format(Format, Args)
+pub(crate) fn format2(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let (directives, items) = format_args(app, args)?;
+    let id = app.streams.output;
+    let text = format(&directives, &items, app.column(id))?;
+    app.write_to(id, &text)?;
+    Ok(succeed(app))
+}
+
+// format(Output, Format, Args), where `Output` is `atom(A)`, `string(S)`,
+// `codes(Cs)` or `chars(Cs)` to get the text, or a stream.
+pub(crate) fn format3(app: &mut App, args: &[Cell]) -> Result<Vec<Branch>, Exception> {
+    let (directives, items) = format_args(app, &args[1..])?;
+    let sink = app.store.deref(&args[0]);
+    match app.store.key(&sink) {
+        Some((kind, 1)) if kind.name() != "$stream" => {
+            let text = format(&directives, &items, 0)?;
+            let value = match text_value(app, kind.name(), &text) {
+                Some(value) => value,
+                None => {
+                    return Err(Exception::domain_error(
+                        "output_sink",
+                        app.store.to_term(&sink),
+                    ))
+                }
+            };
+            let arg = app.store.args(&sink)[0].clone();
+            if !app.store.unify(&arg, &value) {
+                return Ok(fail());
+            }
+        }
+        _ => {
+            let id = app.output_stream(&sink)?;
+            let text = format(&directives, &items, app.column(id))?;
+            app.write_to(id, &text)?;
+        }
+    }
+    Ok(succeed(app))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Query;
+    use crate::parser::parse_query;
+
+    fn term(text: &str) -> Term {
+        match parse_query(&format!("{}.", text)) {
+            Ok(Query::Terms(mut goals)) => goals.remove(0),
+            _ => panic!("not a term: {}", text),
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let cases = [
+            ("f(X, 'A b', \"s\", [1, 2|T])", "f(X,A b,s,[1,2|T])"),
+            ("a :- b, c ; d", "a:-b,c;d"),
+            ("X is 1 + 2 * 3", "X is 1+2*3"),
+            ("(1 + 2) * 3 - -1", "(1+2)*3- -1"),
+            ("- (1)", "- 1"),
+            ("- a", "-a"),
+            ("\\+ \\+ a", "\\+ \\+a"),
+            ("f((a, b), (c :- d))", "f((a,b),(c:-d))"),
+            ("{a, b}", "{a,b}"),
+            ("'$VAR'(1) = '$VAR'(27)", "B=B1"),
+        ];
+        for (text, written) in cases.iter() {
+            assert_eq!(term_to_string(&term(text), WriteOptions::WRITE), *written);
+        }
+        let t = term("f('A b', \"it's\", [], '[]', -(-(1)), a- (-1))");
+        assert_eq!(
+            term_to_string(&t, WriteOptions::WRITEQ),
+            "f('A b',\"it's\",[],[],- - 1,a- -1)"
+        );
+        assert_eq!(
+            term_to_string(&term("[a|b] = 'B' + 1"), WriteOptions::CANONICAL),
+            "=([a|b],+('B',1))"
+        );
+        let t = term("f((=)/2, -(-), -)");
+        assert_eq!(term_to_string(&t, WriteOptions::WRITEQ), "f((=)/2,-(-),-)");
+    }
+
+    #[test]
+    fn test_format() {
+        // the items of a list, read as the argument of a term since a list
+        // alone is read as files to consult.
+        let args = |text: &str| {
+            let mut items = Vec::new();
+            let mut rest = match term(&format!("f({})", text)) {
+                Term::Combined { ref mut args, .. } => args.remove(0),
+                _ => unreachable!(),
+            };
+            while let Term::Combined { ref mut args, .. } = rest {
+                let tail = args.pop().unwrap();
+                items.push(args.pop().unwrap());
+                rest = tail;
+            }
+            items
+        };
+        let cases = [
+            ("~w and ~q~n", "['a b', 'a b']", "a b and 'a b'\n"),
+            ("~a~~~d ~2d", "[abc, 42, 314]", "abc~42 3.14"),
+            ("~s!", "[\"hi\"]", "hi!"),
+            ("~e ~4f ~0f", "[1.5, 2, 2.5]", "1.500000e+00 2.0000 2"),
+            ("~w~10|~w", "[ab, c]", "ab        c"),
+            ("~t~w~10|~w", "[ab, c]", "        abc"),
+            ("~t~w~t~6|", "[ab]", "  ab  "),
+            ("~`-t~30|", "[]", "------------------------------"),
+            ("~w~t~5+~w~t~5+|", "[a, b]", "a    b    |"),
+            ("~3c", "[120]", "xxx"),
+        ];
+        for (directives, arguments, text) in cases.iter() {
+            assert_eq!(format(directives, &args(arguments), 0).unwrap(), *text);
+        }
+        assert!(format("~w ~w", &args("[a]"), 0).is_err());
+        assert!(format("~d", &args("[a]"), 0).is_err());
+    }
+}